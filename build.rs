@@ -0,0 +1,22 @@
+// Generates the C header of the API in src/ffi.rs to $OUT_DIR/videocoreiv_sim.h, and also to
+// the path in VIDEOCOREIV_SIM_HEADER if it is set.
+
+fn main() {
+    println!("cargo:rerun-if-changed=src/ffi.rs");
+    println!("cargo:rerun-if-changed=cbindgen.toml");
+    println!("cargo:rerun-if-env-changed=VIDEOCOREIV_SIM_HEADER");
+
+    let crate_dir = std::env::var("CARGO_MANIFEST_DIR").unwrap();
+    let out_dir = std::env::var("OUT_DIR").unwrap();
+    let config = cbindgen::Config::from_file(format!("{}/cbindgen.toml", crate_dir)).unwrap();
+
+    let bindings = cbindgen::Builder::new()
+        .with_config(config)
+        .with_src(format!("{}/src/ffi.rs", crate_dir))
+        .generate()
+        .expect("Unable to generate the C header.");
+    bindings.write_to_file(format!("{}/videocoreiv_sim.h", out_dir));
+    if let Ok(path) = std::env::var("VIDEOCOREIV_SIM_HEADER") {
+        bindings.write_to_file(path);
+    }
+}
@@ -0,0 +1,11 @@
+# copy.bin copies 16 words with a VPM DMA load and store, from the first uniform to the second.
+program copy.bin
+threads 2
+
+buffer in f32[32] random 1 -100.0 100.0
+buffer out f32[32]
+
+uniforms @in @out
+uniforms @in+64 @out+64
+
+expect out f32 initial in
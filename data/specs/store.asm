@@ -0,0 +1,18 @@
+# Stores the first uniform, plus one, to the address given by the second uniform.
+# The same program as store.bin.
+
+# VPM setup for writing one horizontal 32-bit vector at row 0.
+VPM_SETUP = 0x1a00
+# DMA store setup: units 1, depth 1, horizontal.
+DMA_SETUP = 0x80000000 | (1 << 23) | (1 << 16) | (1 << 14)
+
+one = r0
+
+    ldi(vpmvcd_wr_setup, VPM_SETUP)
+    ldi(one, 1)
+    iadd(vpm, uniform, one)
+    ldi(vpmvcd_wr_setup, DMA_SETUP)
+    mov(vpm_st_addr, uniform)
+    nop(sig='thread end')
+    nop()
+    nop()
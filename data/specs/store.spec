@@ -0,0 +1,11 @@
+# store.bin stores the first uniform, plus one, to the address given by the second uniform.
+program store.bin
+threads 3
+
+buffer out u32[4] = 7 7 7 7
+
+uniforms 41 @out
+uniforms %thread @out+4
+uniforms 1.5 @out+8
+
+expect out u32 = 42 2 0x3fc00001 7
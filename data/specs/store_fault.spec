@@ -0,0 +1,7 @@
+# The store is out of the memory.
+program store.bin
+mem_size 0x1000
+
+uniforms 0 0x1000
+
+expect fault
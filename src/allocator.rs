@@ -0,0 +1,129 @@
+use std::collections::BTreeMap;
+
+// First-fit allocator for a range of GPU memory.
+pub struct Allocator {
+    free: Vec<(usize, usize)>, // (address, size) sorted by address.
+    allocated: BTreeMap<usize, usize>,
+}
+
+impl Allocator {
+    pub fn new(base: usize, size: usize) -> Self {
+        Allocator {
+            free: if size > 0 { vec![(base, size)] } else { vec![] },
+            allocated: BTreeMap::new(),
+        }
+    }
+
+    // Adds a range which does not overlap the existing ones.
+    pub fn add_range(&mut self, base: usize, size: usize) {
+        if size > 0 {
+            let idx = self.free.iter().position(|&(free_addr, _)| free_addr > base).unwrap_or(self.free.len());
+            self.free.insert(idx, (base, size));
+        }
+    }
+
+    // Returns the address of a new block, or None if no free range is large enough.
+    // The alignment must be a power of two.
+    pub fn alloc(&mut self, size: usize, align: usize) -> Option<usize> {
+        if !align.is_power_of_two() {
+            panic!("The alignment is not a power of two.");
+        }
+        let size = size.max(1);
+
+        for idx in 0..self.free.len() {
+            let (free_addr, free_size) = self.free[idx];
+            let addr = (free_addr + align - 1) & !(align - 1);
+            let padding = addr - free_addr;
+
+            if padding + size > free_size {
+                continue;
+            }
+
+            self.free.remove(idx);
+            if padding + size < free_size {
+                self.free.insert(idx, (addr + size, free_size - padding - size));
+            }
+            if padding > 0 {
+                self.free.insert(idx, (free_addr, padding));
+            }
+
+            self.allocated.insert(addr, size);
+            return Some(addr);
+        }
+        None
+    }
+
+    // Allocates the block at addr, which must lie within a single free range.
+    pub fn alloc_at(&mut self, addr: usize, size: usize) -> bool {
+        let size = size.max(1);
+        let idx = match self.free.iter().position(|&(free_addr, free_size)| free_addr <= addr && addr + size <= free_addr + free_size) {
+            Some(idx) => idx,
+            None => return false,
+        };
+
+        let (free_addr, free_size) = self.free.remove(idx);
+        if addr + size < free_addr + free_size {
+            self.free.insert(idx, (addr + size, free_addr + free_size - addr - size));
+        }
+        if addr > free_addr {
+            self.free.insert(idx, (free_addr, addr - free_addr));
+        }
+
+        self.allocated.insert(addr, size);
+        true
+    }
+
+    // Releases the block at addr. Returns false if it was not allocated.
+    pub fn free(&mut self, addr: usize) -> bool {
+        let size = match self.allocated.remove(&addr) {
+            Some(size) => size,
+            None => return false,
+        };
+
+        let idx = self.free.iter().position(|&(free_addr, _)| free_addr > addr).unwrap_or(self.free.len());
+        self.free.insert(idx, (addr, size));
+
+        // Merge with the following and the preceding ranges.
+        if idx + 1 < self.free.len() && self.free[idx].0 + self.free[idx].1 == self.free[idx + 1].0 {
+            self.free[idx].1 += self.free[idx + 1].1;
+            self.free.remove(idx + 1);
+        }
+        if idx > 0 && self.free[idx - 1].0 + self.free[idx - 1].1 == self.free[idx].0 {
+            self.free[idx - 1].1 += self.free[idx].1;
+            self.free.remove(idx);
+        }
+        true
+    }
+
+    pub fn size_of(&self, addr: usize) -> Option<usize> {
+        self.allocated.get(&addr).copied()
+    }
+}
+
+#[test]
+fn test_allocator() {
+    let mut allocator = Allocator::new(0x100, 0x1000);
+
+    let a = allocator.alloc(0x10, 0x100).unwrap();
+    let b = allocator.alloc(0x200, 0x800).unwrap();
+    let c = allocator.alloc(0x10, 4).unwrap();
+    assert_eq!((a, b, c), (0x100, 0x800, 0x110));
+    assert_eq!(allocator.alloc(0x1000, 4), None);
+
+    assert!(allocator.free(b));
+    assert!(!allocator.free(b));
+    assert!(allocator.free(a));
+    assert!(allocator.free(c));
+
+    // Everything is merged back into one range.
+    assert_eq!(allocator.alloc(0x1000, 0x100), Some(0x100));
+    assert!(allocator.free(0x100));
+
+    assert!(allocator.alloc_at(0x200, 0x10));
+    assert!(!allocator.alloc_at(0x208, 4));
+    assert!(!allocator.alloc_at(0x1000, 0x200));
+    assert_eq!(allocator.alloc(0x100, 0x100), Some(0x100));
+    assert_eq!(allocator.alloc(0x10, 0x100), Some(0x300));
+    assert!(allocator.free(0x200));
+    assert_eq!(allocator.alloc(0x100, 0x100), Some(0x200));
+}
@@ -0,0 +1,929 @@
+// Assembler for the syntax of py-videocore, which is also the output of the disassembler, e.g.
+//
+//   # comment
+//   STRIDE = 64                      constant
+//   acc = r1                         register alias
+//   .equ COUNT, 4                    constant
+//   L.loop                           label, or loop:
+//       fadd(r0, ra1, r2, cond='zs', set_flags=True).fmul(rb3, r4, r5)
+//       iadd(acc, acc, -1)           small immediate operand
+//       ldi(r1, STRIDE * COUNT)
+//       sema_up(3)
+//       jzc(L.loop)                  same as bra(L.loop, cond='zc')
+//       .dword 0x100009e7009e7000    raw instruction word
+//
+// An instruction is one call, or an add and a mul call chained with '.'. A single call runs
+// on the mul ALU for fmul, mul24, v8muld, v8min and v8max and on the add ALU otherwise.
+// Expressions support the integer and float operators of Python, and labels evaluate to
+// addresses. Relative branches to a label are encoded as the offset from the branch + 32.
+
+use crate::constants::*;
+use crate::disassembler::{read_name, write_name, ADD_OPS, BRANCH_CONDS, CONDS, MUL_OPS, MUL_PACKS, PACKS, SIGNALS, UNPACKS};
+use crate::instructions::*;
+use crate::utils::*;
+
+use std::collections::HashMap;
+
+const MUL_ONLY_OPS: [&str; 5] = ["fmul", "mul24", "v8muld", "v8min", "v8max"];
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Ident(String),
+    Int(i128),
+    Float(f64),
+    Str(String),
+    Punct(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Int(i128),
+    Float(f64),
+    Str(String),
+    Bool(bool),
+    Label(u32),
+    Reg(String),
+    List(Vec<Value>),
+}
+
+const PUNCTS: [&str; 17] = ["<<", ">>", "(", ")", "[", "]", ",", "=", ".", "+", "-", "*", "/", "%", "&", "|", "^"];
+
+fn tokenize(line: &str) -> Result<Vec<Token>, String> {
+    let chars: Vec<char> = line.chars().collect();
+    let mut tokens = vec![];
+    let mut pos = 0;
+    while pos < chars.len() {
+        let c = chars[pos];
+        let start = pos;
+        if c == '#' {
+            break;
+        } else if c.is_whitespace() {
+            pos += 1;
+        } else if c.is_ascii_alphabetic() || c == '_' {
+            while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '_') {
+                pos += 1;
+            }
+            tokens.push(Token::Ident(chars[start..pos].iter().collect()));
+        } else if c.is_ascii_digit() {
+            while pos < chars.len() && (chars[pos].is_ascii_alphanumeric() || chars[pos] == '.'
+                || ((chars[pos] == '+' || chars[pos] == '-') && (chars[pos - 1] == 'e' || chars[pos - 1] == 'E') && !chars[start..pos].contains(&'x'))) {
+                pos += 1;
+            }
+            let s: String = chars[start..pos].iter().collect();
+            tokens.push(parse_number(&s)?);
+        } else if c == '\'' || c == '"' {
+            pos += 1;
+            while pos < chars.len() && chars[pos] != c {
+                pos += 1;
+            }
+            if pos == chars.len() {
+                return Err("The string is not terminated.".to_string());
+            }
+            pos += 1;
+            tokens.push(Token::Str(chars[start + 1..pos - 1].iter().collect()));
+        } else {
+            let rest: String = chars[pos..chars.len().min(pos + 2)].iter().collect();
+            match PUNCTS.iter().chain(&["~", ":"]).find(|punct| rest.starts_with(*punct)) {
+                Some(punct) => {
+                    pos += punct.len();
+                    tokens.push(Token::Punct(punct));
+                }
+                None => return Err(format!("Unexpected character '{}'.", c)),
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+fn parse_number(s: &str) -> Result<Token, String> {
+    let lower = s.to_lowercase();
+    let int = if let Some(hex) = lower.strip_prefix("0x") {
+        i128::from_str_radix(hex, 16).ok()
+    } else if let Some(bin) = lower.strip_prefix("0b") {
+        i128::from_str_radix(bin, 2).ok()
+    } else {
+        lower.parse().ok()
+    };
+    match (int, s.parse()) {
+        (Some(int), _) => Ok(Token::Int(int)),
+        (None, Ok(float)) => Ok(Token::Float(float)),
+        _ => Err(format!("Invalid number '{}'.", s)),
+    }
+}
+
+// A call of an operation, e.g. fadd(r0, r1, r2, cond='zs').
+struct Call {
+    name: String,
+    args: Vec<Value>,
+    kwargs: Vec<(String, Value)>,
+}
+
+impl Call {
+    fn check_kwargs(&self, allowed: &[&str]) -> Result<(), String> {
+        match self.kwargs.iter().find(|(name, _)| !allowed.contains(&name.as_str())) {
+            Some((name, _)) => Err(format!("Unknown keyword argument '{}' of {}.", name, self.name)),
+            None => Ok(()),
+        }
+    }
+
+    fn kwarg(&self, name: &str) -> Option<&Value> {
+        self.kwargs.iter().rev().find(|(kwarg, _)| kwarg == name).map(|(_, value)| value)
+    }
+
+    fn check_num_args(&self, num_args: usize) -> Result<(), String> {
+        if self.args.len() != num_args {
+            return Err(format!("{} takes {} arguments, but {} are given.", self.name, num_args, self.args.len()));
+        }
+        Ok(())
+    }
+}
+
+enum Statement {
+    Label,
+    Constant(Vec<Token>),
+    Word(Vec<Token>),
+    Inst(Vec<Token>),
+}
+
+struct Assembler {
+    symbols: HashMap<String, Value>,
+    labels: HashMap<String, u32>,
+}
+
+struct Parser<'a> {
+    asm: &'a Assembler,
+    tokens: &'a [Token],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self) -> Option<&'a Token> {
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+
+    fn accept(&mut self, punct: &str) -> bool {
+        if self.peek() == Some(&Token::Punct(punct_str(punct))) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, punct: &str) -> Result<(), String> {
+        if !self.accept(punct) {
+            return Err(format!("Expected '{}'.", punct));
+        }
+        Ok(())
+    }
+
+    fn expect_end(&self) -> Result<(), String> {
+        if self.pos != self.tokens.len() {
+            return Err("Unexpected tokens at the end of the line.".to_string());
+        }
+        Ok(())
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        match self.next() {
+            Some(Token::Ident(name)) => Ok(name.clone()),
+            _ => Err("Expected a name.".to_string()),
+        }
+    }
+
+    // Parses op(args).op(args)...
+    fn calls(&mut self) -> Result<Vec<Call>, String> {
+        let mut calls = vec![];
+        loop {
+            let name = self.ident()?;
+            self.expect("(")?;
+            let mut call = Call { name, args: vec![], kwargs: vec![] };
+            while !self.accept(")") {
+                if !call.args.is_empty() || !call.kwargs.is_empty() {
+                    self.expect(",")?;
+                    if self.accept(")") {
+                        break;
+                    }
+                }
+                match (self.peek(), self.tokens.get(self.pos + 1)) {
+                    (Some(Token::Ident(name)), Some(Token::Punct("="))) => {
+                        self.pos += 2;
+                        call.kwargs.push((name.clone(), self.expr()?));
+                    }
+                    _ if !call.kwargs.is_empty() => return Err("A positional argument follows a keyword argument.".to_string()),
+                    _ => call.args.push(self.expr()?),
+                }
+            }
+            calls.push(call);
+            if !self.accept(".") {
+                break;
+            }
+        }
+        self.expect_end()?;
+        Ok(calls)
+    }
+
+    fn expr(&mut self) -> Result<Value, String> {
+        self.binary(0)
+    }
+
+    // Binary operators by increasing precedence, as in Python.
+    fn binary(&mut self, level: usize) -> Result<Value, String> {
+        const LEVELS: [&[&str]; 6] = [&["|"], &["^"], &["&"], &["<<", ">>"], &["+", "-"], &["*", "/", "%"]];
+        if level == LEVELS.len() {
+            return self.unary();
+        }
+        let mut lhs = self.binary(level + 1)?;
+        while let Some(Token::Punct(op)) = self.peek() {
+            if !LEVELS[level].contains(op) {
+                break;
+            }
+            self.pos += 1;
+            let rhs = self.binary(level + 1)?;
+            lhs = binary_op(op, lhs, rhs)?;
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Value, String> {
+        if self.accept("-") {
+            match self.unary()? {
+                Value::Int(val) => Ok(Value::Int(val.wrapping_neg())),
+                Value::Float(val) => Ok(Value::Float(-val)),
+                _ => Err("Invalid operand of '-'.".to_string()),
+            }
+        } else if self.accept("~") {
+            match self.unary()? {
+                Value::Int(val) => Ok(Value::Int(!val)),
+                _ => Err("Invalid operand of '~'.".to_string()),
+            }
+        } else if self.accept("+") {
+            self.unary()
+        } else {
+            self.atom()
+        }
+    }
+
+    fn atom(&mut self) -> Result<Value, String> {
+        match self.next() {
+            Some(Token::Int(val)) => Ok(Value::Int(*val)),
+            Some(Token::Float(val)) => Ok(Value::Float(*val)),
+            Some(Token::Str(s)) => Ok(Value::Str(s.clone())),
+            Some(Token::Punct("(")) => {
+                let value = self.expr()?;
+                self.expect(")")?;
+                Ok(value)
+            }
+            Some(Token::Punct("[")) => {
+                let mut values = vec![];
+                while !self.accept("]") {
+                    if !values.is_empty() {
+                        self.expect(",")?;
+                    }
+                    values.push(self.expr()?);
+                }
+                Ok(Value::List(values))
+            }
+            Some(Token::Ident(name)) if name == "L" && self.accept(".") => {
+                let label = self.ident()?;
+                self.asm.labels.get(&label).map(|addr| Value::Label(*addr)).ok_or(format!("Unknown label '{}'.", label))
+            }
+            Some(Token::Ident(name)) => Ok(match name.as_str() {
+                "True" => Value::Bool(true),
+                "False" => Value::Bool(false),
+                _ => match (self.asm.symbols.get(name), self.asm.labels.get(name)) {
+                    (Some(value), _) => value.clone(),
+                    (None, Some(addr)) => Value::Label(*addr),
+                    (None, None) => Value::Reg(name.clone()),
+                },
+            }),
+            _ => Err("Expected an expression.".to_string()),
+        }
+    }
+}
+
+// Tokens hold the punctuation as static strings.
+fn punct_str(punct: &str) -> &'static str {
+    PUNCTS.iter().chain(&["~", ":"]).find(|p| **p == punct).unwrap()
+}
+
+fn binary_op(op: &str, lhs: Value, rhs: Value) -> Result<Value, String> {
+    use Value::*;
+    let value = match (op, lhs, rhs) {
+        ("/", Int(_), Int(0)) | ("%", Int(_), Int(0)) => return Err("Division by zero.".to_string()),
+        ("+", Int(a), Int(b)) => Int(a.wrapping_add(b)),
+        ("-", Int(a), Int(b)) => Int(a.wrapping_sub(b)),
+        ("*", Int(a), Int(b)) => Int(a.wrapping_mul(b)),
+        ("/", Int(a), Int(b)) => Int(a.div_euclid(b)),
+        ("%", Int(a), Int(b)) => Int(a.rem_euclid(b)),
+        ("<<", Int(a), Int(b)) => Int(a.wrapping_shl(b as u32)),
+        (">>", Int(a), Int(b)) => Int(a.wrapping_shr(b as u32)),
+        ("&", Int(a), Int(b)) => Int(a & b),
+        ("|", Int(a), Int(b)) => Int(a | b),
+        ("^", Int(a), Int(b)) => Int(a ^ b),
+        ("+", Label(a), Int(b)) => Label(a.wrapping_add(b as u32)),
+        ("-", Label(a), Int(b)) => Label(a.wrapping_sub(b as u32)),
+        ("-", Label(a), Label(b)) => Int(a as i128 - b as i128),
+        (op, Int(a), Float(b)) => return binary_op(op, Float(a as f64), Float(b)),
+        (op, Float(a), Int(b)) => return binary_op(op, Float(a), Float(b as f64)),
+        ("+", Float(a), Float(b)) => Float(a + b),
+        ("-", Float(a), Float(b)) => Float(a - b),
+        ("*", Float(a), Float(b)) => Float(a * b),
+        ("/", Float(a), Float(b)) => Float(a / b),
+        (op, _, _) => return Err(format!("Invalid operands of '{}'.", op)),
+    };
+    Ok(value)
+}
+
+fn reg_name(value: &Value) -> Result<&str, String> {
+    match value {
+        Value::Reg(name) => Ok(name),
+        _ => Err("Expected a register.".to_string()),
+    }
+}
+
+fn read_addr(name: &str, regfile_b: bool) -> Option<u8> {
+    (0..64).find(|raddr| read_name(*raddr, regfile_b) == name)
+}
+
+fn write_addr(name: &str, regfile_b: bool) -> Option<u8> {
+    (0..64).find(|waddr| write_name(*waddr, regfile_b) == name)
+}
+
+// Chooses the write swap so that the add and mul results go to the given registers.
+fn write_fields(add_dst: &str, mul_dst: &str) -> Result<(u8, u8, u8), String> {
+    for dst in [add_dst, mul_dst] {
+        if write_addr(dst, false).is_none() && write_addr(dst, true).is_none() {
+            return Err(format!("Unknown destination register '{}'.", dst));
+        }
+    }
+    for ws in 0..2 {
+        if let (Some(waddr_add), Some(waddr_mul)) = (write_addr(add_dst, ws != 0), write_addr(mul_dst, ws == 0)) {
+            return Ok((ws, waddr_add, waddr_mul));
+        }
+    }
+    Err(format!("The registers {} and {} can't be written by one instruction.", add_dst, mul_dst))
+}
+
+fn table_value(value: &Value, table: &[&str], what: &str) -> Result<u8, String> {
+    match value {
+        Value::Str(name) if !name.is_empty() => {
+            table.iter().position(|entry| entry == name).map(|idx| idx as u8).ok_or(format!("Unknown {} '{}'.", what, name))
+        }
+        Value::Int(val) if (0..=255).contains(val) => Ok(*val as u8),
+        _ => Err(format!("Invalid {}.", what)),
+    }
+}
+
+fn cond_value(value: Option<&Value>, default: u8) -> Result<u8, String> {
+    match value {
+        Some(Value::Str(name)) if name == "always" => Ok(COND_ALWAYS),
+        Some(value) => table_value(value, &CONDS, "condition"),
+        None => Ok(default),
+    }
+}
+
+fn bool_value(value: Option<&Value>) -> Result<u8, String> {
+    match value {
+        None | Some(Value::Bool(false)) | Some(Value::Int(0)) => Ok(0),
+        Some(Value::Bool(true)) | Some(Value::Int(1)) => Ok(1),
+        _ => Err("Expected True or False.".to_string()),
+    }
+}
+
+fn pack_value(value: Option<&Value>, pm: u8) -> Result<u8, String> {
+    match value {
+        Some(value) => table_value(value, if pm != 0 { &MUL_PACKS } else { &PACKS }, "pack"),
+        None => Ok(0),
+    }
+}
+
+fn u32_value(value: &Value) -> Result<u32, String> {
+    match value {
+        Value::Int(val) if (i32::MIN as i128..=u32::MAX as i128).contains(val) => Ok(*val as u32),
+        Value::Float(val) => Ok(f32_to_u32(*val as f32)),
+        Value::Label(addr) => Ok(*addr),
+        _ => Err("Expected a 32-bit value.".to_string()),
+    }
+}
+
+// Code of the small immediate with the given value.
+fn small_imm_code(value: &Value) -> Option<u8> {
+    match value {
+        Value::Int(val) if (0..16).contains(val) => Some(*val as u8),
+        Value::Int(val) if (-16..0).contains(val) => Some((*val + 32) as u8),
+        Value::Float(val) => (0..8).find(|exp| *val == (1u32 << exp) as f64).map(|exp| 32 + exp)
+            .or_else(|| (1..9).find(|exp| *val == 1.0 / (1u32 << exp) as f64).map(|exp| 48 - exp)),
+        _ => None,
+    }
+}
+
+// Read ports of an ALU instruction, which are shared by the add and mul parts.
+struct ReadPorts {
+    raddr_a: Option<u8>,
+    raddr_b: Option<u8>,
+    small_imm: Option<u8>,
+}
+
+impl ReadPorts {
+    fn use_a(&mut self, raddr: u8) -> Result<u8, String> {
+        match self.raddr_a {
+            Some(used) if used != raddr => Err("Two registers of regfile A are read.".to_string()),
+            _ => {
+                self.raddr_a = Some(raddr);
+                Ok(ALU_SRC_RA)
+            }
+        }
+    }
+
+    fn use_b(&mut self, raddr: u8) -> Result<u8, String> {
+        match (self.raddr_b, self.small_imm) {
+            (_, Some(_)) => Err("A register of regfile B is read with a small immediate.".to_string()),
+            (Some(used), _) if used != raddr => Err("Two registers of regfile B are read.".to_string()),
+            _ => {
+                self.raddr_b = Some(raddr);
+                Ok(ALU_SRC_RB)
+            }
+        }
+    }
+
+    fn use_small_imm(&mut self, code: u8) -> Result<u8, String> {
+        match (self.raddr_b, self.small_imm) {
+            (Some(_), _) => Err("A register of regfile B is read with a small immediate.".to_string()),
+            (_, Some(used)) if used != code => Err("Two small immediates are used.".to_string()),
+            _ => {
+                self.small_imm = Some(code);
+                Ok(ALU_SRC_RB)
+            }
+        }
+    }
+
+    // Assigns the sources to the input muxes. Registers which are only in one regfile are
+    // placed first, so that the others can go to the remaining read port.
+    fn assign(&mut self, sources: &[&Value]) -> Result<Vec<u8>, String> {
+        let mut muxes = vec![None; sources.len()];
+        for shared in [false, true] {
+            for (idx, source) in sources.iter().enumerate() {
+                if muxes[idx].is_some() {
+                    continue;
+                }
+                let name = match source {
+                    Value::Reg(name) => name.as_str(),
+                    value => match small_imm_code(value) {
+                        Some(code) => {
+                            muxes[idx] = Some(self.use_small_imm(code)?);
+                            continue;
+                        }
+                        None => return Err("The value can't be encoded as a small immediate.".to_string()),
+                    },
+                };
+                if let Some(acc) = name.strip_prefix('r').and_then(|n| n.parse::<u8>().ok()).filter(|n| *n <= 5) {
+                    muxes[idx] = Some(ALU_SRC_R0 + acc);
+                    continue;
+                }
+                muxes[idx] = match (read_addr(name, false), read_addr(name, true)) {
+                    (None, None) => return Err(format!("Unknown source register '{}'.", name)),
+                    (Some(raddr), None) => Some(self.use_a(raddr)?),
+                    (None, Some(raddr)) => Some(self.use_b(raddr)?),
+                    (Some(_), Some(_)) if !shared => None,
+                    (Some(raddr_a), Some(raddr_b)) => {
+                        if self.raddr_a.is_none() || self.raddr_a == Some(raddr_a) {
+                            Some(self.use_a(raddr_a)?)
+                        } else {
+                            Some(self.use_b(raddr_b)?)
+                        }
+                    }
+                };
+            }
+        }
+        Ok(muxes.into_iter().map(|mux| mux.unwrap()).collect())
+    }
+}
+
+// Operation code, destination and sources of an add or mul part.
+fn alu_op<'a>(call: &'a Call, ops: &[&str], mov_op: u8, unary_ops: &[u8]) -> Result<(u8, &'a str, Vec<&'a Value>), String> {
+    match call.name.as_str() {
+        "nop" => {
+            call.check_num_args(0)?;
+            Ok((0, "null", vec![]))
+        }
+        "mov" => {
+            call.check_num_args(2)?;
+            Ok((mov_op, reg_name(&call.args[0])?, vec![&call.args[1], &call.args[1]]))
+        }
+        name => {
+            let op = match ops.iter().position(|op| *op == name) {
+                Some(op) => op as u8,
+                None => return Err(format!("Unknown operation '{}'.", name)),
+            };
+            if unary_ops.contains(&op) {
+                call.check_num_args(2)?;
+                Ok((op, reg_name(&call.args[0])?, vec![&call.args[1], &call.args[1]]))
+            } else {
+                call.check_num_args(3)?;
+                Ok((op, reg_name(&call.args[0])?, vec![&call.args[1], &call.args[2]]))
+            }
+        }
+    }
+}
+
+fn encode_alu(calls: &[Call]) -> Result<InstFormat, String> {
+    let nop = Call { name: "nop".to_string(), args: vec![], kwargs: vec![] };
+    let (add, mul) = match calls {
+        [call] if MUL_ONLY_OPS.contains(&call.name.as_str()) => (&nop, call),
+        [call] => (call, &nop),
+        [add, mul] => (add, mul),
+        _ => return Err("An instruction has at most an add and a mul part.".to_string()),
+    };
+    add.check_kwargs(&["cond", "set_flags", "sig", "unpack", "pm", "pack"])?;
+    mul.check_kwargs(&["cond", "set_flags", "sig", "unpack", "pm", "pack", "rotate"])?;
+
+    let (op_add, add_dst, add_srcs) = alu_op(add, &ADD_OPS, ADDOP_OR, &[ADDOP_FTOI, ADDOP_ITOF, ADDOP_NOT, ADDOP_CLZ])?;
+    let (op_mul, mul_dst, mul_srcs) = alu_op(mul, &MUL_OPS, MULOP_V8MIN, &[])?;
+    let cond_add = cond_value(add.kwarg("cond"), if op_add == ADDOP_NOP { COND_NEVER } else { COND_ALWAYS })?;
+    let cond_mul = cond_value(mul.kwarg("cond"), if op_mul == MULOP_NOP { COND_NEVER } else { COND_ALWAYS })?;
+
+    // The other keywords apply to the whole instruction and may be given on either part.
+    let both = |name: &str| add.kwarg(name).or_else(|| mul.kwarg(name));
+    let sf = bool_value(both("set_flags"))?;
+    let pm = bool_value(both("pm"))?;
+    let sig = match both("sig") {
+        Some(value) => table_value(value, &SIGNALS, "signal")?,
+        None => SIG_NOP,
+    };
+    let unpack = match both("unpack") {
+        Some(value) => table_value(value, &UNPACKS, "unpack")?,
+        None => 0,
+    };
+    let pack = pack_value(both("pack"), pm)?;
+    let (ws, waddr_add, waddr_mul) = write_fields(add_dst, mul_dst)?;
+
+    let mut ports = ReadPorts { raddr_a: None, raddr_b: None, small_imm: None };
+    if let Some(rotate) = mul.kwarg("rotate") {
+        let code = match rotate {
+            Value::Reg(name) if name == "r5" => 48,
+            Value::Int(val) if (1..16).contains(val) => 48 + *val as u8,
+            _ => return Err("The rotation must be 1 to 15 or r5.".to_string()),
+        };
+        ports.use_small_imm(code)?;
+    }
+    let muxes = ports.assign(&[&add_srcs[..], &mul_srcs[..]].concat())?;
+    let (add_a, add_b) = if add_srcs.is_empty() { (0, 0) } else { (muxes[0], muxes[1]) };
+    let (mul_a, mul_b) = if mul_srcs.is_empty() { (0, 0) } else { (muxes[add_srcs.len()], muxes[add_srcs.len() + 1]) };
+    let raddr_a = ports.raddr_a.unwrap_or(RA_NOP);
+
+    match ports.small_imm {
+        Some(_) if sig != SIG_NOP => Err("A signal can't be used with a small immediate or rotation.".to_string()),
+        Some(small_immed) => Ok(InstFormat::AluSmallImm(InstFormatAluSmallImm {
+            unpack, pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul,
+            op_mul, op_add, raddr_a, small_immed, add_a, add_b, mul_a, mul_b,
+        })),
+        None => Ok(InstFormat::Alu(InstFormatAlu {
+            sig, unpack, pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul,
+            op_mul, op_add, raddr_a, raddr_b: ports.raddr_b.unwrap_or(RB_NOP), add_a, add_b, mul_a, mul_b,
+        })),
+    }
+}
+
+// Branch at addr: bra([raN,] target, cond=.., absolute=True, link=.., link_mul=..), jmp(..)
+// or j<cond>(..), where the target is a label or an offset (address if absolute).
+fn encode_branch(call: &Call, addr: u32) -> Result<InstFormat, String> {
+    let cond_br = match call.name.as_str() {
+        "bra" => {
+            call.check_kwargs(&["cond", "absolute", "link", "link_mul"])?;
+            match call.kwarg("cond") {
+                Some(Value::Str(name)) if name == "always" => COND_BR_ALWAYS,
+                Some(value) => table_value(value, &BRANCH_CONDS, "branch condition")?,
+                None => COND_BR_ALWAYS,
+            }
+        }
+        name => {
+            call.check_kwargs(&["absolute", "link", "link_mul"])?;
+            match &name[1..] {
+                "mp" => COND_BR_ALWAYS,
+                cond => BRANCH_CONDS.iter().position(|c| *c == cond).unwrap() as u8,
+            }
+        }
+    };
+
+    let (reg, raddr_a, target) = match &call.args[..] {
+        [Value::Reg(name)] => (name.as_str(), None, &Value::Int(0)),
+        [Value::Reg(name), target] => (name.as_str(), None, target),
+        [target] => ("", Some(0), target),
+        _ => return Err(format!("{} takes a register and a target.", call.name)),
+    };
+    let raddr_a = match raddr_a {
+        Some(raddr_a) => raddr_a,
+        None => read_addr(reg, false).filter(|raddr| *raddr < 32).ok_or("The branch register must be ra0 to ra31.")?,
+    };
+    let reg = (!reg.is_empty()) as u8;
+
+    let rel = 1 - bool_value(call.kwarg("absolute"))?;
+    let immediate = match target {
+        Value::Label(target) if rel != 0 => target.wrapping_sub(addr.wrapping_add(4 * 8)),
+        target => u32_value(target)?,
+    };
+
+    let link = call.kwarg("link").map(reg_name).transpose()?.unwrap_or("null");
+    let link_mul = call.kwarg("link_mul").map(reg_name).transpose()?.unwrap_or("null");
+    let (ws, waddr_add, waddr_mul) = write_fields(link, link_mul)?;
+    Ok(InstFormat::Branch(InstFormatBranch { cond_br, rel, reg, raddr_a, ws, waddr_add, waddr_mul, immediate }))
+}
+
+// Destinations and keywords shared by ldi and the semaphore instructions.
+struct WriteFields {
+    pm: u8,
+    pack: u8,
+    cond_add: u8,
+    cond_mul: u8,
+    sf: u8,
+    ws: u8,
+    waddr_add: u8,
+    waddr_mul: u8,
+}
+
+fn write_kwargs(call: &Call, dsts: &[Value]) -> Result<WriteFields, String> {
+    let (add_dst, mul_dst) = match dsts {
+        [] => ("null", "null"),
+        // A single destination of regfile B is written by the mul side, without the write swap.
+        [dst] if write_addr(reg_name(dst)?, false).is_none() => ("null", reg_name(dst)?),
+        [dst] => (reg_name(dst)?, "null"),
+        [add_dst, mul_dst] => (reg_name(add_dst)?, reg_name(mul_dst)?),
+        _ => return Err(format!("{} takes at most two destinations.", call.name)),
+    };
+    let (ws, waddr_add, waddr_mul) = write_fields(add_dst, mul_dst)?;
+    let cond_add = cond_value(call.kwarg("cond"), COND_ALWAYS)?;
+    let cond_mul = cond_value(call.kwarg("cond_mul"), cond_add)?;
+    let pm = bool_value(call.kwarg("pm"))?;
+    let pack = pack_value(call.kwarg("pack"), pm)?;
+    let sf = bool_value(call.kwarg("set_flags"))?;
+    Ok(WriteFields { pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul })
+}
+
+// ldi(dst, [dst_mul,] value), where the value is a 32-bit number or a list of 16 per-element
+// values from -2 to 1, or 0 to 3 with unsigned=True.
+fn encode_ldi(call: &Call) -> Result<InstFormat, String> {
+    call.check_kwargs(&["cond", "cond_mul", "set_flags", "pack", "pm", "unsigned"])?;
+    let (value, dsts) = match call.args.split_last() {
+        Some((value, dsts)) if !dsts.is_empty() => (value, dsts),
+        _ => return Err("ldi takes destinations and a value.".to_string()),
+    };
+    let WriteFields { pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul } = write_kwargs(call, dsts)?;
+    let unsigned = bool_value(call.kwarg("unsigned"))?;
+
+    let values = match value {
+        Value::List(values) => values,
+        value if unsigned == 0 => {
+            let immediate = u32_value(value)?;
+            return Ok(InstFormat::LoadImm32(InstFormatLoadImm32 { pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul, immediate }));
+        }
+        _ => return Err("unsigned=True requires per-element values.".to_string()),
+    };
+    if values.len() != 16 {
+        return Err("Per-element values must be 16 values.".to_string());
+    }
+    let range = if unsigned != 0 { 0..=3 } else { -2..=1 };
+    let (mut per_element_ms_bit, mut per_element_ls_bit) = (0u16, 0u16);
+    for (elem, value) in values.iter().enumerate() {
+        match value {
+            Value::Int(val) if range.contains(val) => {
+                per_element_ms_bit |= ((*val as u16 >> 1) & 1) << elem;
+                per_element_ls_bit |= (*val as u16 & 1) << elem;
+            }
+            _ => return Err(format!("Per-element values must be {} to {}.", range.start(), range.end())),
+        }
+    }
+    let fields = InstFormatLoadImmPerElem { pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul, per_element_ms_bit, per_element_ls_bit };
+    if unsigned != 0 {
+        Ok(InstFormat::LoadImmPerElemUnsigned(fields))
+    } else {
+        Ok(InstFormat::LoadImmPerElemSigned(fields))
+    }
+}
+
+// sema_up(n, [dsts..]) or sema_down(n, [dsts..]).
+fn encode_semaphore(call: &Call) -> Result<InstFormat, String> {
+    call.check_kwargs(&["cond", "cond_mul", "set_flags", "pack", "pm"])?;
+    let semaphore = match call.args.first() {
+        Some(Value::Int(val)) if (0..16).contains(val) => *val as u8,
+        _ => return Err("The semaphore must be 0 to 15.".to_string()),
+    };
+    let WriteFields { pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul } = write_kwargs(call, &call.args[1..])?;
+    let sa = (call.name == "sema_down") as u8;
+    Ok(InstFormat::Semaphore(InstFormatSemaphore { pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul, sa, semaphore }))
+}
+
+fn is_branch(name: &str) -> bool {
+    name == "bra" || name == "jmp" || (name.len() > 1 && name.starts_with('j') && BRANCH_CONDS.contains(&&name[1..]))
+}
+
+impl Assembler {
+    fn parser<'a>(&'a self, tokens: &'a [Token]) -> Parser<'a> {
+        Parser { asm: self, tokens, pos: 0 }
+    }
+
+    fn encode(&self, tokens: &[Token], addr: u32) -> Result<u64, String> {
+        let calls = self.parser(tokens).calls()?;
+        let inst = match &calls[..] {
+            [call] if is_branch(&call.name) => encode_branch(call, addr)?,
+            [call] if call.name == "ldi" => encode_ldi(call)?,
+            [call] if call.name == "sema_up" || call.name == "sema_down" => encode_semaphore(call)?,
+            _ => encode_alu(&calls)?,
+        };
+        encode_inst(&inst)
+    }
+}
+
+fn classify(tokens: Vec<Token>) -> Result<(Statement, Option<String>), String> {
+    use Token::*;
+    match &tokens[..] {
+        [Ident(l), Punct("."), Ident(name)] if l == "L" => Ok((Statement::Label, Some(name.clone()))),
+        [Ident(name), Punct(":")] => Ok((Statement::Label, Some(name.clone()))),
+        [Ident(name), Punct("="), ..] => Ok((Statement::Constant(tokens[2..].to_vec()), Some(name.clone()))),
+        [Punct("."), Ident(directive), Ident(name), Punct(","), ..] if directive == "equ" => {
+            Ok((Statement::Constant(tokens[4..].to_vec()), Some(name.clone())))
+        }
+        [Punct("."), Ident(directive), ..] if directive == "dword" => Ok((Statement::Word(tokens[2..].to_vec()), None)),
+        [Punct("."), Ident(directive), ..] => Err(format!("Unknown directive '.{}'.", directive)),
+        _ => Ok((Statement::Inst(tokens), None)),
+    }
+}
+
+// Assembles a program which is loaded at address 0.
+pub fn assemble(text: &str) -> Result<Vec<u64>, String> {
+    assemble_at(text, 0)
+}
+
+// Assembles a program which is loaded at base_addr. Errors are prefixed by the line number.
+pub fn assemble_at(text: &str, base_addr: u32) -> Result<Vec<u64>, String> {
+    let mut asm = Assembler { symbols: HashMap::new(), labels: HashMap::new() };
+
+    // The first pass assigns the addresses of the labels.
+    let mut statements = vec![];
+    let mut addr = base_addr;
+    for (idx, line) in text.lines().enumerate() {
+        let tokens = tokenize(line).map_err(|err| format!("line {}: {}", idx + 1, err))?;
+        if tokens.is_empty() {
+            continue;
+        }
+        let (statement, name) = classify(tokens).map_err(|err| format!("line {}: {}", idx + 1, err))?;
+        match statement {
+            Statement::Label => {
+                let label = name.clone().unwrap();
+                if asm.labels.insert(label.clone(), addr).is_some() {
+                    return Err(format!("line {}: The label '{}' is defined twice.", idx + 1, label));
+                }
+            }
+            Statement::Word(_) | Statement::Inst(_) => addr = addr.wrapping_add(8),
+            Statement::Constant(_) => {}
+        }
+        statements.push((idx + 1, statement, name));
+    }
+
+    let mut insts = vec![];
+    for (line, statement, name) in statements {
+        let addr = base_addr.wrapping_add(insts.len() as u32 * 8);
+        let result = match statement {
+            Statement::Label => Ok(()),
+            Statement::Constant(tokens) => {
+                let mut parser = asm.parser(&tokens);
+                parser.expr().and_then(|value| parser.expect_end().map(|_| value)).map(|value| {
+                    asm.symbols.insert(name.unwrap(), value);
+                })
+            }
+            Statement::Word(tokens) => {
+                let mut parser = asm.parser(&tokens);
+                match (parser.expr(), parser.expect_end()) {
+                    (Ok(Value::Int(word)), Ok(())) if (i64::MIN as i128..=u64::MAX as i128).contains(&word) => {
+                        insts.push(word as u64);
+                        Ok(())
+                    }
+                    (Ok(Value::Int(_)), Ok(())) => Err("The word is out of range.".to_string()),
+                    (Err(err), _) | (_, Err(err)) => Err(err),
+                    _ => Err("Expected an integer.".to_string()),
+                }
+            }
+            Statement::Inst(tokens) => asm.encode(&tokens, addr).map(|inst| insts.push(inst)),
+        };
+        result.map_err(|err| format!("line {}: {}", line, err))?;
+    }
+    Ok(insts)
+}
+
+#[test]
+fn test_assemble() {
+    use crate::disassembler::disassemble;
+
+    let check = |text: &str| {
+        let insts = assemble(text).unwrap();
+        assert_eq!(insts.len(), 1, "{}", text);
+        assert_eq!(disassemble(insts[0]), text);
+    };
+    check("nop()");
+    check("nop(sig='thread end')");
+    check("fadd(r0, ra1, r2, cond='zs', set_flags=True).fmul(rb3, r4, r5)");
+    check("fadd(rb31, rb31, r0, set_flags=True, sig='load tmu0').mov(uniforms_address, r2)");
+    check("fmul(r0, r4, uniform)");
+    check("nop().mov(r5, r2, rotate=1)");
+    check("fadd(r0, ra3, r1).v8adds(r1, ra3, r0, rotate=r5)");
+    check("iadd(r0, r0, -16)");
+    check("fmul(r1, r1, 0.5)");
+    check("itof(ra2, rb4)");
+    check("mov(vpmvcd_rd_setup, vpm)");
+    check("mov(ra1, element_number).fmul(rb2, r0, 2.0)");
+    check("fadd(ra1, r0, r1, pack='16a')");
+    check("fmul(r0, r4, r1, unpack='8a', pm=True, pack='8888')");
+    check("ldi(r1, 0x3f800000)");
+    check("ldi(ra1, rb2, 0xfffffffe, cond='zs', set_flags=True)");
+    check("ldi(r0, [-2, 0, 1, -1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1])");
+    check("ldi(r0, [3, 0, 1, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0], unsigned=True)");
+    check("sema_up(3)");
+    check("sema_down(15, r1, cond='never')");
+    check("bra(-8, cond='anyzc')");
+    check("bra(ra1, 0x00000100, absolute=True, link=rb2)");
+
+    // Registers of both regfiles are read through the port which is free.
+    let inst = assemble("fadd(r0, vpm, ra1).fmul(r1, vpm, r2)").unwrap()[0];
+    assert_eq!(disassemble(inst), "fadd(r0, vpm, ra1).fmul(r1, vpm, r2)");
+    assert_eq!(get_bits(inst, 23, 18) as u8, 1);
+    assert_eq!(get_bits(inst, 17, 12) as u8, RA_VPM_READ);
+
+    assert_eq!(assemble("ldi(r0, 1.0)").unwrap(), assemble("ldi(r0, 0x3f800000)").unwrap());
+    assert_eq!(assemble("ldi(vpmvcd_wr_setup, 0x1a00)").unwrap(), vec![0xe00249f100001a00]);
+    assert_eq!(assemble(".dword 0x1234").unwrap(), vec![0x1234]);
+    // A branch to itself sets bit 63.
+    assert_eq!(assemble(".dword 0xf0f809e7ffffffe0").unwrap(), vec![0xf0f809e7ffffffe0]);
+    assert_eq!(assemble(".dword -1").unwrap(), vec![u64::MAX]);
+    assert!(assemble(".dword 0x10000000000000000").is_err());
+    assert!(assemble("ldi(r0, 0x100000000)").is_err());
+}
+
+#[test]
+fn test_assemble_labels() {
+    use crate::disassembler::disassemble_program;
+
+    let text = "
+        # counts down from COUNT
+        COUNT = 2 * (3 + 1)
+        counter = ra3
+        .equ STEP, -1
+            ldi(counter, COUNT)
+        L.loop
+            isub(counter, counter, -STEP, set_flags=True)
+            jzc(L.loop)
+            nop()
+            nop()
+            nop()
+        done:
+            bra(L.loop, absolute=True, cond='zs')
+            jmp(done)
+            nop(sig='thread end')
+    ";
+    let insts = assemble_at(text, 0x100).unwrap();
+    let lines: Vec<String> = disassemble_program(&insts, 0x100).iter().map(|line| line.split("  #").next().unwrap().to_string()).collect();
+    assert_eq!(lines, [
+        "    ldi(ra3, 0x00000008)",
+        "L.l0",
+        "    isub(ra3, ra3, 1, set_flags=True)",
+        "    bra(L.l0, cond='zc')",
+        "    nop()",
+        "    nop()",
+        "    nop()",
+        "L.l1",
+        "    bra(0x00000108, absolute=True, cond='zs')",
+        "    bra(L.l1)",
+        "    nop(sig='thread end')",
+    ]);
+
+    let error = |text: &str| assemble(text).unwrap_err();
+    assert_eq!(error("nop()\nfoo(r0, r1, r2)"), "line 2: Unknown operation 'foo'.");
+    assert_eq!(error("fadd(r0, ra1, ra2)"), "line 1: Two registers of regfile A are read.");
+    assert_eq!(error("fadd(r0, rb1, 1)"), "line 1: A register of regfile B is read with a small immediate.");
+    assert_eq!(error("fadd(r0, r1, 3.0)"), "line 1: The value can't be encoded as a small immediate.");
+    assert_eq!(error("fadd(r0, r1, 1, sig='thread end')"), "line 1: A signal can't be used with a small immediate or rotation.");
+    assert_eq!(error("fadd(ra0, r1, r2).fmul(ra1, r1, r2)"), "line 1: The registers ra0 and ra1 can't be written by one instruction.");
+    assert_eq!(error("mov(foo, r1)"), "line 1: Unknown destination register 'foo'.");
+    assert_eq!(error("jmp(L.nowhere)"), "line 1: Unknown label 'nowhere'.");
+    assert_eq!(error("x:\nx:"), "line 2: The label 'x' is defined twice.");
+    assert_eq!(error("ldi(r0, [1, 2])"), "line 1: Per-element values must be 16 values.");
+    assert_eq!(error("sema_up(16)"), "line 1: The semaphore must be 0 to 15.");
+    assert_eq!(error("nop(cond='sometimes')"), "line 1: Unknown condition 'sometimes'.");
+}
+
+#[test]
+fn test_assemble_sgemm() {
+    use crate::disassembler::disassemble_program;
+    use crate::kernel::Kernel;
+
+    // The disassembly of sgemm.bin assembles to a program with the same disassembly.
+    let program = std::fs::read("data/sgemm.bin").unwrap();
+    let strip = |lines: Vec<String>| -> Vec<String> { lines.iter().map(|line| line.split("  #").next().unwrap().to_string()).collect() };
+    let lines = strip(disassemble_program(Kernel::from_bytes(&program).unwrap().insts(), 0));
+    let insts = assemble(&lines.join("\n")).unwrap();
+    assert_eq!(strip(disassemble_program(&insts, 0)), lines);
+}
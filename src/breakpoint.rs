@@ -0,0 +1,222 @@
+// Conditions of breakpoints and watchpoints set by the host.
+//
+// A condition is written as REG[ELEM] OP VALUE, e.g. ra5[3] == 0, with OP one of ==, !=, <,
+// <=, > and >= comparing unsigned integers, or as any zf and all nf for the flags zf, nf and
+// cf of the elements. A watched location is mem[ADDR] for a word, mem[ADDR:LEN] for a range of
+// bytes, vpm[ROW] or vpm[ROW:COUNT] for rows of the VPM, or a register r0-r5, ra0-ra31 or
+// rb0-rb31.
+
+use crate::hooks::Register;
+use crate::processor::QPUEmu;
+use crate::runner::{self, UniformArg};
+
+use std::fmt;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compare {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+const COMPARES: [(&str, Compare); 6] = [
+    ("==", Compare::Eq), ("!=", Compare::Ne), ("<=", Compare::Le),
+    (">=", Compare::Ge), ("<", Compare::Lt), (">", Compare::Gt),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Flag {
+    Zero,
+    Negative,
+    Carry,
+}
+
+const FLAGS: [(&str, Flag); 3] = [("zf", Flag::Zero), ("nf", Flag::Negative), ("cf", Flag::Carry)];
+
+// Evaluated before the instruction at the breakpoint is executed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Condition {
+    Element { reg: Register, elem: usize, compare: Compare, value: u32 },
+    AnyFlag(Flag),
+    AllFlags(Flag),
+}
+
+impl Condition {
+    pub fn parse(s: &str) -> Result<Condition, String> {
+        let words: Vec<&str> = s.split_whitespace().collect();
+        if let [quantifier @ ("any" | "all"), flag] = words.as_slice() {
+            let flag = match FLAGS.iter().find(|(name, _)| name == flag) {
+                Some((_, flag)) => *flag,
+                None => return Err(format!("Unknown flag '{}'.", flag)),
+            };
+            return Ok(if *quantifier == "any" { Condition::AnyFlag(flag) } else { Condition::AllFlags(flag) });
+        }
+
+        let (idx, name, compare) = match COMPARES.iter().filter_map(|(name, compare)| s.find(name).map(|idx| (idx, name, compare))).min_by_key(|(idx, _, _)| *idx) {
+            Some(found) => found,
+            None => return Err(format!("Invalid condition '{}'.", s.trim())),
+        };
+        let (target, value) = (s[..idx].trim(), s[idx + name.len()..].trim());
+        let (reg, elem) = match (target.find('['), target.strip_suffix(']')) {
+            (Some(open), Some(target)) => (&target[..open], runner::parse_usize(&target[open + 1..])?),
+            _ => return Err(format!("Invalid condition '{}'.", s.trim())),
+        };
+        if elem >= 16 {
+            return Err(format!("The element {} is out of range.", elem));
+        }
+        Ok(Condition::Element { reg: Register::parse(reg)?, elem, compare: *compare, value: parse_value(value)? })
+    }
+
+    pub fn eval(&self, emu: &QPUEmu) -> bool {
+        let flags = |flag: Flag| match flag {
+            Flag::Zero => emu.zero_flags(),
+            Flag::Negative => emu.negative_flags(),
+            Flag::Carry => emu.carry_flags(),
+        };
+        match *self {
+            Condition::Element { reg, elem, compare, value } => {
+                let val = emu.register(reg)[elem];
+                match compare {
+                    Compare::Eq => val == value,
+                    Compare::Ne => val != value,
+                    Compare::Lt => val < value,
+                    Compare::Le => val <= value,
+                    Compare::Gt => val > value,
+                    Compare::Ge => val >= value,
+                }
+            }
+            Condition::AnyFlag(flag) => flags(flag).iter().any(|flag| *flag),
+            Condition::AllFlags(flag) => flags(flag).iter().all(|flag| *flag),
+        }
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let flag_name = |flag: Flag| FLAGS.iter().find(|(_, other)| *other == flag).unwrap().0;
+        match *self {
+            Condition::Element { reg, elem, compare, value } => {
+                let op = COMPARES.iter().find(|(_, other)| *other == compare).unwrap().0;
+                write!(f, "{}[{}] {} 0x{:08x}", reg, elem, op, value)
+            }
+            Condition::AnyFlag(flag) => write!(f, "any {}", flag_name(flag)),
+            Condition::AllFlags(flag) => write!(f, "all {}", flag_name(flag)),
+        }
+    }
+}
+
+// A number, a negative number or a float containing '.'.
+pub fn parse_value(s: &str) -> Result<u32, String> {
+    match runner::parse_uniform(s) {
+        Ok(UniformArg::U32(val)) => Ok(val),
+        Ok(UniformArg::F32(val)) => Ok(val.to_bits()),
+        Ok(_) => Err(format!("Invalid value '{}'.", s)),
+        Err(err) => Err(err),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+impl Access {
+    // Whether a watchpoint on this access stops on the access of a hit.
+    pub fn matches(self, access: Access) -> bool {
+        self == Access::ReadWrite || self == access
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchTarget {
+    Memory { addr: u32, len: u32 },
+    VpmRows { row: usize, count: usize },
+    Register(Register),
+}
+
+impl WatchTarget {
+    pub fn parse(s: &str) -> Result<WatchTarget, String> {
+        let range = |name: &str| -> Result<Option<(u32, Option<u32>)>, String> {
+            let range = match s.strip_prefix(name).and_then(|s| s.strip_prefix('[')).and_then(|s| s.strip_suffix(']')) {
+                Some(range) => range,
+                None => return Ok(None),
+            };
+            Ok(Some(match range.find(':') {
+                Some(idx) => (runner::parse_u32(&range[..idx])?, Some(runner::parse_u32(&range[idx + 1..])?)),
+                None => (runner::parse_u32(range)?, None),
+            }))
+        };
+
+        if let Some((addr, len)) = range("mem")? {
+            let len = len.unwrap_or(4);
+            if len == 0 {
+                return Err("The length of the range is zero.".to_string());
+            }
+            Ok(WatchTarget::Memory { addr, len })
+        } else if let Some((row, count)) = range("vpm")? {
+            let (row, count) = (row as usize, count.unwrap_or(1) as usize);
+            if count == 0 || row + count > 64 {
+                return Err("The VPM rows are out of range.".to_string());
+            }
+            Ok(WatchTarget::VpmRows { row, count })
+        } else {
+            Register::parse(s).map(WatchTarget::Register)
+        }
+    }
+}
+
+impl fmt::Display for WatchTarget {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WatchTarget::Memory { addr, len } => write!(f, "mem[0x{:08x}:{}]", addr, len),
+            WatchTarget::VpmRows { row, count } => write!(f, "vpm[{}:{}]", row, count),
+            WatchTarget::Register(reg) => write!(f, "{}", reg),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub target: WatchTarget,
+    pub access: Access,
+}
+
+// An access to a watched location by the instruction pc of the thread.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchHit {
+    // The index returned by add_watchpoint.
+    pub watchpoint: usize,
+    // Read or Write.
+    pub access: Access,
+    // The elements which made the access. Uniform reads and VPM DMA are done by every element.
+    pub lanes: u16,
+    pub thread: usize,
+    pub pc: u32,
+}
+
+#[test]
+fn test_breakpoint_parse() {
+    assert_eq!(Condition::parse("ra5[3] == 0"), Ok(Condition::Element { reg: Register::A(5), elem: 3, compare: Compare::Eq, value: 0 }));
+    assert_eq!(Condition::parse("r1[15]>=-1"), Ok(Condition::Element { reg: Register::Acc(1), elem: 15, compare: Compare::Ge, value: 0xffffffff }));
+    assert_eq!(Condition::parse("rb0[0] < 1.0"), Ok(Condition::Element { reg: Register::B(0), elem: 0, compare: Compare::Lt, value: 0x3f800000 }));
+    assert_eq!(Condition::parse(" any  zf "), Ok(Condition::AnyFlag(Flag::Zero)));
+    assert_eq!(Condition::parse("all cf"), Ok(Condition::AllFlags(Flag::Carry)));
+    assert_eq!(Condition::parse("any xf"), Err("Unknown flag 'xf'.".to_string()));
+    assert_eq!(Condition::parse("ra5 == 0"), Err("Invalid condition 'ra5 == 0'.".to_string()));
+    assert_eq!(Condition::parse("ra5[16] == 0"), Err("The element 16 is out of range.".to_string()));
+    assert_eq!(Condition::parse("rc5[1] == 0"), Err("Unknown register 'rc5'.".to_string()));
+    assert_eq!(Condition::parse("ra5[3] != 7").unwrap().to_string(), "ra5[3] != 0x00000007");
+
+    assert_eq!(WatchTarget::parse("mem[0x100]"), Ok(WatchTarget::Memory { addr: 0x100, len: 4 }));
+    assert_eq!(WatchTarget::parse("mem[0x100:64]"), Ok(WatchTarget::Memory { addr: 0x100, len: 64 }));
+    assert_eq!(WatchTarget::parse("vpm[2:3]"), Ok(WatchTarget::VpmRows { row: 2, count: 3 }));
+    assert_eq!(WatchTarget::parse("rb31"), Ok(WatchTarget::Register(Register::B(31))));
+    assert_eq!(WatchTarget::parse("vpm[63:2]"), Err("The VPM rows are out of range.".to_string()));
+    assert_eq!(WatchTarget::parse("mem[0x100:0]"), Err("The length of the range is zero.".to_string()));
+    assert_eq!(WatchTarget::parse("vpm[1]").unwrap().to_string(), "vpm[1:1]");
+}
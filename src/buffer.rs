@@ -0,0 +1,177 @@
+// Buffers allocated from the emulator memory by the host, with typed access to their contents.
+
+use crate::memory_map::*;
+use crate::processor::QPUEmu;
+
+// Element types which can be copied between host slices and buffers in little endian.
+pub trait BufferElement: Copy {
+    const SIZE: usize;
+    fn from_bytes(bytes: &[u8]) -> Self;
+    fn to_bytes(self, bytes: &mut [u8]);
+}
+
+impl BufferElement for u32 {
+    const SIZE: usize = 4;
+    fn from_bytes(bytes: &[u8]) -> Self { u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) }
+    fn to_bytes(self, bytes: &mut [u8]) { bytes.copy_from_slice(&self.to_le_bytes()) }
+}
+
+impl BufferElement for i32 {
+    const SIZE: usize = 4;
+    fn from_bytes(bytes: &[u8]) -> Self { i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) }
+    fn to_bytes(self, bytes: &mut [u8]) { bytes.copy_from_slice(&self.to_le_bytes()) }
+}
+
+impl BufferElement for f32 {
+    const SIZE: usize = 4;
+    fn from_bytes(bytes: &[u8]) -> Self { f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) }
+    fn to_bytes(self, bytes: &mut [u8]) { bytes.copy_from_slice(&self.to_le_bytes()) }
+}
+
+impl BufferElement for u16 {
+    const SIZE: usize = 2;
+    fn from_bytes(bytes: &[u8]) -> Self { u16::from_le_bytes([bytes[0], bytes[1]]) }
+    fn to_bytes(self, bytes: &mut [u8]) { bytes.copy_from_slice(&self.to_le_bytes()) }
+}
+
+impl BufferElement for u8 {
+    const SIZE: usize = 1;
+    fn from_bytes(bytes: &[u8]) -> Self { bytes[0] }
+    fn to_bytes(self, bytes: &mut [u8]) { bytes[0] = self }
+}
+
+// Handle of a block allocated by QPUEmu::alloc. Offsets of the typed accessors are in elements.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Buffer {
+    addr: u32,
+    size: usize,
+}
+
+impl Buffer {
+    pub(crate) fn new(addr: u32, size: usize) -> Self {
+        Buffer { addr, size }
+    }
+
+    // Physical address, which is also the bus address in the L1 and L2 cached alias.
+    pub fn addr(&self) -> u32 {
+        self.addr
+    }
+
+    pub fn bus_addr(&self, alias: BusAlias) -> u32 {
+        phys_to_bus(self.addr, alias)
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    fn check_range(&self, offset: usize, len: usize) {
+        if offset + len > self.size {
+            panic!("The access is out of the buffer.");
+        }
+    }
+
+    // The accesses fault if the memory of the buffer is no longer mapped.
+    pub fn read<T: BufferElement>(&self, emu: &QPUEmu, offset: usize, len: usize) -> Result<Vec<T>, MemoryFault> {
+        self.check_range(offset * T::SIZE, len * T::SIZE);
+
+        let mut bytes = vec![0u8; len * T::SIZE];
+        emu.read_bytes(self.addr + (offset * T::SIZE) as u32, &mut bytes)?;
+        Ok(bytes.chunks(T::SIZE).map(T::from_bytes).collect())
+    }
+
+    pub fn write<T: BufferElement>(&self, emu: &mut QPUEmu, offset: usize, data: &[T]) -> Result<(), MemoryFault> {
+        self.check_range(offset * T::SIZE, data.len() * T::SIZE);
+
+        let mut bytes = vec![0u8; data.len() * T::SIZE];
+        for (val, chunk) in data.iter().zip(bytes.chunks_mut(T::SIZE)) {
+            val.to_bytes(chunk);
+        }
+        emu.write_bytes(self.addr + (offset * T::SIZE) as u32, &bytes)
+    }
+
+    pub fn read_u32(&self, emu: &QPUEmu, offset: usize, len: usize) -> Result<Vec<u32>, MemoryFault> {
+        self.read(emu, offset, len)
+    }
+
+    pub fn read_i32(&self, emu: &QPUEmu, offset: usize, len: usize) -> Result<Vec<i32>, MemoryFault> {
+        self.read(emu, offset, len)
+    }
+
+    pub fn read_f32(&self, emu: &QPUEmu, offset: usize, len: usize) -> Result<Vec<f32>, MemoryFault> {
+        self.read(emu, offset, len)
+    }
+
+    pub fn read_u16(&self, emu: &QPUEmu, offset: usize, len: usize) -> Result<Vec<u16>, MemoryFault> {
+        self.read(emu, offset, len)
+    }
+
+    pub fn read_u8(&self, emu: &QPUEmu, offset: usize, len: usize) -> Result<Vec<u8>, MemoryFault> {
+        self.read(emu, offset, len)
+    }
+
+    pub fn write_u32(&self, emu: &mut QPUEmu, offset: usize, data: &[u32]) -> Result<(), MemoryFault> {
+        self.write(emu, offset, data)
+    }
+
+    pub fn write_i32(&self, emu: &mut QPUEmu, offset: usize, data: &[i32]) -> Result<(), MemoryFault> {
+        self.write(emu, offset, data)
+    }
+
+    pub fn write_f32(&self, emu: &mut QPUEmu, offset: usize, data: &[f32]) -> Result<(), MemoryFault> {
+        self.write(emu, offset, data)
+    }
+
+    pub fn write_u16(&self, emu: &mut QPUEmu, offset: usize, data: &[u16]) -> Result<(), MemoryFault> {
+        self.write(emu, offset, data)
+    }
+
+    pub fn write_u8(&self, emu: &mut QPUEmu, offset: usize, data: &[u8]) -> Result<(), MemoryFault> {
+        self.write(emu, offset, data)
+    }
+}
+
+#[test]
+fn test_buffer_typed_access() {
+    let mut emu = QPUEmu::new(0x1000);
+
+    let a = emu.alloc(6, 4).unwrap();
+    let b = emu.alloc(0x100, 0x100).unwrap();
+    assert_eq!((a.addr(), b.addr()), (0, 0x100));
+    assert_eq!(b.bus_addr(BusAlias::Uncached), 0xc000_0100);
+
+    b.write_f32(&mut emu, 1, &[1.5, -2.0]).unwrap();
+    assert_eq!(b.read_f32(&emu, 0, 3), Ok(vec![0.0, 1.5, -2.0]));
+    assert_eq!(b.read_u32(&emu, 1, 1), Ok(vec![0x3fc0_0000]));
+    assert_eq!(b.read_u16(&emu, 3, 1), Ok(vec![0x3fc0]));
+
+    b.write_i32(&mut emu, 0, &[-1]).unwrap();
+    assert_eq!(b.read_u8(&emu, 0, 2), Ok(vec![0xff, 0xff]));
+
+    a.write_u16(&mut emu, 0, &[1, 2, 3]).unwrap();
+    assert_eq!(a.read_u8(&emu, 0, 6), Ok(vec![1, 0, 2, 0, 3, 0]));
+
+    emu.free(a);
+    assert_eq!(emu.alloc(4, 4).unwrap().addr(), 0);
+    assert_eq!(emu.alloc(0x1000, 4), None);
+
+    // A handle is stale once its memory is no longer mapped.
+    let mut memory_map = MemoryMap::new();
+    memory_map.add_region(0x1000_0000, 0x1000, 0);
+    emu.set_memory_map(memory_map).unwrap();
+    assert_eq!(b.read_u32(&emu, 0, 1), Err(MemoryFault { addr: 0x100, len: 4 }));
+
+    // A region which is not backed by the memory is rejected.
+    let mut memory_map = MemoryMap::new();
+    memory_map.add_region(0, 0x2000, 0);
+    assert!(emu.set_memory_map(memory_map).is_err());
+    assert_eq!(b.write_u32(&mut emu, 0, &[0]), Err(MemoryFault { addr: 0x100, len: 4 }));
+}
+
+#[test]
+#[should_panic]
+fn test_buffer_out_of_range() {
+    let mut emu = QPUEmu::new(0x1000);
+    let buf = emu.alloc(8, 4).unwrap();
+    buf.write_u32(&mut emu, 1, &[0, 0]).unwrap();
+}
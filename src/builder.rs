@@ -0,0 +1,929 @@
+// Builder of QPU programs in Rust, in the style of py-videocore, e.g.
+//
+//   use videocoreiv_sim::builder::*;
+//   use videocoreiv_sim::builder::regs::*;
+//
+//   let mut prog = Program::new();
+//   prog.ldi(ra0, 16);
+//   let lp = prog.here();
+//   prog.fadd(r0, ra1, r2).fmul(rb3, r0, r1).setf().sig(Signal::LdTmu0);
+//   prog.isub(ra0, ra0, 1).setf();
+//   prog.bra(BranchCond::Zc, lp);
+//   prog.nop().sig(Signal::ThreadEnd);
+//   let insts = prog.build().unwrap();
+//
+// An add operation, a mul operation without an add part, ldi, a semaphore or a branch starts
+// an instruction, and a mul operation chained on an add operation is issued with it. The read
+// ports, the small immediates and the write swap are chosen when the program is built, and
+// instructions which don't fit in one word are reported as errors.
+
+use crate::constants::*;
+use crate::instructions::*;
+use crate::utils::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Regfile {
+    A,
+    B,
+    AB,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Read {
+    Acc(u8),
+    Regfile(Regfile, u8),
+}
+
+// A register, given by its read mux or address and its write address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Reg {
+    read: Option<Read>,
+    write: Option<(Regfile, u8)>,
+}
+
+pub const fn ra(n: u8) -> Reg {
+    assert!(n < 32, "The register of regfile A is out of range.");
+    Reg { read: Some(Read::Regfile(Regfile::A, n)), write: Some((Regfile::A, n)) }
+}
+
+pub const fn rb(n: u8) -> Reg {
+    assert!(n < 32, "The register of regfile B is out of range.");
+    Reg { read: Some(Read::Regfile(Regfile::B, n)), write: Some((Regfile::B, n)) }
+}
+
+const fn acc(n: u8, waddr: Option<u8>) -> Reg {
+    let write = match waddr {
+        Some(waddr) => Some((Regfile::AB, waddr)),
+        None => None,
+    };
+    Reg { read: Some(Read::Acc(n)), write }
+}
+
+const fn io(read: Option<(Regfile, u8)>, write: Option<(Regfile, u8)>) -> Reg {
+    let read = match read {
+        Some((regfile, raddr)) => Some(Read::Regfile(regfile, raddr)),
+        None => None,
+    };
+    Reg { read, write }
+}
+
+// Registers named as in py-videocore.
+#[allow(non_upper_case_globals)]
+pub mod regs {
+    use super::*;
+
+    pub const r0: Reg = acc(ALU_SRC_R0, Some(WA_ACC0));
+    pub const r1: Reg = acc(ALU_SRC_R1, Some(WA_ACC1));
+    pub const r2: Reg = acc(ALU_SRC_R2, Some(WA_ACC2));
+    pub const r3: Reg = acc(ALU_SRC_R3, Some(WA_ACC3));
+    pub const r4: Reg = acc(ALU_SRC_R4, None);
+    pub const r5: Reg = acc(ALU_SRC_R5, Some(WA_ACC5));
+
+    pub const uniform: Reg = io(Some((Regfile::AB, RA_UNIFORM_READ)), None);
+    pub const varying_read: Reg = io(Some((Regfile::AB, RA_VARYING_READ)), None);
+    pub const element_number: Reg = io(Some((Regfile::A, RA_ELEMENT_NUMBER)), None);
+    pub const qpu_number: Reg = io(Some((Regfile::B, RB_QPU_NUMBER)), None);
+    pub const null: Reg = io(Some((Regfile::AB, RA_NOP)), Some((Regfile::AB, WA_NOP)));
+    pub const x_pixel_coord: Reg = io(Some((Regfile::A, RA_X_PIXEL_COORD)), None);
+    pub const y_pixel_coord: Reg = io(Some((Regfile::B, RB_Y_PIXEL_COORD)), None);
+    pub const ms_flags: Reg = io(Some((Regfile::A, RA_MS_FLAGS)), Some((Regfile::AB, WA_MS_FLAGS)));
+    pub const rev_flag: Reg = io(Some((Regfile::B, RB_REV_FLAG)), None);
+    pub const vpm: Reg = io(Some((Regfile::AB, RA_VPM_READ)), Some((Regfile::AB, WA_VPM_WRITE)));
+    pub const vpm_ld_busy: Reg = io(Some((Regfile::A, RA_VPM_LD_BUSY)), None);
+    pub const vpm_st_busy: Reg = io(Some((Regfile::B, RB_VPM_ST_BUSY)), None);
+    pub const vpm_ld_wait: Reg = io(Some((Regfile::A, RA_VPM_LD_WAIT)), None);
+    pub const vpm_st_wait: Reg = io(Some((Regfile::B, RB_VPM_ST_WAIT)), None);
+    // Reading acquires the mutex and writing releases it.
+    pub const mutex: Reg = io(Some((Regfile::AB, RA_MUTEX_ACQUIRE)), Some((Regfile::AB, WA_MUTEX_RELEASE)));
+
+    pub const tmu_noswap: Reg = io(None, Some((Regfile::AB, WA_TMU_NOSWAP)));
+    pub const host_interrupt: Reg = io(None, Some((Regfile::AB, WA_HOST_INT)));
+    pub const uniforms_address: Reg = io(None, Some((Regfile::AB, WA_UNIFORMS_ADDRESS)));
+    pub const tlb_stencil_setup: Reg = io(None, Some((Regfile::AB, WA_TLB_STENCIL_SETUP)));
+    pub const tlb_z: Reg = io(None, Some((Regfile::AB, WA_TLB_Z)));
+    pub const tlb_color_ms: Reg = io(None, Some((Regfile::AB, WA_TLB_COLOUR_MS)));
+    pub const tlb_color_all: Reg = io(None, Some((Regfile::AB, WA_TLB_COLOUR_ALL)));
+    pub const tlb_alpha_mask: Reg = io(None, Some((Regfile::AB, 47)));
+    pub const vpmvcd_rd_setup: Reg = io(None, Some((Regfile::A, WA_VPMVCD_RD_SETUP)));
+    pub const vpmvcd_wr_setup: Reg = io(None, Some((Regfile::B, WB_VPMVCD_WR_SETUP)));
+    pub const vpm_ld_addr: Reg = io(None, Some((Regfile::A, WA_VPM_LD_ADDR)));
+    pub const vpm_st_addr: Reg = io(None, Some((Regfile::B, WB_VPM_ST_ADDR)));
+    pub const sfu_recip: Reg = io(None, Some((Regfile::AB, 52)));
+    pub const sfu_recipsqrt: Reg = io(None, Some((Regfile::AB, 53)));
+    pub const sfu_exp: Reg = io(None, Some((Regfile::AB, 54)));
+    pub const sfu_log: Reg = io(None, Some((Regfile::AB, 55)));
+    pub const tmu0_s: Reg = io(None, Some((Regfile::AB, WA_TMU0_S)));
+    pub const tmu0_t: Reg = io(None, Some((Regfile::AB, WA_TMU0_T)));
+    pub const tmu0_r: Reg = io(None, Some((Regfile::AB, WA_TMU0_R)));
+    pub const tmu0_b: Reg = io(None, Some((Regfile::AB, WA_TMU0_B)));
+    pub const tmu1_s: Reg = io(None, Some((Regfile::AB, WA_TMU1_S)));
+    pub const tmu1_t: Reg = io(None, Some((Regfile::AB, WA_TMU1_T)));
+    pub const tmu1_r: Reg = io(None, Some((Regfile::AB, WA_TMU1_R)));
+    pub const tmu1_b: Reg = io(None, Some((Regfile::AB, WA_TMU1_B)));
+
+    pub const ra0: Reg = ra(0);
+    pub const ra1: Reg = ra(1);
+    pub const ra2: Reg = ra(2);
+    pub const ra3: Reg = ra(3);
+    pub const ra4: Reg = ra(4);
+    pub const ra5: Reg = ra(5);
+    pub const ra6: Reg = ra(6);
+    pub const ra7: Reg = ra(7);
+    pub const ra8: Reg = ra(8);
+    pub const ra9: Reg = ra(9);
+    pub const ra10: Reg = ra(10);
+    pub const ra11: Reg = ra(11);
+    pub const ra12: Reg = ra(12);
+    pub const ra13: Reg = ra(13);
+    pub const ra14: Reg = ra(14);
+    pub const ra15: Reg = ra(15);
+    pub const ra16: Reg = ra(16);
+    pub const ra17: Reg = ra(17);
+    pub const ra18: Reg = ra(18);
+    pub const ra19: Reg = ra(19);
+    pub const ra20: Reg = ra(20);
+    pub const ra21: Reg = ra(21);
+    pub const ra22: Reg = ra(22);
+    pub const ra23: Reg = ra(23);
+    pub const ra24: Reg = ra(24);
+    pub const ra25: Reg = ra(25);
+    pub const ra26: Reg = ra(26);
+    pub const ra27: Reg = ra(27);
+    pub const ra28: Reg = ra(28);
+    pub const ra29: Reg = ra(29);
+    pub const ra30: Reg = ra(30);
+    pub const ra31: Reg = ra(31);
+
+    pub const rb0: Reg = rb(0);
+    pub const rb1: Reg = rb(1);
+    pub const rb2: Reg = rb(2);
+    pub const rb3: Reg = rb(3);
+    pub const rb4: Reg = rb(4);
+    pub const rb5: Reg = rb(5);
+    pub const rb6: Reg = rb(6);
+    pub const rb7: Reg = rb(7);
+    pub const rb8: Reg = rb(8);
+    pub const rb9: Reg = rb(9);
+    pub const rb10: Reg = rb(10);
+    pub const rb11: Reg = rb(11);
+    pub const rb12: Reg = rb(12);
+    pub const rb13: Reg = rb(13);
+    pub const rb14: Reg = rb(14);
+    pub const rb15: Reg = rb(15);
+    pub const rb16: Reg = rb(16);
+    pub const rb17: Reg = rb(17);
+    pub const rb18: Reg = rb(18);
+    pub const rb19: Reg = rb(19);
+    pub const rb20: Reg = rb(20);
+    pub const rb21: Reg = rb(21);
+    pub const rb22: Reg = rb(22);
+    pub const rb23: Reg = rb(23);
+    pub const rb24: Reg = rb(24);
+    pub const rb25: Reg = rb(25);
+    pub const rb26: Reg = rb(26);
+    pub const rb27: Reg = rb(27);
+    pub const rb28: Reg = rb(28);
+    pub const rb29: Reg = rb(29);
+    pub const rb30: Reg = rb(30);
+    pub const rb31: Reg = rb(31);
+}
+
+// A source operand: a register or a small immediate.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Src {
+    Reg(Reg),
+    Int(i32),
+    Float(f32),
+}
+
+impl From<Reg> for Src {
+    fn from(reg: Reg) -> Src {
+        Src::Reg(reg)
+    }
+}
+
+impl From<i32> for Src {
+    fn from(val: i32) -> Src {
+        Src::Int(val)
+    }
+}
+
+impl From<f32> for Src {
+    fn from(val: f32) -> Src {
+        Src::Float(val)
+    }
+}
+
+// The value of ldi: 32 bits, or 2 bits per element.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Imm {
+    U32(u32),
+    PerElemSigned([i8; 16]),
+    PerElemUnsigned([u8; 16]),
+}
+
+impl From<u32> for Imm {
+    fn from(val: u32) -> Imm {
+        Imm::U32(val)
+    }
+}
+
+impl From<i32> for Imm {
+    fn from(val: i32) -> Imm {
+        Imm::U32(val as u32)
+    }
+}
+
+impl From<f32> for Imm {
+    fn from(val: f32) -> Imm {
+        Imm::U32(f32_to_u32(val))
+    }
+}
+
+impl From<[i8; 16]> for Imm {
+    fn from(vals: [i8; 16]) -> Imm {
+        Imm::PerElemSigned(vals)
+    }
+}
+
+impl From<[u8; 16]> for Imm {
+    fn from(vals: [u8; 16]) -> Imm {
+        Imm::PerElemUnsigned(vals)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Signal {
+    Breakpoint,
+    ThreadSwitch,
+    ThreadEnd,
+    WaitScoreboard,
+    UnlockScoreboard,
+    LastThreadSwitch,
+    LdCoverage,
+    LdColor,
+    LdColorThreadEnd,
+    LdTmu0,
+    LdTmu1,
+    LdAlphaMask,
+}
+
+impl Signal {
+    fn code(self) -> u8 {
+        match self {
+            Signal::Breakpoint => SIG_BPKT,
+            Signal::ThreadSwitch => SIG_THRSW,
+            Signal::ThreadEnd => SIG_THREND,
+            Signal::WaitScoreboard => SIG_SBWAIT,
+            Signal::UnlockScoreboard => SIG_SBDONE,
+            Signal::LastThreadSwitch => SIG_LTHRSW,
+            Signal::LdCoverage => SIG_LOADCV,
+            Signal::LdColor => SIG_LOADC,
+            Signal::LdColorThreadEnd => SIG_LDCEND,
+            Signal::LdTmu0 => SIG_LDTMU0,
+            Signal::LdTmu1 => SIG_LDTMU1,
+            Signal::LdAlphaMask => SIG_LOADAM,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cond {
+    Never,
+    Always,
+    Zs,
+    Zc,
+    Ns,
+    Nc,
+    Cs,
+    Cc,
+}
+
+impl Cond {
+    fn code(self) -> u8 {
+        match self {
+            Cond::Never => COND_NEVER,
+            Cond::Always => COND_ALWAYS,
+            Cond::Zs => COND_ZS,
+            Cond::Zc => COND_ZC,
+            Cond::Ns => COND_NS,
+            Cond::Nc => COND_NC,
+            Cond::Cs => COND_CS,
+            Cond::Cc => COND_CC,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BranchCond {
+    Always,
+    Zs,
+    Zc,
+    AnyZs,
+    AnyZc,
+    Ns,
+    Nc,
+    AnyNs,
+    AnyNc,
+    Cs,
+    Cc,
+    AnyCs,
+    AnyCc,
+}
+
+impl BranchCond {
+    fn code(self) -> u8 {
+        match self {
+            BranchCond::Always => COND_BR_ALWAYS,
+            BranchCond::Zs => COND_BR_ZS,
+            BranchCond::Zc => COND_BR_ZC,
+            BranchCond::AnyZs => COND_BR_ANYZS,
+            BranchCond::AnyZc => COND_BR_ANYZC,
+            BranchCond::Ns => COND_BR_NS,
+            BranchCond::Nc => COND_BR_NC,
+            BranchCond::AnyNs => COND_BR_ANYNS,
+            BranchCond::AnyNc => COND_BR_ANYNC,
+            BranchCond::Cs => COND_BR_CS,
+            BranchCond::Cc => COND_BR_CC,
+            BranchCond::AnyCs => COND_BR_ANYCS,
+            BranchCond::AnyCc => COND_BR_ANYCC,
+        }
+    }
+}
+
+// A position in the program, which is bound once and may be used before it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Label(usize);
+
+#[derive(Debug, Clone, Copy)]
+struct Part {
+    op: u8,
+    dst: Reg,
+    a: Src,
+    b: Src,
+    cond: Cond,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Target {
+    Label(Label),
+    Addr(u32),
+    Reg(Reg, u32),
+}
+
+#[derive(Debug, Clone)]
+enum Inst {
+    Alu {
+        add: Option<Part>,
+        mul: Option<Part>,
+        sig: Option<Signal>,
+        sf: u8,
+        pm: u8,
+        pack: u8,
+        unpack: u8,
+        rotate: Option<u8>,
+    },
+    Ldi { dst: Reg, imm: Imm, cond: Cond, sf: u8, pm: u8, pack: u8 },
+    Semaphore { sa: u8, semaphore: u8, cond: Cond, sf: u8 },
+    Branch { cond: BranchCond, target: Target, link: Option<Reg> },
+    Raw(u64),
+}
+
+pub struct Program {
+    insts: Vec<Inst>,
+    labels: Vec<Option<usize>>,
+    error: Option<String>,
+}
+
+// The instruction which was added last, to which the modifiers apply.
+pub struct InstRef<'a> {
+    prog: &'a mut Program,
+    idx: usize,
+}
+
+impl<'a> InstRef<'a> {
+    fn fail(self, msg: &str) -> Self {
+        if self.prog.error.is_none() {
+            self.prog.error = Some(format!("instruction {}: {}", self.idx, msg));
+        }
+        self
+    }
+
+    fn mul_op(self, op: u8, dst: Reg, a: Src, b: Src) -> Self {
+        match &mut self.prog.insts[self.idx] {
+            Inst::Alu { mul: mul @ None, .. } => *mul = Some(Part { op, dst, a, b, cond: Cond::Always }),
+            Inst::Alu { .. } => return self.fail("The mul operation is already given."),
+            _ => return self.fail("A mul operation follows an instruction which is not an ALU one."),
+        }
+        self
+    }
+
+    pub fn fmul(self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> Self { self.mul_op(MULOP_FMUL, dst, a.into(), b.into()) }
+    pub fn mul24(self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> Self { self.mul_op(MULOP_MUL24, dst, a.into(), b.into()) }
+    pub fn v8muld(self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> Self { self.mul_op(MULOP_V8MULD, dst, a.into(), b.into()) }
+    pub fn v8min(self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> Self { self.mul_op(MULOP_V8MIN, dst, a.into(), b.into()) }
+    pub fn v8max(self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> Self { self.mul_op(MULOP_V8MAX, dst, a.into(), b.into()) }
+    pub fn v8adds(self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> Self { self.mul_op(MULOP_V8ADDS, dst, a.into(), b.into()) }
+    pub fn v8subs(self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> Self { self.mul_op(MULOP_V8SUBS, dst, a.into(), b.into()) }
+
+    // Moves on the mul ALU, as v8min of the same source.
+    pub fn mov(self, dst: Reg, src: impl Into<Src>) -> Self {
+        let src = src.into();
+        self.mul_op(MULOP_V8MIN, dst, src, src)
+    }
+
+    // Sets the condition of the mul part if there is one, else of the add part.
+    pub fn cond(self, cond: Cond) -> Self {
+        match &mut self.prog.insts[self.idx] {
+            Inst::Alu { mul: Some(part), .. } | Inst::Alu { add: Some(part), .. } => part.cond = cond,
+            Inst::Alu { .. } => return self.fail("A nop has no condition."),
+            Inst::Ldi { cond: inst_cond, .. } | Inst::Semaphore { cond: inst_cond, .. } => *inst_cond = cond,
+            _ => return self.fail("The instruction has no condition."),
+        }
+        self
+    }
+
+    // Sets the flags from the result of the add part, or of the mul part if the add one is nop.
+    pub fn setf(self) -> Self {
+        match &mut self.prog.insts[self.idx] {
+            Inst::Alu { sf, .. } | Inst::Ldi { sf, .. } | Inst::Semaphore { sf, .. } => *sf = 1,
+            _ => return self.fail("The instruction can't set the flags."),
+        }
+        self
+    }
+
+    pub fn sig(self, signal: Signal) -> Self {
+        match &mut self.prog.insts[self.idx] {
+            Inst::Alu { sig: sig @ None, .. } => *sig = Some(signal),
+            Inst::Alu { .. } => return self.fail("The signal is already given."),
+            _ => return self.fail("Only ALU instructions have a signal."),
+        }
+        self
+    }
+
+    // Packs the result written to regfile A, or the mul result with pm.
+    pub fn pack(self, pack: u8) -> Self {
+        match &mut self.prog.insts[self.idx] {
+            Inst::Alu { pack: inst_pack, .. } | Inst::Ldi { pack: inst_pack, .. } => *inst_pack = pack,
+            _ => return self.fail("The instruction has no pack."),
+        }
+        self
+    }
+
+    // Unpacks the operands read from regfile A, or from r4 with pm.
+    pub fn unpack(self, unpack: u8) -> Self {
+        match &mut self.prog.insts[self.idx] {
+            Inst::Alu { unpack: inst_unpack, .. } => *inst_unpack = unpack,
+            _ => return self.fail("Only ALU instructions have an unpack."),
+        }
+        self
+    }
+
+    pub fn pm(self) -> Self {
+        match &mut self.prog.insts[self.idx] {
+            Inst::Alu { pm, .. } | Inst::Ldi { pm, .. } => *pm = 1,
+            _ => return self.fail("The instruction has no pm."),
+        }
+        self
+    }
+
+    // Rotates the accumulator operands of the mul part by 1 to 15 elements.
+    pub fn rotate(self, by: u8) -> Self {
+        if !(1..16).contains(&by) {
+            return self.fail("The rotation must be 1 to 15.");
+        }
+        self.set_rotate(48 + by)
+    }
+
+    // Rotates the accumulator operands of the mul part by r5.
+    pub fn rotate_r5(self) -> Self {
+        self.set_rotate(48)
+    }
+
+    fn set_rotate(self, code: u8) -> Self {
+        match &mut self.prog.insts[self.idx] {
+            Inst::Alu { rotate, .. } => *rotate = Some(code),
+            _ => return self.fail("Only ALU instructions are rotated."),
+        }
+        self
+    }
+
+    // Writes the return address of a branch.
+    pub fn link(self, reg: Reg) -> Self {
+        match &mut self.prog.insts[self.idx] {
+            Inst::Branch { link, .. } => *link = Some(reg),
+            _ => return self.fail("Only branches have a link."),
+        }
+        self
+    }
+}
+
+// Code of the small immediate with the value of the source.
+fn small_imm_code(src: Src) -> Option<u8> {
+    match src {
+        Src::Int(val) if (0..16).contains(&val) => Some(val as u8),
+        Src::Int(val) if (-16..0).contains(&val) => Some((val + 32) as u8),
+        Src::Float(val) => (0..8).find(|exp| val == (1u32 << exp) as f32).map(|exp| 32 + exp)
+            .or_else(|| (1..9).find(|exp| val == 1.0 / (1u32 << exp) as f32).map(|exp| 48 - exp)),
+        _ => None,
+    }
+}
+
+// Read ports of an ALU instruction, which are shared by the add and mul parts.
+struct Ports {
+    raddr_a: Option<u8>,
+    raddr_b: Option<u8>,
+    small_imm: Option<u8>,
+}
+
+impl Ports {
+    fn use_a(&mut self, raddr: u8) -> Result<u8, String> {
+        match self.raddr_a {
+            Some(used) if used != raddr => Err("Two registers of regfile A are read.".to_string()),
+            _ => {
+                self.raddr_a = Some(raddr);
+                Ok(ALU_SRC_RA)
+            }
+        }
+    }
+
+    fn use_b(&mut self, raddr: Option<u8>, small_imm: Option<u8>) -> Result<u8, String> {
+        match (self.raddr_b, self.small_imm, raddr, small_imm) {
+            (Some(_), _, _, Some(_)) | (_, Some(_), Some(_), _) => Err("A register of regfile B is read with a small immediate.".to_string()),
+            (Some(used), _, Some(raddr), _) if used != raddr => Err("Two registers of regfile B are read.".to_string()),
+            (_, Some(used), _, Some(code)) if used != code => Err("Two small immediates are used.".to_string()),
+            _ => {
+                self.raddr_b = self.raddr_b.or(raddr);
+                self.small_imm = self.small_imm.or(small_imm);
+                Ok(ALU_SRC_RB)
+            }
+        }
+    }
+
+    // Assigns the sources to the input muxes. Registers of one regfile are placed first, so
+    // that the registers of both regfiles can go to the remaining port.
+    fn assign(&mut self, srcs: &[Src]) -> Result<Vec<u8>, String> {
+        let mut muxes = vec![None; srcs.len()];
+        for shared in [false, true] {
+            for (idx, src) in srcs.iter().enumerate() {
+                if muxes[idx].is_some() {
+                    continue;
+                }
+                let read = match src {
+                    Src::Reg(reg) => reg.read.ok_or("The register can't be read.")?,
+                    src => {
+                        let code = small_imm_code(*src).ok_or("The value can't be encoded as a small immediate.")?;
+                        muxes[idx] = Some(self.use_b(None, Some(code))?);
+                        continue;
+                    }
+                };
+                muxes[idx] = match read {
+                    Read::Acc(mux) => Some(mux),
+                    Read::Regfile(Regfile::A, raddr) => Some(self.use_a(raddr)?),
+                    Read::Regfile(Regfile::B, raddr) => Some(self.use_b(Some(raddr), None)?),
+                    Read::Regfile(Regfile::AB, _) if !shared => None,
+                    Read::Regfile(Regfile::AB, raddr) => {
+                        if self.raddr_a.is_none() || self.raddr_a == Some(raddr) {
+                            Some(self.use_a(raddr)?)
+                        } else {
+                            Some(self.use_b(Some(raddr), None)?)
+                        }
+                    }
+                };
+            }
+        }
+        Ok(muxes.into_iter().map(|mux| mux.unwrap()).collect())
+    }
+}
+
+// Chooses the write swap so that the add and mul results go to the given registers.
+fn write_fields(add_dst: Option<Reg>, mul_dst: Option<Reg>) -> Result<(u8, u8, u8), String> {
+    let waddr = |dst: Option<Reg>, regfile_b: bool| match dst.map(|dst| dst.write) {
+        None => Some(WA_NOP),
+        Some(None) => None,
+        Some(Some((Regfile::A, _))) if regfile_b => None,
+        Some(Some((Regfile::B, _))) if !regfile_b => None,
+        Some(Some((_, waddr))) => Some(waddr),
+    };
+    for dst in add_dst.iter().chain(mul_dst.iter()) {
+        if dst.write.is_none() {
+            return Err("The register can't be written.".to_string());
+        }
+    }
+    for ws in 0..2 {
+        if let (Some(waddr_add), Some(waddr_mul)) = (waddr(add_dst, ws != 0), waddr(mul_dst, ws == 0)) {
+            return Ok((ws, waddr_add, waddr_mul));
+        }
+    }
+    Err("The destinations can't be written by one instruction.".to_string())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn encode_alu(add: Option<Part>, mul: Option<Part>, sig: Option<Signal>, sf: u8, pm: u8, pack: u8, unpack: u8, rotate: Option<u8>) -> Result<InstFormat, String> {
+    let (ws, waddr_add, waddr_mul) = write_fields(add.map(|part| part.dst), mul.map(|part| part.dst))?;
+
+    let mut ports = Ports { raddr_a: None, raddr_b: None, small_imm: rotate };
+    if rotate.is_some() && mul.is_none() {
+        return Err("The rotation applies to the mul part.".to_string());
+    }
+    let srcs: Vec<Src> = add.iter().chain(mul.iter()).flat_map(|part| vec![part.a, part.b]).collect();
+    let muxes = ports.assign(&srcs)?;
+    let (add_a, add_b) = if add.is_some() { (muxes[0], muxes[1]) } else { (0, 0) };
+    let (mul_a, mul_b) = match mul {
+        Some(_) => (muxes[srcs.len() - 2], muxes[srcs.len() - 1]),
+        None => (0, 0),
+    };
+
+    let op_add = add.map_or(ADDOP_NOP, |part| part.op);
+    let op_mul = mul.map_or(MULOP_NOP, |part| part.op);
+    let cond_add = add.map_or(COND_NEVER, |part| part.cond.code());
+    let cond_mul = mul.map_or(COND_NEVER, |part| part.cond.code());
+    let raddr_a = ports.raddr_a.unwrap_or(RA_NOP);
+
+    match ports.small_imm {
+        Some(_) if sig.is_some() => Err("A signal can't be used with a small immediate or rotation.".to_string()),
+        Some(small_immed) => Ok(InstFormat::AluSmallImm(InstFormatAluSmallImm {
+            unpack, pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul,
+            op_mul, op_add, raddr_a, small_immed, add_a, add_b, mul_a, mul_b,
+        })),
+        None => Ok(InstFormat::Alu(InstFormatAlu {
+            sig: sig.map_or(SIG_NOP, Signal::code), unpack, pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul,
+            op_mul, op_add, raddr_a, raddr_b: ports.raddr_b.unwrap_or(RB_NOP), add_a, add_b, mul_a, mul_b,
+        })),
+    }
+}
+
+fn encode_ldi(dst: Reg, imm: Imm, cond: Cond, sf: u8, pm: u8, pack: u8) -> Result<InstFormat, String> {
+    // A destination of regfile B is written by the mul side, without the write swap.
+    let (ws, waddr_add, waddr_mul) = match dst.write {
+        Some((Regfile::B, _)) => write_fields(None, Some(dst))?,
+        _ => write_fields(Some(dst), None)?,
+    };
+    let (cond_add, cond_mul) = (cond.code(), cond.code());
+
+    let vals: Vec<u8> = match imm {
+        Imm::U32(immediate) => {
+            return Ok(InstFormat::LoadImm32(InstFormatLoadImm32 { pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul, immediate }));
+        }
+        Imm::PerElemSigned(vals) if vals.iter().all(|val| (-2..=1).contains(val)) => vals.iter().map(|val| *val as u8 & 3).collect(),
+        Imm::PerElemUnsigned(vals) if vals.iter().all(|val| *val <= 3) => vals.to_vec(),
+        Imm::PerElemSigned(_) => return Err("Signed per-element values must be -2 to 1.".to_string()),
+        Imm::PerElemUnsigned(_) => return Err("Unsigned per-element values must be 0 to 3.".to_string()),
+    };
+    let per_element_ms_bit = vals.iter().enumerate().fold(0u16, |bits, (elem, val)| bits | ((*val as u16 >> 1) & 1) << elem);
+    let per_element_ls_bit = vals.iter().enumerate().fold(0u16, |bits, (elem, val)| bits | (*val as u16 & 1) << elem);
+    let fields = InstFormatLoadImmPerElem { pm, pack, cond_add, cond_mul, sf, ws, waddr_add, waddr_mul, per_element_ms_bit, per_element_ls_bit };
+    match imm {
+        Imm::PerElemUnsigned(_) => Ok(InstFormat::LoadImmPerElemUnsigned(fields)),
+        _ => Ok(InstFormat::LoadImmPerElemSigned(fields)),
+    }
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Program { insts: vec![], labels: vec![], error: None }
+    }
+
+    pub fn len(&self) -> usize {
+        self.insts.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.insts.is_empty()
+    }
+
+    fn push(&mut self, inst: Inst) -> InstRef<'_> {
+        self.insts.push(inst);
+        let idx = self.insts.len() - 1;
+        InstRef { prog: self, idx }
+    }
+
+    fn alu(&mut self, add: Option<Part>, mul: Option<Part>) -> InstRef<'_> {
+        self.push(Inst::Alu { add, mul, sig: None, sf: 0, pm: 0, pack: 0, unpack: 0, rotate: None })
+    }
+
+    fn add_op(&mut self, op: u8, dst: Reg, a: Src, b: Src) -> InstRef<'_> {
+        self.alu(Some(Part { op, dst, a, b, cond: Cond::Always }), None)
+    }
+
+    fn mul_op(&mut self, op: u8, dst: Reg, a: Src, b: Src) -> InstRef<'_> {
+        self.alu(None, Some(Part { op, dst, a, b, cond: Cond::Always }))
+    }
+
+    // Creates a label, which is bound later by bind.
+    pub fn label(&mut self) -> Label {
+        self.labels.push(None);
+        Label(self.labels.len() - 1)
+    }
+
+    // Binds the label to the next instruction.
+    pub fn bind(&mut self, label: Label) {
+        if self.labels[label.0].is_some() && self.error.is_none() {
+            self.error = Some(format!("instruction {}: The label is bound twice.", self.insts.len()));
+        }
+        self.labels[label.0] = Some(self.insts.len());
+    }
+
+    // Creates a label bound to the next instruction.
+    pub fn here(&mut self) -> Label {
+        let label = self.label();
+        self.bind(label);
+        label
+    }
+
+    pub fn nop(&mut self) -> InstRef<'_> {
+        self.alu(None, None)
+    }
+
+    pub fn fadd(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_FADD, dst, a.into(), b.into()) }
+    pub fn fsub(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_FSUB, dst, a.into(), b.into()) }
+    pub fn fmin(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_FMIN, dst, a.into(), b.into()) }
+    pub fn fmax(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_FMAX, dst, a.into(), b.into()) }
+    pub fn fminabs(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_FMINABS, dst, a.into(), b.into()) }
+    pub fn fmaxabs(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_FMAXABS, dst, a.into(), b.into()) }
+    pub fn iadd(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_ADD, dst, a.into(), b.into()) }
+    pub fn isub(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_SUB, dst, a.into(), b.into()) }
+    pub fn shr(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_SHR, dst, a.into(), b.into()) }
+    pub fn asr(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_ASR, dst, a.into(), b.into()) }
+    pub fn ror(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_ROR, dst, a.into(), b.into()) }
+    pub fn shl(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_SHL, dst, a.into(), b.into()) }
+    pub fn imin(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_MIN, dst, a.into(), b.into()) }
+    pub fn imax(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_MAX, dst, a.into(), b.into()) }
+    pub fn band(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_AND, dst, a.into(), b.into()) }
+    pub fn bor(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_OR, dst, a.into(), b.into()) }
+    pub fn bxor(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_XOR, dst, a.into(), b.into()) }
+    pub fn v8adds(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_V8ADDS, dst, a.into(), b.into()) }
+    pub fn v8subs(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.add_op(ADDOP_V8SUBS, dst, a.into(), b.into()) }
+
+    pub fn ftoi(&mut self, dst: Reg, src: impl Into<Src>) -> InstRef<'_> { self.unary_op(ADDOP_FTOI, dst, src.into()) }
+    pub fn itof(&mut self, dst: Reg, src: impl Into<Src>) -> InstRef<'_> { self.unary_op(ADDOP_ITOF, dst, src.into()) }
+    pub fn bnot(&mut self, dst: Reg, src: impl Into<Src>) -> InstRef<'_> { self.unary_op(ADDOP_NOT, dst, src.into()) }
+    pub fn clz(&mut self, dst: Reg, src: impl Into<Src>) -> InstRef<'_> { self.unary_op(ADDOP_CLZ, dst, src.into()) }
+
+    // Moves on the add ALU, as bor of the same source.
+    pub fn mov(&mut self, dst: Reg, src: impl Into<Src>) -> InstRef<'_> { self.unary_op(ADDOP_OR, dst, src.into()) }
+
+    fn unary_op(&mut self, op: u8, dst: Reg, src: Src) -> InstRef<'_> {
+        self.add_op(op, dst, src, src)
+    }
+
+    // Operations of the mul ALU only, which are issued with a nop on the add ALU.
+    pub fn fmul(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.mul_op(MULOP_FMUL, dst, a.into(), b.into()) }
+    pub fn mul24(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.mul_op(MULOP_MUL24, dst, a.into(), b.into()) }
+    pub fn v8muld(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.mul_op(MULOP_V8MULD, dst, a.into(), b.into()) }
+    pub fn v8min(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.mul_op(MULOP_V8MIN, dst, a.into(), b.into()) }
+    pub fn v8max(&mut self, dst: Reg, a: impl Into<Src>, b: impl Into<Src>) -> InstRef<'_> { self.mul_op(MULOP_V8MAX, dst, a.into(), b.into()) }
+
+    pub fn ldi(&mut self, dst: Reg, imm: impl Into<Imm>) -> InstRef<'_> {
+        self.push(Inst::Ldi { dst, imm: imm.into(), cond: Cond::Always, sf: 0, pm: 0, pack: 0 })
+    }
+
+    pub fn sema_up(&mut self, semaphore: u8) -> InstRef<'_> {
+        self.push(Inst::Semaphore { sa: 0, semaphore, cond: Cond::Always, sf: 0 })
+    }
+
+    pub fn sema_down(&mut self, semaphore: u8) -> InstRef<'_> {
+        self.push(Inst::Semaphore { sa: 1, semaphore, cond: Cond::Always, sf: 0 })
+    }
+
+    // Branches to the label, relative to the branch + 32.
+    pub fn bra(&mut self, cond: BranchCond, label: Label) -> InstRef<'_> {
+        self.push(Inst::Branch { cond, target: Target::Label(label), link: None })
+    }
+
+    pub fn jmp(&mut self, label: Label) -> InstRef<'_> {
+        self.bra(BranchCond::Always, label)
+    }
+
+    // Branches to the absolute address.
+    pub fn bra_abs(&mut self, cond: BranchCond, addr: u32) -> InstRef<'_> {
+        self.push(Inst::Branch { cond, target: Target::Addr(addr), link: None })
+    }
+
+    // Branches to the address in ra0 to ra31 plus the offset, e.g. to return to a link.
+    pub fn bra_reg(&mut self, cond: BranchCond, reg: Reg, offset: u32) -> InstRef<'_> {
+        self.push(Inst::Branch { cond, target: Target::Reg(reg, offset), link: None })
+    }
+
+    // Appends an encoded instruction.
+    pub fn raw(&mut self, inst: u64) -> InstRef<'_> {
+        self.push(Inst::Raw(inst))
+    }
+
+    fn encode(&self, idx: usize) -> Result<u64, String> {
+        let inst = match self.insts[idx].clone() {
+            Inst::Alu { add, mul, sig, sf, pm, pack, unpack, rotate } => encode_alu(add, mul, sig, sf, pm, pack, unpack, rotate)?,
+            Inst::Ldi { dst, imm, cond, sf, pm, pack } => encode_ldi(dst, imm, cond, sf, pm, pack)?,
+            Inst::Semaphore { sa, semaphore, cond, sf } => {
+                if semaphore > 15 {
+                    return Err("The semaphore must be 0 to 15.".to_string());
+                }
+                let (cond_add, cond_mul) = (cond.code(), cond.code());
+                InstFormat::Semaphore(InstFormatSemaphore {
+                    pm: 0, pack: 0, cond_add, cond_mul, sf, ws: 0, waddr_add: WA_NOP, waddr_mul: WB_NOP, sa, semaphore,
+                })
+            }
+            Inst::Branch { cond, target, link } => {
+                let (rel, reg, raddr_a, immediate) = match target {
+                    Target::Label(label) => {
+                        let target = self.labels[label.0].ok_or("The label is not bound.")?;
+                        (1, 0, 0, (target as u32).wrapping_sub(idx as u32 + 4).wrapping_mul(8))
+                    }
+                    Target::Addr(addr) => (0, 0, 0, addr),
+                    Target::Reg(reg, offset) => match reg.read {
+                        Some(Read::Regfile(Regfile::A, raddr)) if raddr < 32 => (0, 1, raddr, offset),
+                        _ => return Err("The branch register must be ra0 to ra31.".to_string()),
+                    },
+                };
+                let (ws, waddr_add, waddr_mul) = write_fields(link, None)?;
+                InstFormat::Branch(InstFormatBranch { cond_br: cond.code(), rel, reg, raddr_a, ws, waddr_add, waddr_mul, immediate })
+            }
+            Inst::Raw(inst) => return Ok(inst),
+        };
+        encode_inst(&inst)
+    }
+
+    // Encodes the program, or returns the first error with the index of its instruction.
+    pub fn build(&self) -> Result<Vec<u64>, String> {
+        if let Some(err) = &self.error {
+            return Err(err.clone());
+        }
+        (0..self.insts.len()).map(|idx| self.encode(idx).map_err(|err| format!("instruction {}: {}", idx, err))).collect()
+    }
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test]
+fn test_builder() {
+    use crate::assembler::assemble;
+    use regs::*;
+
+    let mut prog = Program::new();
+    prog.ldi(ra0, 16);
+    let lp = prog.here();
+    prog.fadd(r0, ra1, r2).fmul(rb3, r0, r1).setf().sig(Signal::LdTmu0);
+    prog.fadd(r0, ra1, r2).cond(Cond::Zs).fmul(rb3, r4, uniform).cond(Cond::Nc);
+    prog.isub(ra0, ra0, 1).setf();
+    prog.bra(BranchCond::Zc, lp);
+    prog.fmul(r1, r1, 0.5);
+    prog.nop().mov(r5, r2).rotate(3);
+    prog.mov(vpmvcd_rd_setup, vpm).v8adds(r1, r1, r2).rotate_r5();
+    prog.itof(ra2, rb4);
+    prog.ldi(vpmvcd_wr_setup, 0x1a00u32);
+    prog.ldi(r0, [-2i8, 0, 1, -1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]).cond(Cond::Zs);
+    prog.ldi(r1, 1.0f32).setf();
+    prog.sema_down(15);
+    prog.bra_reg(BranchCond::Always, ra3, 0x100).link(rb2);
+    prog.nop().sig(Signal::ThreadEnd);
+
+    let expected = "
+        ldi(ra0, 16)
+    L.lp
+        fadd(r0, ra1, r2, set_flags=True, sig='load tmu0').fmul(rb3, r0, r1)
+        fadd(r0, ra1, r2, cond='zs').fmul(rb3, r4, uniform, cond='nc')
+        isub(ra0, ra0, 1, set_flags=True)
+        bra(L.lp, cond='zc')
+        fmul(r1, r1, 0.5)
+        nop().mov(r5, r2, rotate=3)
+        mov(vpmvcd_rd_setup, vpm).v8adds(r1, r1, r2, rotate=r5)
+        itof(ra2, rb4)
+        ldi(vpmvcd_wr_setup, 0x1a00)
+        ldi(r0, [-2, 0, 1, -1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1], cond='zs')
+        ldi(r1, 1.0, set_flags=True)
+        sema_down(15)
+        bra(ra3, 0x100, absolute=True, link=rb2)
+        nop(sig='thread end')
+    ";
+    assert_eq!(prog.build().unwrap(), assemble(expected).unwrap());
+}
+
+#[test]
+fn test_builder_errors() {
+    use regs::*;
+
+    let error = |build: &dyn Fn(&mut Program)| {
+        let mut prog = Program::new();
+        prog.nop();
+        build(&mut prog);
+        prog.build().unwrap_err()
+    };
+    assert_eq!(error(&|prog| { prog.fadd(r0, ra1, ra2); }), "instruction 1: Two registers of regfile A are read.");
+    assert_eq!(error(&|prog| { prog.fadd(r0, rb1, 1); }), "instruction 1: A register of regfile B is read with a small immediate.");
+    assert_eq!(error(&|prog| { prog.fadd(r0, r1, 1).fmul(r2, r1, 2); }), "instruction 1: Two small immediates are used.");
+    assert_eq!(error(&|prog| { prog.fadd(r0, r1, 3.0); }), "instruction 1: The value can't be encoded as a small immediate.");
+    assert_eq!(error(&|prog| { prog.iadd(r0, r1, 1).sig(Signal::ThreadEnd); }), "instruction 1: A signal can't be used with a small immediate or rotation.");
+    assert_eq!(error(&|prog| { prog.fadd(ra0, r1, r2).fmul(ra1, r1, r2); }), "instruction 1: The destinations can't be written by one instruction.");
+    assert_eq!(error(&|prog| { prog.mov(r4, r1); }), "instruction 1: The register can't be written.");
+    assert_eq!(error(&|prog| { prog.mov(r0, tmu0_s); }), "instruction 1: The register can't be read.");
+    assert_eq!(error(&|prog| { prog.fadd(r0, r1, r2).fmul(r3, r1, r2).fmul(r3, r1, r2); }), "instruction 1: The mul operation is already given.");
+    assert_eq!(error(&|prog| { prog.ldi(r0, 1).sig(Signal::ThreadEnd); }), "instruction 1: Only ALU instructions have a signal.");
+    assert_eq!(error(&|prog| { prog.ldi(r0, [4u8; 16]); }), "instruction 1: Unsigned per-element values must be 0 to 3.");
+    assert_eq!(error(&|prog| { prog.sema_up(16); }), "instruction 1: The semaphore must be 0 to 15.");
+    assert_eq!(error(&|prog| { let label = prog.label(); prog.jmp(label); }), "instruction 1: The label is not bound.");
+    assert_eq!(error(&|prog| { prog.bra_reg(BranchCond::Always, rb1, 0); }), "instruction 1: The branch register must be ra0 to ra31.");
+}
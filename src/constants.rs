@@ -3,7 +3,7 @@ pub const ADDOP_NOP		: u8 = 0b00000;
 pub const ADDOP_FADD	: u8 = 0b00001;
 pub const ADDOP_FSUB	: u8 = 0b00010;
 pub const ADDOP_FMIN	: u8 = 0b00011;
-pub const ADDOP_FMAX	: u8 = 000100;
+pub const ADDOP_FMAX	: u8 = 0b00100;
 pub const ADDOP_FMINABS	: u8 = 0b00101;
 pub const ADDOP_FMAXABS	: u8 = 0b00110;
 pub const ADDOP_FTOI	: u8 = 0b00111;
@@ -212,6 +212,11 @@ pub const WA_ACC5 : u8 = 0b100101;
 pub const WA_HOST_INT : u8 = 0b100110;
 pub const WA_NOP : u8 = 0b100111;
 pub const WA_UNIFORMS_ADDRESS : u8 = 0b101000;
+pub const WA_MS_FLAGS : u8 = 0b101010;
+pub const WA_TLB_STENCIL_SETUP : u8 = 0b101011;
+pub const WA_TLB_Z : u8 = 0b101100;
+pub const WA_TLB_COLOUR_MS : u8 = 0b101101;
+pub const WA_TLB_COLOUR_ALL : u8 = 0b101110;
 pub const WA_VPM_WRITE : u8 = 0b110000;
 pub const WA_VPMVCD_RD_SETUP : u8 = 0b110001;
 pub const WA_VPM_LD_ADDR : u8 = 0b110010;
@@ -267,6 +272,11 @@ pub const WB_ACC5 : u8 = 0b100101;
 pub const WB_HOST_INT : u8 = 0b100110;
 pub const WB_NOP : u8 = 0b100111;
 pub const WB_UNIFORMS_ADDRESS : u8 = 0b101000;
+pub const WB_MS_FLAGS : u8 = 0b101010;
+pub const WB_TLB_STENCIL_SETUP : u8 = 0b101011;
+pub const WB_TLB_Z : u8 = 0b101100;
+pub const WB_TLB_COLOUR_MS : u8 = 0b101101;
+pub const WB_TLB_COLOUR_ALL : u8 = 0b101110;
 pub const WB_VPM_WRITE : u8 = 0b110000;
 pub const WB_VPMVCD_WR_SETUP : u8 = 0b110001;
 pub const WB_VPM_ST_ADDR : u8 = 0b110010;
@@ -0,0 +1,431 @@
+// Interactive debugger run from the breakpoint hook of the emulator.
+//
+//   s, step                 executes one instruction and stops again
+//   c, continue             runs until the next breakpoint
+//   q, quit                 stops the program
+//   b, break LOC [if COND]  sets a breakpoint, stopping only if the condition holds
+//   d, delete LOC           clears a breakpoint
+//   w, watch TARGET [r|w|rw]  stops after the reads or writes of a location (default: rw)
+//   unwatch N               removes the watchpoint N
+//   i, info                 lists the breakpoints and the watchpoints
+//   l, list [LOC] [COUNT]   disassembles COUNT instructions (default: 8 from the current one)
+//   p, print REG            prints every element of r0-r5, ra0-ra31 or rb0-rb31, or REG[ELEM]
+//   p, print flags|unif     prints the flags of each element or the uniform pointer
+//   regs                    prints the accumulators
+//   x ADDR [COUNT]          prints COUNT words of the memory (default: 4)
+//   vpm ROW [COUNT]         prints COUNT rows of the VPM (default: 1)
+//   set REG[ELEM] VALUE     writes an element of a register, or every element without [ELEM]
+//   set unif VALUE          writes the uniform pointer
+//   set mem[ADDR] VALUE     writes a word of the memory
+//   set vpm[ROW][ELEM] VALUE  writes an element of the VPM, or the whole row without [ELEM]
+//
+// A location LOC is the address of an instruction, as printed by the disassembler, or a
+// label L.lN of a branch target. Numbers are decimal or hexadecimal with 0x, and a value is
+// also a negative number or a float containing '.'. The conditions and the watched locations
+// are described in breakpoint.rs.
+
+use crate::breakpoint::{self, Access, Condition, WatchHit, WatchTarget, Watchpoint};
+use crate::disassembler;
+use crate::hooks::{Hooks, Register};
+use crate::processor::QPUEmu;
+use crate::runner;
+
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+
+// What the program does after the debugger returns.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Resume {
+    Step,
+    Continue,
+    Quit,
+}
+
+// The program being debugged, with the labels of its branch targets.
+struct Program {
+    base_addr: u32,
+    insts: Vec<u64>,
+    labels: BTreeMap<u32, String>,
+}
+
+impl Program {
+    fn new(emu: &QPUEmu) -> Program {
+        let base_addr = emu.code_addr();
+        let insts = emu.program();
+        let labels = disassembler::program_labels(&insts, base_addr);
+        Program { base_addr, insts, labels }
+    }
+
+    fn addr(&self, pc: u32) -> u32 {
+        self.base_addr.wrapping_add(pc * 8)
+    }
+
+    // Resolves a location into an instruction index.
+    fn pc(&self, loc: &str) -> Result<u32, String> {
+        let addr = match loc.strip_prefix("L.") {
+            Some(name) => match self.labels.iter().find(|(_, label)| label.as_str() == name) {
+                Some((addr, _)) => *addr,
+                None => return Err(format!("Unknown label '{}'.", loc)),
+            },
+            None => runner::parse_u32(loc)?,
+        };
+        if addr < self.base_addr || !(addr - self.base_addr).is_multiple_of(8) {
+            return Err(format!("The address 0x{:08x} is not an instruction of the program.", addr));
+        }
+        Ok((addr - self.base_addr) / 8)
+    }
+
+    fn line(&self, pc: u32) -> String {
+        let addr = self.addr(pc);
+        let text = match self.insts.get(pc as usize) {
+            Some(inst) => disassembler::disassemble_at(*inst, addr, &self.labels),
+            None => "?".to_string(),
+        };
+        match self.labels.get(&addr) {
+            Some(label) => format!("0x{:08x} <L.{}>: {}", addr, label, text),
+            None => format!("0x{:08x}: {}", addr, text),
+        }
+    }
+}
+
+// Splits NAME[I][J] into the name and the indices.
+fn parse_target(s: &str) -> Result<(&str, Vec<u32>), String> {
+    let (name, mut rest) = match s.find('[') {
+        Some(idx) => (&s[..idx], &s[idx..]),
+        None => (s, ""),
+    };
+    let mut indices = vec![];
+    while !rest.is_empty() {
+        let end = match (rest.starts_with('['), rest.find(']')) {
+            (true, Some(end)) => end,
+            _ => return Err(format!("Invalid target '{}'.", s)),
+        };
+        indices.push(runner::parse_u32(&rest[1..end])?);
+        rest = &rest[end + 1..];
+    }
+    Ok((name, indices))
+}
+
+fn parse_elem(idx: u32, count: usize, what: &str) -> Result<usize, String> {
+    if idx as usize >= count {
+        return Err(format!("The {} {} is out of range.", what, idx));
+    }
+    Ok(idx as usize)
+}
+
+// The elements selected by an optional index.
+fn elem_range(elem: Option<&u32>) -> Result<Range<usize>, String> {
+    match elem {
+        Some(elem) => parse_elem(*elem, 16, "element").map(|elem| elem..elem + 1),
+        None => Ok(0..16),
+    }
+}
+
+fn format_elems(name: &str, vals: &[u32; 16]) -> String {
+    let words: Vec<String> = vals.iter().map(|val| format!("0x{:08x}", val)).collect();
+    format!("{}:\n  {}\n  {}", name, words[..8].join(" "), words[8..].join(" "))
+}
+
+fn format_access(access: Access) -> &'static str {
+    match access {
+        Access::Read => "r",
+        Access::Write => "w",
+        Access::ReadWrite => "rw",
+    }
+}
+
+// The watchpoint, the access and the lanes of a hit by the instruction pc.
+fn format_hit(emu: &QPUEmu, hit: &WatchHit) -> String {
+    let access = if hit.access == Access::Read { "read" } else { "write" };
+    format!("watchpoint {}: {} by lanes 0x{:04x} at {}", hit.watchpoint, access, hit.lanes, Program::new(emu).line(hit.pc))
+}
+
+fn format_flags(name: &str, flags: &[bool; 16]) -> String {
+    let bits: String = flags.iter().map(|flag| if *flag { '1' } else { '0' }).collect();
+    format!("{}: {}", name, bits)
+}
+
+// Runs a command line and returns how to resume the program, if the command resumes it.
+pub fn command(emu: &mut QPUEmu, line: &str, out: &mut String) -> Result<Option<Resume>, String> {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let (name, args) = match args.split_first() {
+        Some((name, args)) => (*name, args),
+        None => return Ok(None),
+    };
+    let program = Program::new(emu);
+    let count_arg = |idx: usize, default: usize| args.get(idx).map_or(Ok(default), |arg| runner::parse_usize(arg));
+
+    match (name, args.len()) {
+        ("s", 0) | ("step", 0) => return Ok(Some(Resume::Step)),
+        ("c", 0) | ("continue", 0) => return Ok(Some(Resume::Continue)),
+        ("q", 0) | ("quit", 0) => return Ok(Some(Resume::Quit)),
+        ("b", 1) | ("break", 1) => {
+            let pc = program.pc(args[0])?;
+            emu.set_breakpoint(pc);
+            writeln!(out, "breakpoint at {}", program.line(pc)).unwrap();
+        }
+        ("b", 3..) | ("break", 3..) if args[1] == "if" => {
+            let pc = program.pc(args[0])?;
+            let condition = Condition::parse(&args[2..].join(" "))?;
+            emu.set_conditional_breakpoint(pc, condition);
+            writeln!(out, "breakpoint at {} if {}", program.line(pc), condition).unwrap();
+        }
+        ("d", 1) | ("delete", 1) => {
+            let pc = program.pc(args[0])?;
+            if !emu.clear_breakpoint(pc) {
+                return Err(format!("No breakpoint at 0x{:08x}.", program.addr(pc)));
+            }
+        }
+        ("w", 1..=2) | ("watch", 1..=2) => {
+            let target = WatchTarget::parse(args[0])?;
+            let access = match args.get(1) {
+                Some(&"r") => Access::Read,
+                Some(&"w") => Access::Write,
+                Some(&"rw") | None => Access::ReadWrite,
+                Some(access) => return Err(format!("Invalid access '{}'.", access)),
+            };
+            let idx = emu.add_watchpoint(Watchpoint { target, access });
+            writeln!(out, "watchpoint {}: {} {}", idx, target, format_access(access)).unwrap();
+        }
+        ("unwatch", 1) => {
+            let idx = runner::parse_usize(args[0])?;
+            if !emu.remove_watchpoint(idx) {
+                return Err(format!("No watchpoint {}.", idx));
+            }
+        }
+        ("i", 0) | ("info", 0) => {
+            for (pc, condition) in emu.breakpoints() {
+                match condition {
+                    Some(condition) => writeln!(out, "breakpoint at {} if {}", program.line(pc), condition).unwrap(),
+                    None => writeln!(out, "breakpoint at {}", program.line(pc)).unwrap(),
+                }
+            }
+            for (idx, watchpoint) in emu.watchpoints() {
+                writeln!(out, "watchpoint {}: {} {}", idx, watchpoint.target, format_access(watchpoint.access)).unwrap();
+            }
+        }
+        ("l", 0..=2) | ("list", 0..=2) => {
+            let start = match args.first() {
+                Some(loc) => program.pc(loc)?,
+                None => emu.pc(),
+            };
+            for pc in start..start.saturating_add(count_arg(1, 8)? as u32) {
+                if pc as usize >= program.insts.len() {
+                    break;
+                }
+                let marker = if pc == emu.pc() { "=>" } else { "  " };
+                writeln!(out, "{} {}", marker, program.line(pc)).unwrap();
+            }
+        }
+        ("p", 1) | ("print", 1) => match args[0] {
+            "flags" => {
+                writeln!(out, "{}", format_flags("z", &emu.zero_flags())).unwrap();
+                writeln!(out, "{}", format_flags("n", &emu.negative_flags())).unwrap();
+                writeln!(out, "{}", format_flags("c", &emu.carry_flags())).unwrap();
+            }
+            "unif" => writeln!(out, "unif: 0x{:08x}", emu.uniform_ptr()).unwrap(),
+            target => {
+                let (name, indices) = parse_target(target)?;
+                let vals = emu.register(Register::parse(name)?);
+                match indices.as_slice() {
+                    [] => writeln!(out, "{}", format_elems(name, &vals)).unwrap(),
+                    [elem] => {
+                        let val = vals[parse_elem(*elem, 16, "element")?];
+                        writeln!(out, "{} = 0x{:08x} ({}, {})", target, val, val as i32, f32::from_bits(val)).unwrap();
+                    }
+                    _ => return Err(format!("Invalid target '{}'.", target)),
+                }
+            }
+        },
+        ("regs", 0) => {
+            for idx in 0..6 {
+                writeln!(out, "{}", format_elems(&format!("r{}", idx), &emu.accumulator(idx))).unwrap();
+            }
+        }
+        ("x", 1..=2) => {
+            let addr = runner::parse_u32(args[0])?;
+            let count = count_arg(1, 4)?;
+            for row in 0..count.div_ceil(4) {
+                let row_addr = addr.wrapping_add(row as u32 * 16);
+                let mut words = vec![];
+                for idx in 0..(count - row * 4).min(4) {
+                    let word = emu.read_u32(row_addr.wrapping_add(idx as u32 * 4)).map_err(|fault| fault.to_string())?;
+                    words.push(format!("0x{:08x}", word));
+                }
+                writeln!(out, "0x{:08x}: {}", row_addr, words.join(" ")).unwrap();
+            }
+        }
+        ("vpm", 1..=2) => {
+            let row = parse_elem(runner::parse_u32(args[0])?, 64, "VPM row")?;
+            let count = count_arg(1, 1)?;
+            for row in row..(row + count).min(64) {
+                writeln!(out, "{}", format_elems(&format!("vpm[{}]", row), &emu.vpm_row(row))).unwrap();
+            }
+        }
+        ("set", 2) => {
+            let val = breakpoint::parse_value(args[1])?;
+            let (name, indices) = parse_target(args[0])?;
+            match (name, indices.as_slice()) {
+                ("unif", []) => emu.set_uniform_ptr(val),
+                ("mem", [addr]) => emu.write_u32(*addr, val).map_err(|fault| fault.to_string())?,
+                ("vpm", [row]) | ("vpm", [row, _]) => {
+                    let row = parse_elem(*row, 64, "VPM row")?;
+                    for elem in elem_range(indices.get(1))? {
+                        emu.set_vpm(row, elem, val);
+                    }
+                }
+                (name, []) | (name, [_]) => {
+                    let reg = Register::parse(name)?;
+                    for elem in elem_range(indices.first())? {
+                        emu.set_register(reg, elem, val);
+                    }
+                }
+                _ => return Err(format!("Invalid target '{}'.", args[0])),
+            }
+        }
+        _ => return Err(format!("Invalid command '{}'.", line.trim())),
+    }
+    Ok(None)
+}
+
+// Reads commands until one resumes the program. The end of the input quits.
+pub fn repl(emu: &mut QPUEmu, input: &mut dyn BufRead, output: &mut dyn Write) -> io::Result<Resume> {
+    writeln!(output, "stopped at {}", Program::new(emu).line(emu.pc()))?;
+    loop {
+        write!(output, "(qdb) ")?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(Resume::Quit);
+        }
+        let mut out = String::new();
+        let result = command(emu, &line, &mut out);
+        output.write_all(out.as_bytes())?;
+        match result {
+            Ok(Some(resume)) => return Ok(resume),
+            Ok(None) => {}
+            Err(err) => writeln!(output, "{}", err)?,
+        }
+    }
+}
+
+// Hooks which run the debugger on the standard input and output at breakpoints and
+// watchpoints. Quitting, or an error of the input or output, stops the run with
+// StopReason::Stopped.
+#[derive(Debug, Default)]
+pub struct Debugger {
+    // The error which stopped the run.
+    pub error: Option<io::Error>,
+}
+
+impl Debugger {
+    fn stop(&mut self, emu: &mut QPUEmu) {
+        let stdin = io::stdin();
+        let stdout = io::stdout();
+        match repl(emu, &mut stdin.lock(), &mut stdout.lock()) {
+            Ok(Resume::Step) => emu.set_single_step(true),
+            Ok(Resume::Continue) => emu.set_single_step(false),
+            Ok(Resume::Quit) => emu.request_stop(),
+            Err(err) => {
+                self.error = Some(err);
+                emu.request_stop();
+            }
+        }
+    }
+}
+
+impl Hooks for Debugger {
+    fn breakpoint(&mut self, emu: &mut QPUEmu, _pc: u32) {
+        self.stop(emu);
+    }
+
+    fn watchpoint(&mut self, emu: &mut QPUEmu, hit: &WatchHit) {
+        println!("{}", format_hit(emu, hit));
+        self.stop(emu);
+    }
+}
+
+#[test]
+fn test_debugger() {
+    use crate::assembler;
+
+    // Changes r1 at the breakpoint before it is copied to r2.
+    struct Script;
+
+    impl Hooks for Script {
+        fn breakpoint(&mut self, emu: &mut QPUEmu, _pc: u32) {
+            let mut input = "p r1[2]\nset r1[2] 0x2a\nbogus\nc\n".as_bytes();
+            let mut output = vec![];
+            assert_eq!(repl(emu, &mut input, &mut output).unwrap(), Resume::Continue);
+            let output = String::from_utf8(output).unwrap();
+            assert!(output.starts_with("stopped at 0x00000010 <L.l0>: mov(r2, r1)\n(qdb) r1[2] = 0x00000005 (5, "));
+            assert!(output.ends_with("(qdb) Invalid command 'bogus'.\n(qdb) "));
+        }
+    }
+
+    let insts = assembler::assemble("
+        ldi(r0, 7)
+        ldi(r1, 5)
+    L.loop
+        mov(r2, r1)
+        jzc(L.loop)
+        nop()
+        nop()
+        nop()
+        nop(sig='thread end')
+        nop()
+        nop()
+    ").unwrap();
+
+    let mut emu = QPUEmu::new(0x1000);
+    emu.set_hooks(Box::new(Script));
+    let mut out = String::new();
+    emu.execute(&insts, &vec![0], 1);
+    assert_eq!(command(&mut emu, "b L.l0", &mut out), Ok(None));
+    emu.execute(&insts, &vec![0], 1);
+    assert_eq!(emu.accumulator(2)[2], 42);
+
+    out.clear();
+    assert_eq!(command(&mut emu, "info", &mut out), Ok(None));
+    assert_eq!(out, "breakpoint at 0x00000010 <L.l0>: mov(r2, r1)\n");
+
+    out.clear();
+    command(&mut emu, "b 0x8 if ra3[1] != 0x10", &mut out).unwrap();
+    command(&mut emu, "w vpm[4:2] w", &mut out).unwrap();
+    command(&mut emu, "watch r2", &mut out).unwrap();
+    assert_eq!(command(&mut emu, "unwatch 1", &mut out), Ok(None));
+    assert_eq!(command(&mut emu, "unwatch 1", &mut out), Err("No watchpoint 1.".to_string()));
+    assert_eq!(command(&mut emu, "w mem[0x100] x", &mut out), Err("Invalid access 'x'.".to_string()));
+    out.clear();
+    command(&mut emu, "i", &mut out).unwrap();
+    assert_eq!(out, "breakpoint at 0x00000008: ldi(r1, 0x00000005) if ra3[1] != 0x00000010\nbreakpoint at 0x00000010 <L.l0>: mov(r2, r1)\nwatchpoint 0: vpm[4:2] w\n");
+    command(&mut emu, "d 0x8", &mut out).unwrap();
+
+    out.clear();
+    command(&mut emu, "set ra3 -1", &mut out).unwrap();
+    command(&mut emu, "set ra3[15] 1.5", &mut out).unwrap();
+    command(&mut emu, "set mem[0x100] 0x12345678", &mut out).unwrap();
+    command(&mut emu, "set vpm[2][1] 9", &mut out).unwrap();
+    command(&mut emu, "set unif 0x200", &mut out).unwrap();
+    assert_eq!(emu.reg_a(3)[0], 0xffffffff);
+    assert_eq!(emu.reg_a(3)[15], 0x3fc00000);
+    assert_eq!(emu.read_u32(0x100), Ok(0x12345678));
+    assert_eq!(emu.vpm_row(2)[1], 9);
+    assert_eq!(emu.uniform_ptr(), 0x200);
+
+    command(&mut emu, "x 0x100 5", &mut out).unwrap();
+    command(&mut emu, "p unif", &mut out).unwrap();
+    assert_eq!(out, "0x00000100: 0x12345678 0x00000000 0x00000000 0x00000000\n0x00000110: 0x00000000\nunif: 0x00000200\n");
+
+    assert_eq!(command(&mut emu, "d 0x10", &mut out), Ok(None));
+    assert_eq!(command(&mut emu, "d 0x10", &mut out), Err("No breakpoint at 0x00000010.".to_string()));
+    assert_eq!(command(&mut emu, "b 0x14", &mut out), Err("The address 0x00000014 is not an instruction of the program.".to_string()));
+    assert_eq!(command(&mut emu, "b L.l1", &mut out), Err("Unknown label 'L.l1'.".to_string()));
+    assert_eq!(command(&mut emu, "p r6", &mut out), Err("Unknown register 'r6'.".to_string()));
+    assert_eq!(command(&mut emu, "p rb1[16]", &mut out), Err("The element 16 is out of range.".to_string()));
+    assert_eq!(command(&mut emu, "x 0x10000000", &mut out), Err("memory fault: 4 bytes at 0x10000000".to_string()));
+    assert_eq!(command(&mut emu, "s", &mut out), Ok(Some(Resume::Step)));
+}
@@ -0,0 +1,465 @@
+// Disassembler which renders instructions in the syntax of py-videocore, e.g.
+//
+//   fadd(r0, ra1, r2, cond='zs', set_flags=True).fmul(rb3, r4, r5)
+//   ldi(r1, 0x10)
+//   bra(L.l0, cond='anyzc')
+//   nop(sig='thread end')
+//
+// Relative branch targets inside the program are named L.l0, L.l1, ... in address order.
+
+use crate::constants::*;
+use crate::instructions::*;
+use crate::utils::*;
+
+use std::collections::BTreeMap;
+
+pub(crate) const ADD_OPS: [&str; 32] = [
+    "nop", "fadd", "fsub", "fmin", "fmax", "fminabs", "fmaxabs", "ftoi",
+    "itof", "", "", "", "iadd", "isub", "shr", "asr",
+    "ror", "shl", "imin", "imax", "band", "bor", "bxor", "bnot",
+    "clz", "", "", "", "", "", "v8adds", "v8subs",
+];
+
+pub(crate) const MUL_OPS: [&str; 8] = ["nop", "fmul", "mul24", "v8muld", "v8min", "v8max", "v8adds", "v8subs"];
+
+pub(crate) const SIGNALS: [&str; 16] = [
+    "breakpoint", "", "thread switch", "thread end", "wait scoreboard", "unlock scoreboard",
+    "last thread switch", "load coverage", "load color", "load color and thread end",
+    "load tmu0", "load tmu1", "load alpha mask", "", "", "",
+];
+
+pub(crate) const CONDS: [&str; 8] = ["never", "", "zs", "zc", "ns", "nc", "cs", "cc"];
+
+pub(crate) const BRANCH_CONDS: [&str; 16] = [
+    "zs", "zc", "anyzs", "anyzc", "ns", "nc", "anyns", "anync",
+    "cs", "cc", "anycs", "anycc", "", "", "", "",
+];
+
+pub(crate) const PACKS: [&str; 16] = [
+    "", "16a", "16b", "8888", "8a", "8b", "8c", "8d",
+    "32s", "16as", "16bs", "8888s", "8as", "8bs", "8cs", "8ds",
+];
+
+pub(crate) const MUL_PACKS: [&str; 8] = ["", "", "", "8888", "8a", "8b", "8c", "8d"];
+
+pub(crate) const UNPACKS: [&str; 8] = ["", "16a", "16b", "8d_rep", "8a", "8b", "8c", "8d"];
+
+// Name of a register read through the read address of regfile A or B.
+pub(crate) fn read_name(raddr: u8, regfile_b: bool) -> String {
+    let name = match (raddr, regfile_b) {
+        (0..=31, false) => return format!("ra{}", raddr),
+        (0..=31, true) => return format!("rb{}", raddr),
+        (RA_UNIFORM_READ, _) => "uniform",
+        (RA_VARYING_READ, _) => "varying_read",
+        (RA_ELEMENT_NUMBER, false) => "element_number",
+        (RB_QPU_NUMBER, true) => "qpu_number",
+        (RA_NOP, _) => "null",
+        (RA_X_PIXEL_COORD, false) => "x_pixel_coord",
+        (RB_Y_PIXEL_COORD, true) => "y_pixel_coord",
+        (RA_MS_FLAGS, false) => "ms_flags",
+        (RB_REV_FLAG, true) => "rev_flag",
+        (RA_VPM_READ, _) => "vpm",
+        (RA_VPM_LD_BUSY, false) => "vpm_ld_busy",
+        (RB_VPM_ST_BUSY, true) => "vpm_st_busy",
+        (RA_VPM_LD_WAIT, false) => "vpm_ld_wait",
+        (RB_VPM_ST_WAIT, true) => "vpm_st_wait",
+        (RA_MUTEX_ACQUIRE, _) => "mutex_acquire",
+        _ => return format!("<raddr_{} {}>", if regfile_b { 'b' } else { 'a' }, raddr),
+    };
+    name.to_string()
+}
+
+// Name of a register written through the write address of regfile A or B.
+pub(crate) fn write_name(waddr: u8, regfile_b: bool) -> String {
+    let name = match (waddr, regfile_b) {
+        (0..=31, false) => return format!("ra{}", waddr),
+        (0..=31, true) => return format!("rb{}", waddr),
+        (WA_ACC0..=WA_ACC3, _) => return format!("r{}", waddr - WA_ACC0),
+        (WA_TMU_NOSWAP, _) => "tmu_noswap",
+        (WA_ACC5, _) => "r5",
+        (WA_HOST_INT, _) => "host_interrupt",
+        (WA_NOP, _) => "null",
+        (WA_UNIFORMS_ADDRESS, _) => "uniforms_address",
+        (WA_MS_FLAGS, _) => "ms_flags",
+        (WA_TLB_STENCIL_SETUP, _) => "tlb_stencil_setup",
+        (WA_TLB_Z, _) => "tlb_z",
+        (WA_TLB_COLOUR_MS, _) => "tlb_color_ms",
+        (WA_TLB_COLOUR_ALL, _) => "tlb_color_all",
+        (47, _) => "tlb_alpha_mask",
+        (WA_VPM_WRITE, _) => "vpm",
+        (WA_VPMVCD_RD_SETUP, false) => "vpmvcd_rd_setup",
+        (WB_VPMVCD_WR_SETUP, true) => "vpmvcd_wr_setup",
+        (WA_VPM_LD_ADDR, false) => "vpm_ld_addr",
+        (WB_VPM_ST_ADDR, true) => "vpm_st_addr",
+        (WA_MUTEX_RELEASE, _) => "mutex_release",
+        (52, _) => "sfu_recip",
+        (53, _) => "sfu_recipsqrt",
+        (54, _) => "sfu_exp",
+        (55, _) => "sfu_log",
+        (WA_TMU0_S, _) => "tmu0_s",
+        (WA_TMU0_T, _) => "tmu0_t",
+        (WA_TMU0_R, _) => "tmu0_r",
+        (WA_TMU0_B, _) => "tmu0_b",
+        (WA_TMU1_S, _) => "tmu1_s",
+        (WA_TMU1_T, _) => "tmu1_t",
+        (WA_TMU1_R, _) => "tmu1_r",
+        (WA_TMU1_B, _) => "tmu1_b",
+        _ => return format!("<waddr_{} {}>", if regfile_b { 'b' } else { 'a' }, waddr),
+    };
+    name.to_string()
+}
+
+// Value of a small immediate, or None for a vector rotation.
+fn small_imm_name(imm: u8) -> Option<String> {
+    match imm {
+        0..=15 => Some(format!("{}", imm)),
+        16..=31 => Some(format!("{}", imm as i32 - 32)),
+        32..=39 => Some(format!("{:?}", (1u32 << (imm - 32)) as f32)),
+        40..=47 => Some(format!("{:?}", 1.0 / (1u32 << (48 - imm)) as f32)),
+        _ => None,
+    }
+}
+
+fn rotation_name(imm: u8) -> String {
+    if imm == 48 { "r5".to_string() } else { format!("{}", imm - 48) }
+}
+
+// Operands of an ALU instruction which are shared by the add and mul parts.
+struct AluFields {
+    sig: u8,
+    unpack: u8,
+    pm: u8,
+    pack: u8,
+    cond_add: u8,
+    cond_mul: u8,
+    sf: u8,
+    ws: u8,
+    waddr_add: u8,
+    waddr_mul: u8,
+    op_mul: u8,
+    op_add: u8,
+    raddr_a: u8,
+    raddr_b: u8,
+    small_imm: bool,
+    add_a: u8,
+    add_b: u8,
+    mul_a: u8,
+    mul_b: u8,
+}
+
+impl AluFields {
+    fn source(&self, mux: u8) -> String {
+        match mux {
+            ALU_SRC_R0..=ALU_SRC_R5 => format!("r{}", mux),
+            ALU_SRC_RA => read_name(self.raddr_a, false),
+            _ if self.small_imm => small_imm_name(self.raddr_b).unwrap_or_else(|| "r0".to_string()),
+            _ => read_name(self.raddr_b, true),
+        }
+    }
+
+    fn render(&self) -> String {
+        let add_dst = write_name(self.waddr_add, self.ws != 0);
+        let mul_dst = write_name(self.waddr_mul, self.ws == 0);
+        let add_unary = [ADDOP_FTOI, ADDOP_ITOF, ADDOP_NOT, ADDOP_CLZ].contains(&self.op_add);
+
+        let mut add_args = vec![];
+        let add_name = if self.op_add == ADDOP_NOP {
+            "nop"
+        } else if self.op_add == ADDOP_OR && self.add_a == self.add_b {
+            add_args = vec![add_dst, self.source(self.add_a)];
+            "mov"
+        } else {
+            add_args.push(add_dst);
+            add_args.push(self.source(self.add_a));
+            if !add_unary {
+                add_args.push(self.source(self.add_b));
+            }
+            match ADD_OPS[self.op_add as usize] {
+                "" => "<add_op>",
+                name => name,
+            }
+        };
+
+        let mut mul_args = vec![];
+        let mul_name = if self.op_mul == MULOP_NOP {
+            "nop"
+        } else if self.op_mul == MULOP_V8MIN && self.mul_a == self.mul_b {
+            mul_args = vec![mul_dst, self.source(self.mul_a)];
+            "mov"
+        } else {
+            mul_args = vec![mul_dst, self.source(self.mul_a), self.source(self.mul_b)];
+            MUL_OPS[self.op_mul as usize]
+        };
+
+        // Keywords of the add part, and of the mul part if it is the only one.
+        let mul_only = self.op_add == ADDOP_NOP && self.op_mul != MULOP_NOP;
+        let mut add_kwargs = vec![];
+        let mut mul_kwargs = vec![];
+
+        if self.op_add != ADDOP_NOP && !CONDS[self.cond_add as usize].is_empty() {
+            add_kwargs.push(format!("cond='{}'", CONDS[self.cond_add as usize]));
+        }
+        if self.op_mul != MULOP_NOP && !CONDS[self.cond_mul as usize].is_empty() {
+            mul_kwargs.push(format!("cond='{}'", CONDS[self.cond_mul as usize]));
+        }
+        if self.small_imm && self.raddr_b >= 48 && self.op_mul != MULOP_NOP {
+            mul_kwargs.push(format!("rotate={}", rotation_name(self.raddr_b)));
+        }
+
+        let main_kwargs = if mul_only { &mut mul_kwargs } else { &mut add_kwargs };
+        if self.sf != 0 {
+            main_kwargs.push("set_flags=True".to_string());
+        }
+        if !SIGNALS[self.sig as usize].is_empty() && self.sig != SIG_NOP {
+            main_kwargs.push(format!("sig='{}'", SIGNALS[self.sig as usize]));
+        }
+        if self.unpack != 0 {
+            main_kwargs.push(format!("unpack='{}'", UNPACKS[self.unpack as usize]));
+        }
+        if self.pm != 0 {
+            main_kwargs.push("pm=True".to_string());
+        }
+
+        // The pack applies to the result written to regfile A, or to the mul result with pm.
+        if self.pack != 0 {
+            let kwarg = pack_kwarg(self.pm, self.pack);
+            if self.pm != 0 || self.ws != 0 { mul_kwargs.push(kwarg) } else { add_kwargs.push(kwarg) }
+        }
+
+        let part = |name: &str, mut args: Vec<String>, kwargs: Vec<String>| {
+            args.extend(kwargs);
+            format!("{}({})", name, args.join(", "))
+        };
+
+        // The add ALU runs mov, v8adds and v8subs unless an add part precedes them.
+        if mul_only && add_kwargs.is_empty() && !["mov", "v8adds", "v8subs"].contains(&mul_name) {
+            part(mul_name, mul_args, mul_kwargs)
+        } else if self.op_mul == MULOP_NOP && mul_kwargs.is_empty() {
+            part(add_name, add_args, add_kwargs)
+        } else {
+            format!("{}.{}", part(add_name, add_args, add_kwargs), part(mul_name, mul_args, mul_kwargs))
+        }
+    }
+}
+
+fn pack_kwarg(pm: u8, pack: u8) -> String {
+    let name = if pm != 0 { MUL_PACKS.get(pack as usize).copied().unwrap_or("") } else { PACKS[pack as usize] };
+    if name.is_empty() { format!("pack={}", pack) } else { format!("pack='{}'", name) }
+}
+
+fn write_kwargs(cond_add: u8, cond_mul: u8, sf: u8, pm: u8, pack: u8) -> Vec<String> {
+    let mut kwargs = vec![];
+    if cond_add != COND_ALWAYS {
+        kwargs.push(format!("cond='{}'", CONDS[cond_add as usize]));
+    }
+    if cond_mul != cond_add {
+        kwargs.push(format!("cond_mul='{}'", CONDS[cond_mul as usize]));
+    }
+    if sf != 0 {
+        kwargs.push("set_flags=True".to_string());
+    }
+    if pack != 0 {
+        kwargs.push(pack_kwarg(pm, pack));
+    }
+    if pm != 0 {
+        kwargs.push("pm=True".to_string());
+    }
+    kwargs
+}
+
+// Destinations of an instruction which writes the same value through both write addresses.
+fn write_dsts(ws: u8, waddr_add: u8, waddr_mul: u8) -> Vec<String> {
+    let add_dst = write_name(waddr_add, ws != 0);
+    let mul_dst = write_name(waddr_mul, ws == 0);
+    match (add_dst.as_str(), mul_dst.as_str()) {
+        (_, "null") => vec![add_dst],
+        ("null", _) => vec![mul_dst],
+        _ => vec![add_dst, mul_dst],
+    }
+}
+
+// Target address of a relative branch at addr.
+fn branch_target(addr: u32, fields: &InstFormatBranch) -> Option<u32> {
+    if fields.rel != 0 && fields.reg == 0 {
+        Some(addr.wrapping_add(4 * 8).wrapping_add(fields.immediate))
+    } else {
+        None
+    }
+}
+
+fn is_valid(inst: u64) -> bool {
+    let sig = get_bits(inst, 63, 60) as u8;
+    sig != SIG_LDI || [0b000, 0b001, 0b011, 0b100].contains(&(get_bits(inst, 59, 57) as u8))
+}
+
+// Renders the instruction at addr. Branch targets are looked up in labels.
+fn render(inst: u64, addr: u32, labels: &BTreeMap<u32, String>) -> String {
+    if !is_valid(inst) {
+        return format!("invalid(0x{:016x})", inst);
+    }
+
+    match decode_inst(inst) {
+        InstFormat::Alu(f) => AluFields {
+            sig: f.sig, unpack: f.unpack, pm: f.pm, pack: f.pack, cond_add: f.cond_add, cond_mul: f.cond_mul,
+            sf: f.sf, ws: f.ws, waddr_add: f.waddr_add, waddr_mul: f.waddr_mul, op_mul: f.op_mul,
+            op_add: f.op_add, raddr_a: f.raddr_a, raddr_b: f.raddr_b, small_imm: false,
+            add_a: f.add_a, add_b: f.add_b, mul_a: f.mul_a, mul_b: f.mul_b,
+        }.render(),
+        InstFormat::AluSmallImm(f) => AluFields {
+            sig: SIG_NOP, unpack: f.unpack, pm: f.pm, pack: f.pack, cond_add: f.cond_add, cond_mul: f.cond_mul,
+            sf: f.sf, ws: f.ws, waddr_add: f.waddr_add, waddr_mul: f.waddr_mul, op_mul: f.op_mul,
+            op_add: f.op_add, raddr_a: f.raddr_a, raddr_b: f.small_immed, small_imm: true,
+            add_a: f.add_a, add_b: f.add_b, mul_a: f.mul_a, mul_b: f.mul_b,
+        }.render(),
+        InstFormat::Branch(f) => {
+            let mut args = vec![];
+            if f.reg != 0 {
+                args.push(format!("ra{}", f.raddr_a));
+            }
+            match branch_target(addr, &f).and_then(|target| labels.get(&target)) {
+                Some(label) => args.push(format!("L.{}", label)),
+                None if f.rel != 0 => args.push(format!("{}", f.immediate as i32)),
+                None => args.push(format!("0x{:08x}", f.immediate)),
+            }
+            if f.rel == 0 {
+                args.push("absolute=True".to_string());
+            }
+            if !BRANCH_CONDS[f.cond_br as usize].is_empty() {
+                args.push(format!("cond='{}'", BRANCH_CONDS[f.cond_br as usize]));
+            } else if f.cond_br != COND_BR_ALWAYS {
+                args.push(format!("cond={}", f.cond_br));
+            }
+            for (name, waddr, regfile_b) in [("link", f.waddr_add, f.ws != 0), ("link_mul", f.waddr_mul, f.ws == 0)] {
+                if waddr != WA_NOP {
+                    args.push(format!("{}={}", name, write_name(waddr, regfile_b)));
+                }
+            }
+            format!("bra({})", args.join(", "))
+        }
+        InstFormat::LoadImm32(f) => {
+            let mut args = write_dsts(f.ws, f.waddr_add, f.waddr_mul);
+            args.push(format!("0x{:08x}", f.immediate));
+            args.extend(write_kwargs(f.cond_add, f.cond_mul, f.sf, f.pm, f.pack));
+            format!("ldi({})", args.join(", "))
+        }
+        InstFormat::LoadImmPerElemSigned(f) | InstFormat::LoadImmPerElemUnsigned(f) => {
+            let signed = get_bits(inst, 59, 57) == 0b001;
+            let values: Vec<String> = (0..16).map(|elem| {
+                let val = ((f.per_element_ms_bit >> elem) & 1) << 1 | ((f.per_element_ls_bit >> elem) & 1);
+                if signed && val >= 2 { format!("{}", val as i32 - 4) } else { format!("{}", val) }
+            }).collect();
+
+            let mut args = write_dsts(f.ws, f.waddr_add, f.waddr_mul);
+            args.push(format!("[{}]", values.join(", ")));
+            if !signed {
+                args.push("unsigned=True".to_string());
+            }
+            args.extend(write_kwargs(f.cond_add, f.cond_mul, f.sf, f.pm, f.pack));
+            format!("ldi({})", args.join(", "))
+        }
+        InstFormat::Semaphore(f) => {
+            let mut args = vec![format!("{}", f.semaphore)];
+            if f.waddr_add != WA_NOP || f.waddr_mul != WB_NOP {
+                args.extend(write_dsts(f.ws, f.waddr_add, f.waddr_mul));
+            }
+            args.extend(write_kwargs(f.cond_add, f.cond_mul, f.sf, f.pm, f.pack));
+            format!("{}({})", if f.sa == 0 { "sema_up" } else { "sema_down" }, args.join(", "))
+        }
+    }
+}
+
+// Disassembles a single instruction. Relative branch targets are shown as offsets.
+pub fn disassemble(inst: u64) -> String {
+    render(inst, 0, &BTreeMap::new())
+}
+
+// Disassembles the instruction at addr, naming the branch targets found in labels.
+pub fn disassemble_at(inst: u64, addr: u32, labels: &BTreeMap<u32, String>) -> String {
+    render(inst, addr, labels)
+}
+
+// Names the relative branch targets inside a program loaded at base_addr by their address.
+pub fn program_labels(insts: &[u64], base_addr: u32) -> BTreeMap<u32, String> {
+    let end_addr = base_addr as u64 + insts.len() as u64 * 8;
+    let mut labels = BTreeMap::new();
+    for (idx, inst) in insts.iter().enumerate() {
+        if get_bits(*inst, 63, 60) as u8 != SIG_BRA {
+            continue;
+        }
+        if let InstFormat::Branch(fields) = decode_inst(*inst) {
+            let target = branch_target(base_addr + idx as u32 * 8, &fields);
+            if let Some(target) = target.filter(|target| *target >= base_addr && (*target as u64) < end_addr && target % 8 == 0) {
+                labels.insert(target, String::new());
+            }
+        }
+    }
+    for (idx, label) in labels.values_mut().enumerate() {
+        *label = format!("l{}", idx);
+    }
+    labels
+}
+
+// Disassembles a program loaded at base_addr into label definitions and instructions.
+pub fn disassemble_program(insts: &[u64], base_addr: u32) -> Vec<String> {
+    let labels = program_labels(insts, base_addr);
+    let mut lines = vec![];
+    for (idx, inst) in insts.iter().enumerate() {
+        let addr = base_addr + idx as u32 * 8;
+        if let Some(label) = labels.get(&addr) {
+            lines.push(format!("L.{}", label));
+        }
+        lines.push(format!("    {}  # 0x{:08x}: 0x{:016x}", render(*inst, addr, &labels), addr, inst));
+    }
+    lines
+}
+
+#[test]
+fn test_disassemble() {
+    let nop = (SIG_NOP as u64) << 60 | (COND_NEVER as u64) << 49 | (WA_NOP as u64) << 38 | (WB_NOP as u64) << 32;
+    assert_eq!(disassemble(nop), "nop()");
+    assert_eq!(disassemble(nop & !(0xf << 60) | (SIG_THREND as u64) << 60), "nop(sig='thread end')");
+
+    // fadd(r0, ra1, r2, cond='zs', set_flags=True).fmul(rb3, r4, r5)
+    let inst = (SIG_NOP as u64) << 60 | (COND_ZS as u64) << 49 | (COND_ALWAYS as u64) << 46 | 1 << 45
+        | (WA_ACC0 as u64) << 38 | (3u64 << 32) | (MULOP_FMUL as u64) << 29 | (ADDOP_FADD as u64) << 24
+        | 1 << 18 | (RB_NOP as u64) << 12 | (ALU_SRC_RA as u64) << 9 | (ALU_SRC_R2 as u64) << 6
+        | (ALU_SRC_R4 as u64) << 3 | ALU_SRC_R5 as u64;
+    assert_eq!(disassemble(inst), "fadd(r0, ra1, r2, cond='zs', set_flags=True).fmul(rb3, r4, r5)");
+
+    // mov(r1, uniform) and a mul only instruction with a small immediate and rotation.
+    let inst = (SIG_NOP as u64) << 60 | (COND_ALWAYS as u64) << 49 | (WA_ACC1 as u64) << 38 | (WB_NOP as u64) << 32
+        | (ADDOP_OR as u64) << 24 | (RA_UNIFORM_READ as u64) << 18 | (ALU_SRC_RA as u64) << 9 | (ALU_SRC_RA as u64) << 6;
+    assert_eq!(disassemble(inst), "mov(r1, uniform)");
+    let inst = (SIG_NOPSI as u64) << 60 | (COND_ALWAYS as u64) << 46 | (WA_NOP as u64) << 38 | (WB_ACC2 as u64) << 32
+        | (MULOP_FMUL as u64) << 29 | (RA_NOP as u64) << 18 | 51 << 12 | (ALU_SRC_R0 as u64) << 3 | ALU_SRC_R1 as u64;
+    assert_eq!(disassemble(inst), "fmul(r2, r0, r1, rotate=3)");
+    let inst = (SIG_NOPSI as u64) << 60 | (COND_ALWAYS as u64) << 49 | (WA_RA5 as u64) << 38 | (WB_NOP as u64) << 32
+        | (ADDOP_SHL as u64) << 24 | (RA_NOP as u64) << 18 | 30 << 12 | (ALU_SRC_R0 as u64) << 9 | (ALU_SRC_RB as u64) << 6;
+    assert_eq!(disassemble(inst), "shl(ra5, r0, -2)");
+
+    // Load immediates and semaphores.
+    let ldi = (SIG_LDI as u64) << 60 | (COND_ALWAYS as u64) << 49 | (COND_ALWAYS as u64) << 46
+        | (WA_NOP as u64) << 38 | (WB_VPMVCD_WR_SETUP as u64) << 32 | 0x1a00;
+    assert_eq!(disassemble(ldi), "ldi(vpmvcd_wr_setup, 0x00001a00)");
+    let ldi = ldi & !0xffff_ffff | 1 << 57 | 0x0001_8000;
+    assert_eq!(disassemble(ldi), "ldi(vpmvcd_wr_setup, [-2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1])");
+    assert_eq!(disassemble(ldi | 0b010 << 57), "ldi(vpmvcd_wr_setup, [2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1], unsigned=True)");
+    let sema = (SIG_LDI as u64) << 60 | 0b100 << 57 | (COND_ALWAYS as u64) << 49 | (COND_ALWAYS as u64) << 46
+        | (WA_NOP as u64) << 38 | (WB_NOP as u64) << 32 | 1 << 4 | 3;
+    assert_eq!(disassemble(sema), "sema_down(3)");
+    assert_eq!(disassemble((SIG_LDI as u64) << 60 | 0b111 << 57), "invalid(0xee00000000000000)");
+}
+
+#[test]
+fn test_disassemble_program() {
+    let nop = (SIG_NOP as u64) << 60 | (WA_NOP as u64) << 38 | (WB_NOP as u64) << 32;
+    // A relative branch back to the first instruction, with a link.
+    let bra = (SIG_BRA as u64) << 60 | (COND_BR_ANYZC as u64) << 52 | 1 << 51
+        | (WA_RA3 as u64) << 38 | (WB_NOP as u64) << 32 | (-40i32 as u32) as u64;
+    let lines = disassemble_program(&[nop, bra, nop], 0x100);
+
+    assert_eq!(lines[0], "L.l0");
+    assert_eq!(lines[1], format!("    nop()  # 0x00000100: 0x{:016x}", nop));
+    assert_eq!(lines[2], format!("    bra(L.l0, cond='anyzc', link=ra3)  # 0x00000108: 0x{:016x}", bra));
+    assert_eq!(disassemble(bra), "bra(-40, cond='anyzc', link=ra3)");
+}
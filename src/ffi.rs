@@ -0,0 +1,303 @@
+// C API of the simulator. The header videocoreiv_sim.h is generated from this file into
+// OUT_DIR by the build script, and copied to $VIDEOCOREIV_SIM_HEADER if it is set.
+//
+// Addresses are bus addresses. Uniforms are passed as 32bit words, so floats and buffer
+// addresses are packed by the caller. The functions return VCSIM_OK or a negative error code.
+// A panic of the emulator is caught and reported as VCSIM_ERROR_PANIC.
+
+use crate::kernel::{Kernel, LaunchStatus, Uniform};
+use crate::processor::{QPUEmu, ThreadStats};
+
+use std::panic::{self, AssertUnwindSafe};
+use std::slice;
+
+pub const VCSIM_OK: i32 = 0;
+pub const VCSIM_ERROR_INVALID_ARGUMENT: i32 = -1;
+pub const VCSIM_ERROR_OUT_OF_MEMORY: i32 = -2;
+pub const VCSIM_ERROR_FAULT: i32 = -3;
+pub const VCSIM_ERROR_PANIC: i32 = -4;
+pub const VCSIM_ERROR_STOPPED: i32 = -5;
+
+// Opaque handles.
+pub struct VcsimEmu {
+    emu: QPUEmu,
+}
+
+pub struct VcsimProgram {
+    kernel: Kernel,
+}
+
+#[repr(C)]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct VcsimThreadStats {
+    pub instructions: u64,
+    pub uniforms: u64,
+    pub tmu_loads: u64,
+    pub vpm_dma_loads: u64,
+    pub vpm_dma_stores: u64,
+    pub host_interrupts: u64,
+}
+
+impl From<ThreadStats> for VcsimThreadStats {
+    fn from(stats: ThreadStats) -> Self {
+        VcsimThreadStats {
+            instructions: stats.instructions,
+            uniforms: stats.uniforms,
+            tmu_loads: stats.tmu_loads,
+            vpm_dma_loads: stats.vpm_dma_loads,
+            vpm_dma_stores: stats.vpm_dma_stores,
+            host_interrupts: stats.host_interrupts,
+        }
+    }
+}
+
+fn catch_panic<F: FnOnce() -> i32>(f: F) -> i32 {
+    panic::catch_unwind(AssertUnwindSafe(f)).unwrap_or(VCSIM_ERROR_PANIC)
+}
+
+/// Creates an emulator with mem_size bytes of RAM mapped from bus address 0.
+/// Returns NULL on failure.
+#[no_mangle]
+pub extern "C" fn vcsim_create(mem_size: usize) -> *mut VcsimEmu {
+    match panic::catch_unwind(|| QPUEmu::new(mem_size)) {
+        Ok(emu) => Box::into_raw(Box::new(VcsimEmu { emu })),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// # Safety
+/// emu must be NULL or a handle returned by vcsim_create which is not destroyed yet.
+#[no_mangle]
+pub unsafe extern "C" fn vcsim_destroy(emu: *mut VcsimEmu) {
+    if !emu.is_null() {
+        drop(Box::from_raw(emu));
+    }
+}
+
+/// Allocates size bytes aligned to align, a power of two, and stores the bus address in addr.
+///
+/// # Safety
+/// emu must be a valid handle and addr must point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn vcsim_alloc(emu: *mut VcsimEmu, size: usize, align: usize, addr: *mut u32) -> i32 {
+    if emu.is_null() || addr.is_null() || !align.is_power_of_two() {
+        return VCSIM_ERROR_INVALID_ARGUMENT;
+    }
+    catch_panic(|| match (*emu).emu.alloc(size, align) {
+        Some(buffer) => {
+            *addr = buffer.addr();
+            VCSIM_OK
+        },
+        None => VCSIM_ERROR_OUT_OF_MEMORY,
+    })
+}
+
+/// Frees the buffer allocated at addr.
+///
+/// # Safety
+/// emu must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn vcsim_free(emu: *mut VcsimEmu, addr: u32) -> i32 {
+    if emu.is_null() {
+        return VCSIM_ERROR_INVALID_ARGUMENT;
+    }
+    let emu = &mut (*emu).emu;
+    match emu.buffer_at(addr) {
+        Some(buffer) => catch_panic(|| {
+            emu.free(buffer);
+            VCSIM_OK
+        }),
+        None => VCSIM_ERROR_INVALID_ARGUMENT,
+    }
+}
+
+/// Copies len bytes from data to the bus address addr.
+///
+/// # Safety
+/// emu must be a valid handle and data must point to len readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn vcsim_write(emu: *mut VcsimEmu, addr: u32, data: *const u8, len: usize) -> i32 {
+    if emu.is_null() || (data.is_null() && len > 0) {
+        return VCSIM_ERROR_INVALID_ARGUMENT;
+    }
+    if len == 0 {
+        return VCSIM_OK;
+    }
+    match (*emu).emu.write_bytes(addr, slice::from_raw_parts(data, len)) {
+        Ok(()) => VCSIM_OK,
+        Err(_) => VCSIM_ERROR_FAULT,
+    }
+}
+
+/// Copies len bytes from the bus address addr to data.
+///
+/// # Safety
+/// emu must be a valid handle and data must point to len writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn vcsim_read(emu: *const VcsimEmu, addr: u32, data: *mut u8, len: usize) -> i32 {
+    if emu.is_null() || (data.is_null() && len > 0) {
+        return VCSIM_ERROR_INVALID_ARGUMENT;
+    }
+    if len == 0 {
+        return VCSIM_OK;
+    }
+    match (*emu).emu.read_bytes(addr, slice::from_raw_parts_mut(data, len)) {
+        Ok(()) => VCSIM_OK,
+        Err(_) => VCSIM_ERROR_FAULT,
+    }
+}
+
+/// Creates a program from num_insts 64bit instructions. Returns NULL on failure.
+///
+/// # Safety
+/// insts must point to num_insts readable instructions.
+#[no_mangle]
+pub unsafe extern "C" fn vcsim_load_program(insts: *const u64, num_insts: usize) -> *mut VcsimProgram {
+    if insts.is_null() || num_insts == 0 {
+        return std::ptr::null_mut();
+    }
+    let kernel = Kernel::new(slice::from_raw_parts(insts, num_insts).to_vec());
+    Box::into_raw(Box::new(VcsimProgram { kernel }))
+}
+
+/// # Safety
+/// program must be NULL or a handle returned by vcsim_load_program which is not freed yet.
+#[no_mangle]
+pub unsafe extern "C" fn vcsim_free_program(program: *mut VcsimProgram) {
+    if !program.is_null() {
+        drop(Box::from_raw(program));
+    }
+}
+
+/// Runs num_threads threads of the program. Thread i reads the uniforms
+/// uniforms[i * num_uniforms .. (i + 1) * num_uniforms]. The statistics of each thread are
+/// stored in stats unless it is NULL. Returns VCSIM_ERROR_FAULT if a thread accessed unmapped
+/// memory; the threads after it are not run. Returns VCSIM_ERROR_STOPPED if the threads
+/// stopped before their end.
+///
+/// # Safety
+/// emu and program must be valid handles, uniforms must point to num_threads * num_uniforms
+/// words and stats must be NULL or point to num_threads writable elements.
+#[no_mangle]
+pub unsafe extern "C" fn vcsim_launch(emu: *mut VcsimEmu, program: *const VcsimProgram, uniforms: *const u32,
+                                      num_uniforms: usize, num_threads: usize, stats: *mut VcsimThreadStats) -> i32 {
+    let num_words = match num_uniforms.checked_mul(num_threads) {
+        Some(num_words) => num_words,
+        None => return VCSIM_ERROR_INVALID_ARGUMENT,
+    };
+    if emu.is_null() || program.is_null() || (uniforms.is_null() && num_words > 0) {
+        return VCSIM_ERROR_INVALID_ARGUMENT;
+    }
+    let words = if num_words > 0 {
+        slice::from_raw_parts(uniforms, num_words)
+    } else {
+        &[]
+    };
+
+    catch_panic(|| {
+        let mut launch = (*program).kernel.launch();
+        for thread in 0..num_threads {
+            let thread_words = &words[thread * num_uniforms..(thread + 1) * num_uniforms];
+            launch = launch.thread(thread_words.iter().map(|word| Uniform::U32(*word)).collect());
+        }
+
+        let result = launch.run(&mut (*emu).emu);
+        if !stats.is_null() {
+            for (idx, thread_stats) in result.threads.iter().enumerate() {
+                *stats.add(idx) = VcsimThreadStats::from(*thread_stats);
+            }
+        }
+        match result.status {
+            LaunchStatus::Completed => VCSIM_OK,
+            LaunchStatus::Fault { .. } => VCSIM_ERROR_FAULT,
+            LaunchStatus::OutOfMemory => VCSIM_ERROR_OUT_OF_MEMORY,
+            LaunchStatus::Stopped { .. } => VCSIM_ERROR_STOPPED,
+        }
+    })
+}
+
+/// Runs the program stored at code_addr with the uniforms at uniforms_addr.
+///
+/// # Safety
+/// emu must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn vcsim_execute_at(emu: *mut VcsimEmu, code_addr: u32, uniforms_addr: u32) -> i32 {
+    if emu.is_null() {
+        return VCSIM_ERROR_INVALID_ARGUMENT;
+    }
+    let emu = &mut (*emu).emu;
+    catch_panic(|| {
+        emu.execute_at(code_addr, uniforms_addr);
+        if emu.fault().is_some() { VCSIM_ERROR_FAULT } else { VCSIM_OK }
+    })
+}
+
+/// Stores the address of the fault which stopped the last run in addr. Returns
+/// VCSIM_ERROR_INVALID_ARGUMENT if there was no fault.
+///
+/// # Safety
+/// emu must be a valid handle and addr must point to writable memory.
+#[no_mangle]
+pub unsafe extern "C" fn vcsim_last_fault(emu: *const VcsimEmu, addr: *mut u32) -> i32 {
+    if emu.is_null() || addr.is_null() {
+        return VCSIM_ERROR_INVALID_ARGUMENT;
+    }
+    match (*emu).emu.fault() {
+        Some(fault) => {
+            *addr = fault.addr;
+            VCSIM_OK
+        },
+        None => VCSIM_ERROR_INVALID_ARGUMENT,
+    }
+}
+
+/// Returns the number of host interrupts raised by the programs so far.
+///
+/// # Safety
+/// emu must be a valid handle.
+#[no_mangle]
+pub unsafe extern "C" fn vcsim_host_interrupts(emu: *const VcsimEmu) -> u32 {
+    if emu.is_null() {
+        return 0;
+    }
+    (*emu).emu.host_interrupts()
+}
+
+#[test]
+fn test_ffi_launch() {
+    // Stores the first uniform to the address given by the second one and interrupts the host.
+    let insts = crate::test::store_uniform_program(0, true);
+
+    unsafe {
+        let emu = vcsim_create(0x1000);
+        let program = vcsim_load_program(insts.as_ptr(), insts.len());
+        assert!(!emu.is_null() && !program.is_null());
+
+        let mut out = 0;
+        assert_eq!(vcsim_alloc(emu, 8, 16, &mut out), VCSIM_OK);
+        assert_eq!(vcsim_alloc(emu, 8, 3, &mut out), VCSIM_ERROR_INVALID_ARGUMENT);
+
+        let uniforms = [0x1111, 0xc000_0000 | out, 0x2222, out + 4];
+        let mut stats = [VcsimThreadStats::default(); 2];
+        assert_eq!(vcsim_launch(emu, program, uniforms.as_ptr(), 2, 2, stats.as_mut_ptr()), VCSIM_OK);
+        assert_eq!(stats[1].instructions, 8);
+        assert_eq!(vcsim_host_interrupts(emu), 2);
+
+        let mut data = [0u8; 8];
+        assert_eq!(vcsim_read(emu, out, data.as_mut_ptr(), 8), VCSIM_OK);
+        assert_eq!(data, [0x11, 0x11, 0, 0, 0x22, 0x22, 0, 0]);
+
+        let uniforms = [0, 0x2000];
+        let mut fault = 0;
+        assert_eq!(vcsim_launch(emu, program, uniforms.as_ptr(), 2, 1, std::ptr::null_mut()), VCSIM_ERROR_FAULT);
+        assert_eq!(vcsim_last_fault(emu, &mut fault), VCSIM_OK);
+        assert_eq!(fault, 0x2000);
+        assert_eq!(vcsim_read(emu, 0xffc, data.as_mut_ptr(), 8), VCSIM_ERROR_FAULT);
+        assert_eq!(vcsim_launch(emu, program, uniforms.as_ptr(), usize::MAX, 2, std::ptr::null_mut()), VCSIM_ERROR_INVALID_ARGUMENT);
+
+        assert_eq!(vcsim_free(emu, out), VCSIM_OK);
+        assert_eq!(vcsim_free(emu, out), VCSIM_ERROR_INVALID_ARGUMENT);
+        vcsim_free_program(program);
+        vcsim_destroy(emu);
+    }
+}
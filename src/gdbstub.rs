@@ -0,0 +1,615 @@
+// GDB remote serial protocol stub, debugging the threads started on the emulator with
+// QPUEmu::start or start_at from `target remote HOST:PORT` or `target remote SOCKET`.
+//
+// Each thread of the program is a GDB thread with the id thread + 1. The threads run one after
+// another and share the registers, which are those of the running thread. After Hg selects
+// another thread, g, G, p and P reply with an error until it runs. The target description
+// target.xml gives the 16 elements of a register as a vector:
+//
+//   0-5     r0-r5       16 x 32 bits
+//   6-37    ra0-ra31    16 x 32 bits
+//   38-69   rb0-rb31    16 x 32 bits
+//   70      pc          bus address of the next instruction, read only
+//   71      unif        uniform pointer
+//   72-74   zf, nf, cf  the flag of each element in one bit, read only
+//
+// Packets:
+//   ?, g, G, p, P, m, M           stop reason, registers and memory
+//   c, s, vCont                   continue and step, the whole program or one thread
+//   Z0/Z1, z0/z1                  breakpoints at the address of an instruction
+//   Z2-Z4, z2-z4                  write, read and access watchpoints on memory
+//   H, T, qfThreadInfo, qsThreadInfo, qC, qThreadExtraInfo
+//   qSupported, qXfer:features:read, qAttached, QStartNoAckMode, D, k
+//
+// Other packets get the empty reply of unsupported packets.
+
+use crate::breakpoint::{Access, WatchTarget, Watchpoint};
+use crate::hooks::Register;
+use crate::processor::{QPUEmu, StopReason};
+
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::net::TcpListener;
+use std::os::unix::net::UnixListener;
+use std::path::Path;
+
+const NUM_REGS: usize = 75;
+const REG_PC: usize = 70;
+const REG_UNIF: usize = 71;
+const REG_ZF: usize = 72;
+
+// Limit of a packet, announced in qSupported.
+const PACKET_SIZE: usize = 0x4000;
+
+// Signals of the stop replies.
+const SIGTRAP: u8 = 5;
+const SIGSEGV: u8 = 11;
+
+// Error codes of the E replies.
+const EFAULT: u8 = 14;
+const EINVAL: u8 = 22;
+
+fn reg_name(reg: usize) -> String {
+    match reg {
+        0..=69 => register(reg).unwrap().to_string(),
+        REG_PC => "pc".to_string(),
+        REG_UNIF => "unif".to_string(),
+        _ => ["zf", "nf", "cf"][reg - REG_ZF].to_string(),
+    }
+}
+
+// The register file register of a register number.
+fn register(reg: usize) -> Option<Register> {
+    match reg {
+        0..=5 => Some(Register::Acc(reg as u8)),
+        6..=37 => Some(Register::A(reg as u8 - 6)),
+        38..=69 => Some(Register::B(reg as u8 - 38)),
+        _ => None,
+    }
+}
+
+pub fn target_xml() -> String {
+    let mut xml = String::new();
+    xml.push_str("<?xml version=\"1.0\"?>\n<!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n<target version=\"1.0\">\n");
+    xml.push_str("  <feature name=\"org.videocore.qpu\">\n");
+    xml.push_str("    <vector id=\"v16u32\" type=\"uint32\" count=\"16\"/>\n");
+    for reg in 0..NUM_REGS {
+        let (bitsize, ty) = match reg {
+            0..=69 => (512, "v16u32"),
+            REG_PC => (32, "code_ptr"),
+            REG_UNIF => (32, "data_ptr"),
+            _ => (16, "uint16"),
+        };
+        writeln!(xml, "    <reg name=\"{}\" bitsize=\"{}\" type=\"{}\" regnum=\"{}\"/>", reg_name(reg), bitsize, ty, reg).unwrap();
+    }
+    xml.push_str("  </feature>\n</target>\n");
+    xml
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..s.len()).step_by(2).map(|idx| s.get(idx..idx + 2).and_then(|byte| u8::from_str_radix(byte, 16).ok())).collect()
+}
+
+fn parse_hex(s: &str) -> Option<u32> {
+    u32::from_str_radix(s, 16).ok()
+}
+
+// Parses ADDR,LEN.
+fn parse_range(s: &str) -> Option<(u32, u32)> {
+    let idx = s.find(',')?;
+    Some((parse_hex(&s[..idx])?, parse_hex(&s[idx + 1..])?))
+}
+
+fn flag_bits(flags: &[bool; 16]) -> u16 {
+    flags.iter().enumerate().fold(0, |bits, (elem, flag)| bits | (*flag as u16) << elem)
+}
+
+fn error(code: u8) -> String {
+    format!("E{:02x}", code)
+}
+
+// Reads the bytes of a stream in chunks.
+struct ByteReader {
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl ByteReader {
+    fn new() -> ByteReader {
+        ByteReader { buf: vec![], pos: 0 }
+    }
+
+    // Returns None at the end of the stream.
+    fn next<R: Read>(&mut self, stream: &mut R) -> io::Result<Option<u8>> {
+        if self.pos == self.buf.len() {
+            let mut chunk = [0u8; 4096];
+            let len = stream.read(&mut chunk)?;
+            if len == 0 {
+                return Ok(None);
+            }
+            self.buf = chunk[..len].to_vec();
+            self.pos = 0;
+        }
+        self.pos += 1;
+        Ok(Some(self.buf[self.pos - 1]))
+    }
+}
+
+// A stub debugging the threads of the emulator. The threads must have been started.
+pub struct GdbStub<'a> {
+    emu: &'a mut QPUEmu,
+    stop: StopReason,
+    no_ack: bool,
+    // Set by D and k to end the session.
+    done: bool,
+    // The memory watchpoints by kind, address and length of the Z packet.
+    watchpoints: Vec<((u8, u32, u32), usize)>,
+    // The thread selected by Hg for the register packets, None for any thread.
+    reg_thread: Option<usize>,
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(emu: &'a mut QPUEmu) -> GdbStub<'a> {
+        GdbStub { emu, stop: StopReason::Stepped, no_ack: false, done: false, watchpoints: vec![], reg_thread: None }
+    }
+
+    // The thread running or about to run.
+    fn current_thread(&self) -> usize {
+        self.emu.thread().unwrap_or(0)
+    }
+
+    fn thread_state(&self, thread: usize) -> &'static str {
+        match self.emu.thread() {
+            Some(current) if thread < current => "ended",
+            Some(current) if thread == current => "running",
+            _ if self.stop == StopReason::Finished => "ended",
+            _ => "waiting",
+        }
+    }
+
+    fn stop_reply(&self) -> String {
+        let (signal, thread, watch) = match self.stop {
+            StopReason::Finished => return "W00".to_string(),
+            // resume does not keep it as the stop reason.
+            StopReason::InvalidThread { .. } => return error(EINVAL),
+            StopReason::Fault { thread, .. } => (SIGSEGV, thread, String::new()),
+            StopReason::Watchpoint(hit) => {
+                let watch = self.watchpoints.iter().find(|(_, idx)| *idx == hit.watchpoint).map(|((kind, addr, _), _)| {
+                    let name = ["watch", "rwatch", "awatch"][*kind as usize - 2];
+                    format!("{}:{:x};", name, addr)
+                });
+                (SIGTRAP, hit.thread, watch.unwrap_or_default())
+            }
+            StopReason::Breakpoint { thread, .. } | StopReason::Reached { thread, .. } | StopReason::Event { thread, .. }
+            | StopReason::Stopped { thread, .. } => {
+                (SIGTRAP, thread, String::new())
+            }
+            StopReason::Stepped => (SIGTRAP, self.current_thread(), String::new()),
+        };
+        format!("T{:02x}thread:{:x};{}", signal, thread + 1, watch)
+    }
+
+    // Selects the thread of the register packets for Hg. The thread of c and s is given by
+    // vCont instead, so Hc is ignored.
+    fn select_thread(&mut self, args: &str) -> String {
+        let (op, thread) = args.split_at(args.len().min(1));
+        if op != "g" {
+            return "OK".to_string();
+        }
+        self.reg_thread = match thread {
+            "0" | "-1" => None,
+            _ => match parse_hex(thread) {
+                Some(thread) if thread > 0 && thread as usize <= self.emu.num_threads() => Some(thread as usize - 1),
+                _ => return error(EINVAL),
+            },
+        };
+        "OK".to_string()
+    }
+
+    // Whether the registers are those of the thread selected by Hg.
+    fn has_registers(&self) -> bool {
+        self.reg_thread.is_none() || self.reg_thread == self.emu.thread()
+    }
+
+    fn read_register(&self, reg: usize) -> Vec<u8> {
+        match reg {
+            0..=69 => self.emu.register(register(reg).unwrap()).iter().flat_map(|val| val.to_le_bytes()).collect(),
+            REG_PC => self.emu.code_addr().wrapping_add(self.emu.pc() * 8).to_le_bytes().to_vec(),
+            REG_UNIF => self.emu.uniform_ptr().to_le_bytes().to_vec(),
+            _ => {
+                let flags = [self.emu.zero_flags(), self.emu.negative_flags(), self.emu.carry_flags()];
+                flag_bits(&flags[reg - REG_ZF]).to_le_bytes().to_vec()
+            }
+        }
+    }
+
+    // The read only registers are only written with their values.
+    fn write_register(&mut self, reg: usize, bytes: &[u8]) -> bool {
+        if bytes.len() != self.read_register(reg).len() {
+            return false;
+        }
+        let words: Vec<u32> = bytes.chunks(4).map(|word| u32::from_le_bytes([word[0], word[1], word[2], word[3]])).collect();
+        match register(reg) {
+            Some(register) => {
+                for (elem, val) in words.iter().enumerate() {
+                    self.emu.set_register(register, elem, *val);
+                }
+                true
+            }
+            None if reg == REG_UNIF => {
+                self.emu.set_uniform_ptr(words[0]);
+                true
+            }
+            None => bytes == self.read_register(reg).as_slice(),
+        }
+    }
+
+    // The instruction of a breakpoint address.
+    fn breakpoint_pc(&self, addr: u32) -> Option<u32> {
+        let offset = addr.checked_sub(self.emu.code_addr())?;
+        if offset % 8 != 0 {
+            return None;
+        }
+        Some(offset / 8)
+    }
+
+    fn set_point(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.splitn(3, ',');
+        let (kind, addr, len) = match (fields.next().and_then(|kind| kind.parse::<u8>().ok()), fields.next().and_then(parse_hex), fields.next().and_then(parse_hex)) {
+            (Some(kind), Some(addr), Some(len)) if kind <= 4 => (kind, addr, len),
+            _ => return error(EINVAL),
+        };
+
+        if kind <= 1 {
+            let pc = match self.breakpoint_pc(addr) {
+                Some(pc) => pc,
+                None => return error(EINVAL),
+            };
+            if insert {
+                self.emu.set_breakpoint(pc);
+            } else {
+                self.emu.clear_breakpoint(pc);
+            }
+            return "OK".to_string();
+        }
+
+        if len == 0 {
+            return error(EINVAL);
+        }
+        let key = (kind, addr, len);
+        if insert {
+            let access = [Access::Write, Access::Read, Access::ReadWrite][kind as usize - 2];
+            let idx = self.emu.add_watchpoint(Watchpoint { target: WatchTarget::Memory { addr, len }, access });
+            self.watchpoints.push((key, idx));
+        } else if let Some(pos) = self.watchpoints.iter().position(|(other, _)| *other == key) {
+            let (_, idx) = self.watchpoints.remove(pos);
+            self.emu.remove_watchpoint(idx);
+        }
+        "OK".to_string()
+    }
+
+    // Runs the program for c, s and vCont. Only the first action of vCont is used, and a step
+    // of a thread runs the threads before it to their end.
+    fn resume(&mut self, action: &str) -> String {
+        let (action, thread) = match action.find(':') {
+            Some(idx) => (&action[..idx], i64::from_str_radix(&action[idx + 1..], 16).ok()),
+            None => (action, None),
+        };
+        self.stop = match (action.chars().next(), thread) {
+            (Some('s'), Some(thread)) | (Some('S'), Some(thread)) if thread > 0 => {
+                match self.emu.step_qpu(thread as usize - 1) {
+                    StopReason::InvalidThread { .. } => return error(EINVAL),
+                    reason => reason,
+                }
+            }
+            (Some('s'), _) | (Some('S'), _) => self.emu.step(),
+            (Some('c'), _) | (Some('C'), _) => self.emu.run(),
+            _ => return error(EINVAL),
+        };
+        self.stop_reply()
+    }
+
+    // Returns the reply to a packet, or None for k, which has no reply.
+    pub fn packet(&mut self, packet: &str) -> Option<String> {
+        let (name, args) = packet.split_at(packet.find(|c: char| !c.is_ascii_alphabetic()).unwrap_or(packet.len()));
+        let reply = match packet.chars().next() {
+            Some('?') => self.stop_reply(),
+            Some('g') | Some('G') | Some('p') | Some('P') if !self.has_registers() => error(EINVAL),
+            Some('g') => (0..NUM_REGS).map(|reg| to_hex(&self.read_register(reg))).collect(),
+            Some('G') => {
+                let bytes = match from_hex(&packet[1..]) {
+                    Some(bytes) => bytes,
+                    None => return Some(error(EINVAL)),
+                };
+                let sizes: Vec<usize> = (0..NUM_REGS).map(|reg| self.read_register(reg).len()).collect();
+                if bytes.len() != sizes.iter().sum::<usize>() {
+                    return Some(error(EINVAL));
+                }
+                let mut offset = 0;
+                let mut ok = true;
+                for (reg, size) in sizes.into_iter().enumerate() {
+                    ok &= self.write_register(reg, &bytes[offset..offset + size]);
+                    offset += size;
+                }
+                if ok { "OK".to_string() } else { error(EINVAL) }
+            }
+            Some('p') => match parse_hex(&packet[1..]) {
+                Some(reg) if (reg as usize) < NUM_REGS => to_hex(&self.read_register(reg as usize)),
+                _ => error(EINVAL),
+            },
+            Some('P') => {
+                let idx = packet.find('=').unwrap_or(packet.len());
+                match (parse_hex(&packet[1..idx]), packet.get(idx + 1..).and_then(from_hex)) {
+                    (Some(reg), Some(bytes)) if (reg as usize) < NUM_REGS && self.write_register(reg as usize, &bytes) => "OK".to_string(),
+                    _ => error(EINVAL),
+                }
+            }
+            Some('m') => match parse_range(&packet[1..]) {
+                Some((addr, len)) if (len as usize) * 2 <= PACKET_SIZE => {
+                    let mut buf = vec![0u8; len as usize];
+                    match self.emu.read_bytes(addr, &mut buf) {
+                        Ok(()) => to_hex(&buf),
+                        Err(_) => error(EFAULT),
+                    }
+                }
+                _ => error(EINVAL),
+            },
+            Some('M') => {
+                let idx = packet.find(':').unwrap_or(packet.len());
+                match (parse_range(&packet[1..idx]), packet.get(idx + 1..).and_then(from_hex)) {
+                    (Some((addr, len)), Some(bytes)) if bytes.len() == len as usize => match self.emu.write_bytes(addr, &bytes) {
+                        Ok(()) => "OK".to_string(),
+                        Err(_) => error(EFAULT),
+                    },
+                    _ => error(EINVAL),
+                }
+            }
+            Some('c') => self.resume("c"),
+            Some('s') => self.resume("s"),
+            Some('Z') => self.set_point(true, &packet[1..]),
+            Some('z') => self.set_point(false, &packet[1..]),
+            Some('H') => self.select_thread(&packet[1..]),
+            Some('T') => match parse_hex(&packet[1..]) {
+                Some(thread) if thread > 0 && thread as usize <= self.emu.num_threads() && self.thread_state(thread as usize - 1) != "ended" => "OK".to_string(),
+                _ => error(EINVAL),
+            },
+            Some('D') => {
+                self.detach();
+                self.done = true;
+                "OK".to_string()
+            }
+            Some('k') => {
+                self.done = true;
+                return None;
+            }
+            _ => match (name, args) {
+                ("vCont", "?") => "vCont;c;C;s;S".to_string(),
+                ("vCont", args) if args.starts_with(';') => self.resume(args[1..].split(';').next().unwrap()),
+                ("qSupported", _) => format!("PacketSize={:x};qXfer:features:read+;QStartNoAckMode+", PACKET_SIZE),
+                ("qXfer", args) => match args.strip_prefix(":features:read:target.xml:").and_then(parse_range) {
+                    Some((offset, len)) => {
+                        let xml = target_xml();
+                        let start = (offset as usize).min(xml.len());
+                        let end = start.saturating_add(len as usize).min(xml.len());
+                        format!("{}{}", if end < xml.len() { 'm' } else { 'l' }, &xml[start..end])
+                    }
+                    None => String::new(),
+                },
+                ("qfThreadInfo", "") => {
+                    let threads: Vec<String> = (1..=self.emu.num_threads()).map(|thread| format!("{:x}", thread)).collect();
+                    format!("m{}", threads.join(","))
+                }
+                ("qsThreadInfo", "") => "l".to_string(),
+                ("qC", "") => format!("QC{:x}", self.current_thread() + 1),
+                ("qAttached", _) => "1".to_string(),
+                ("qThreadExtraInfo", args) => match args.strip_prefix(',').and_then(parse_hex) {
+                    Some(thread) if thread > 0 && thread as usize <= self.emu.num_threads() => {
+                        to_hex(format!("QPU {} {}", thread - 1, self.thread_state(thread as usize - 1)).as_bytes())
+                    }
+                    _ => error(EINVAL),
+                },
+                ("QStartNoAckMode", "") => {
+                    self.no_ack = true;
+                    "OK".to_string()
+                }
+                _ => String::new(),
+            },
+        };
+        Some(reply)
+    }
+
+    // Removes the breakpoints and the watchpoints, so that the program runs to its end.
+    fn detach(&mut self) {
+        for (pc, _) in self.emu.breakpoints() {
+            self.emu.clear_breakpoint(pc);
+        }
+        for (_, idx) in self.watchpoints.drain(..) {
+            self.emu.remove_watchpoint(idx);
+        }
+    }
+
+    fn send<S: Write>(&self, stream: &mut S, reply: &str) -> io::Result<()> {
+        let mut data = vec![];
+        for byte in reply.bytes() {
+            if byte == b'#' || byte == b'$' || byte == b'}' || byte == b'*' {
+                data.extend_from_slice(&[b'}', byte ^ 0x20]);
+            } else {
+                data.push(byte);
+            }
+        }
+        let checksum = data.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+        stream.write_all(b"$")?;
+        stream.write_all(&data)?;
+        write!(stream, "#{:02x}", checksum)?;
+        stream.flush()
+    }
+
+    // Serves the packets of a connection until GDB detaches, kills the program or disconnects.
+    pub fn session<S: Read + Write>(&mut self, stream: &mut S) -> io::Result<()> {
+        let mut bytes = ByteReader::new();
+        loop {
+            let byte = match bytes.next(stream)? {
+                Some(byte) => byte,
+                None => return Ok(()),
+            };
+            match byte {
+                b'$' => {}
+                // An interrupt only arrives while stopped, as the program runs in this thread.
+                0x03 => {
+                    let reply = self.stop_reply();
+                    self.send(stream, &reply)?;
+                    continue;
+                }
+                _ => continue,
+            }
+
+            let mut data = vec![];
+            let mut checksum = 0u8;
+            loop {
+                match bytes.next(stream)? {
+                    Some(b'#') => break,
+                    Some(byte) => {
+                        checksum = checksum.wrapping_add(byte);
+                        data.push(byte);
+                    }
+                    None => return Ok(()),
+                }
+            }
+            let expected = match (bytes.next(stream)?, bytes.next(stream)?) {
+                (Some(hi), Some(lo)) => from_hex(&String::from_utf8_lossy(&[hi, lo])).map(|bytes| bytes[0]),
+                _ => return Ok(()),
+            };
+            if !self.no_ack {
+                if expected != Some(checksum) {
+                    stream.write_all(b"-")?;
+                    continue;
+                }
+                stream.write_all(b"+")?;
+            }
+
+            let mut packet = vec![];
+            let mut escaped = false;
+            for byte in data {
+                if escaped {
+                    packet.push(byte ^ 0x20);
+                    escaped = false;
+                } else if byte == b'}' {
+                    escaped = true;
+                } else {
+                    packet.push(byte);
+                }
+            }
+            if let Some(reply) = self.packet(&String::from_utf8_lossy(&packet)) {
+                self.send(stream, &reply)?;
+            }
+            if self.done {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Waits for GDB on a TCP address HOST:PORT, or on a Unix socket if the address contains '/',
+// and serves the connection.
+pub fn listen(emu: &mut QPUEmu, addr: &str) -> io::Result<()> {
+    let mut stub = GdbStub::new(emu);
+    if addr.contains('/') {
+        let path = Path::new(addr);
+        crate::server::remove_stale_socket(path)?;
+        let (mut stream, _) = UnixListener::bind(path)?.accept()?;
+        stub.session(&mut stream)
+    } else {
+        let (mut stream, _) = TcpListener::bind(addr)?.accept()?;
+        stub.session(&mut stream)
+    }
+}
+
+#[test]
+fn test_gdbstub() {
+    use crate::assembler;
+    use crate::kernel::Kernel;
+    use std::os::unix::net::UnixStream;
+    use std::thread;
+
+    // Loads the uniform to ra0, then adds 1 to it in r1.
+    let insts = assembler::assemble("
+        mov(ra0, uniform)
+        iadd(r1, ra0, 1)
+        nop(sig='thread end')
+        nop()
+        nop()
+        nop()
+        nop()
+        nop()
+    ").unwrap();
+
+    let mut emu = QPUEmu::new(0x10000);
+    let (code_buf, _) = Kernel::new(insts).launch().thread(vec![5u32.into()]).thread(vec![7u32.into()]).start(&mut emu).unwrap();
+    let code_addr = code_buf.addr();
+    let mut stub = GdbStub::new(&mut emu);
+    let mut packet = |packet: &str| stub.packet(packet).unwrap();
+
+    assert!(packet("qSupported:multiprocess+").contains("qXfer:features:read+"));
+    assert_eq!(packet("qfThreadInfo"), "m1,2");
+    assert_eq!(packet("qsThreadInfo"), "l");
+    assert_eq!(packet("?"), "T05thread:1;");
+    assert_eq!(packet("vCont?"), "vCont;c;C;s;S");
+    assert_eq!(packet("qXfer:features:read:target.xml:0,c"), "m<?xml versio");
+    assert!(packet("qXfer:features:read:target.xml:200,10000").ends_with("</target>\n"));
+    assert_eq!(packet("qRcmd,00"), "");
+
+    assert_eq!(packet(&format!("Z0,{:x},8", code_addr + 8)), "OK");
+    assert_eq!(packet(&format!("Z0,{:x},8", code_addr + 4)), "E16");
+    assert_eq!(packet("c"), "T05thread:1;");
+    assert_eq!(packet("p46"), format!("{:08x}", (code_addr + 8).swap_bytes()));
+    assert_eq!(packet("p6")[..8], *"05000000");
+    assert_eq!(packet("P1=2a000000"), "E16");
+    assert_eq!(packet(&format!("P6={}", "2a000000".repeat(16))), "OK");
+    assert_eq!(packet("P46=00000000"), "E16");
+    assert_eq!(packet("s"), "T05thread:1;");
+    assert_eq!(packet("p1")[..16], *"2b0000002b000000");
+    assert_eq!(packet("g").len(), (70 * 64 + 4 + 4 + 3 * 2) * 2);
+    assert_eq!(packet("qThreadExtraInfo,2"), to_hex(b"QPU 1 waiting"));
+    assert_eq!(packet("Hg2"), "OK");
+    assert_eq!(packet("p1"), "E16");
+    assert_eq!(packet("g"), "E16");
+    assert_eq!(packet("Hg3"), "E16");
+    assert_eq!(packet("Hc-1"), "OK");
+    assert_eq!(packet("Hg1"), "OK");
+    assert_eq!(packet("p1")[..8], *"2b000000");
+    assert_eq!(packet("vCont;s:3"), "E16");
+
+    // The uniform of the second thread is read at the breakpoint of the first instruction.
+    assert_eq!(packet(&format!("z0,{:x},8", code_addr + 8)), "OK");
+    assert_eq!(packet(&format!("Z0,{:x},8", code_addr)), "OK");
+    assert_eq!(packet(&format!("Z3,{:x},4", code_addr + 64 + 4)), "OK");
+    assert_eq!(packet("c"), "T05thread:2;");
+    assert_eq!(packet("T1"), "E16");
+    assert_eq!(packet("qC"), "QC2");
+    assert_eq!(packet("c"), format!("T05thread:2;rwatch:{:x};", code_addr + 64 + 4));
+    assert_eq!(packet("m8000,4"), "00000000");
+    assert_eq!(packet("M8000,4:01020304"), "OK");
+    assert_eq!(packet("m8000,4"), "01020304");
+    assert_eq!(packet("m10000,4"), "E0e");
+    assert_eq!(packet("c"), "W00");
+    assert_eq!(stub.emu.reg_a(0)[0], 7);
+
+    // The framing of the packets and the acknowledgements.
+    let (mut client, mut server_end) = UnixStream::pair().unwrap();
+    let handle = thread::spawn(move || {
+        let mut emu = QPUEmu::new(0x1000);
+        GdbStub::new(&mut emu).session(&mut server_end).unwrap();
+    });
+    client.write_all(b"$qC#b4$qAttached#00$QStartNoAckMode#b0$p47#db$D#44").unwrap();
+    let mut replies = String::new();
+    client.read_to_string(&mut replies).unwrap();
+    assert_eq!(replies, "+$QC1#c5-+$OK#9a$00000000#80$OK#9a");
+    handle.join().unwrap();
+}
@@ -0,0 +1,147 @@
+// Partitioning of a 1D or 2D problem into tiles and the tiles into blocks, one per thread.
+//
+// The tiles are split as evenly as possible along each axis, so the threads at the end get
+// the remainder. Tiles at the right and bottom edges may be partial.
+
+use crate::kernel::Uniform;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Grid {
+    pub width: usize,
+    pub height: usize,
+    pub tile_width: usize,
+    pub tile_height: usize,
+}
+
+// The block of a thread, in elements and in tiles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct WorkItem {
+    pub thread: usize,
+    pub num_threads: usize,
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+    pub tile_x: usize,
+    pub tile_y: usize,
+    pub tiles_x: usize,
+    pub tiles_y: usize,
+}
+
+impl WorkItem {
+    // Uniforms in the order: thread index, thread count, first tile x, first tile y, tiles along
+    // x, tiles along y. The tiles at the right and bottom edges of the grid may be partial.
+    pub fn uniforms(&self) -> Vec<Uniform> {
+        [self.thread, self.num_threads, self.tile_x, self.tile_y, self.tiles_x, self.tiles_y].iter()
+            .map(|val| Uniform::U32(*val as u32))
+            .collect()
+    }
+}
+
+impl Grid {
+    pub fn new_1d(len: usize, tile_len: usize) -> Self {
+        Grid::new_2d(len, 1, tile_len, 1)
+    }
+
+    pub fn new_2d(width: usize, height: usize, tile_width: usize, tile_height: usize) -> Self {
+        if tile_width == 0 || tile_height == 0 {
+            panic!("The tile size is zero.");
+        }
+        Grid { width, height, tile_width, tile_height }
+    }
+
+    pub fn tiles(&self) -> (usize, usize) {
+        (self.width.div_ceil(self.tile_width), self.height.div_ceil(self.tile_height))
+    }
+
+    // Chooses the number of threads along x and y for at most num_qpus threads. The layout
+    // with the most threads wins, then the one with the squarest blocks of tiles.
+    pub fn layout(&self, num_qpus: usize) -> (usize, usize) {
+        let (tiles_x, tiles_y) = self.tiles();
+        let mut best = (1, 1);
+        let mut best_key = (0, usize::MAX);
+
+        for div_y in 1..=num_qpus.min(tiles_y).max(1) {
+            let div_x = (num_qpus / div_y).min(tiles_x).max(1);
+            let block_x = tiles_x.div_ceil(div_x);
+            let block_y = tiles_y.div_ceil(div_y);
+            let key = (div_x * div_y, block_x.abs_diff(block_y));
+
+            if key.0 > best_key.0 || (key.0 == best_key.0 && key.1 < best_key.1) {
+                best = (div_x, div_y);
+                best_key = key;
+            }
+        }
+        best
+    }
+
+    pub fn partition(&self, num_qpus: usize) -> Vec<WorkItem> {
+        let (div_x, div_y) = self.layout(num_qpus);
+        self.partition_with(div_x, div_y)
+    }
+
+    // Splits the tiles into div_x by div_y blocks, numbered in row-major order.
+    pub fn partition_with(&self, div_x: usize, div_y: usize) -> Vec<WorkItem> {
+        let (tiles_x, tiles_y) = self.tiles();
+        if div_x == 0 || div_y == 0 || div_x > tiles_x.max(1) || div_y > tiles_y.max(1) {
+            panic!("The number of blocks is out of range.");
+        }
+
+        let mut items = vec![];
+        for by in 0..div_y {
+            let (tile_y, end_y) = (by * tiles_y / div_y, (by + 1) * tiles_y / div_y);
+            for bx in 0..div_x {
+                let (tile_x, end_x) = (bx * tiles_x / div_x, (bx + 1) * tiles_x / div_x);
+
+                let x = tile_x * self.tile_width;
+                let y = tile_y * self.tile_height;
+                items.push(WorkItem {
+                    thread: items.len(),
+                    num_threads: div_x * div_y,
+                    x,
+                    y,
+                    width: (end_x * self.tile_width).min(self.width) - x,
+                    height: (end_y * self.tile_height).min(self.height) - y,
+                    tile_x,
+                    tile_y,
+                    tiles_x: end_x - tile_x,
+                    tiles_y: end_y - tile_y,
+                });
+            }
+        }
+        items
+    }
+}
+
+#[test]
+fn test_grid_partition_1d() {
+    let grid = Grid::new_1d(100, 16);
+    assert_eq!(grid.tiles(), (7, 1));
+
+    let items = grid.partition(4);
+    assert_eq!(items.len(), 4);
+    let bounds: Vec<(usize, usize, usize)> = items.iter().map(|item| (item.x, item.width, item.tiles_x)).collect();
+    assert_eq!(bounds, vec![(0, 16, 1), (16, 32, 2), (48, 32, 2), (80, 20, 2)]);
+    assert!(items.iter().all(|item| item.num_threads == 4 && item.height == 1));
+
+    // There are fewer tiles than QPUs.
+    assert_eq!(Grid::new_1d(20, 16).partition(12).len(), 2);
+}
+
+#[test]
+fn test_grid_partition_2d() {
+    let grid = Grid::new_2d(3072, 96, 64, 16);
+    assert_eq!(grid.layout(12), (12, 1));
+
+    let items = grid.partition_with(6, 2);
+    assert_eq!(items.len(), 12);
+    assert_eq!((items[7].x, items[7].y, items[7].width, items[7].height), (512, 48, 512, 48));
+
+    let grid = Grid::new_2d(70, 20, 32, 8);
+    let items = grid.partition(4);
+    assert_eq!(grid.layout(4), (2, 2));
+    let bounds: Vec<(usize, usize, usize, usize)> = items.iter().map(|item| (item.x, item.y, item.width, item.height)).collect();
+    assert_eq!(bounds, vec![(0, 0, 32, 8), (32, 0, 38, 8), (0, 8, 32, 12), (32, 8, 38, 12)]);
+    assert_eq!(items[3].uniforms(), vec![Uniform::U32(3), Uniform::U32(4), Uniform::U32(1),
+                                         Uniform::U32(1), Uniform::U32(2), Uniform::U32(2)]);
+}
@@ -0,0 +1,147 @@
+// Callbacks of the emulator on execution events, for tracing, profiling and debugging tools.
+//
+// The hooks are detached from the emulator while a callback runs, so events caused by the
+// callback itself are not reported. The callbacks at instruction boundaries get mutable
+// access to the emulator, while the ones in the middle of an instruction only inspect it.
+
+use crate::breakpoint::WatchHit;
+use crate::processor::QPUEmu;
+
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+// A physical register.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    Acc(u8),
+    A(u8),
+    B(u8),
+}
+
+impl Register {
+    // Parses r0-r5, ra0-ra31 or rb0-rb31.
+    pub fn parse(name: &str) -> Result<Register, String> {
+        let (reg, count, digits): (fn(u8) -> Register, u8, &str) = if let Some(digits) = name.strip_prefix("ra") {
+            (Register::A, 32, digits)
+        } else if let Some(digits) = name.strip_prefix("rb") {
+            (Register::B, 32, digits)
+        } else if let Some(digits) = name.strip_prefix('r') {
+            (Register::Acc, 6, digits)
+        } else {
+            return Err(format!("Unknown register '{}'.", name));
+        };
+        match digits.parse::<u8>() {
+            Ok(idx) if idx < count && digits.chars().all(|c| c.is_ascii_digit()) => Ok(reg(idx)),
+            _ => Err(format!("Unknown register '{}'.", name)),
+        }
+    }
+}
+
+impl fmt::Display for Register {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Register::Acc(idx) => write!(f, "r{}", idx),
+            Register::A(idx) => write!(f, "ra{}", idx),
+            Register::B(idx) => write!(f, "rb{}", idx),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DmaDirection {
+    Load,
+    Store,
+}
+
+// Every callback does nothing by default.
+#[allow(unused_variables)]
+pub trait Hooks {
+    // Called before the instruction pc is executed, on the breakpoint signal, a breakpoint set
+    // with set_breakpoint or while single stepping.
+    fn breakpoint(&mut self, emu: &mut QPUEmu, pc: u32) {}
+
+    // Called after the instruction pc is executed.
+    fn retire(&mut self, emu: &mut QPUEmu, pc: u32, inst: u64) {}
+
+    // Called after an instruction which accessed a watched location, once per watchpoint and
+    // kind of access.
+    fn watchpoint(&mut self, emu: &mut QPUEmu, hit: &WatchHit) {}
+
+    // The elements not written are None.
+    fn register_write(&mut self, emu: &QPUEmu, reg: Register, values: &[Option<u32>; 16]) {}
+
+    // Reads of the memory by uniforms, TMU lookups and VPM DMA loads. Instruction fetches are
+    // not reported.
+    fn memory_read(&mut self, emu: &QPUEmu, addr: u32, len: usize) {}
+
+    // Writes of the memory by VPM DMA stores.
+    fn memory_write(&mut self, emu: &QPUEmu, addr: u32, len: usize) {}
+
+    // A VPM DMA transfer starting at the memory address addr.
+    fn vpm_dma(&mut self, emu: &QPUEmu, direction: DmaDirection, addr: u32) {}
+
+    // A read or write of a row of the VPM through the vpm register by the elements in lanes.
+    fn vpm_access(&mut self, emu: &QPUEmu, row: usize, write: bool, lanes: u16) {}
+
+    // A TMU request issued by writing the addresses or coordinates s.
+    fn tmu_request(&mut self, emu: &QPUEmu, tmu: u8, s: &[u32; 16]) {}
+
+    // A semaphore instruction, incrementing or decrementing the semaphore idx.
+    fn semaphore(&mut self, emu: &QPUEmu, idx: u8, increment: bool) {}
+
+    // A read of MUTEX_ACQUIRE or a write of MUTEX_RELEASE.
+    fn mutex(&mut self, emu: &QPUEmu, acquire: bool) {}
+
+    fn host_interrupt(&mut self, emu: &QPUEmu) {}
+}
+
+// Shares hooks with the host, which reads their results after the run.
+impl<H: Hooks> Hooks for Arc<Mutex<H>> {
+    fn breakpoint(&mut self, emu: &mut QPUEmu, pc: u32) {
+        self.lock().unwrap().breakpoint(emu, pc)
+    }
+
+    fn retire(&mut self, emu: &mut QPUEmu, pc: u32, inst: u64) {
+        self.lock().unwrap().retire(emu, pc, inst)
+    }
+
+    fn watchpoint(&mut self, emu: &mut QPUEmu, hit: &WatchHit) {
+        self.lock().unwrap().watchpoint(emu, hit)
+    }
+
+    fn register_write(&mut self, emu: &QPUEmu, reg: Register, values: &[Option<u32>; 16]) {
+        self.lock().unwrap().register_write(emu, reg, values)
+    }
+
+    fn memory_read(&mut self, emu: &QPUEmu, addr: u32, len: usize) {
+        self.lock().unwrap().memory_read(emu, addr, len)
+    }
+
+    fn memory_write(&mut self, emu: &QPUEmu, addr: u32, len: usize) {
+        self.lock().unwrap().memory_write(emu, addr, len)
+    }
+
+    fn vpm_dma(&mut self, emu: &QPUEmu, direction: DmaDirection, addr: u32) {
+        self.lock().unwrap().vpm_dma(emu, direction, addr)
+    }
+
+    fn vpm_access(&mut self, emu: &QPUEmu, row: usize, write: bool, lanes: u16) {
+        self.lock().unwrap().vpm_access(emu, row, write, lanes)
+    }
+
+    fn tmu_request(&mut self, emu: &QPUEmu, tmu: u8, s: &[u32; 16]) {
+        self.lock().unwrap().tmu_request(emu, tmu, s)
+    }
+
+    fn semaphore(&mut self, emu: &QPUEmu, idx: u8, increment: bool) {
+        self.lock().unwrap().semaphore(emu, idx, increment)
+    }
+
+    fn mutex(&mut self, emu: &QPUEmu, acquire: bool) {
+        self.lock().unwrap().mutex(emu, acquire)
+    }
+
+    fn host_interrupt(&mut self, emu: &QPUEmu) {
+        self.lock().unwrap().host_interrupt(emu)
+    }
+}
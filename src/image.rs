@@ -0,0 +1,203 @@
+use crate::tile_buffer::*;
+use crate::memory_map::{Memory, MemoryFault};
+
+use std::fs;
+use std::io::{self, Read, Write};
+
+// An RGBA image with 8bit channels, stored top row first.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Self {
+        Image {
+            width,
+            height,
+            pixels: vec![0; width * height * 4],
+        }
+    }
+
+    // Reads back a frame buffer. Row 0 of the frame buffer becomes the top row of the image.
+    pub fn from_frame_buffer<M: Memory + ?Sized>(mem: &M, fb: &FrameBuffer) -> Result<Self, MemoryFault> {
+        let mut image = Image::new(fb.width, fb.height);
+        for y in 0..fb.height {
+            for x in 0..fb.width {
+                image.set_pixel(x, y, fb.read_pixel(mem, x, y)?);
+            }
+        }
+        Ok(image)
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u32 {
+        let idx = (y * self.width + x) * 4;
+        u32::from_le_bytes([self.pixels[idx], self.pixels[idx + 1], self.pixels[idx + 2], self.pixels[idx + 3]])
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, colour: u32) {
+        let idx = (y * self.width + x) * 4;
+        self.pixels[idx..idx + 4].copy_from_slice(&colour.to_le_bytes());
+    }
+
+    // Largest difference of any channel between two images of the same size.
+    pub fn max_diff(&self, other: &Image) -> u8 {
+        if self.width != other.width || self.height != other.height {
+            panic!("The image sizes are different.");
+        }
+
+        self.pixels.iter().zip(other.pixels.iter())
+            .map(|(a, b)| a.abs_diff(*b))
+            .max()
+            .unwrap_or(0)
+    }
+
+    // Writes a binary PPM (P6). The alpha channel is dropped.
+    pub fn save_ppm(&self, path: &str) -> io::Result<()> {
+        let mut data = format!("P6\n{} {}\n255\n", self.width, self.height).into_bytes();
+        for pixel in self.pixels.chunks(4) {
+            data.extend_from_slice(&pixel[0..3]);
+        }
+        fs::write(path, data)
+    }
+
+    // Reads a binary PPM (P6) with 8bit channels. Alpha is set to 255.
+    pub fn load_ppm(path: &str) -> io::Result<Image> {
+        let mut data = vec![];
+        fs::File::open(path)?.read_to_end(&mut data)?;
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid PPM file.");
+
+        // The header has four fields separated by white spaces and comments.
+        let mut fields = vec![];
+        let mut pos = 0;
+        while fields.len() < 4 {
+            while pos < data.len() && (data[pos].is_ascii_whitespace() || data[pos] == b'#') {
+                if data[pos] == b'#' {
+                    while pos < data.len() && data[pos] != b'\n' {
+                        pos += 1;
+                    }
+                } else {
+                    pos += 1;
+                }
+            }
+            let beg = pos;
+            while pos < data.len() && !data[pos].is_ascii_whitespace() {
+                pos += 1;
+            }
+            if beg == pos {
+                return Err(invalid());
+            }
+            fields.push(String::from_utf8_lossy(&data[beg..pos]).into_owned());
+        }
+        pos += 1;
+
+        if fields[0] != "P6" || fields[3] != "255" {
+            return Err(invalid());
+        }
+        let width: usize = fields[1].parse().map_err(|_| invalid())?;
+        let height: usize = fields[2].parse().map_err(|_| invalid())?;
+
+        if data.len() < pos + width * height * 3 {
+            return Err(invalid());
+        }
+
+        let mut image = Image::new(width, height);
+        for (idx, rgb) in data[pos..pos + width * height * 3].chunks(3).enumerate() {
+            image.pixels[idx * 4..idx * 4 + 3].copy_from_slice(rgb);
+            image.pixels[idx * 4 + 3] = 255;
+        }
+        Ok(image)
+    }
+
+    // Writes an RGBA PNG. The image data is stored without compression.
+    pub fn save_png(&self, path: &str) -> io::Result<()> {
+        let mut raw = vec![];
+        for row in self.pixels.chunks(self.width * 4) {
+            raw.push(0); // Filter type: None
+            raw.extend_from_slice(row);
+        }
+
+        let mut zlib = vec![0x78, 0x01];
+        let mut blocks = raw.chunks(0xffff).peekable();
+        if blocks.peek().is_none() {
+            zlib.extend_from_slice(&[1, 0, 0, 0xff, 0xff]);
+        }
+        while let Some(block) = blocks.next() {
+            let len = block.len() as u16;
+            zlib.push(if blocks.peek().is_none() { 1 } else { 0 });
+            zlib.extend_from_slice(&len.to_le_bytes());
+            zlib.extend_from_slice(&(!len).to_le_bytes());
+            zlib.extend_from_slice(block);
+        }
+        zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+
+        let mut ihdr = vec![];
+        ihdr.extend_from_slice(&(self.width as u32).to_be_bytes());
+        ihdr.extend_from_slice(&(self.height as u32).to_be_bytes());
+        ihdr.extend_from_slice(&[8, 6, 0, 0, 0]); // 8bit RGBA, no interlace
+
+        let mut f = fs::File::create(path)?;
+        f.write_all(&[0x89, b'P', b'N', b'G', 0x0d, 0x0a, 0x1a, 0x0a])?;
+        write_png_chunk(&mut f, b"IHDR", &ihdr)?;
+        write_png_chunk(&mut f, b"IDAT", &zlib)?;
+        write_png_chunk(&mut f, b"IEND", &[])
+    }
+}
+
+fn write_png_chunk(f: &mut fs::File, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    let mut body = kind.to_vec();
+    body.extend_from_slice(data);
+
+    f.write_all(&(data.len() as u32).to_be_bytes())?;
+    f.write_all(&body)?;
+    f.write_all(&crc32(&body).to_be_bytes())
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xffff_ffffu32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xedb8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let mut a = 1u32;
+    let mut b = 0u32;
+    for &byte in data {
+        a = (a + byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+    b << 16 | a
+}
+
+#[test]
+fn test_image_ppm_round_trip() {
+    let mut image = Image::new(3, 2);
+    image.set_pixel(0, 0, 0xff00_00ff);
+    image.set_pixel(2, 1, 0xff12_3456);
+
+    let path = std::env::temp_dir().join(format!("videocoreiv-sim-{}.ppm", std::process::id()));
+    let path = path.to_str().unwrap();
+    image.save_ppm(path).unwrap();
+    let mut loaded = Image::load_ppm(path).unwrap();
+    fs::remove_file(path).unwrap();
+
+    // Alpha is not stored in PPM files.
+    image.set_pixel(1, 0, 0xff00_0000);
+    image.set_pixel(2, 0, 0xff00_0000);
+    image.set_pixel(0, 1, 0xff00_0000);
+    image.set_pixel(1, 1, 0xff00_0000);
+    assert_eq!(image.max_diff(&loaded), 0);
+
+    loaded.set_pixel(0, 0, 0xff00_00f0);
+    assert_eq!(image.max_diff(&loaded), 0x0f);
+
+    assert_eq!(crc32(b"IEND"), 0xae42_6082);
+}
@@ -1,7 +1,7 @@
 use crate::utils::*;
 use crate::constants::*;
     
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct InstFormatAlu {
     pub sig : u8,
     pub unpack : u8,
@@ -23,7 +23,7 @@ pub struct InstFormatAlu {
     pub mul_b : u8,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct InstFormatAluSmallImm {
     pub unpack : u8,
     pub pm : u8,
@@ -44,7 +44,7 @@ pub struct InstFormatAluSmallImm {
     pub mul_b : u8,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct InstFormatBranch {
     pub cond_br : u8,
     pub rel : u8,
@@ -56,7 +56,7 @@ pub struct InstFormatBranch {
     pub immediate : u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct InstFormatLoadImm32 {
     pub pm : u8,
     pub pack : u8,
@@ -69,7 +69,7 @@ pub struct InstFormatLoadImm32 {
     pub immediate : u32,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct InstFormatLoadImmPerElem {
     pub pm : u8,
     pub pack : u8,
@@ -83,7 +83,7 @@ pub struct InstFormatLoadImmPerElem {
     pub per_element_ls_bit : u16,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct InstFormatSemaphore {
     pub pm : u8,
     pub pack : u8,
@@ -97,7 +97,7 @@ pub struct InstFormatSemaphore {
     pub semaphore: u8,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum InstFormat {
     Alu(InstFormatAlu),
     AluSmallImm(InstFormatAluSmallImm),
@@ -223,4 +223,188 @@ pub fn decode_inst(inst: u64) -> InstFormat {
                 mul_b       : get_bits(inst, 2, 0) as u8
             })
     }
-}
\ No newline at end of file
+}
+
+// Checks that the field fits in the given number of bits.
+fn check_field(name: &str, value: u64, bits: usize) -> Result<u64, String> {
+    if value >> bits != 0 {
+        return Err(format!("The field {} is out of range.", name));
+    }
+    Ok(value)
+}
+
+fn check_add_op(op_add: u8) -> Result<u64, String> {
+    let op_add = check_field("op_add", op_add as u64, 5)?;
+    if (0b01001..=0b01011).contains(&op_add) || (0b11001..=0b11101).contains(&op_add) {
+        return Err("The add operation is reserved.".to_string());
+    }
+    Ok(op_add)
+}
+
+// Encodes the fields from bit 56 down to bit 32, shared by the ALU and load immediate formats.
+#[allow(clippy::too_many_arguments)]
+fn encode_write_fields(pm: u8, pack: u8, cond_add: u8, cond_mul: u8, sf: u8, ws: u8, waddr_add: u8, waddr_mul: u8) -> Result<u64, String> {
+    Ok(check_field("pm", pm as u64, 1)? << 56
+        | check_field("pack", pack as u64, 4)? << 52
+        | check_field("cond_add", cond_add as u64, 3)? << 49
+        | check_field("cond_mul", cond_mul as u64, 3)? << 46
+        | check_field("sf", sf as u64, 1)? << 45
+        | check_field("ws", ws as u64, 1)? << 44
+        | check_field("waddr_add", waddr_add as u64, 6)? << 38
+        | check_field("waddr_mul", waddr_mul as u64, 6)? << 32)
+}
+
+// Encodes the instruction, the inverse of decode_inst. Fields which don't fit in their bits,
+// reserved operations and branch conditions, and signals of the other formats are rejected.
+pub fn encode_inst(inst: &InstFormat) -> Result<u64, String> {
+    match inst {
+        InstFormat::Alu(f) => {
+            if [SIG_NOPSI, SIG_LDI, SIG_BRA].contains(&f.sig) {
+                return Err("The signal is not of an ALU instruction.".to_string());
+            }
+            Ok(check_field("sig", f.sig as u64, 4)? << 60
+                | check_field("unpack", f.unpack as u64, 3)? << 57
+                | encode_write_fields(f.pm, f.pack, f.cond_add, f.cond_mul, f.sf, f.ws, f.waddr_add, f.waddr_mul)?
+                | check_field("op_mul", f.op_mul as u64, 3)? << 29
+                | check_add_op(f.op_add)? << 24
+                | check_field("raddr_a", f.raddr_a as u64, 6)? << 18
+                | check_field("raddr_b", f.raddr_b as u64, 6)? << 12
+                | check_field("add_a", f.add_a as u64, 3)? << 9
+                | check_field("add_b", f.add_b as u64, 3)? << 6
+                | check_field("mul_a", f.mul_a as u64, 3)? << 3
+                | check_field("mul_b", f.mul_b as u64, 3)?)
+        }
+        InstFormat::AluSmallImm(f) => {
+            Ok((SIG_NOPSI as u64) << 60
+                | check_field("unpack", f.unpack as u64, 3)? << 57
+                | encode_write_fields(f.pm, f.pack, f.cond_add, f.cond_mul, f.sf, f.ws, f.waddr_add, f.waddr_mul)?
+                | check_field("op_mul", f.op_mul as u64, 3)? << 29
+                | check_add_op(f.op_add)? << 24
+                | check_field("raddr_a", f.raddr_a as u64, 6)? << 18
+                | check_field("small_immed", f.small_immed as u64, 6)? << 12
+                | check_field("add_a", f.add_a as u64, 3)? << 9
+                | check_field("add_b", f.add_b as u64, 3)? << 6
+                | check_field("mul_a", f.mul_a as u64, 3)? << 3
+                | check_field("mul_b", f.mul_b as u64, 3)?)
+        }
+        InstFormat::Branch(f) => {
+            let cond_br = check_field("cond_br", f.cond_br as u64, 4)?;
+            if (0b1100..=0b1110).contains(&cond_br) {
+                return Err("The branch condition is reserved.".to_string());
+            }
+            Ok((SIG_BRA as u64) << 60
+                | cond_br << 52
+                | check_field("rel", f.rel as u64, 1)? << 51
+                | check_field("reg", f.reg as u64, 1)? << 50
+                | check_field("raddr_a", f.raddr_a as u64, 5)? << 45
+                | check_field("ws", f.ws as u64, 1)? << 44
+                | check_field("waddr_add", f.waddr_add as u64, 6)? << 38
+                | check_field("waddr_mul", f.waddr_mul as u64, 6)? << 32
+                | f.immediate as u64)
+        }
+        InstFormat::LoadImm32(f) => {
+            Ok((SIG_LDI as u64) << 60
+                | encode_write_fields(f.pm, f.pack, f.cond_add, f.cond_mul, f.sf, f.ws, f.waddr_add, f.waddr_mul)?
+                | f.immediate as u64)
+        }
+        InstFormat::LoadImmPerElemSigned(f) | InstFormat::LoadImmPerElemUnsigned(f) => {
+            let mode = if let InstFormat::LoadImmPerElemSigned(_) = inst { 0b001 } else { 0b011 };
+            Ok((SIG_LDI as u64) << 60 | mode << 57
+                | encode_write_fields(f.pm, f.pack, f.cond_add, f.cond_mul, f.sf, f.ws, f.waddr_add, f.waddr_mul)?
+                | (f.per_element_ms_bit as u64) << 16
+                | f.per_element_ls_bit as u64)
+        }
+        InstFormat::Semaphore(f) => {
+            Ok((SIG_LDI as u64) << 60 | 0b100 << 57
+                | encode_write_fields(f.pm, f.pack, f.cond_add, f.cond_mul, f.sf, f.ws, f.waddr_add, f.waddr_mul)?
+                | check_field("sa", f.sa as u64, 1)? << 4
+                | check_field("semaphore", f.semaphore as u64, 4)?)
+        }
+    }
+}
+
+// The word which encode_inst produces for the decoded word, or None if it is reserved.
+#[cfg(test)]
+fn canonical_inst(inst: u64) -> Option<u64> {
+    let sig = get_bits(inst, 63, 60) as u8;
+    let op_add = get_bits(inst, 28, 24);
+    match sig {
+        SIG_BRA if (0b1100..=0b1110).contains(&get_bits(inst, 55, 52)) => None,
+        SIG_BRA => Some(inst & !(0xf << 56)),
+        SIG_LDI => match get_bits(inst, 59, 57) {
+            0b000 | 0b001 | 0b011 => Some(inst),
+            0b100 => Some(inst & !0xffff_ffe0),
+            _ => None,
+        },
+        _ if (0b01001..=0b01011).contains(&op_add) || (0b11001..=0b11101).contains(&op_add) => None,
+        _ => Some(inst),
+    }
+}
+
+#[cfg(test)]
+fn check_round_trip(inst: u64) {
+    match canonical_inst(inst) {
+        Some(canonical) => {
+            let fields = decode_inst(inst);
+            assert_eq!(encode_inst(&fields), Ok(canonical), "{:016x}", inst);
+            assert_eq!(decode_inst(canonical), fields);
+        }
+        None if get_bits(inst, 63, 60) as u8 == SIG_LDI => {}
+        None => assert!(encode_inst(&decode_inst(inst)).is_err(), "{:016x}", inst),
+    }
+}
+
+#[test]
+fn test_encode_inst_exhaustive() {
+    // Every combination of the signal, pack, conditions and write address select, and of
+    // the signal and the operations.
+    for low in [0, 0x0fff_ffff_ffff, 0x0a5a_c3c3_5a5a] {
+        for high in 0..1u64 << 20 {
+            check_round_trip(high << 44 | low);
+        }
+    }
+    for sig in 0..16u64 {
+        for ops in 0..256u64 {
+            check_round_trip(sig << 60 | 0x0000_0fff_00ff_ffff | ops << 24);
+        }
+    }
+}
+
+#[test]
+fn test_encode_inst_random() {
+    use rand::{Rng, SeedableRng};
+    let mut rng = rand::rngs::StdRng::seed_from_u64(1);
+
+    for _ in 0..200000 {
+        check_round_trip(rng.gen());
+    }
+}
+
+#[test]
+fn test_encode_inst_invalid() {
+    let alu = match decode_inst(0x100009e7_009e7000) {
+        InstFormat::Alu(alu) => alu,
+        inst => panic!("{:?}", inst),
+    };
+    assert_eq!(encode_inst(&InstFormat::Alu(alu.clone())), Ok(0x100009e7_009e7000));
+
+    let invalid = [
+        InstFormatAlu { sig: SIG_LDI, ..alu.clone() },
+        InstFormatAlu { sig: 16, ..alu.clone() },
+        InstFormatAlu { waddr_add: 64, ..alu.clone() },
+        InstFormatAlu { cond_mul: 8, ..alu.clone() },
+        InstFormatAlu { op_add: 0b11001, ..alu.clone() },
+        InstFormatAlu { mul_b: 8, ..alu.clone() },
+    ];
+    for fields in invalid {
+        assert!(encode_inst(&InstFormat::Alu(fields)).is_err());
+    }
+
+    let branch = InstFormatBranch { cond_br: COND_BR_ALWAYS, rel: 1, reg: 0, raddr_a: 0, ws: 0, waddr_add: WA_NOP, waddr_mul: WB_NOP, immediate: 0xffff_ffe0 };
+    assert_eq!(encode_inst(&InstFormat::Branch(branch.clone())), Ok(0xf0f809e7_ffffffe0));
+    assert!(encode_inst(&InstFormat::Branch(InstFormatBranch { cond_br: 0b1100, ..branch.clone() })).is_err());
+    assert!(encode_inst(&InstFormat::Branch(InstFormatBranch { raddr_a: 32, ..branch })).is_err());
+
+    let sema = InstFormatSemaphore { pm: 0, pack: 0, cond_add: 0, cond_mul: 0, sf: 0, ws: 0, waddr_add: WA_NOP, waddr_mul: WB_NOP, sa: 1, semaphore: 16 };
+    assert!(encode_inst(&InstFormat::Semaphore(sema)).is_err());
+}
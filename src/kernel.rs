@@ -0,0 +1,233 @@
+// Launching programs on the emulator with typed uniforms per thread.
+//
+// The program and the uniforms of all threads are copied to buffers, which are allocated for
+// the launch and freed after it. Each thread runs until its thread end.
+
+use crate::buffer::Buffer;
+use crate::grid::{Grid, WorkItem};
+use crate::memory_map::MemoryFault;
+use crate::processor::{QPUEmu, StopReason, ThreadStats};
+use crate::utils::*;
+
+use std::panic::{self, AssertUnwindSafe};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Uniform {
+    U32(u32),
+    F32(f32),
+    Buffer(Buffer),
+    // Address of the first uniform of the thread, for programs which rewind their uniforms.
+    ThreadUniforms,
+}
+
+impl From<u32> for Uniform {
+    fn from(val: u32) -> Self {
+        Uniform::U32(val)
+    }
+}
+
+impl From<f32> for Uniform {
+    fn from(val: f32) -> Self {
+        Uniform::F32(val)
+    }
+}
+
+impl From<Buffer> for Uniform {
+    fn from(buffer: Buffer) -> Self {
+        Uniform::Buffer(buffer)
+    }
+}
+
+impl From<&Buffer> for Uniform {
+    fn from(buffer: &Buffer) -> Self {
+        Uniform::Buffer(*buffer)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LaunchStatus {
+    Completed,
+    Fault { thread: usize, fault: MemoryFault },
+    // The program and the uniforms did not fit in the free memory, so no thread was run.
+    OutOfMemory,
+    // A hook stopped the threads before they ended, e.g. the user quit the debugger.
+    Stopped { thread: usize, pc: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct LaunchResult {
+    pub status: LaunchStatus,
+    // Statistics of the threads which were run, in order.
+    pub threads: Vec<ThreadStats>,
+}
+
+impl LaunchResult {
+    // The result of the threads started on the emulator, which stopped for the reason.
+    pub fn from_stop(emu: &QPUEmu, reason: StopReason) -> LaunchResult {
+        let mut threads = emu.ended_thread_stats().to_vec();
+        let status = match reason {
+            StopReason::Fault { thread, fault } => {
+                threads.push(emu.thread_stats());
+                LaunchStatus::Fault { thread, fault }
+            }
+            StopReason::Stopped { thread, pc } => {
+                threads.push(emu.thread_stats());
+                LaunchStatus::Stopped { thread, pc }
+            }
+            // The run control functions stop the threads before they end, at the next instruction
+            // of the running thread.
+            StopReason::Stepped
+            | StopReason::Reached { .. }
+            | StopReason::Breakpoint { .. }
+            | StopReason::Watchpoint(_)
+            | StopReason::Event { .. }
+            | StopReason::InvalidThread { .. } => match emu.thread() {
+                Some(thread) => {
+                    threads.push(emu.thread_stats());
+                    LaunchStatus::Stopped { thread, pc: emu.pc() }
+                }
+                None => LaunchStatus::Completed,
+            },
+            StopReason::Finished => LaunchStatus::Completed,
+        };
+        LaunchResult { status, threads }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.status == LaunchStatus::Completed
+    }
+}
+
+pub struct Kernel {
+    insts: Vec<u64>,
+}
+
+impl Kernel {
+    pub fn new(insts: Vec<u64>) -> Self {
+        Kernel { insts }
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        if !bytes.len().is_multiple_of(8) {
+            return Err("The program size is not a multiple of 8 bytes.".to_string());
+        }
+        let insts = bytes.chunks(8).map(|chunk| {
+            let lo = u8x4_to_u32([chunk[0], chunk[1], chunk[2], chunk[3]]) as u64;
+            let hi = u8x4_to_u32([chunk[4], chunk[5], chunk[6], chunk[7]]) as u64;
+            hi << 32 | lo
+        }).collect();
+        Ok(Kernel::new(insts))
+    }
+
+    pub fn insts(&self) -> &[u64] {
+        &self.insts
+    }
+
+    pub fn launch(&self) -> Launch<'_> {
+        Launch { kernel: self, threads: vec![] }
+    }
+}
+
+pub struct Launch<'a> {
+    kernel: &'a Kernel,
+    threads: Vec<Vec<Uniform>>,
+}
+
+impl<'a> Launch<'a> {
+    // Adds a thread which reads the given uniforms.
+    pub fn thread(mut self, uniforms: Vec<Uniform>) -> Self {
+        self.threads.push(uniforms);
+        self
+    }
+
+    pub fn threads(mut self, threads: Vec<Vec<Uniform>>) -> Self {
+        self.threads.extend(threads);
+        self
+    }
+
+    // Adds a thread for each block of the grid partitioned for num_qpus. The uniforms of a
+    // thread are made from its block, typically starting with WorkItem::uniforms().
+    pub fn grid<F: Fn(&WorkItem) -> Vec<Uniform>>(mut self, grid: &Grid, num_qpus: usize, uniforms: F) -> Self {
+        for item in grid.partition(num_qpus) {
+            self.threads.push(uniforms(&item));
+        }
+        self
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    // Copies the program and the uniforms to new buffers. Returns them with the uniform pointer
+    // of each thread, or None if they do not fit in the free memory.
+    fn load(&self, emu: &mut QPUEmu) -> Option<(Buffer, Buffer, Vec<u32>)> {
+        let code_buf = emu.alloc(self.kernel.insts.len() * 8, 8)?;
+        let total: usize = self.threads.iter().map(|uniforms| uniforms.len()).sum();
+        let uniforms_buf = match emu.alloc(total.max(1) * 4, 16) {
+            Some(buffer) => buffer,
+            None => {
+                emu.free(code_buf);
+                return None;
+            }
+        };
+
+        match self.write(emu, &code_buf, &uniforms_buf) {
+            Ok(uniform_ptrs) => Some((code_buf, uniforms_buf, uniform_ptrs)),
+            Err(_) => {
+                emu.free(uniforms_buf);
+                emu.free(code_buf);
+                None
+            }
+        }
+    }
+
+    // Writes the program and the uniforms to the buffers, returning the uniform pointer of each
+    // thread.
+    fn write(&self, emu: &mut QPUEmu, code_buf: &Buffer, uniforms_buf: &Buffer) -> Result<Vec<u32>, MemoryFault> {
+        let code: Vec<u32> = self.kernel.insts.iter().flat_map(|inst| vec![*inst as u32, (inst >> 32) as u32]).collect();
+        code_buf.write_u32(emu, 0, &code)?;
+
+        let mut uniform_ptrs = vec![];
+        let mut offset = 0;
+        for uniforms in &self.threads {
+            let uniform_ptr = uniforms_buf.addr() + offset as u32 * 4;
+            let words: Vec<u32> = uniforms.iter().map(|uniform| match uniform {
+                Uniform::U32(val) => *val,
+                Uniform::F32(val) => f32_to_u32(*val),
+                Uniform::Buffer(buffer) => buffer.addr(),
+                Uniform::ThreadUniforms => uniform_ptr,
+            }).collect();
+
+            uniforms_buf.write_u32(emu, offset, &words)?;
+            uniform_ptrs.push(uniform_ptr);
+            offset += words.len();
+        }
+        Ok(uniform_ptrs)
+    }
+
+    // Prepares the threads for the run control functions of the emulator instead of running
+    // them. Returns the buffers of the program and the uniforms, to be freed after the run, or
+    // None if there is no memory for them.
+    pub fn start(self, emu: &mut QPUEmu) -> Option<(Buffer, Buffer)> {
+        let (code_buf, uniforms_buf, uniform_ptrs) = self.load(emu)?;
+        emu.start_at(code_buf.addr(), &uniform_ptrs);
+        Some((code_buf, uniforms_buf))
+    }
+
+    // Runs the threads one after another. A memory fault stops the launch.
+    pub fn run(self, emu: &mut QPUEmu) -> LaunchResult {
+        let (code_buf, uniforms_buf, uniform_ptrs) = match self.load(emu) {
+            Some(loaded) => loaded,
+            None => return LaunchResult { status: LaunchStatus::OutOfMemory, threads: vec![] },
+        };
+
+        // The buffers are also freed when the emulator panics, for the callers which catch it.
+        let reason = panic::catch_unwind(AssertUnwindSafe(|| emu.execute_threads_at(code_buf.addr(), &uniform_ptrs)));
+        emu.free(uniforms_buf);
+        emu.free(code_buf);
+        match reason {
+            Ok(reason) => LaunchResult::from_stop(emu, reason),
+            Err(payload) => panic::resume_unwind(payload),
+        }
+    }
+}
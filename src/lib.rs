@@ -1,7 +1,30 @@
 pub mod constants;
 pub mod instructions;
+pub mod disassembler;
+pub mod assembler;
+pub mod builder;
+pub mod debugger;
+pub mod gdbstub;
+pub mod trace;
 pub mod utils;
 pub mod processor;
+pub mod hooks;
+pub mod breakpoint;
+pub mod memory_map;
+pub mod tiling;
+pub mod tile_buffer;
+pub mod image;
+pub mod texture;
+pub mod allocator;
+pub mod buffer;
+pub mod kernel;
+pub mod grid;
+pub mod ffi;
+pub mod server;
+pub mod runner;
+pub mod spec;
+pub mod mailbox;
+pub mod v3d;
 
 #[cfg(test)]
 mod test;
\ No newline at end of file
@@ -0,0 +1,287 @@
+// Emulation of the firmware mailbox property interface (channel 8) for the GPU related tags.
+//
+// A property buffer is a sequence of 32bit words:
+//   total size in bytes, request/response code, tags..., end tag (0).
+// Each tag is:
+//   tag id, value buffer size in bytes, request/response code, value buffer.
+
+use crate::processor::{QPUEmu, StopReason};
+use crate::memory_map::*;
+use crate::buffer::Buffer;
+
+use std::collections::HashMap;
+
+pub const TAG_END: u32 = 0x0000_0000;
+pub const TAG_MEM_ALLOC: u32 = 0x0003_000c;
+pub const TAG_MEM_LOCK: u32 = 0x0003_000d;
+pub const TAG_MEM_UNLOCK: u32 = 0x0003_000e;
+pub const TAG_MEM_FREE: u32 = 0x0003_000f;
+pub const TAG_EXECUTE_QPU: u32 = 0x0003_0011;
+pub const TAG_QPU_ENABLE: u32 = 0x0003_0012;
+
+pub const CODE_REQUEST: u32 = 0x0000_0000;
+pub const CODE_RESPONSE_SUCCESS: u32 = 0x8000_0000;
+pub const CODE_RESPONSE_ERROR: u32 = 0x8000_0001;
+
+pub const MEM_FLAG_DISCARDABLE: u32 = 1 << 0;
+pub const MEM_FLAG_NORMAL: u32 = 0 << 2;
+pub const MEM_FLAG_DIRECT: u32 = 1 << 2;
+pub const MEM_FLAG_COHERENT: u32 = 2 << 2;
+pub const MEM_FLAG_L1_NONALLOCATING: u32 = MEM_FLAG_DIRECT | MEM_FLAG_COHERENT;
+pub const MEM_FLAG_ZERO: u32 = 1 << 4;
+pub const MEM_FLAG_NO_INIT: u32 = 1 << 5;
+pub const MEM_FLAG_HINT_PERMALOCK: u32 = 1 << 6;
+
+// Instructions executed by a QPU in 1 ms at 250 MHz.
+const INSTRUCTIONS_PER_MS: u64 = 250_000;
+
+// Bus address alias of the memory allocated with the flags.
+fn alias_of_flags(flags: u32) -> BusAlias {
+    match flags & MEM_FLAG_L1_NONALLOCATING {
+        MEM_FLAG_DIRECT => BusAlias::Uncached,
+        MEM_FLAG_COHERENT => BusAlias::L2Cached,
+        MEM_FLAG_L1_NONALLOCATING => BusAlias::L2Coherent,
+        _ => BusAlias::L1L2Cached,
+    }
+}
+
+struct MemHandle {
+    buffer: Buffer,
+    flags: u32,
+    lock_count: u32,
+}
+
+pub struct Mailbox {
+    handles: HashMap<u32, MemHandle>,
+    next_handle: u32,
+    qpu_enabled: bool,
+}
+
+impl Default for Mailbox {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Mailbox {
+    // Creates a mailbox. The memory is allocated from the buffers of the emulator.
+    pub fn new() -> Self {
+        Mailbox {
+            handles: HashMap::new(),
+            next_handle: 1,
+            qpu_enabled: false,
+        }
+    }
+
+    pub fn mem_alloc(&mut self, emu: &mut QPUEmu, size: u32, align: u32, flags: u32) -> u32 {
+        let align = if align == 0 { 1 } else { align as usize };
+        if !align.is_power_of_two() {
+            return 0;
+        }
+        let buffer = match emu.alloc(size as usize, align) {
+            Some(buffer) => buffer,
+            None => return 0,
+        };
+
+        if flags & MEM_FLAG_ZERO != 0 && buffer.write_u8(emu, 0, &vec![0; size as usize]).is_err() {
+            emu.free(buffer);
+            return 0;
+        }
+
+        let handle = self.next_handle;
+        self.next_handle += 1;
+        self.handles.insert(handle, MemHandle {
+            buffer,
+            flags,
+            lock_count: 0,
+        });
+        handle
+    }
+
+    // Returns the bus address of the block, or 0 if the handle is invalid.
+    pub fn mem_lock(&mut self, handle: u32) -> u32 {
+        match self.handles.get_mut(&handle) {
+            Some(mem) => {
+                mem.lock_count += 1;
+                mem.buffer.bus_addr(alias_of_flags(mem.flags))
+            },
+            None => 0,
+        }
+    }
+
+    pub fn mem_unlock(&mut self, handle: u32) -> u32 {
+        match self.handles.get_mut(&handle) {
+            Some(mem) if mem.lock_count > 0 => {
+                mem.lock_count -= 1;
+                0
+            },
+            _ => 1,
+        }
+    }
+
+    pub fn mem_free(&mut self, emu: &mut QPUEmu, handle: u32) -> u32 {
+        match self.handles.remove(&handle) {
+            Some(mem) => {
+                emu.free(mem.buffer);
+                0
+            },
+            None => 1,
+        }
+    }
+
+    pub fn qpu_enable(&mut self, enable: u32) -> u32 {
+        self.qpu_enabled = enable != 0;
+        0
+    }
+
+    // Runs num_qpus jobs described at control, an array of (uniforms address, code address) pairs.
+    // The jobs are run one after another and a memory fault fails the request. The timeout in ms
+    // limits the instructions of the jobs together to timeout * INSTRUCTIONS_PER_MS, one per cycle
+    // of the QPU clock, and the request fails if a job has not ended by then. A timeout of 0 does
+    // not limit the jobs.
+    pub fn execute_qpu(&mut self, emu: &mut QPUEmu, num_qpus: u32, control: u32, _noflush: u32, timeout: u32) -> u32 {
+        if !self.qpu_enabled {
+            return 1;
+        }
+
+        let mut instructions = if timeout == 0 { u64::MAX } else { (timeout as u64).saturating_mul(INSTRUCTIONS_PER_MS) };
+        for job in 0..num_qpus {
+            let entry = control.wrapping_add(job.wrapping_mul(8));
+            let (uniforms, code) = match (emu.read_u32(entry), emu.read_u32(entry.wrapping_add(4))) {
+                (Ok(uniforms), Ok(code)) => (uniforms, code),
+                _ => return 1,
+            };
+            if emu.execute_at_for(code, uniforms, instructions) != StopReason::Finished {
+                return 1;
+            }
+            instructions -= emu.retired();
+        }
+        0
+    }
+
+    // Handles a property buffer. Returns false if the buffer is malformed.
+    pub fn property(&mut self, emu: &mut QPUEmu, buffer: &mut [u32]) -> bool {
+        let valid = self.process_tags(emu, buffer);
+        if buffer.len() >= 2 {
+            buffer[1] = if valid { CODE_RESPONSE_SUCCESS } else { CODE_RESPONSE_ERROR };
+        }
+        valid
+    }
+
+    // Handles a property buffer at the bus address addr.
+    pub fn property_at(&mut self, emu: &mut QPUEmu, addr: u32) -> bool {
+        let size = match emu.read_u32(addr) {
+            Ok(size) => size,
+            Err(_) => return false,
+        };
+        let mut buffer = Vec::new();
+        for idx in 0..size / 4 {
+            match emu.read_u32(addr.wrapping_add(idx * 4)) {
+                Ok(word) => buffer.push(word),
+                Err(_) => return false,
+            }
+        }
+
+        let valid = self.property(emu, &mut buffer);
+
+        for (idx, word) in buffer.iter().enumerate() {
+            if emu.write_u32(addr.wrapping_add(idx as u32 * 4), *word).is_err() {
+                return false;
+            }
+        }
+        valid
+    }
+
+    fn process_tags(&mut self, emu: &mut QPUEmu, buffer: &mut [u32]) -> bool {
+        if buffer.len() < 3 || buffer[0] as usize != buffer.len() * 4 || buffer[1] != CODE_REQUEST {
+            return false;
+        }
+
+        let mut pos = 2;
+        while pos < buffer.len() {
+            let tag = buffer[pos];
+            if tag == TAG_END {
+                return true;
+            }
+            if pos + 3 > buffer.len() {
+                return false;
+            }
+
+            let value_size = buffer[pos + 1] as usize;
+            let values_beg = pos + 3;
+            let values_end = values_beg + value_size.div_ceil(4);
+            if values_end > buffer.len() {
+                return false;
+            }
+
+            let values = &mut buffer[values_beg..values_end];
+            let response = match tag {
+                TAG_MEM_ALLOC if values.len() >= 3 => {
+                    values[0] = self.mem_alloc(emu, values[0], values[1], values[2]);
+                    Some(4)
+                },
+                TAG_MEM_LOCK if !values.is_empty() => {
+                    values[0] = self.mem_lock(values[0]);
+                    Some(4)
+                },
+                TAG_MEM_UNLOCK if !values.is_empty() => {
+                    values[0] = self.mem_unlock(values[0]);
+                    Some(4)
+                },
+                TAG_MEM_FREE if !values.is_empty() => {
+                    values[0] = self.mem_free(emu, values[0]);
+                    Some(4)
+                },
+                TAG_QPU_ENABLE if !values.is_empty() => {
+                    values[0] = self.qpu_enable(values[0]);
+                    Some(4)
+                },
+                TAG_EXECUTE_QPU if values.len() >= 4 => {
+                    values[0] = self.execute_qpu(emu, values[0], values[1], values[2], values[3]);
+                    Some(4)
+                },
+                _ => None, // Unknown tags are left without a response.
+            };
+
+            if let Some(len) = response {
+                buffer[pos + 2] = CODE_RESPONSE_SUCCESS | len;
+            }
+            pos = values_end;
+        }
+        false
+    }
+}
+
+#[test]
+fn test_mailbox_mem_tags() {
+    let mut emu = QPUEmu::new(0x10000);
+    let mut mailbox = Mailbox::new();
+
+    emu.alloc(0x10, 4).unwrap();
+    emu.mem[0x1000] = 0xaa;
+    let mut buffer = vec![
+        0, CODE_REQUEST,
+        TAG_MEM_ALLOC, 12, 0, 0x100, 0x1000, MEM_FLAG_L1_NONALLOCATING | MEM_FLAG_ZERO,
+        TAG_MEM_LOCK, 4, 0, 1,
+        0x0004_0001, 8, 0, 0, 0, // Unknown tag
+        TAG_END,
+    ];
+    buffer[0] = buffer.len() as u32 * 4;
+    assert!(mailbox.property(&mut emu, &mut buffer));
+
+    assert_eq!(buffer[1], CODE_RESPONSE_SUCCESS);
+    assert_eq!(&buffer[2..12], &[TAG_MEM_ALLOC, 12, CODE_RESPONSE_SUCCESS | 4, 1, 0x1000, 0x0c | 0x10,
+                                 TAG_MEM_LOCK, 4, CODE_RESPONSE_SUCCESS | 4, 0x4000_1000]);
+    assert_eq!(buffer[14], 0);
+    assert_eq!(emu.mem[0x1000], 0);
+
+    assert_eq!(mailbox.mem_unlock(1), 0);
+    assert_eq!(mailbox.mem_unlock(1), 1);
+    assert_eq!(mailbox.mem_free(&mut emu, 1), 0);
+    assert_eq!(mailbox.mem_free(&mut emu, 1), 1);
+    assert_eq!(mailbox.mem_lock(1), 0);
+
+    let mut malformed = vec![12, CODE_REQUEST, TAG_MEM_LOCK];
+    assert!(!mailbox.property(&mut emu, &mut malformed));
+    assert_eq!(malformed[1], CODE_RESPONSE_ERROR);
+}
@@ -1,15 +1,17 @@
-mod processor;
-mod utils;
-mod instructions;
-mod constants;
-
-use processor::QPUEmu;
-use utils::*;
+use videocoreiv_sim::processor::QPUEmu;
+use videocoreiv_sim::utils::*;
+use videocoreiv_sim::assembler;
+use videocoreiv_sim::debugger;
+use videocoreiv_sim::disassembler;
+use videocoreiv_sim::kernel::Kernel;
+use videocoreiv_sim::runner;
+use videocoreiv_sim::spec;
 
 use std::fs;
 use std::io::{Read};
 use byteorder::{ByteOrder, LittleEndian};
 use std::time::{Instant};
+use std::sync::{Arc, Mutex};
 use rand::distributions::{Uniform, Distribution};
 
 fn sgemm(M: usize, N: usize, K: usize, alpha: f32, A: &Vec<f32>, B: &Vec<f32>, beta: f32, C: &mut Vec<f32>) {
@@ -25,26 +27,164 @@ fn sgemm(M: usize, N: usize, K: usize, alpha: f32, A: &Vec<f32>, B: &Vec<f32>, b
     }
 }
 
-fn read<T: std::str::FromStr>() -> T {
-    let mut s = String::new();
-    std::io::stdin().read_line(&mut s).ok();
-    s.trim().parse().ok().unwrap()
+// Prints the assembly of a program binary: disasm PROGRAM [base address]
+fn run_disasm(args: &[String]) {
+    let (path, base_addr) = match args {
+        [path] => (path, 0),
+        [path, base_addr] => (path, runner::parse_u32(base_addr).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        })),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let bytes = fs::read(path).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        std::process::exit(2);
+    });
+    let kernel = Kernel::from_bytes(&bytes).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        std::process::exit(2);
+    });
+
+    for line in disassembler::disassemble_program(kernel.insts(), base_addr) {
+        println!("{}", line);
+    }
 }
 
-fn breakpoint_handler(emu: &QPUEmu, inst_pc: u32) {
-    println!("breakpoint at 0x{0:>08x}", inst_pc);
-    loop {
-        println!("command >");
-        
-        let s: String = read();
-
-        if s == "c" {
-            break;
+// Assembles a source file into a program binary: asm SOURCE OUTPUT [base address]
+fn run_asm(args: &[String]) {
+    let (source, output, base_addr) = match args {
+        [source, output] => (source, output, Ok(0)),
+        [source, output, base_addr] => (source, output, runner::parse_u32(base_addr)),
+        _ => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
         }
+    };
+
+    let result = base_addr
+        .and_then(|base_addr| {
+            let text = fs::read_to_string(source).map_err(|err| format!("{}: {}", source, err))?;
+            assembler::assemble_at(&text, base_addr).map_err(|err| format!("{}: {}", source, err))
+        })
+        .and_then(|insts| {
+            let bytes: Vec<u8> = insts.iter().flat_map(|inst| inst.to_le_bytes()).collect();
+            fs::write(output, bytes).map_err(|err| format!("{}: {}", output, err))
+        });
+    if let Err(err) = result {
+        eprintln!("{}", err);
+        std::process::exit(1);
     }
 }
 
+// Serves jobs on a Unix domain socket: server [socket path] [memory size in bytes]
+fn run_server(args: &[String]) {
+    let path = args.first().map(|s| s.as_str()).unwrap_or("/tmp/videocoreiv-sim.sock");
+    if args.len() > 2 {
+        eprintln!("{}", USAGE);
+        std::process::exit(2);
+    }
+    let mem_size = match args.get(1) {
+        Some(mem_size) => runner::parse_usize(mem_size).unwrap_or_else(|err| {
+            eprintln!("{}", err);
+            std::process::exit(2);
+        }),
+        None => 64 << 20,
+    };
+
+    println!("listening on {}", path);
+    if let Err(err) = videocoreiv_sim::server::serve(path, mem_size) {
+        eprintln!("{}: {}", path, err);
+        std::process::exit(1);
+    }
+}
+
+const USAGE: &str = "usage: videocoreiv-sim [sgemm]
+       videocoreiv-sim run PROGRAM [options]
+       videocoreiv-sim disasm PROGRAM [BASE_ADDR]
+       videocoreiv-sim asm SOURCE OUTPUT [BASE_ADDR]
+       videocoreiv-sim test SPEC|DIRECTORY...
+       videocoreiv-sim server [SOCKET] [MEM_SIZE]
+       videocoreiv-sim -h|--help";
+
+const HELP: &str = "
+run options:
+  PROGRAM                   program binary, or assembly source if it ends with .asm
+  -n, --threads N           number of threads (default: the lines of the uniforms file, or 1)
+  -u, --uniforms LIST       comma separated uniforms of every thread
+  -U, --uniforms-file FILE  uniforms of each thread, one line per thread
+  -b, --buffer NAME=SIZE    allocates a zeroed buffer
+  -l, --load NAME=FILE      allocates a buffer initialized from the file
+  -l, --load ADDR=FILE      writes the file to the memory at ADDR
+  -d, --dump NAME=FILE      writes the buffer to the file after the run
+  -d, --dump ADDR:LEN=FILE  writes the memory range to the file after the run
+  -m, --mem-size BYTES      size of the emulator memory (default 16MB)
+  -s, --stats               prints the statistics of each thread
+  -g, --debug               stops at the first instruction in the interactive debugger
+  --gdb ADDR                waits for GDB on HOST:PORT, or on a Unix socket path containing '/'
+  -t, --trace FILE          writes a trace of the executed instructions
+  --trace-format FORMAT     text or binary (default: text)
+  --trace-pc START:END      traces only the instructions from START up to END, END excluded
+  --trace-qpu LIST          traces only the comma separated QPUs
+
+  Numbers are decimal or hexadecimal with 0x. A uniform is a number, a negative number, a
+  float containing '.', @NAME or @NAME+OFFSET for an address in a buffer, %thread for the
+  thread index, %threads for the thread count or %uniforms for the address of the thread's
+  uniforms.
+
+asm syntax:
+  The syntax of py-videocore, which is also the output of disasm, e.g.
+    # comment
+    STRIDE = 64
+    L.loop
+        fadd(r0, ra1, r2, cond='zs', set_flags=True).fmul(rb3, r4, r5)
+        iadd(r1, r1, -1)
+        jzc(L.loop)
+        .dword 0x100009e7009e7000
+
+test spec lines:
+  # comment
+  program PATH                                  program binary or .asm source, relative to the spec file
+  threads N
+  mem_size BYTES
+  buffer NAME TYPE[COUNT]                       zeroed
+  buffer NAME TYPE[COUNT] = VALUES...           inline values, the rest is zeroed
+  buffer NAME TYPE[COUNT] random SEED [MIN MAX] uniformly distributed values
+  buffer NAME TYPE[COUNT] file PATH
+  uniforms VALUES...                            one line per thread, or one for every thread
+  expect NAME TYPE = VALUES... [tolerance T]    the first elements of the buffer
+  expect NAME TYPE file PATH [tolerance T]
+  expect NAME TYPE initial OTHER [tolerance T]  the initial contents of another buffer
+  expect fault                                  the run faults instead of completing
+
+  TYPE is u32, i32, f32, u16 or u8. Uniforms are written as for run.";
+
 fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args[1..].iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}\n{}", USAGE, HELP);
+        return;
+    }
+    match args.get(1).map(|s| s.as_str()) {
+        None | Some("sgemm") => run_sgemm(),
+        Some("run") => std::process::exit(runner::main(&args[2..])),
+        Some("disasm") => run_disasm(&args[2..]),
+        Some("asm") => run_asm(&args[2..]),
+        Some("test") => std::process::exit(spec::main(&args[2..])),
+        Some("server") => run_server(&args[2..]),
+        Some(_) => {
+            eprintln!("{}", USAGE);
+            std::process::exit(2);
+        }
+    }
+}
+
+// Runs ./data/sgemm.bin on random matrices and checks the result.
+fn run_sgemm() {
     let mut f = fs::File::open("./data/sgemm.bin").unwrap();
 
     let mut buf = vec![];
@@ -73,10 +213,6 @@ fn main() {
     let b_stride: usize = r;
     let c_stride: usize = r;
 
-    let a_addr = 1024 * 4;
-    let b_addr = a_addr + a_matrix.len() * 4;
-    let c_addr = b_addr + b_matrix.len() * 4;
-    
     let mut rng = rand::thread_rng();
     let distrib = Uniform::new(0.0, 1.0);
 
@@ -92,7 +228,16 @@ fn main() {
         c_matrix[i] = distrib.sample(&mut rng);
     }
 
-    let mut emu = QPUEmu::new((1024 + a_matrix.len() + b_matrix.len() + c_matrix.len()) * 4, breakpoint_handler);
+    const UNIFORM_SIZE: usize = 14;
+
+    let mut emu = QPUEmu::new((1024 + a_matrix.len() + b_matrix.len() + c_matrix.len()) * 4);
+    let debugger = Arc::new(Mutex::new(debugger::Debugger::default()));
+    emu.set_hooks(Box::new(debugger.clone()));
+    let uniforms_buf = emu.alloc(N_THREADS * UNIFORM_SIZE * 4, 16).unwrap();
+    let a_buf = emu.alloc(a_matrix.len() * 4, 16).unwrap();
+    let b_buf = emu.alloc(b_matrix.len() * 4, 16).unwrap();
+    let c_buf = emu.alloc(c_matrix.len() * 4, 16).unwrap();
+    let (a_addr, b_addr, c_addr) = (a_buf.addr() as usize, b_buf.addr() as usize, c_buf.addr() as usize);
 
     let mut th = 0;
     let h = (p+16*P_DIV-1)/(16*P_DIV);
@@ -101,13 +246,11 @@ fn main() {
     let alpha: f32 = 1.0;
     let beta: f32 = 1.0;
 
-    const UNIFORM_SIZE: usize = 14;
-
     let mut uniforms: [[u32; UNIFORM_SIZE]; N_THREADS] = [[0; UNIFORM_SIZE]; N_THREADS];
 
     let mut uniform_ptrs = vec![0u32; N_THREADS];
     for th in 0..uniform_ptrs.len() {
-        uniform_ptrs[th] = (th * UNIFORM_SIZE * 4) as u32;
+        uniform_ptrs[th] = uniforms_buf.addr() + (th * UNIFORM_SIZE * 4) as u32;
     }
 
     for i in 0..P_DIV {
@@ -116,7 +259,7 @@ fn main() {
             let q_idx = q as u32;
             let r_idx = if j != R_DIV-1 { w as u32 } else { ((r-j*w*64) / 64) as u32 };
 
-            uniforms[th][0] = (th * UNIFORM_SIZE * 4) as u32;
+            uniforms[th][0] = uniform_ptrs[th];
             uniforms[th][1] = p_idx;
             uniforms[th][2] = q_idx;
             uniforms[th][3] = r_idx;
@@ -138,42 +281,11 @@ fn main() {
     }
 
     for th in 0..N_THREADS {
-        for i in 0..UNIFORM_SIZE {
-            let bytes = unsafe {
-                std::mem::transmute::<u32, [u8; 4]>(uniforms[th][i])
-            };
-            for b in 0..4 {
-                emu.mem[(th * UNIFORM_SIZE + i) * 4 + b] = bytes[b];
-            }
-        }
-    }
-
-    for idx in 0..a_matrix.len() {
-        let bytes = unsafe {
-            std::mem::transmute::<f32, [u8; 4]>(a_matrix[idx])
-        };
-        for b in 0..4 {
-            emu.mem[a_addr + idx * 4 + b] = bytes[b];
-        }
-    }
-
-    for idx in 0..b_matrix.len() {
-        let bytes = unsafe {
-            std::mem::transmute::<f32, [u8; 4]>(b_matrix[idx])
-        };
-        for b in 0..4 {
-            emu.mem[b_addr + idx * 4 + b] = bytes[b];
-        }
-    }
-
-    for idx in 0..c_matrix.len() {
-        let bytes = unsafe {
-            std::mem::transmute::<f32, [u8; 4]>(c_matrix[idx])
-        };
-        for b in 0..4 {
-            emu.mem[c_addr + idx * 4 + b] = bytes[b];
-        }
+        uniforms_buf.write_u32(&mut emu, th * UNIFORM_SIZE, &uniforms[th]).unwrap();
     }
+    a_buf.write_f32(&mut emu, 0, &a_matrix).unwrap();
+    b_buf.write_f32(&mut emu, 0, &b_matrix).unwrap();
+    c_buf.write_f32(&mut emu, 0, &c_matrix).unwrap();
 
     let mut ref_matrix = c_matrix.clone();
     sgemm(p, r, q, alpha, &a_matrix, &b_matrix, beta, &mut ref_matrix);
@@ -181,16 +293,16 @@ fn main() {
     let start = Instant::now();
 
     emu.execute(&insts, &uniform_ptrs, N_THREADS);
-
-    for idx in 0..c_matrix.len() {
-        let mut bytes = [0u8; 4];
-        for b in 0..4 {
-            bytes[b] = emu.mem[c_addr + idx * 4 + b];
-        }
-        c_matrix[idx] = unsafe {
-            std::mem::transmute::<[u8; 4], f32>(bytes)
-        };
+    if let Some(err) = debugger.lock().unwrap().error.take() {
+        eprintln!("debugger: {}", err);
+        std::process::exit(2);
     }
+    // The user quit the debugger before the threads ended.
+    if emu.thread().is_some() {
+        return;
+    }
+
+    c_matrix = c_buf.read_f32(&emu, 0, c_matrix.len()).unwrap();
 
     assert_eq!(c_matrix, ref_matrix);
 
@@ -0,0 +1,159 @@
+// Translation of the bus addresses used by the QPUs to offsets in the emulated memory.
+//
+// Bits 31:30 of a bus address select the cache alias and are ignored by the translation.
+// The remaining physical address is looked up in the mapped RAM regions.
+
+use std::fmt;
+
+pub const BUS_ALIAS_MASK: u32 = 0xc000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum BusAlias {
+    L1L2Cached = 0x0,   // 0x0... L1 and L2 cached.
+    L2Coherent = 0x1,   // 0x4... L2 cache coherent, non-allocating.
+    L2Cached = 0x2,     // 0x8... L2 cached only.
+    Uncached = 0x3,     // 0xC... Direct, uncached.
+}
+
+impl BusAlias {
+    pub fn from_bus_addr(addr: u32) -> Self {
+        match addr >> 30 {
+            0 => BusAlias::L1L2Cached,
+            1 => BusAlias::L2Coherent,
+            2 => BusAlias::L2Cached,
+            _ => BusAlias::Uncached,
+        }
+    }
+}
+
+pub fn bus_to_phys(addr: u32) -> u32 {
+    addr & !BUS_ALIAS_MASK
+}
+
+pub fn phys_to_bus(addr: u32, alias: BusAlias) -> u32 {
+    bus_to_phys(addr) | (alias as u32) << 30
+}
+
+// An access to an address which is not backed by mapped RAM.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryFault {
+    pub addr: u32,
+    pub len: usize,
+}
+
+impl fmt::Display for MemoryFault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "memory fault: {} bytes at 0x{:08x}", self.len, self.addr)
+    }
+}
+
+// A range of physical addresses backed by the emulated memory starting at offset.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct MemoryRegion {
+    pub base: u32,
+    pub size: usize,
+    pub offset: usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct MemoryMap {
+    regions: Vec<MemoryRegion>,
+}
+
+impl MemoryMap {
+    pub fn new() -> Self {
+        MemoryMap { regions: vec![] }
+    }
+
+    // Maps the physical addresses from 0 directly to the memory offsets.
+    pub fn flat(size: usize) -> Self {
+        let mut map = MemoryMap::new();
+        map.add_region(0, size, 0);
+        map
+    }
+
+    pub fn add_region(&mut self, base: u32, size: usize, offset: usize) {
+        let end = base as usize + size;
+        if end > (!BUS_ALIAS_MASK) as usize + 1 {
+            panic!("The region overlaps the alias bits.");
+        }
+        if self.regions.iter().any(|r| (base as usize) < r.base as usize + r.size && (r.base as usize) < end) {
+            panic!("The region overlaps another region.");
+        }
+        self.regions.push(MemoryRegion { base, size, offset });
+    }
+
+    pub fn regions(&self) -> &[MemoryRegion] {
+        &self.regions
+    }
+
+    // Returns the memory offset of len bytes at the bus address addr.
+    pub fn translate(&self, addr: u32, len: usize) -> Result<usize, MemoryFault> {
+        let phys = bus_to_phys(addr) as usize;
+
+        for region in &self.regions {
+            let base = region.base as usize;
+            if phys >= base && phys + len <= base + region.size {
+                return Ok(region.offset + phys - base);
+            }
+        }
+        Err(MemoryFault { addr, len })
+    }
+
+    // Returns the physical address of the memory offset.
+    pub fn phys_addr(&self, offset: usize) -> Option<u32> {
+        self.regions.iter()
+            .find(|r| offset >= r.offset && offset < r.offset + r.size)
+            .map(|r| r.base + (offset - r.offset) as u32)
+    }
+}
+
+impl Default for MemoryMap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Memory accessed with bus addresses, such as the frame buffers.
+pub trait Memory {
+    fn read_bytes(&self, addr: u32, buf: &mut [u8]) -> Result<(), MemoryFault>;
+    fn write_bytes(&mut self, addr: u32, data: &[u8]) -> Result<(), MemoryFault>;
+}
+
+// The emulated memory seen through a memory map.
+pub struct MappedMemory<'a> {
+    pub mem: &'a mut [u8],
+    pub memory_map: &'a MemoryMap,
+}
+
+impl<'a> Memory for MappedMemory<'a> {
+    fn read_bytes(&self, addr: u32, buf: &mut [u8]) -> Result<(), MemoryFault> {
+        let offset = self.memory_map.translate(addr, buf.len())?;
+        buf.copy_from_slice(&self.mem[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    fn write_bytes(&mut self, addr: u32, data: &[u8]) -> Result<(), MemoryFault> {
+        let offset = self.memory_map.translate(addr, data.len())?;
+        self.mem[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[test]
+fn test_memory_map_translate() {
+    let mut map = MemoryMap::new();
+    map.add_region(0x1000_0000, 0x1000, 0);
+    map.add_region(0x2000_0000, 0x1000, 0x1000);
+
+    assert_eq!(map.translate(0x1000_0010, 4), Ok(0x10));
+    assert_eq!(map.translate(0x5000_0010, 4), Ok(0x10));
+    assert_eq!(map.translate(0xe000_0ffc, 4), Ok(0x1ffc));
+    assert_eq!(map.translate(0x1000_0ffe, 4), Err(MemoryFault { addr: 0x1000_0ffe, len: 4 }));
+    assert_eq!(map.translate(0x0000_0000, 1), Err(MemoryFault { addr: 0, len: 1 }));
+
+    assert_eq!(map.phys_addr(0x1010), Some(0x2000_0010));
+    assert_eq!(map.phys_addr(0x2000), None);
+    assert_eq!(phys_to_bus(0x2000_0010, BusAlias::Uncached), 0xe000_0010);
+    assert_eq!(BusAlias::from_bus_addr(0x8000_0000), BusAlias::L2Cached);
+}
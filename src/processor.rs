@@ -1,4 +1,11 @@
 use crate::constants::*;
+use crate::tile_buffer::*;
+use crate::texture::*;
+use crate::memory_map::*;
+use crate::allocator::Allocator;
+use crate::buffer::Buffer;
+use crate::hooks::{DmaDirection, Hooks, Register};
+use crate::breakpoint::{Access, Condition, WatchHit, WatchTarget, Watchpoint};
 use super::instructions::*;
 use super::utils::*;
 
@@ -132,7 +139,53 @@ impl VPMDMALoad {
     }
 }
 
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
+
+const NOP_INST: u64 = 1 << 60;
+
+// Counters of the work done by a thread.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ThreadStats {
+    pub instructions: u64,
+    pub uniforms: u64,
+    pub tmu_loads: u64,
+    pub vpm_dma_loads: u64,
+    pub vpm_dma_stores: u64,
+    pub host_interrupts: u64,
+}
+
+// Events which stop run_until_event after the instruction raising them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Event {
+    ThreadEnd,
+    HostInterrupt,
+    Semaphore,
+    Mutex,
+    VpmDma,
+    TmuRequest,
+}
+
+// Why the run control functions returned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopReason {
+    // The requested instructions were executed.
+    Stepped,
+    // The instruction pc of the thread is the next one, for run_until.
+    Reached { thread: usize, pc: u32 },
+    // A breakpoint before the instruction pc is executed. Resuming executes the instruction.
+    Breakpoint { thread: usize, pc: u32 },
+    // The last instruction executed accessed a watched location. Every hit is in watch_hits.
+    Watchpoint(WatchHit),
+    // The last instruction executed raised the event.
+    Event { thread: usize, event: Event },
+    Fault { thread: usize, fault: MemoryFault },
+    // A hook called request_stop. Resuming continues where the thread stopped.
+    Stopped { thread: usize, pc: u32 },
+    // step_qpu was given a thread which was not started. Nothing was executed.
+    InvalidThread { thread: usize },
+    // Every thread has ended.
+    Finished,
+}
 
 pub struct QPUEmu {
 	pc: usize,
@@ -140,24 +193,54 @@ pub struct QPUEmu {
 	reg_ra: RegisterFile<u32>,
 	reg_rb: RegisterFile<u32>,
 	insts: Vec<u64>,
+    code_addr: Option<u32>,
     zf: [bool; 16],
     nf: [bool; 16],
     cf: [bool; 16],
     uniform_ptr: u32,
     slots: [(u32, u64); 3],
 	pub mem: Vec<u8>,
+    memory_map: MemoryMap,
+    allocator: Allocator,
+    fault: Option<MemoryFault>,
     vpm: Vec<Vec<u8>>,
     vpm_dma_load: VPMDMALoad,
     vpm_read: VPMRead,
     vpm_dma_store: VPMDMAStore,
     vpm_write: VPMWrite,
     tmu0_req_fifo: VecDeque<(u8, [u32; 16])>, // The first element represents parameter type: s, t, r, b := 0, 1, 2, 3.
-
-    breakpoint_handler: fn(&QPUEmu, u32) -> (),
+    tmu0_config_fifo: VecDeque<TextureConfig>,
+    pub tile_buffer: TileBuffer,
+    pixel_coords: [(u32, u32); 16],
+    fragment_z: [u32; 16],
+    rev_flag: bool,
+    ms_flags: [u8; 16], // Covered samples of each element. Only bit 0 is used without multisampling.
+    tlb_passed: Option<[u8; 16]>, // Samples which passed the stencil and depth tests.
+    tlb_ms_sample: usize,
+    host_interrupts: u32,
+    stats: ThreadStats,
+    ended_stats: Vec<ThreadStats>,
+
+    threads: Vec<u32>, // The uniform pointers of the threads started.
+    thread: usize,
+    next_thread: usize,
+    thread_running: bool,
+    pipeline_fill: usize,
+    end_delay_slots: Option<usize>,
+    retired: u64,
+    events: Vec<Event>,
+    resume_breakpoint: bool,
+    stop_requested: bool,
+
+    breakpoints: BTreeMap<u32, Option<Condition>>,
+    watchpoints: Vec<Option<Watchpoint>>,
+    watch_hits: Vec<WatchHit>,
+    single_step: bool,
+    hooks: Option<Box<dyn Hooks + Send>>,
 }
 
 impl QPUEmu {
-    pub fn new(mem_size: usize, breakpoint_handler: fn(&QPUEmu, u32) -> ()) -> Self {
+    pub fn new(mem_size: usize) -> Self {
         let mut vpm = Vec::new();
         for _ in 0..16 {
             vpm.push(vec![0; 64 * 4]);
@@ -169,27 +252,544 @@ impl QPUEmu {
             reg_ra: RegisterFile::new(16, 32, 0),
             reg_rb: RegisterFile::new(16, 32, 0),
             insts: vec![],
+            code_addr: None,
             zf: [true; 16],
             nf: [false; 16],
             cf: [false; 16],
             uniform_ptr: 0,
-            slots: [(0, 1<<60); 3],
+            slots: [(0, NOP_INST); 3],
             mem: vec![0; mem_size],
+            memory_map: MemoryMap::flat(mem_size),
+            allocator: Allocator::new(0, mem_size),
+            fault: None,
             vpm: vpm,
             vpm_dma_load: VPMDMALoad::new(),
             vpm_read: VPMRead::new(),
             vpm_dma_store: VPMDMAStore::new(),
             vpm_write: VPMWrite::new(),
             tmu0_req_fifo: VecDeque::new(),
+            tmu0_config_fifo: VecDeque::new(),
+            tile_buffer: TileBuffer::new(),
+            pixel_coords: [(0, 0); 16],
+            fragment_z: [0; 16],
+            rev_flag: false,
+            ms_flags: [0xf; 16],
+            tlb_passed: None,
+            tlb_ms_sample: 0,
+            host_interrupts: 0,
+            stats: ThreadStats::default(),
+            ended_stats: vec![],
+
+            threads: vec![],
+            thread: 0,
+            next_thread: 0,
+            thread_running: false,
+            pipeline_fill: 0,
+            end_delay_slots: None,
+            retired: 0,
+            events: vec![],
+            resume_breakpoint: false,
+            stop_requested: false,
+
+            breakpoints: BTreeMap::new(),
+            watchpoints: vec![],
+            watch_hits: vec![],
+            single_step: false,
+            hooks: None,
+        }
+    }
+
+    // Number of host interrupts raised by the programs so far.
+    pub fn host_interrupts(&self) -> u32 {
+        self.host_interrupts
+    }
+
+    // Statistics of the last thread run.
+    pub fn thread_stats(&self) -> ThreadStats {
+        self.stats
+    }
+
+    // The statistics of the threads which ended since the threads were started, in order.
+    pub fn ended_thread_stats(&self) -> &[ThreadStats] {
+        &self.ended_stats
+    }
+
+    // Sets the screen coordinates of the fragment in each element for the fragment shader.
+    pub fn set_pixel_coords(&mut self, coords: &[(u32, u32); 16]) {
+        self.pixel_coords = *coords;
+        self.tlb_passed = None;
+        self.tlb_ms_sample = 0;
+    }
+
+    // Sets the interpolated Z of each fragment, used by the depth test unless the shader writes TLB_Z.
+    pub fn set_fragment_z(&mut self, z: &[u32; 16]) {
+        self.fragment_z = *z;
+        self.tlb_passed = None;
+    }
+
+    // Marks the fragments as back facing.
+    pub fn set_rev_flag(&mut self, rev_flag: bool) {
+        self.rev_flag = rev_flag;
+    }
+
+    // Sets which elements hold a fragment, with all of their samples covered.
+    // Uncovered elements never write to the tile buffer.
+    pub fn set_coverage(&mut self, coverage: u16) {
+        for (elem, flags) in self.ms_flags.iter_mut().enumerate() {
+            *flags = if coverage & (1 << elem) != 0 { 0xf } else { 0 };
+        }
+    }
+
+    pub fn coverage(&self) -> u16 {
+        let mut coverage = 0;
+        for elem in 0..16 {
+            if self.covered_samples(elem) != 0 {
+                coverage |= 1 << elem;
+            }
+        }
+        coverage
+    }
+
+    // Sets the covered samples of each element in bits 3:0, as read from MS_FLAGS.
+    pub fn set_ms_flags(&mut self, ms_flags: &[u8; 16]) {
+        for (flags, new_flags) in self.ms_flags.iter_mut().zip(ms_flags.iter()) {
+            *flags = new_flags & 0xf;
+        }
+    }
+
+    pub fn ms_flags(&self) -> [u8; 16] {
+        self.ms_flags
+    }
 
-            breakpoint_handler: breakpoint_handler,
+    fn covered_samples(&self, elem: usize) -> u8 {
+        let sample_mask = (1 << self.tile_buffer.samples()) - 1;
+        self.ms_flags[elem] & sample_mask
+    }
+
+    // Rejects the samples which fail the early Z test and returns the elements still covered.
+    pub fn early_z(&mut self) -> u16 {
+        for elem in 0..16 {
+            let (x, y) = self.pixel_coords[elem];
+            if !self.tile_buffer.contains(x, y) {
+                continue;
+            }
+            for sample in 0..self.tile_buffer.samples() {
+                if !self.tile_buffer.early_z_test(x, y, sample, self.fragment_z[elem]) {
+                    self.ms_flags[elem] &= !(1 << sample);
+                }
+            }
+        }
+        self.coverage()
+    }
+
+    // Replaces the memory map. Every region must be backed by mem, otherwise the map is kept.
+    // Buffers allocated before are discarded.
+    pub fn set_memory_map(&mut self, memory_map: MemoryMap) -> Result<(), String> {
+        for region in memory_map.regions() {
+            match region.offset.checked_add(region.size) {
+                Some(end) if end <= self.mem.len() => {},
+                _ => return Err("The region is out of the memory.".to_string()),
+            }
+        }
+        self.allocator = Allocator::new(0, 0);
+        for region in memory_map.regions() {
+            self.allocator.add_range(region.base as usize, region.size);
         }
+        self.memory_map = memory_map;
+        Ok(())
     }
 
-    fn read_mem_u32(&mut self, addr: usize) -> u32 {
+    // Allocates a buffer from the mapped RAM. The alignment must be a power of two.
+    pub fn alloc(&mut self, size: usize, align: usize) -> Option<Buffer> {
+        self.allocator.alloc(size, align).map(|addr| Buffer::new(addr as u32, size))
+    }
+
+    // Allocates the buffer at the bus address addr, if the range is free.
+    pub fn alloc_at(&mut self, addr: u32, size: usize) -> Option<Buffer> {
+        let addr = bus_to_phys(addr);
+        if self.allocator.alloc_at(addr as usize, size) {
+            Some(Buffer::new(addr, size))
+        } else {
+            None
+        }
+    }
+
+    // Returns the buffer allocated at the bus address addr.
+    pub fn buffer_at(&self, addr: u32) -> Option<Buffer> {
+        let addr = bus_to_phys(addr);
+        self.allocator.size_of(addr as usize).map(|size| Buffer::new(addr, size))
+    }
+
+    pub fn free(&mut self, buffer: Buffer) {
+        if !self.allocator.free(buffer.addr() as usize) {
+            panic!("The buffer is not allocated.");
+        }
+    }
+
+    pub fn memory_map(&self) -> &MemoryMap {
+        &self.memory_map
+    }
+
+    // The fault which stopped the last execution, if any.
+    pub fn fault(&self) -> Option<MemoryFault> {
+        self.fault
+    }
+
+    pub fn read_bytes(&self, addr: u32, buf: &mut [u8]) -> Result<(), MemoryFault> {
+        let offset = self.memory_map.translate(addr, buf.len())?;
+        buf.copy_from_slice(&self.mem[offset..offset + buf.len()]);
+        Ok(())
+    }
+
+    pub fn write_bytes(&mut self, addr: u32, data: &[u8]) -> Result<(), MemoryFault> {
+        let offset = self.memory_map.translate(addr, data.len())?;
+        self.mem[offset..offset + data.len()].copy_from_slice(data);
+        Ok(())
+    }
+
+    // Writes the current tile of the tile buffer to the frame buffer at its bus address.
+    pub fn store_tile_buffer(&mut self, fb: &FrameBuffer) -> Result<(), MemoryFault> {
+        self.tile_buffer.store_colour(&mut MappedMemory { mem: &mut self.mem, memory_map: &self.memory_map }, fb)
+    }
+
+    pub fn load_tile_buffer(&mut self, fb: &FrameBuffer) -> Result<(), MemoryFault> {
+        self.tile_buffer.load_colour(&MappedMemory { mem: &mut self.mem, memory_map: &self.memory_map }, fb)
+    }
+
+    pub fn read_u32(&self, addr: u32) -> Result<u32, MemoryFault> {
+        let mut bytes = [0u8; 4];
+        self.read_bytes(addr, &mut bytes)?;
+        Ok(u8x4_to_u32(bytes))
+    }
+
+    pub fn write_u32(&mut self, addr: u32, val: u32) -> Result<(), MemoryFault> {
+        self.write_bytes(addr, &u32_to_u8x4(val))
+    }
+
+    // The index of the instruction being executed, valid inside the breakpoint handler.
+    pub fn pc(&self) -> u32 {
+        self.slots[0].0
+    }
+
+    // The bus address of the running program, or 0 for a program given as instructions.
+    pub fn code_addr(&self) -> u32 {
+        self.code_addr.unwrap_or(0)
+    }
+
+    // The instructions of the running program. A program in memory is read up to the end of
+    // its buffer, or else up to the delay slots of its first thread end.
+    pub fn program(&self) -> Vec<u64> {
+        let code_addr = match self.code_addr {
+            Some(code_addr) => code_addr,
+            None => return self.insts.clone(),
+        };
+        let limit = self.buffer_at(code_addr).map(|buffer| buffer.size() / 8);
+        let mut insts = vec![];
+        let mut end_delay_slots = None;
+        while limit.map_or(end_delay_slots != Some(0), |limit| insts.len() < limit) {
+            let addr = code_addr.wrapping_add(insts.len() as u32 * 8);
+            let inst = match (self.read_u32(addr), self.read_u32(addr.wrapping_add(4))) {
+                (Ok(lo), Ok(hi)) => (hi as u64) << 32 | lo as u64,
+                _ => break,
+            };
+            let sig = (inst >> 60) as u8;
+            end_delay_slots = match end_delay_slots {
+                Some(n) => Some(n - 1),
+                None if sig == SIG_THREND || sig == SIG_LDCEND => Some(2),
+                None => None,
+            };
+            insts.push(inst);
+        }
+        insts
+    }
+
+    // Replaces the hooks called on the execution events.
+    pub fn set_hooks(&mut self, hooks: Box<dyn Hooks + Send>) {
+        self.hooks = Some(hooks);
+    }
+
+    pub fn take_hooks(&mut self) -> Option<Box<dyn Hooks + Send>> {
+        self.hooks.take()
+    }
+
+    // Calls the hooks, which are detached from the emulator meanwhile.
+    fn hook<F: FnOnce(&mut dyn Hooks, &mut QPUEmu)>(&mut self, f: F) {
+        if let Some(mut hooks) = self.hooks.take() {
+            f(hooks.as_mut(), self);
+            if self.hooks.is_none() {
+                self.hooks = Some(hooks);
+            }
+        }
+    }
+
+    fn write_register(&mut self, reg: Register, values: &[Option<u32>; 16]) {
+        match reg {
+            Register::Acc(idx) => self.reg_r.set_vec(idx as usize, values),
+            Register::A(idx) => self.reg_ra.set_vec(idx as usize, values),
+            Register::B(idx) => self.reg_rb.set_vec(idx as usize, values),
+        }
+        let lanes = values.iter().enumerate().fold(0u16, |lanes, (elem, value)| lanes | (value.is_some() as u16) << elem);
+        if lanes != 0 {
+            self.hook(|hooks, emu| hooks.register_write(emu, reg, values));
+            self.watch_register(reg, Access::Write, lanes);
+        }
+    }
+
+    // Stops at the instruction pc before it is executed.
+    pub fn set_breakpoint(&mut self, pc: u32) {
+        self.breakpoints.insert(pc, None);
+    }
+
+    // Stops at the instruction pc if the condition holds before it is executed.
+    pub fn set_conditional_breakpoint(&mut self, pc: u32, condition: Condition) {
+        self.breakpoints.insert(pc, Some(condition));
+    }
+
+    // Returns false if there was no breakpoint at pc.
+    pub fn clear_breakpoint(&mut self, pc: u32) -> bool {
+        self.breakpoints.remove(&pc).is_some()
+    }
+
+    pub fn breakpoints(&self) -> Vec<(u32, Option<Condition>)> {
+        self.breakpoints.iter().map(|(pc, condition)| (*pc, *condition)).collect()
+    }
+
+    fn breakpoint_hit(&self, pc: u32) -> bool {
+        match self.breakpoints.get(&pc) {
+            Some(Some(condition)) => condition.eval(self),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    // Stops after the instructions accessing the location. Returns the index of the watchpoint.
+    pub fn add_watchpoint(&mut self, watchpoint: Watchpoint) -> usize {
+        self.watchpoints.push(Some(watchpoint));
+        self.watchpoints.len() - 1
+    }
+
+    // Returns false if there was no watchpoint of the index.
+    pub fn remove_watchpoint(&mut self, idx: usize) -> bool {
+        self.watchpoints.get_mut(idx).and_then(|watchpoint| watchpoint.take()).is_some()
+    }
+
+    pub fn watchpoints(&self) -> Vec<(usize, Watchpoint)> {
+        self.watchpoints.iter().enumerate().filter_map(|(idx, watchpoint)| watchpoint.map(|watchpoint| (idx, watchpoint))).collect()
+    }
+
+    // The watched accesses of the last instruction executed.
+    pub fn watch_hits(&self) -> &[WatchHit] {
+        &self.watch_hits
+    }
+
+    // Records the access of the lanes to the locations matching the target. The thread and the
+    // pc are filled in after the instruction.
+    fn watch<F: Fn(&WatchTarget) -> bool>(&mut self, access: Access, lanes: u16, matches: F) {
+        for (idx, watchpoint) in self.watchpoints.iter().enumerate() {
+            match watchpoint {
+                Some(watchpoint) if watchpoint.access.matches(access) && matches(&watchpoint.target) => (),
+                _ => continue,
+            }
+            match self.watch_hits.iter_mut().find(|hit| hit.watchpoint == idx && hit.access == access) {
+                Some(hit) => hit.lanes |= lanes,
+                None => self.watch_hits.push(WatchHit { watchpoint: idx, access, lanes, thread: 0, pc: 0 }),
+            }
+        }
+    }
+
+    fn watch_register(&mut self, reg: Register, access: Access, lanes: u16) {
+        if !self.watchpoints.is_empty() {
+            self.watch(access, lanes, |target| *target == WatchTarget::Register(reg));
+        }
+    }
+
+    fn watch_vpm(&mut self, row: usize, access: Access, lanes: u16) {
+        if !self.watchpoints.is_empty() {
+            self.watch(access, lanes, |target| match *target {
+                WatchTarget::VpmRows { row: first, count } => row >= first && row < first + count,
+                _ => false,
+            });
+        }
+    }
+
+    // Reports an access of the program to the VPM through the vpm register to the hooks and the
+    // watchpoints.
+    fn vpm_access(&mut self, row: usize, access: Access, lanes: u16) {
+        self.hook(|hooks, emu| hooks.vpm_access(emu, row, access == Access::Write, lanes));
+        self.watch_vpm(row, access, lanes);
+    }
+
+    // Reports an access of the program to the memory to the hooks and the watchpoints.
+    fn memory_access(&mut self, addr: u32, len: usize, access: Access, lanes: u16) {
+        if access == Access::Read {
+            self.hook(|hooks, emu| hooks.memory_read(emu, addr, len));
+        } else {
+            self.hook(|hooks, emu| hooks.memory_write(emu, addr, len));
+        }
+        if !self.watchpoints.is_empty() {
+            self.watch(access, lanes, |target| match *target {
+                WatchTarget::Memory { addr: first, len: watch_len } => {
+                    (addr as u64) < first as u64 + watch_len as u64 && (first as u64) < addr as u64 + len as u64
+                }
+                _ => false,
+            });
+        }
+    }
+
+    // Calls the breakpoint hook before every instruction.
+    pub fn set_single_step(&mut self, single_step: bool) {
+        self.single_step = single_step;
+    }
+
+    pub fn single_step(&self) -> bool {
+        self.single_step
+    }
+
+    // Makes the run control functions return Stopped after the current hook, e.g. when the user
+    // quits a debugger.
+    pub fn request_stop(&mut self) {
+        self.stop_requested = true;
+    }
+
+    // The elements of the accumulator r0 to r5.
+    pub fn accumulator(&self, idx: usize) -> [u32; 16] {
+        if idx >= 6 {
+            panic!("The accumulator index is out of range.");
+        }
+        Self::elems(&self.reg_r, idx)
+    }
+
+    pub fn set_accumulator(&mut self, idx: usize, elem: usize, val: u32) {
+        if idx >= 6 || elem >= 16 {
+            panic!("The accumulator index is out of range.");
+        }
+        self.reg_r.set(elem, idx, val);
+    }
+
+    // The elements of the register idx of the regfile A.
+    pub fn reg_a(&self, idx: usize) -> [u32; 16] {
+        if idx >= 32 {
+            panic!("The register index is out of range.");
+        }
+        Self::elems(&self.reg_ra, idx)
+    }
+
+    pub fn set_reg_a(&mut self, idx: usize, elem: usize, val: u32) {
+        if idx >= 32 || elem >= 16 {
+            panic!("The register index is out of range.");
+        }
+        self.reg_ra.set(elem, idx, val);
+    }
+
+    // The elements of the register idx of the regfile B.
+    pub fn reg_b(&self, idx: usize) -> [u32; 16] {
+        if idx >= 32 {
+            panic!("The register index is out of range.");
+        }
+        Self::elems(&self.reg_rb, idx)
+    }
+
+    pub fn set_reg_b(&mut self, idx: usize, elem: usize, val: u32) {
+        if idx >= 32 || elem >= 16 {
+            panic!("The register index is out of range.");
+        }
+        self.reg_rb.set(elem, idx, val);
+    }
+
+    pub fn register(&self, reg: Register) -> [u32; 16] {
+        match reg {
+            Register::Acc(idx) => self.accumulator(idx as usize),
+            Register::A(idx) => self.reg_a(idx as usize),
+            Register::B(idx) => self.reg_b(idx as usize),
+        }
+    }
+
+    pub fn set_register(&mut self, reg: Register, elem: usize, val: u32) {
+        match reg {
+            Register::Acc(idx) => self.set_accumulator(idx as usize, elem, val),
+            Register::A(idx) => self.set_reg_a(idx as usize, elem, val),
+            Register::B(idx) => self.set_reg_b(idx as usize, elem, val),
+        }
+    }
+
+    fn elems(regs: &RegisterFile<u32>, idx: usize) -> [u32; 16] {
+        let mut vals = [0; 16];
+        vals.copy_from_slice(regs.get_vec(idx));
+        vals
+    }
+
+    pub fn zero_flags(&self) -> [bool; 16] {
+        self.zf
+    }
+
+    pub fn negative_flags(&self) -> [bool; 16] {
+        self.nf
+    }
+
+    pub fn carry_flags(&self) -> [bool; 16] {
+        self.cf
+    }
+
+    // The address of the next uniform to be read.
+    pub fn uniform_ptr(&self) -> u32 {
+        self.uniform_ptr
+    }
+
+    pub fn set_uniform_ptr(&mut self, uniform_ptr: u32) {
+        self.uniform_ptr = uniform_ptr;
+    }
+
+    // The elements of the 32-bit VPM row, out of 64 rows.
+    pub fn vpm_row(&self, row: usize) -> [u32; 16] {
+        if row >= 64 {
+            panic!("The VPM row is out of range.");
+        }
+        let mut vals = [0; 16];
+        for (elem, val) in vals.iter_mut().enumerate() {
+            let mut bytes = [0u8; 4];
+            bytes.copy_from_slice(&self.vpm[elem][row * 4..row * 4 + 4]);
+            *val = u8x4_to_u32(bytes);
+        }
+        vals
+    }
+
+    pub fn set_vpm(&mut self, row: usize, elem: usize, val: u32) {
+        if row >= 64 || elem >= 16 {
+            panic!("The VPM row is out of range.");
+        }
+        self.vpm[elem][row * 4..row * 4 + 4].copy_from_slice(&u32_to_u8x4(val));
+    }
+
+    // Translates a bus address accessed by the program. A fault is recorded and stops the thread.
+    fn translate(&mut self, addr: u32, len: usize) -> Option<usize> {
+        match self.memory_map.translate(addr, len) {
+            Ok(offset) => Some(offset),
+            Err(fault) => {
+                if self.fault.is_none() {
+                    self.fault = Some(fault);
+                }
+                None
+            },
+        }
+    }
+
+    // Records a fault for an access past the end of the address space and returns 0, as a
+    // faulting read does.
+    fn address_overflow(&mut self, addr: u32, len: usize) -> u32 {
+        if self.fault.is_none() {
+            self.fault = Some(MemoryFault { addr, len });
+        }
+        0
+    }
+
+    fn read_mem_u32(&mut self, addr: u32) -> u32 {
         if addr & 3 != 0 {
             panic!("Not aligned by 4bytes.");
         }
+        let addr = match self.translate(addr, 4) {
+            Some(offset) => offset,
+            None => return 0,
+        };
         let b0 = self.mem[addr] as u32;
         let b1 = self.mem[addr + 1] as u32;
         let b2 = self.mem[addr + 2] as u32;
@@ -222,6 +822,7 @@ impl QPUEmu {
                         self.vpm_read.addr += self.vpm_read.stride;
                     }
 
+                    self.vpm_access(y, Access::Read, 1 << elem);
                     self.read_vpm_mem_u32(x + elem, y * 4)
                 } else {
                     let x = get_bits_u32(self.vpm_read.addr as u32, 3, 0) as usize;
@@ -231,6 +832,7 @@ impl QPUEmu {
                         self.vpm_read.addr += self.vpm_read.stride;
                     }
 
+                    self.vpm_access(y * 16 + elem, Access::Read, 1 << elem);
                     self.read_vpm_mem_u32(x, (y * 16 + elem) * 4)
                 }
             },
@@ -244,12 +846,23 @@ impl QPUEmu {
         if addr <= RA_RA31 {
             self.reg_ra.get(elem, addr as usize)
         } else if addr == RA_UNIFORM_READ {
-            self.read_mem_u32(self.uniform_ptr as usize)
+            if elem == 0 {
+                self.memory_access(self.uniform_ptr, 4, Access::Read, 0xffff);
+            }
+            self.read_mem_u32(self.uniform_ptr)
         } else if addr == RA_ELEMENT_NUMBER {
             elem as u32
+        } else if addr == RA_X_PIXEL_COORD {
+            self.pixel_coords[elem].0
+        } else if addr == RA_MS_FLAGS {
+            self.covered_samples(elem) as u32
         } else if addr == RA_NOP {
             0
         } else if addr == RA_MUTEX_ACQUIRE {
+            if elem == 0 {
+                self.events.push(Event::Mutex);
+                self.hook(|hooks, emu| hooks.mutex(emu, true));
+            }
             0
         } else if addr == RA_VPM_READ {
             self.read_vpm(elem)
@@ -266,10 +879,21 @@ impl QPUEmu {
         if addr <= RB_RB31 {
             self.reg_rb.get(elem, addr as usize)
         } else if addr == RB_UNIFORM_READ {
-            self.read_mem_u32(self.uniform_ptr as usize)
+            if elem == 0 {
+                self.memory_access(self.uniform_ptr, 4, Access::Read, 0xffff);
+            }
+            self.read_mem_u32(self.uniform_ptr)
         } else if addr == RB_NOP {
             0
+        } else if addr == RB_Y_PIXEL_COORD {
+            self.pixel_coords[elem].1
+        } else if addr == RB_REV_FLAG {
+            self.rev_flag as u32
         } else if addr == RB_MUTEX_ACQUIRE {
+            if elem == 0 {
+                self.events.push(Event::Mutex);
+                self.hook(|hooks, emu| hooks.mutex(emu, true));
+            }
             0
         } else if addr == RB_VPM_READ {
             self.read_vpm(elem)
@@ -321,6 +945,9 @@ impl QPUEmu {
     }
 
     fn execute_vpm_dma_load(&mut self, addr: u32) -> () {
+        self.stats.vpm_dma_loads += 1;
+        self.events.push(Event::VpmDma);
+        self.hook(|hooks, emu| hooks.vpm_dma(emu, DmaDirection::Load, addr));
         let mpitch = if self.vpm_dma_load.mpitch != 0 {
             8 * 2u32.pow(self.vpm_dma_load.mpitch)
         } else {
@@ -347,8 +974,12 @@ impl QPUEmu {
                     let x = get_bits_u32(vpm_addr, 3, 0) as usize;
                     let y = get_bits_u32(vpm_addr, 31, 4) as usize;
 
-                    for byte in 0..4 {
-                        self.vpm[x][y * 4 + byte] = self.mem[mem_addr + byte];
+                    if let Some(offset) = self.translate(mem_addr as u32, 4) {
+                        self.memory_access(mem_addr as u32, 4, Access::Read, 0xffff);
+                        self.watch_vpm(y, Access::Write, 0xffff);
+                        for byte in 0..4 {
+                            self.vpm[x][y * 4 + byte] = self.mem[offset + byte];
+                        }
                     }
 
                     vpm_addr += vpitch;
@@ -402,6 +1033,9 @@ impl QPUEmu {
     }
 
     fn execute_vpm_dma_store(&mut self, addr: u32) -> () {
+        self.stats.vpm_dma_stores += 1;
+        self.events.push(Event::VpmDma);
+        self.hook(|hooks, emu| hooks.vpm_dma(emu, DmaDirection::Store, addr));
         let mstride = self.vpm_dma_store.stride as usize;
         let modew = self.vpm_dma_store.modew;
 
@@ -425,8 +1059,12 @@ impl QPUEmu {
                     let x = get_bits_u32(vpm_addr, 3, 0) as usize;
                     let y = get_bits_u32(vpm_addr, 31, 4) as usize;
 
-                    for byte in 0..4 {
-                        self.mem[mem_addr + byte] = self.vpm[x][y * 4 + byte];
+                    if let Some(offset) = self.translate(mem_addr as u32, 4) {
+                        for byte in 0..4 {
+                            self.mem[offset + byte] = self.vpm[x][y * 4 + byte];
+                        }
+                        self.memory_access(mem_addr as u32, 4, Access::Write, 0xffff);
+                        self.watch_vpm(y, Access::Read, 0xffff);
                     }
 
                     vpm_addr += vpitch;
@@ -467,6 +1105,7 @@ impl QPUEmu {
 
                     for elem in 0..16 {
                         if let Some(value) = values[elem] {
+                            self.vpm_access(y, Access::Write, 1 << elem);
                             self.write_vpm_mem_u32(x + elem, y * 4, value);
                         }
                     }
@@ -478,6 +1117,7 @@ impl QPUEmu {
 
                     for elem in 0..16 {
                         if let Some(value) = values[elem] {
+                            self.vpm_access(y * 16 + elem, Access::Write, 1 << elem);
                             self.write_vpm_mem_u32(x, (y * 16 + elem) * 4, value);
                         }
                     }
@@ -489,17 +1129,115 @@ impl QPUEmu {
         }
     }
 
+    // Runs the stencil and depth tests once per fragment, with the Z written by the shader if any.
+    // Fragments outside the current tile fail.
+    fn test_fragments(&mut self, z: &[Option<u32>; 16]) -> [u8; 16] {
+        if let Some(passed) = self.tlb_passed {
+            return passed;
+        }
+
+        let mut passed = [0; 16];
+        for elem in 0..16 {
+            let (x, y) = self.pixel_coords[elem];
+            if !self.tile_buffer.contains(x, y) {
+                continue;
+            }
+            let z = z[elem].unwrap_or(self.fragment_z[elem]);
+            let covered = self.covered_samples(elem);
+
+            for sample in 0..self.tile_buffer.samples() {
+                if covered & (1 << sample) != 0 && self.tile_buffer.test_sample(x, y, sample, z, self.rev_flag) {
+                    passed[elem] |= 1 << sample;
+                }
+            }
+        }
+
+        self.tlb_passed = Some(passed);
+        passed
+    }
+
+    fn write_tlb_z(&mut self, values: &[Option<u32>; 16]) {
+        self.test_fragments(values);
+    }
+
+    // Writes the colour to every sample of the fragments which passed the tests.
+    fn write_tlb_colour(&mut self, values: &[Option<u32>; 16]) {
+        let passed = self.test_fragments(&[None; 16]);
+
+        for (elem, value) in values.iter().enumerate() {
+            if let Some(value) = value {
+                let (x, y) = self.pixel_coords[elem];
+                for sample in 0..self.tile_buffer.samples() {
+                    if passed[elem] & (1 << sample) != 0 {
+                        self.tile_buffer.write_colour(x, y, sample, *value);
+                    }
+                }
+            }
+        }
+    }
+
+    // Writes the colour to a single sample. Successive writes go to samples 0, 1, 2 and 3.
+    fn write_tlb_colour_ms(&mut self, values: &[Option<u32>; 16]) {
+        let passed = self.test_fragments(&[None; 16]);
+        let sample = self.tlb_ms_sample % self.tile_buffer.samples();
+        self.tlb_ms_sample += 1;
+
+        for (elem, value) in values.iter().enumerate() {
+            if let Some(value) = value {
+                if passed[elem] & (1 << sample) != 0 {
+                    let (x, y) = self.pixel_coords[elem];
+                    self.tile_buffer.write_colour(x, y, sample, *value);
+                }
+            }
+        }
+    }
+
+    // Writing MS_FLAGS can only remove samples from the coverage, e.g. for alpha to coverage.
+    fn write_ms_flags(&mut self, values: &[Option<u32>; 16]) {
+        for (elem, value) in values.iter().enumerate() {
+            if let Some(value) = value {
+                self.ms_flags[elem] &= (*value & 0xf) as u8;
+            }
+        }
+    }
+
+    // Writing s issues the request. A texture lookup with t reads its config from the uniforms,
+    // while a lookup with s only is a general memory access.
+    fn write_tmu0_s(&mut self, values: &[Option<u32>; 16]) {
+        if self.tmu0_req_fifo.len() >= 8 {
+            panic!("TMU0 request fifo is overflow.");
+        }
+
+        let pending_params = self.tmu0_req_fifo.iter().rev().take_while(|(param_type, _)| *param_type != 0);
+        if pending_params.clone().any(|(param_type, _)| *param_type == 1) {
+            self.memory_access(self.uniform_ptr, 8, Access::Read, 0xffff);
+            let param0 = self.read_mem_u32(self.uniform_ptr);
+            let param1 = match self.uniform_ptr.checked_add(4) {
+                Some(addr) => self.read_mem_u32(addr),
+                None => self.address_overflow(self.uniform_ptr, 8),
+            };
+            self.next_uniform();
+            self.next_uniform();
+            self.tmu0_config_fifo.push_back(TextureConfig::decode(param0, param1));
+        }
+
+        let s = unwrap_u32x16(values);
+        self.tmu0_req_fifo.push_back((0, s));
+        self.events.push(Event::TmuRequest);
+        self.hook(|hooks, emu| hooks.tmu_request(emu, 0, &s));
+    }
+
     fn write_ra(&mut self, addr: u8, values: &[Option<u32>; 16]) -> () {
         if addr >= WA_RA0 && addr <= WA_RA31 {
-            self.reg_ra.set_vec(addr as usize, values);
+            self.write_register(Register::A(addr), values);
         } else if addr == WA_ACC0 {
-            self.reg_r.set_vec(0, values);
+            self.write_register(Register::Acc(0), values);
         } else if addr == WA_ACC1 {
-            self.reg_r.set_vec(1, values);
+            self.write_register(Register::Acc(1), values);
         } else if addr == WA_ACC2 {
-            self.reg_r.set_vec(2, values);
+            self.write_register(Register::Acc(2), values);
         } else if addr == WA_ACC3 {
-            self.reg_r.set_vec(3, values);
+            self.write_register(Register::Acc(3), values);
         } else if addr == WB_ACC5 {
             unimplemented!();
         } else if addr == WA_NOP {
@@ -511,10 +1249,7 @@ impl QPUEmu {
         } else if addr == WA_TMU_NOSWAP {
             // TODO: not implemented
         } else if addr == WA_TMU0_S {
-            if self.tmu0_req_fifo.len() >= 8 {
-                panic!("TMU0 request fifo is overflow.");
-            }
-            self.tmu0_req_fifo.push_back((0, unwrap_u32x16(values)));
+            self.write_tmu0_s(values);
         } else if addr == WA_TMU0_T {
             if self.tmu0_req_fifo.len() >= 8 {
                 panic!("TMU0 request fifo is overflow.");
@@ -530,6 +1265,18 @@ impl QPUEmu {
                 panic!("TMU0 request fifo is overflow.");
             }
             self.tmu0_req_fifo.push_back((3, unwrap_u32x16(values)));
+        } else if addr == WA_TLB_STENCIL_SETUP {
+            if let Some(value) = values[0] {
+                self.tile_buffer.setup_stencil(value);
+            }
+        } else if addr == WA_TLB_Z {
+            self.write_tlb_z(values);
+        } else if addr == WA_MS_FLAGS {
+            self.write_ms_flags(values);
+        } else if addr == WA_TLB_COLOUR_MS {
+            self.write_tlb_colour_ms(values);
+        } else if addr == WA_TLB_COLOUR_ALL {
+            self.write_tlb_colour(values);
         } else if addr == WA_VPM_WRITE {
             self.write_vpm(values);
         } else if addr == WA_VPMVCD_RD_SETUP {
@@ -541,9 +1288,13 @@ impl QPUEmu {
                 self.execute_vpm_dma_load(value);
             }
         } else if addr == WA_MUTEX_RELEASE {
-            // TODO: not implemented
+            self.events.push(Event::Mutex);
+            self.hook(|hooks, emu| hooks.mutex(emu, false));
         } else if addr == WA_HOST_INT {
-            // TODO: not implemented
+            self.host_interrupts += 1;
+            self.stats.host_interrupts += 1;
+            self.events.push(Event::HostInterrupt);
+            self.hook(|hooks, emu| hooks.host_interrupt(emu));
         } else {
             panic!("Invalid address.")
         }
@@ -551,20 +1302,18 @@ impl QPUEmu {
 
     fn write_rb(&mut self, addr: u8, values: &[Option<u32>; 16]) -> () {
         if addr >= WB_RB0 && addr <= WB_RB31 {
-            self.reg_rb.set_vec(addr as usize, values);
+            self.write_register(Register::B(addr), values);
         } else if addr == WB_ACC0 {
-            self.reg_r.set_vec(0, values);
+            self.write_register(Register::Acc(0), values);
         } else if addr == WB_ACC1 {
-            self.reg_r.set_vec(1, values);
+            self.write_register(Register::Acc(1), values);
         } else if addr == WB_ACC2 {
-            self.reg_r.set_vec(2, values);
+            self.write_register(Register::Acc(2), values);
         } else if addr == WB_ACC3 {
-            self.reg_r.set_vec(3, values);
+            self.write_register(Register::Acc(3), values);
         } else if addr == WB_ACC5 {
-            for elem in 0..16 {
-                if let Some(value) = values[0] {
-                    self.reg_r.set(elem, 5, value);
-                }
+            if values[0].is_some() {
+                self.write_register(Register::Acc(5), &[values[0]; 16]);
             }
         } else if addr == WB_NOP {
             // Nop
@@ -575,10 +1324,7 @@ impl QPUEmu {
         } else if addr == WB_TMU_NOSWAP {
             // TODO: not implemented
         } else if addr == WB_TMU0_S {
-            if self.tmu0_req_fifo.len() >= 8 {
-                panic!("TMU0 request fifo is overflow.");
-            }
-            self.tmu0_req_fifo.push_back((0, unwrap_u32x16(values)));
+            self.write_tmu0_s(values);
         } else if addr == WB_TMU0_T {
             if self.tmu0_req_fifo.len() >= 8 {
                 panic!("TMU0 request fifo is overflow.");
@@ -594,6 +1340,18 @@ impl QPUEmu {
                 panic!("TMU0 request fifo is overflow.");
             }
             self.tmu0_req_fifo.push_back((3, unwrap_u32x16(values)));
+        } else if addr == WB_TLB_STENCIL_SETUP {
+            if let Some(value) = values[0] {
+                self.tile_buffer.setup_stencil(value);
+            }
+        } else if addr == WB_TLB_Z {
+            self.write_tlb_z(values);
+        } else if addr == WB_MS_FLAGS {
+            self.write_ms_flags(values);
+        } else if addr == WB_TLB_COLOUR_MS {
+            self.write_tlb_colour_ms(values);
+        } else if addr == WB_TLB_COLOUR_ALL {
+            self.write_tlb_colour(values);
         } else if addr == WB_VPM_WRITE {
             self.write_vpm(values);
         } else if addr == WB_VPMVCD_WR_SETUP {
@@ -605,14 +1363,36 @@ impl QPUEmu {
                 self.execute_vpm_dma_store(value);
             }
         } else if addr == WB_MUTEX_RELEASE {
-            // TODO: not implemented
+            self.events.push(Event::Mutex);
+            self.hook(|hooks, emu| hooks.mutex(emu, false));
         } else if addr == WB_HOST_INT {
-            // TODO: not implemented
+            self.host_interrupts += 1;
+            self.stats.host_interrupts += 1;
+            self.events.push(Event::HostInterrupt);
+            self.hook(|hooks, emu| hooks.host_interrupt(emu));
         } else {
             panic!("Invalid address.")
         }
     }
 
+    // Records the reads of a source by the lanes for the watchpoints. The regfile B is not read
+    // by the instructions with a small immediate.
+    fn watch_source(&mut self, src: u8, raddr_a: u8, raddr_b: Option<u8>, lanes: u16) {
+        if self.watchpoints.is_empty() || lanes == 0 {
+            return;
+        }
+        let reg = match src {
+            ALU_SRC_R0..=ALU_SRC_R5 => Register::Acc(src - ALU_SRC_R0),
+            ALU_SRC_RA if raddr_a < 32 => Register::A(raddr_a),
+            ALU_SRC_RB => match raddr_b {
+                Some(raddr_b) if raddr_b < 32 => Register::B(raddr_b),
+                _ => return,
+            },
+            _ => return,
+        };
+        self.watch_register(reg, Access::Read, lanes);
+    }
+
     fn mux_add_a(&mut self, elem: usize, add_a: u8, val: u32, rb_val: u32) -> u32 {
         match add_a {
             ALU_SRC_R0 => self.reg_r.get(elem, 0),
@@ -723,7 +1503,13 @@ impl QPUEmu {
         self.cf[elem] = false;
     }
 
+    fn next_uniform(&mut self) {
+        self.uniform_ptr = self.uniform_ptr.wrapping_add(4);
+        self.stats.uniforms += 1;
+    }
+
     fn execute_tmu0_load(&mut self) {
+        self.stats.tmu_loads += 1;
         let mut param_available = [false; 4];
         let mut param_value = [[0u32; 16]; 4];
 
@@ -743,21 +1529,52 @@ impl QPUEmu {
         if param_available[0] && param_available[1] && param_available[2] {
             panic!("Not implemented for Cube texture."); 
         } else if param_available[0] && param_available[1] {
-            panic!("Not implemented for 2D texture.");
+            let config = match self.tmu0_config_fifo.pop_front() {
+                Some(config) => config,
+                None => panic!("Texture config is not available."),
+            };
+            let mut config = config;
+            let (addr, size) = (config.addr as u32, config.size());
+            self.memory_access(addr, size, Access::Read, 0xffff);
+            config.addr = match self.translate(config.addr as u32, config.size()) {
+                Some(offset) => offset,
+                None => return,
+            };
+            let mut values = [None; 16];
+            for (value, (s, t)) in values.iter_mut().zip(param_value[0].iter().zip(param_value[1].iter())) {
+                *value = Some(config.sample(&self.mem, u32_to_f32(*s), u32_to_f32(*t)));
+            }
+            self.write_register(Register::Acc(4), &values);
         } else if param_available[0] {
             let addr = param_value[0];
-            for elem in 0..16 {
-                let val = self.read_mem_u32(addr[elem] as usize);
-                self.reg_r.set(elem, 4, val);
+            let mut values = [None; 16];
+            for (elem, (value, addr)) in values.iter_mut().zip(addr.iter()).enumerate() {
+                self.memory_access(*addr, 4, Access::Read, 1 << elem);
+                *value = Some(self.read_mem_u32(*addr));
             }
+            self.write_register(Register::Acc(4), &values);
         } else {
             panic!("Parameter s is required.");
         }
     }
 
+    // Loads the colour of the covered pixels in the current tile. The other elements of r4 are
+    // left undefined.
+    fn execute_tlb_colour_load(&mut self) {
+        let mut values = [None; 16];
+        for (elem, value) in values.iter_mut().enumerate() {
+            let (x, y) = self.pixel_coords[elem];
+            if self.covered_samples(elem) != 0 && self.tile_buffer.contains(x, y) {
+                *value = Some(self.tile_buffer.read_colour(x, y, 0));
+            }
+        }
+        self.write_register(Register::Acc(4), &values);
+    }
+
     fn execute_alu(&mut self, fields: &InstFormatAlu) {
         let mut add_alu_results = [None; 16];
         let mut mul_alu_results = [None; 16];
+        let (mut add_lanes, mut mul_lanes) = (0u16, 0u16);
 
         for elem in 0..16 {
             let do_add = match fields.cond_add {
@@ -788,6 +1605,7 @@ impl QPUEmu {
             let rb_val = self.read_rb(elem, fields.raddr_b);
 
             if do_add {
+                add_lanes |= 1 << elem;
                 let add_a_val = self.mux_add_a(elem, fields.add_a, ra_val, rb_val);
                 let add_b_val = self.mux_add_b(elem, fields.add_b, ra_val, rb_val);
 
@@ -801,6 +1619,7 @@ impl QPUEmu {
             }
             
             if do_mul {
+                mul_lanes |= 1 << elem;
                 let mul_a_val = self.mux_add_a(elem, fields.mul_a, ra_val, rb_val);
                 let mul_b_val = self.mux_add_b(elem, fields.mul_b, ra_val, rb_val);
 
@@ -816,6 +1635,17 @@ impl QPUEmu {
             }
         }
 
+        self.watch_source(fields.add_a, fields.raddr_a, Some(fields.raddr_b), add_lanes);
+        self.watch_source(fields.add_b, fields.raddr_a, Some(fields.raddr_b), add_lanes);
+        self.watch_source(fields.mul_a, fields.raddr_a, Some(fields.raddr_b), mul_lanes);
+        self.watch_source(fields.mul_b, fields.raddr_a, Some(fields.raddr_b), mul_lanes);
+
+        // The uniform is consumed before the results are written, so that TMU writes read
+        // their config from the following uniforms.
+        if fields.raddr_a == RA_UNIFORM_READ || fields.raddr_b == RB_UNIFORM_READ {
+            self.next_uniform();
+        }
+
         if fields.ws == 0 {
             self.write_ra(fields.waddr_add, &add_alu_results);
             self.write_rb(fields.waddr_mul, &mul_alu_results);
@@ -824,12 +1654,10 @@ impl QPUEmu {
             self.write_ra(fields.waddr_mul, &mul_alu_results);
         }
 
-        if fields.raddr_a == RA_UNIFORM_READ || fields.raddr_b == RB_UNIFORM_READ {
-            self.uniform_ptr = self.uniform_ptr + 4;
-        }
-
         if fields.sig == SIG_LDTMU0 {
             self.execute_tmu0_load();
+        } else if fields.sig == SIG_LOADC || fields.sig == SIG_LDCEND {
+            self.execute_tlb_colour_load();
         }
     }
 
@@ -865,6 +1693,7 @@ impl QPUEmu {
         let mut mul_alu_results = [None; 16];
         
         let (rb_val, rotate) = self.decode_small_imm(fields.small_immed);
+        let (mut add_lanes, mut mul_lanes) = (0u16, 0u16);
 
         for elem in 0..16 {
 
@@ -896,6 +1725,7 @@ impl QPUEmu {
             let rotated_elem = (elem + rotate) % 16;
 
             if do_add {
+                add_lanes |= 1 << elem;
                 let add_a_val = self.mux_add_a(elem, fields.add_a, ra_val, rb_val);
                 let add_b_val = self.mux_add_b(elem, fields.add_b, ra_val, rb_val);
 
@@ -909,6 +1739,7 @@ impl QPUEmu {
             }
             
             if do_mul {
+                mul_lanes |= 1 << elem;
                 let mul_a_val = self.mux_add_a(elem, fields.mul_a, ra_val, rb_val);
                 let mul_b_val = self.mux_add_b(elem, fields.mul_b, ra_val, rb_val);
 
@@ -924,6 +1755,15 @@ impl QPUEmu {
             }
         }
 
+        self.watch_source(fields.add_a, fields.raddr_a, None, add_lanes);
+        self.watch_source(fields.add_b, fields.raddr_a, None, add_lanes);
+        self.watch_source(fields.mul_a, fields.raddr_a, None, mul_lanes);
+        self.watch_source(fields.mul_b, fields.raddr_a, None, mul_lanes);
+
+        if fields.raddr_a == RA_UNIFORM_READ {
+            self.next_uniform();
+        }
+
         if fields.ws == 0 {
             self.write_ra(fields.waddr_add, &add_alu_results);
             self.write_rb(fields.waddr_mul, &mul_alu_results);
@@ -931,10 +1771,6 @@ impl QPUEmu {
             self.write_rb(fields.waddr_add, &add_alu_results);
             self.write_ra(fields.waddr_mul, &mul_alu_results);
         }
-
-        if fields.raddr_a == RA_UNIFORM_READ {
-            self.uniform_ptr = self.uniform_ptr + 4;
-        }
     }
 
     fn execute_branch(&mut self, fields: &InstFormatBranch) -> () {
@@ -957,6 +1793,7 @@ impl QPUEmu {
 
         if br {
             let br_val = if fields.reg != 0 {
+                self.watch_register(Register::A(fields.raddr_a), Access::Read, 1);
                 self.reg_ra.get(0, fields.raddr_a as usize)
             } else {
                 fields.immediate
@@ -970,7 +1807,7 @@ impl QPUEmu {
         }
 
         if fields.raddr_a == RA_UNIFORM_READ {
-            self.uniform_ptr = self.uniform_ptr + 4;
+            self.next_uniform();
         }
     }
 
@@ -1066,6 +1903,9 @@ impl QPUEmu {
     }
 
     fn execute_semaphore(&mut self, fields: &InstFormatSemaphore) {
+        let (idx, increment) = (fields.semaphore, fields.sa == 0);
+        self.events.push(Event::Semaphore);
+        self.hook(|hooks, emu| hooks.semaphore(emu, idx, increment));
     }
 
     fn execute_inst(&mut self, inst: &InstFormat) -> () {
@@ -1094,35 +1934,299 @@ impl QPUEmu {
         }
     }
 
-    pub fn execute(&mut self, insts: &Vec<u64>, uniform_ptrs: &Vec<u32>, n_threads: usize) -> () {
-        self.insts = insts.clone();
+    fn fetch_inst(&mut self, pc: usize) -> u64 {
+        match self.code_addr {
+            Some(code_addr) => {
+                let addr = (pc as u32).checked_mul(8).and_then(|offset| code_addr.checked_add(offset));
+                match addr.filter(|addr| addr.checked_add(4).is_some()) {
+                    Some(addr) => {
+                        let lo = self.read_mem_u32(addr) as u64;
+                        let hi = self.read_mem_u32(addr + 4) as u64;
+                        hi << 32 | lo
+                    },
+                    None => self.address_overflow(code_addr.wrapping_add((pc as u32).wrapping_mul(8)), 8) as u64,
+                }
+            },
+            None => self.insts[pc],
+        }
+    }
 
-        for th in 0..n_threads {
-            self.uniform_ptr = uniform_ptrs[th];
-            self.pc = 0;
+    // Executes the instruction in the first pipeline slot and fetches the next one. Returns
+    // false when the thread has ended: after it retires the delay slots of a thread end signal,
+    // on a fault, or for a program given as instructions, when the fetch runs past its end.
+    fn cycle(&mut self) -> bool {
+        if self.code_addr.is_none() && self.pc >= self.insts.len() {
+            return false;
+        }
 
-            while self.pc < self.insts.len() {
-                let (inst_pc, inst) = self.slots[0];
+        let (inst_pc, inst) = self.slots[0];
+        let decoded_inst = decode_inst(inst);
 
-                self.slots[0] = self.slots[1];
-                self.slots[1] = self.slots[2];
-                self.slots[2] = (self.pc as u32, self.insts[self.pc]);
-                
-                let decoded_inst = decode_inst(inst);
+        let thread_end = match &decoded_inst {
+            InstFormat::Alu(alu_inst) => alu_inst.sig == SIG_THREND || alu_inst.sig == SIG_LDCEND,
+            _ => false,
+        };
 
-                if let InstFormat::Alu(alu_inst) = &decoded_inst {
-                    if alu_inst.sig == SIG_BPKT {
-                        let handler = self.breakpoint_handler;
-                        handler(self, inst_pc);
-                    }
+        // Nothing is fetched after the delay slots of the thread end.
+        let next_inst = if thread_end || self.end_delay_slots.is_some() {
+            NOP_INST
+        } else {
+            self.fetch_inst(self.pc)
+        };
+        self.slots[0] = self.slots[1];
+        self.slots[1] = self.slots[2];
+        self.slots[2] = (self.pc as u32, next_inst);
+
+        self.execute_inst(&decoded_inst);
+        if self.pipeline_fill > 0 {
+            self.pipeline_fill -= 1;
+        } else {
+            self.stats.instructions += 1;
+            self.retired += 1;
+            self.hook(|hooks, emu| hooks.retire(emu, inst_pc, inst));
+        }
+        if self.fault.is_some() {
+            return false;
+        }
+
+        self.pc = self.pc + 1;
+
+        match self.end_delay_slots {
+            Some(1) => return false,
+            Some(n) => self.end_delay_slots = Some(n - 1),
+            None if thread_end => self.end_delay_slots = Some(2),
+            None => {},
+        }
+        true
+    }
+
+    // Starts the next thread and fills its pipeline. Returns false if every thread has run.
+    fn begin_thread(&mut self) -> bool {
+        let uniform_ptr = match self.threads.get(self.next_thread) {
+            Some(uniform_ptr) => *uniform_ptr,
+            None => return false,
+        };
+        self.thread = self.next_thread;
+        self.next_thread += 1;
+
+        self.uniform_ptr = uniform_ptr;
+        self.pc = 0;
+        self.slots = [(0, NOP_INST); 3];
+        self.tlb_passed = None;
+        self.tlb_ms_sample = 0;
+        self.stats = ThreadStats::default();
+        self.end_delay_slots = None;
+        self.pipeline_fill = self.slots.len();
+
+        // The instructions filling the pipeline at the start are not part of the program.
+        self.thread_running = true;
+        while self.thread_running && self.pipeline_fill > 0 {
+            self.thread_running = self.cycle();
+        }
+        true
+    }
+
+    // Runs the threads until stop returns a reason, after each instruction executed with the
+    // thread which executed it. A fault stops every thread.
+    fn run_with<F: FnMut(&QPUEmu, usize) -> Option<StopReason>>(&mut self, stop_at_breakpoints: bool, mut stop: F) -> StopReason {
+        loop {
+            if let Some(fault) = self.fault {
+                return StopReason::Fault { thread: self.thread, fault };
+            }
+            if !self.thread_running {
+                if !self.begin_thread() {
+                    return StopReason::Finished;
+                }
+                continue;
+            }
+
+            let (pc, inst) = self.slots[0];
+            let signal = match decode_inst(inst) {
+                InstFormat::Alu(alu_inst) => alu_inst.sig == SIG_BPKT,
+                _ => false,
+            };
+            let breakpoint = signal || self.breakpoint_hit(pc);
+            if !std::mem::take(&mut self.resume_breakpoint) && (breakpoint || self.single_step) {
+                self.hook(|hooks, emu| hooks.breakpoint(emu, pc));
+                if std::mem::take(&mut self.stop_requested) {
+                    self.resume_breakpoint = true;
+                    return StopReason::Stopped { thread: self.thread, pc };
+                }
+                if stop_at_breakpoints && breakpoint {
+                    self.resume_breakpoint = true;
+                    return StopReason::Breakpoint { thread: self.thread, pc };
                 }
+            }
+
+            let thread = self.thread;
+            self.events.clear();
+            self.watch_hits.clear();
+            self.thread_running = self.cycle();
+            if let Some(fault) = self.fault {
+                return StopReason::Fault { thread, fault };
+            }
+            if !self.thread_running {
+                self.events.push(Event::ThreadEnd);
+                self.ended_stats.push(self.stats);
+                self.begin_thread();
+            }
 
-                self.execute_inst(&decoded_inst);
+            if !self.watch_hits.is_empty() {
+                for hit in self.watch_hits.iter_mut() {
+                    hit.thread = thread;
+                    hit.pc = pc;
+                }
+                for hit in self.watch_hits.clone() {
+                    self.hook(|hooks, emu| hooks.watchpoint(emu, &hit));
+                }
+                if stop_at_breakpoints && !self.stop_requested {
+                    return StopReason::Watchpoint(self.watch_hits[0]);
+                }
+            }
+            if std::mem::take(&mut self.stop_requested) {
+                return StopReason::Stopped { thread, pc };
+            }
 
-                self.pc = self.pc + 1;
+            if let Some(reason) = stop(self, thread) {
+                return reason;
             }
         }
     }
+
+    // Prepares the threads of a program given as instructions, one per uniform pointer, for the
+    // run control functions. The threads are run one after another, as the QPUs 0, 1, ...
+    pub fn start(&mut self, insts: &[u64], uniform_ptrs: &[u32]) {
+        self.insts = insts.to_vec();
+        self.code_addr = None;
+        self.start_threads(uniform_ptrs);
+    }
+
+    // Prepares the threads of a program stored in memory at code_addr.
+    pub fn start_at(&mut self, code_addr: u32, uniform_ptrs: &[u32]) {
+        self.code_addr = Some(code_addr);
+        self.start_threads(uniform_ptrs);
+    }
+
+    fn start_threads(&mut self, uniform_ptrs: &[u32]) {
+        self.threads = uniform_ptrs.to_vec();
+        self.thread = 0;
+        self.next_thread = 0;
+        self.thread_running = false;
+        self.resume_breakpoint = false;
+        self.stop_requested = false;
+        self.retired = 0;
+        self.ended_stats.clear();
+        self.fault = None;
+    }
+
+    // The thread which executes the next instruction, None when every thread has ended.
+    pub fn thread(&self) -> Option<usize> {
+        if self.thread_running {
+            Some(self.thread)
+        } else {
+            None
+        }
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.threads.len()
+    }
+
+    // The number of instructions executed since the threads were started.
+    pub fn retired(&self) -> u64 {
+        self.retired
+    }
+
+    // Runs until a breakpoint, a fault or the end of every thread.
+    pub fn run(&mut self) -> StopReason {
+        self.run_with(true, |_, _| None)
+    }
+
+    pub fn step(&mut self) -> StopReason {
+        self.run_for(1)
+    }
+
+    // Executes one instruction of the thread qpu. As the threads run one after another, the
+    // threads before it are run to their end first. Returns its thread end event if it has
+    // already ended, or InvalidThread if no such thread was started.
+    pub fn step_qpu(&mut self, qpu: usize) -> StopReason {
+        if qpu >= self.threads.len() {
+            return StopReason::InvalidThread { thread: qpu };
+        }
+        if self.next_thread > qpu && self.thread() != Some(qpu) {
+            return StopReason::Event { thread: qpu, event: Event::ThreadEnd };
+        }
+        self.run_with(true, |_, thread| if thread == qpu { Some(StopReason::Stepped) } else { None })
+    }
+
+    // Executes the given number of instructions, unless it stops before.
+    pub fn run_for(&mut self, instructions: u64) -> StopReason {
+        let end = self.retired + instructions;
+        if instructions == 0 {
+            return StopReason::Stepped;
+        }
+        self.run_with(true, |emu, _| if emu.retired >= end { Some(StopReason::Stepped) } else { None })
+    }
+
+    // Runs until the instruction pc of any thread is the next one, executing at least one
+    // instruction.
+    pub fn run_until(&mut self, pc: u32) -> StopReason {
+        self.run_with(true, |emu, _| match emu.thread() {
+            Some(thread) if emu.pc() == pc => Some(StopReason::Reached { thread, pc }),
+            _ => None,
+        })
+    }
+
+    // Runs until an instruction raises one of the events.
+    pub fn run_until_event(&mut self, events: &[Event]) -> StopReason {
+        self.run_with(true, |emu, thread| {
+            emu.events.iter().find(|event| events.contains(event)).map(|event| StopReason::Event { thread, event: *event })
+        })
+    }
+
+    pub fn execute(&mut self, insts: &Vec<u64>, uniform_ptrs: &Vec<u32>, n_threads: usize) -> () {
+        self.start(insts, &uniform_ptrs[..n_threads]);
+        self.run_with(false, |_, _| None);
+    }
+
+    // Runs a program stored in memory at code_addr until its thread end.
+    pub fn execute_at(&mut self, code_addr: u32, uniform_ptr: u32) {
+        self.execute_threads_at(code_addr, &[uniform_ptr]);
+    }
+
+    // Runs a program stored in memory like execute_at, executing at most the given number of
+    // instructions. Returns Stepped if it has not ended by then.
+    pub fn execute_at_for(&mut self, code_addr: u32, uniform_ptr: u32, instructions: u64) -> StopReason {
+        self.start_at(code_addr, &[uniform_ptr]);
+        let reason = if instructions == 0 {
+            StopReason::Stepped
+        } else {
+            self.run_with(false, |emu, _| {
+                if emu.retired >= instructions && emu.thread().is_some() { Some(StopReason::Stepped) } else { None }
+            })
+        };
+        self.code_addr = None;
+        reason
+    }
+
+    // Runs the threads of a program stored in memory one after another, until the end of the
+    // last one or a fault.
+    pub fn execute_threads_at(&mut self, code_addr: u32, uniform_ptrs: &[u32]) -> StopReason {
+        self.start_at(code_addr, uniform_ptrs);
+        let reason = self.run_with(false, |_, _| None);
+        self.code_addr = None;
+        reason
+    }
+}
+
+impl Memory for QPUEmu {
+    fn read_bytes(&self, addr: u32, buf: &mut [u8]) -> Result<(), MemoryFault> {
+        QPUEmu::read_bytes(self, addr, buf)
+    }
+
+    fn write_bytes(&mut self, addr: u32, data: &[u8]) -> Result<(), MemoryFault> {
+        QPUEmu::write_bytes(self, addr, data)
+    }
 }
 
 
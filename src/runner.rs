@@ -0,0 +1,543 @@
+// Command line runner for program files.
+//
+// Usage: run PROGRAM [options]
+//   PROGRAM                   program binary, or assembly source if it ends with .asm
+//   -n, --threads N           number of threads (default: the lines of the uniforms file, or 1)
+//   -u, --uniforms LIST       comma separated uniforms of every thread
+//   -U, --uniforms-file FILE  uniforms of each thread, one line per thread. A single line is
+//                             used for every thread and '#' starts a comment
+//   -b, --buffer NAME=SIZE    allocates a zeroed buffer
+//   -l, --load NAME=FILE      allocates a buffer initialized from the file
+//   -l, --load ADDR=FILE      writes the file to the memory at ADDR
+//   -d, --dump NAME=FILE      writes the buffer to the file after the run
+//   -d, --dump ADDR:LEN=FILE  writes the memory range to the file after the run
+//   -m, --mem-size BYTES      size of the emulator memory (default 16MB)
+//   -s, --stats               prints the statistics of each thread
+//   -g, --debug               stops at the first instruction in the debugger (see src/debugger.rs)
+//   --gdb ADDR                waits for GDB on HOST:PORT, or on a Unix socket path containing
+//                             '/', before the first instruction (see src/gdbstub.rs). The
+//                             program runs to its end after GDB detaches
+//   -t, --trace FILE          writes a trace of the executed instructions (see src/trace.rs)
+//   --trace-format FORMAT     text or binary (default: text)
+//   --trace-pc START:END      traces only the instructions from START up to END, END excluded
+//   --trace-qpu LIST          traces only the comma separated QPUs
+//
+// Numbers are decimal or hexadecimal with 0x. A uniform is a number, a negative number, a
+// float containing '.', @NAME or @NAME+OFFSET for an address in a buffer, %thread for the
+// thread index, %threads for the thread count or %uniforms for the address of the thread's
+// uniforms.
+//
+// Buffers are allocated in the order of the options, then the memory is dumped after the
+// run even if it faulted.
+
+use crate::assembler;
+use crate::buffer::Buffer;
+use crate::debugger::Debugger;
+use crate::gdbstub;
+use crate::kernel::{Kernel, LaunchResult, LaunchStatus, Uniform};
+use crate::processor::{QPUEmu, StopReason};
+use crate::trace::{TraceFilter, TraceFormat, Tracer};
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::BufWriter;
+use std::sync::{Arc, Mutex};
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum UniformArg {
+    U32(u32),
+    F32(f32),
+    Buffer { name: String, offset: u32 },
+    ThreadIndex,
+    ThreadCount,
+    ThreadUniforms,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum MemoryInit {
+    Zeroed { name: String, size: usize },
+    Buffer { name: String, path: String },
+    At { addr: u32, path: String },
+    Data { name: String, data: Vec<u8> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum DumpRange {
+    Buffer(String),
+    At { addr: u32, len: usize },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Options {
+    pub program: String,
+    pub threads: Option<usize>,
+    // The uniforms of each thread, or a single list for every thread.
+    pub uniforms: Vec<Vec<UniformArg>>,
+    pub memory: Vec<MemoryInit>,
+    pub dumps: Vec<(DumpRange, String)>,
+    pub mem_size: usize,
+    pub stats: bool,
+    pub debug: bool,
+    pub gdb: Option<String>,
+    pub trace: Option<String>,
+    pub trace_format: TraceFormat,
+    pub trace_filter: TraceFilter,
+}
+
+pub fn parse_number(s: &str) -> Result<u64, String> {
+    let result = if s.starts_with("0x") || s.starts_with("0X") {
+        u64::from_str_radix(&s[2..], 16)
+    } else {
+        s.parse()
+    };
+    result.map_err(|_| format!("Invalid number '{}'.", s))
+}
+
+pub fn parse_u32(s: &str) -> Result<u32, String> {
+    let val = parse_number(s)?;
+    if val > u32::MAX as u64 {
+        return Err(format!("The number '{}' is out of range.", s));
+    }
+    Ok(val as u32)
+}
+
+pub fn parse_usize(s: &str) -> Result<usize, String> {
+    parse_number(s).map(|val| val as usize)
+}
+
+pub fn is_name(s: &str) -> bool {
+    s.chars().next().is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+pub fn parse_uniform(s: &str) -> Result<UniformArg, String> {
+    match s {
+        "%thread" => return Ok(UniformArg::ThreadIndex),
+        "%threads" => return Ok(UniformArg::ThreadCount),
+        "%uniforms" => return Ok(UniformArg::ThreadUniforms),
+        _ => {}
+    }
+
+    if let Some(arg) = s.strip_prefix('@') {
+        let (name, offset) = match arg.find('+') {
+            Some(idx) => (&arg[..idx], parse_u32(&arg[idx + 1..])?),
+            None => (arg, 0),
+        };
+        if !is_name(name) {
+            return Err(format!("Invalid buffer name '{}'.", name));
+        }
+        Ok(UniformArg::Buffer { name: name.to_string(), offset })
+    } else if s.contains('.') {
+        s.parse().map(UniformArg::F32).map_err(|_| format!("Invalid float '{}'.", s))
+    } else if let Some(val) = s.strip_prefix('-') {
+        let val = parse_number(val)?;
+        if val > i32::MAX as u64 + 1 {
+            return Err(format!("The number '{}' is out of range.", s));
+        }
+        Ok(UniformArg::U32((val as i64).wrapping_neg() as u32))
+    } else {
+        parse_u32(s).map(UniformArg::U32)
+    }
+}
+
+pub fn parse_uniform_list(s: &str) -> Result<Vec<UniformArg>, String> {
+    s.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|token| !token.is_empty())
+        .map(parse_uniform)
+        .collect()
+}
+
+// Parses one line per thread, skipping empty lines and comments.
+pub fn parse_uniforms_file(text: &str) -> Result<Vec<Vec<UniformArg>>, String> {
+    text.lines()
+        .map(|line| line.split('#').next().unwrap())
+        .filter(|line| !line.trim().is_empty())
+        .map(parse_uniform_list)
+        .collect()
+}
+
+// Splits "KEY=VALUE".
+fn split_assignment<'a>(option: &str, arg: &'a str) -> Result<(&'a str, &'a str), String> {
+    match arg.find('=') {
+        Some(idx) => Ok((&arg[..idx], &arg[idx + 1..])),
+        None => Err(format!("The argument of {} is not in the form KEY=VALUE.", option)),
+    }
+}
+
+impl Options {
+    pub fn new(program: String) -> Options {
+        Options {
+            program,
+            threads: None,
+            uniforms: vec![],
+            memory: vec![],
+            dumps: vec![],
+            mem_size: 16 << 20,
+            stats: false,
+            debug: false,
+            gdb: None,
+            trace: None,
+            trace_format: TraceFormat::Text,
+            trace_filter: TraceFilter::default(),
+        }
+    }
+
+    // Parses the arguments following the subcommand.
+    pub fn parse(args: &[String]) -> Result<Options, String> {
+        let mut options = Options::new(String::new());
+        let mut program = None;
+
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            if !arg.starts_with('-') {
+                if program.is_some() {
+                    return Err(format!("Unexpected argument '{}'.", arg));
+                }
+                program = Some(arg.clone());
+                continue;
+            }
+
+            let option = arg.as_str();
+            if option == "-s" || option == "--stats" {
+                options.stats = true;
+                continue;
+            }
+            if option == "-g" || option == "--debug" {
+                options.debug = true;
+                continue;
+            }
+
+            let value = match args.next() {
+                Some(value) => value.as_str(),
+                None => return Err(format!("{} requires an argument.", option)),
+            };
+
+            match option {
+                "-n" | "--threads" => {
+                    let threads = parse_usize(value)?;
+                    if threads == 0 {
+                        return Err("The number of threads is zero.".to_string());
+                    }
+                    options.threads = Some(threads);
+                }
+                "-u" | "--uniforms" | "-U" | "--uniforms-file" => {
+                    if !options.uniforms.is_empty() {
+                        return Err("The uniforms are given more than once.".to_string());
+                    }
+                    options.uniforms = if option == "-u" || option == "--uniforms" {
+                        vec![parse_uniform_list(value)?]
+                    } else {
+                        let text = fs::read_to_string(value).map_err(|err| format!("{}: {}", value, err))?;
+                        parse_uniforms_file(&text)?
+                    };
+                }
+                "-b" | "--buffer" => {
+                    let (name, size) = split_assignment(option, value)?;
+                    if !is_name(name) {
+                        return Err(format!("Invalid buffer name '{}'.", name));
+                    }
+                    options.memory.push(MemoryInit::Zeroed { name: name.to_string(), size: parse_usize(size)? });
+                }
+                "-l" | "--load" => {
+                    let (target, path) = split_assignment(option, value)?;
+                    let path = path.to_string();
+                    options.memory.push(if is_name(target) {
+                        MemoryInit::Buffer { name: target.to_string(), path }
+                    } else {
+                        MemoryInit::At { addr: parse_u32(target)?, path }
+                    });
+                }
+                "-d" | "--dump" => {
+                    let (range, path) = split_assignment(option, value)?;
+                    let range = if is_name(range) {
+                        DumpRange::Buffer(range.to_string())
+                    } else {
+                        match range.find(':') {
+                            Some(idx) => DumpRange::At { addr: parse_u32(&range[..idx])?, len: parse_usize(&range[idx + 1..])? },
+                            None => return Err(format!("Invalid range '{}'.", range)),
+                        }
+                    };
+                    options.dumps.push((range, path.to_string()));
+                }
+                "-m" | "--mem-size" => options.mem_size = parse_usize(value)?,
+                "--gdb" => options.gdb = Some(value.to_string()),
+                "-t" | "--trace" => options.trace = Some(value.to_string()),
+                "--trace-format" => {
+                    options.trace_format = match value {
+                        "text" => TraceFormat::Text,
+                        "binary" => TraceFormat::Binary,
+                        _ => return Err(format!("Unknown trace format '{}'.", value)),
+                    };
+                }
+                "--trace-pc" => {
+                    let (start, end) = match value.find(':') {
+                        Some(idx) => (parse_u32(&value[..idx])?, parse_u32(&value[idx + 1..])?),
+                        None => return Err(format!("Invalid range '{}'.", value)),
+                    };
+                    options.trace_filter.pcs = Some(start..end);
+                }
+                "--trace-qpu" => {
+                    let qpus: Result<Vec<usize>, String> = value.split(',').map(parse_usize).collect();
+                    options.trace_filter.qpus = Some(qpus?);
+                }
+                _ => return Err(format!("Unknown option '{}'.", option)),
+            }
+        }
+
+        match program {
+            Some(program) => options.program = program,
+            None => return Err("No program file is given.".to_string()),
+        }
+        if options.debug && options.gdb.is_some() {
+            return Err("The options --debug and --gdb are exclusive.".to_string());
+        }
+        if options.debug && options.trace.is_some() {
+            return Err("The options --debug and --trace are exclusive.".to_string());
+        }
+        Ok(options)
+    }
+
+    pub fn num_threads(&self) -> usize {
+        self.threads.unwrap_or_else(|| self.uniforms.len().max(1))
+    }
+}
+
+// The emulator after a run, with the buffers by name.
+pub struct Run {
+    pub emu: QPUEmu,
+    pub buffers: HashMap<String, Buffer>,
+    pub result: LaunchResult,
+}
+
+// Loads a program binary, or assembles the source if the path ends with .asm.
+pub fn load_program(path: &str) -> Result<Kernel, String> {
+    if path.ends_with(".asm") {
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path, err))?;
+        return assembler::assemble(&text).map(Kernel::new).map_err(|err| format!("{}: {}", path, err));
+    }
+    let program = fs::read(path).map_err(|err| format!("{}: {}", path, err))?;
+    Kernel::from_bytes(&program).map_err(|err| format!("{}: {}", path, err))
+}
+
+// Loads the program and the memory and runs the threads.
+pub fn launch(options: &Options) -> Result<Run, String> {
+    let read_file = |path: &str| fs::read(path).map_err(|err| format!("{}: {}", path, err));
+
+    let kernel = load_program(&options.program)?;
+
+    let num_threads = options.num_threads();
+    if options.uniforms.len() > 1 && options.uniforms.len() != num_threads {
+        return Err(format!("The uniforms are given for {} threads, but {} threads are run.", options.uniforms.len(), num_threads));
+    }
+
+    let mut emu = QPUEmu::new(options.mem_size);
+    let debugger = if options.debug {
+        let debugger = Arc::new(Mutex::new(Debugger::default()));
+        emu.set_hooks(Box::new(debugger.clone()));
+        emu.set_single_step(true);
+        Some(debugger)
+    } else {
+        None
+    };
+    let tracer = match &options.trace {
+        Some(path) => {
+            let file = fs::File::create(path).map_err(|err| format!("{}: {}", path, err))?;
+            let tracer = Arc::new(Mutex::new(Tracer::new(BufWriter::new(file), options.trace_format, options.trace_filter.clone())));
+            emu.set_hooks(Box::new(tracer.clone()));
+            Some((path, tracer))
+        }
+        None => None,
+    };
+    let mut buffers: HashMap<String, Buffer> = HashMap::new();
+
+    for init in &options.memory {
+        match init {
+            MemoryInit::Zeroed { name, .. } | MemoryInit::Buffer { name, .. } | MemoryInit::Data { name, .. }
+                if buffers.contains_key(name) => {
+                return Err(format!("The buffer '{}' is defined more than once.", name));
+            }
+            MemoryInit::Zeroed { name, size } => {
+                let buffer = emu.alloc(*size, 16).ok_or_else(|| format!("No memory for the buffer '{}'.", name))?;
+                buffers.insert(name.clone(), buffer);
+            }
+            MemoryInit::Buffer { name, path } => {
+                let data = read_file(path)?;
+                let buffer = emu.alloc(data.len(), 16).ok_or_else(|| format!("No memory for the buffer '{}'.", name))?;
+                buffer.write_u8(&mut emu, 0, &data).map_err(|fault| fault.to_string())?;
+                buffers.insert(name.clone(), buffer);
+            }
+            MemoryInit::Data { name, data } => {
+                let buffer = emu.alloc(data.len(), 16).ok_or_else(|| format!("No memory for the buffer '{}'.", name))?;
+                buffer.write_u8(&mut emu, 0, data).map_err(|fault| fault.to_string())?;
+                buffers.insert(name.clone(), buffer);
+            }
+            MemoryInit::At { addr, path } => {
+                let data = read_file(path)?;
+                let buffer = emu.alloc_at(*addr, data.len()).ok_or_else(|| format!("The memory at 0x{:08x} is not free.", addr))?;
+                buffer.write_u8(&mut emu, 0, &data).map_err(|fault| fault.to_string())?;
+            }
+        }
+    }
+
+    let mut threads = vec![];
+    for thread in 0..num_threads {
+        let args = options.uniforms.get(if options.uniforms.len() > 1 { thread } else { 0 });
+        let mut uniforms = vec![];
+        for arg in args.into_iter().flatten() {
+            uniforms.push(match arg {
+                UniformArg::U32(val) => Uniform::U32(*val),
+                UniformArg::F32(val) => Uniform::F32(*val),
+                UniformArg::Buffer { name, offset } => match buffers.get(name) {
+                    Some(buffer) if *offset == 0 => Uniform::Buffer(*buffer),
+                    Some(buffer) => Uniform::U32(buffer.addr() + offset),
+                    None => return Err(format!("Unknown buffer '{}'.", name)),
+                },
+                UniformArg::ThreadIndex => Uniform::U32(thread as u32),
+                UniformArg::ThreadCount => Uniform::U32(num_threads as u32),
+                UniformArg::ThreadUniforms => Uniform::ThreadUniforms,
+            });
+        }
+        threads.push(uniforms);
+    }
+
+    let result = match &options.gdb {
+        Some(addr) => debug_remote(&mut emu, &kernel, threads, addr)?,
+        None => kernel.launch().threads(threads).run(&mut emu),
+    };
+    if let Some((path, tracer)) = tracer {
+        emu.take_hooks();
+        tracer.lock().unwrap().flush().map_err(|err| format!("{}: {}", path, err))?;
+    }
+    if let Some(err) = debugger.and_then(|debugger| debugger.lock().unwrap().error.take()) {
+        return Err(format!("debugger: {}", err));
+    }
+    Ok(Run { emu, buffers, result })
+}
+
+// Serves GDB, then runs the threads to their end.
+fn debug_remote(emu: &mut QPUEmu, kernel: &Kernel, threads: Vec<Vec<Uniform>>, addr: &str) -> Result<LaunchResult, String> {
+    let (code_buf, uniforms_buf) = kernel.launch().threads(threads).start(emu)
+        .ok_or_else(|| "No memory for the program and the uniforms.".to_string())?;
+    eprintln!("waiting for gdb on {}", addr);
+    gdbstub::listen(emu, addr).map_err(|err| format!("{}: {}", addr, err))?;
+
+    let reason = loop {
+        match emu.run() {
+            reason @ StopReason::Finished | reason @ StopReason::Fault { .. } => break reason,
+            _ => {}
+        }
+    };
+    let result = LaunchResult::from_stop(emu, reason);
+    emu.free(uniforms_buf);
+    emu.free(code_buf);
+    Ok(result)
+}
+
+// Runs the threads and dumps the memory.
+pub fn run(options: &Options) -> Result<LaunchResult, String> {
+    let Run { emu, buffers, result } = launch(options)?;
+
+    for (range, path) in &options.dumps {
+        let (addr, len) = match range {
+            DumpRange::Buffer(name) => match buffers.get(name) {
+                Some(buffer) => (buffer.addr(), buffer.size()),
+                None => return Err(format!("Unknown buffer '{}'.", name)),
+            },
+            DumpRange::At { addr, len } => (*addr, *len),
+        };
+
+        let mut data = vec![0u8; len];
+        emu.read_bytes(addr, &mut data).map_err(|fault| format!("Cannot dump to {}: {}", path, fault))?;
+        fs::write(path, &data).map_err(|err| format!("{}: {}", path, err))?;
+    }
+
+    Ok(result)
+}
+
+// Runs the subcommand and returns the exit status: 0 on completion or when the user quits the
+// debugger, 1 on a fault and 2 on an error in the arguments, the files or the debugger's input
+// and output.
+pub fn main(args: &[String]) -> i32 {
+    let options = match Options::parse(args) {
+        Ok(options) => options,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 2;
+        }
+    };
+
+    let result = match run(&options) {
+        Ok(result) => result,
+        Err(err) => {
+            eprintln!("{}", err);
+            return 2;
+        }
+    };
+
+    if options.stats {
+        for (thread, stats) in result.threads.iter().enumerate() {
+            println!("thread {}: {} instructions, {} uniforms, {} TMU loads, {} VPM DMA loads, {} VPM DMA stores, {} host interrupts",
+                     thread, stats.instructions, stats.uniforms, stats.tmu_loads,
+                     stats.vpm_dma_loads, stats.vpm_dma_stores, stats.host_interrupts);
+        }
+    }
+
+    match result.status {
+        LaunchStatus::Completed => 0,
+        LaunchStatus::Fault { thread, fault } => {
+            eprintln!("thread {}: {}", thread, fault);
+            1
+        }
+        LaunchStatus::OutOfMemory => {
+            eprintln!("No memory for the program and the uniforms.");
+            2
+        }
+        LaunchStatus::Stopped { thread, pc } => {
+            eprintln!("thread {}: stopped at 0x{:08x}", thread, pc);
+            0
+        }
+    }
+}
+
+#[test]
+fn test_runner_parse() {
+    let args: Vec<String> = ["prog.bin", "-n", "4", "-u", "1,-2, 0x10,1.5,@out,%thread,%uniforms",
+                             "-b", "out=0x100", "-l", "0x2000=in.bin", "-d", "out=out.bin", "-d", "0x2000:16=in_out.bin", "-s", "-g"]
+        .iter().map(|s| s.to_string()).collect();
+    let options = Options::parse(&args).unwrap();
+
+    assert_eq!(options.program, "prog.bin");
+    assert_eq!(options.num_threads(), 4);
+    assert_eq!(options.uniforms, vec![vec![
+        UniformArg::U32(1), UniformArg::U32(-2i32 as u32), UniformArg::U32(0x10), UniformArg::F32(1.5),
+        UniformArg::Buffer { name: "out".to_string(), offset: 0 }, UniformArg::ThreadIndex, UniformArg::ThreadUniforms,
+    ]]);
+    assert_eq!(options.memory, vec![
+        MemoryInit::Zeroed { name: "out".to_string(), size: 0x100 },
+        MemoryInit::At { addr: 0x2000, path: "in.bin".to_string() },
+    ]);
+    assert_eq!(options.dumps[1], (DumpRange::At { addr: 0x2000, len: 16 }, "in_out.bin".to_string()));
+    assert!(options.stats);
+    assert!(options.debug);
+
+    let args: Vec<String> = ["prog.bin", "--gdb", "localhost:1234"].iter().map(|arg| arg.to_string()).collect();
+    assert_eq!(Options::parse(&args).unwrap().gdb, Some("localhost:1234".to_string()));
+    let args: Vec<String> = ["prog.bin", "--gdb", "/tmp/gdb.sock", "-g"].iter().map(|arg| arg.to_string()).collect();
+    assert_eq!(Options::parse(&args), Err("The options --debug and --gdb are exclusive.".to_string()));
+    let args: Vec<String> = ["prog.bin", "-t", "out.trace", "--trace-format", "binary", "--trace-pc", "0x100:0x180", "--trace-qpu", "0,2"]
+        .iter().map(|arg| arg.to_string()).collect();
+    let options = Options::parse(&args).unwrap();
+    assert_eq!(options.trace, Some("out.trace".to_string()));
+    assert_eq!(options.trace_format, TraceFormat::Binary);
+    assert_eq!(options.trace_filter, TraceFilter { pcs: Some(0x100..0x180), qpus: Some(vec![0, 2]) });
+
+    let uniforms = parse_uniforms_file("# a b\n1 2\n\n%threads, 3.0 # c\n").unwrap();
+    assert_eq!(uniforms, vec![vec![UniformArg::U32(1), UniformArg::U32(2)],
+                              vec![UniformArg::ThreadCount, UniformArg::F32(3.0)]]);
+
+    assert!(Options::parse(&["-n".to_string()]).is_err());
+    assert!(Options::parse(&["-s".to_string()]).is_err());
+    assert!(parse_uniform("0x100000000").is_err());
+    assert!(parse_uniform("@1").is_err());
+    assert_eq!(parse_uniform("@a+0x10"), Ok(UniformArg::Buffer { name: "a".to_string(), offset: 0x10 }));
+}
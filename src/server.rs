@@ -0,0 +1,459 @@
+// Job server which runs programs submitted over a Unix domain socket on a persistent emulator.
+//
+// Every message is a header of two little endian 32bit words, the type and the payload size,
+// followed by the payload. All values in payloads are little endian 32bit words unless noted.
+//
+// Requests:
+//   REQ_ALLOC  size, align                    -> RESP_OK addr
+//   REQ_FREE   addr                           -> RESP_OK
+//   REQ_WRITE  addr, bytes...                 -> RESP_OK
+//   REQ_READ   addr, len                      -> RESP_OK bytes...
+//   REQ_JOB    num_insts, insts (64bit)...,
+//              num_threads, num_uniforms, uniforms...,
+//              num_uploads, (addr, len, bytes padded to 4)...,
+//              num_downloads, (addr, len)...  -> RESP_INTERRUPT and RESP_THREAD per thread,
+//                                                then RESP_JOB_DONE
+// Responses:
+//   RESP_OK         request specific payload
+//   RESP_ERROR      UTF-8 message
+//   RESP_INTERRUPT  thread, number of host interrupts raised by the thread
+//   RESP_THREAD     thread, instructions, uniforms, tmu_loads, vpm_dma_loads, vpm_dma_stores,
+//                   host_interrupts (64bit each)
+//   RESP_JOB_DONE   status (0 completed, 1 fault), fault addr,
+//                   num_downloads, (addr, len, bytes padded to 4)...
+//
+// A job runs at most 65536 threads. Connections are served concurrently and each request holds
+// the emulator while it runs.
+
+use crate::kernel::{Kernel, LaunchStatus, Uniform};
+use crate::processor::QPUEmu;
+
+use std::io::{self, Read, Write};
+use std::os::unix::fs::FileTypeExt;
+use std::os::unix::net::UnixListener;
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+pub const REQ_ALLOC: u32 = 1;
+pub const REQ_FREE: u32 = 2;
+pub const REQ_WRITE: u32 = 3;
+pub const REQ_READ: u32 = 4;
+pub const REQ_JOB: u32 = 5;
+
+pub const RESP_OK: u32 = 0x100;
+pub const RESP_ERROR: u32 = 0x101;
+pub const RESP_INTERRUPT: u32 = 0x102;
+pub const RESP_THREAD: u32 = 0x103;
+pub const RESP_JOB_DONE: u32 = 0x104;
+
+pub const JOB_COMPLETED: u32 = 0;
+pub const JOB_FAULT: u32 = 1;
+
+// Limit of a message payload, to reject corrupted headers.
+const MAX_PAYLOAD: usize = 256 << 20;
+// Limit of the threads of a job, which may have no uniforms.
+const MAX_THREADS: usize = 1 << 16;
+
+pub fn read_message<R: Read>(stream: &mut R) -> io::Result<(u32, Vec<u8>)> {
+    let mut header = [0u8; 8];
+    stream.read_exact(&mut header)?;
+    let kind = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if len > MAX_PAYLOAD {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "The payload is too large."));
+    }
+
+    let mut payload = vec![0u8; len];
+    stream.read_exact(&mut payload)?;
+    Ok((kind, payload))
+}
+
+pub fn write_message<W: Write>(stream: &mut W, kind: u32, payload: &[u8]) -> io::Result<()> {
+    let mut message = Vec::with_capacity(8 + payload.len());
+    message.extend_from_slice(&kind.to_le_bytes());
+    message.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    message.extend_from_slice(payload);
+    stream.write_all(&message)?;
+    stream.flush()
+}
+
+// Builds a payload.
+#[derive(Default)]
+pub struct PayloadWriter {
+    bytes: Vec<u8>,
+}
+
+impl PayloadWriter {
+    pub fn new() -> Self {
+        PayloadWriter { bytes: vec![] }
+    }
+
+    pub fn u32(&mut self, val: u32) -> &mut Self {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    pub fn u64(&mut self, val: u64) -> &mut Self {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+        self
+    }
+
+    // Appends the bytes padded to a multiple of 4 bytes.
+    pub fn bytes(&mut self, data: &[u8]) -> &mut Self {
+        self.bytes.extend_from_slice(data);
+        self.bytes.resize(self.bytes.len().next_multiple_of(4), 0);
+        self
+    }
+
+    pub fn finish(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.bytes)
+    }
+}
+
+// Parses a payload. Every accessor fails if the payload is too short.
+pub struct PayloadReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PayloadReader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Self {
+        PayloadReader { bytes, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.bytes.len() - self.pos < len {
+            return Err("The payload is truncated.".to_string());
+        }
+        let data = &self.bytes[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(data)
+    }
+
+    pub fn u32(&mut self) -> Result<u32, String> {
+        let data = self.take(4)?;
+        Ok(u32::from_le_bytes([data[0], data[1], data[2], data[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64, String> {
+        let lo = self.u32()? as u64;
+        let hi = self.u32()? as u64;
+        Ok(hi << 32 | lo)
+    }
+
+    // Reads len bytes and skips the padding to a multiple of 4 bytes.
+    pub fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        let data = self.take(len)?;
+        self.take(len.next_multiple_of(4) - len)?;
+        Ok(data)
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.bytes.len() - self.pos
+    }
+
+    pub fn rest(&mut self) -> &'a [u8] {
+        let data = &self.bytes[self.pos..];
+        self.pos = self.bytes.len();
+        data
+    }
+}
+
+struct Job<'a> {
+    insts: Vec<u64>,
+    threads: Vec<Vec<Uniform>>,
+    uploads: Vec<(u32, &'a [u8])>,
+    downloads: Vec<(u32, usize)>,
+}
+
+impl<'a> Job<'a> {
+    fn parse(payload: &'a [u8]) -> Result<Self, String> {
+        let mut reader = PayloadReader::new(payload);
+
+        // The counts are checked against the payload before anything is allocated.
+        let num_insts = reader.u32()? as usize;
+        if num_insts > reader.remaining() / 8 {
+            return Err("The payload is truncated.".to_string());
+        }
+        let insts = (0..num_insts).map(|_| reader.u64()).collect::<Result<Vec<u64>, String>>()?;
+
+        let num_threads = reader.u32()? as usize;
+        let num_uniforms = reader.u32()? as usize;
+        if num_threads > MAX_THREADS {
+            return Err("Too many threads.".to_string());
+        }
+        if num_threads as u64 * num_uniforms as u64 > (reader.remaining() / 4) as u64 {
+            return Err("The payload is truncated.".to_string());
+        }
+        let mut threads = vec![];
+        for _ in 0..num_threads {
+            let uniforms = (0..num_uniforms).map(|_| reader.u32().map(Uniform::U32)).collect::<Result<Vec<Uniform>, String>>()?;
+            threads.push(uniforms);
+        }
+
+        let mut uploads = vec![];
+        for _ in 0..reader.u32()? {
+            let addr = reader.u32()?;
+            let len = reader.u32()? as usize;
+            uploads.push((addr, reader.bytes(len)?));
+        }
+
+        let mut downloads = vec![];
+        for _ in 0..reader.u32()? {
+            downloads.push((reader.u32()?, reader.u32()? as usize));
+        }
+        Ok(Job { insts, threads, uploads, downloads })
+    }
+}
+
+pub struct Server {
+    emu: Mutex<QPUEmu>,
+}
+
+impl Server {
+    pub fn new(mem_size: usize) -> Self {
+        Server { emu: Mutex::new(QPUEmu::new(mem_size)) }
+    }
+
+    // Serves the requests of a connection until it is closed.
+    pub fn handle_connection<S: Read + Write>(&self, stream: &mut S) -> io::Result<()> {
+        loop {
+            let (kind, payload) = match read_message(stream) {
+                Ok(message) => message,
+                Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+                Err(err) => return Err(err),
+            };
+
+            // A job which panicked leaves the emulator usable.
+            let mut emu = self.emu.lock().unwrap_or_else(|err| err.into_inner());
+            let result = match kind {
+                REQ_ALLOC => Self::alloc(&mut emu, &payload),
+                REQ_FREE => Self::free(&mut emu, &payload),
+                REQ_WRITE => Self::write(&mut emu, &payload),
+                REQ_READ => Self::read(&emu, &payload),
+                REQ_JOB => Self::run_job(&mut emu, &payload, stream)?,
+                _ => Err(format!("Unknown request {}.", kind)),
+            };
+
+            match result {
+                Ok(Some(payload)) => write_message(stream, RESP_OK, &payload)?,
+                Ok(None) => {},
+                Err(message) => write_message(stream, RESP_ERROR, message.as_bytes())?,
+            }
+        }
+    }
+
+    fn alloc(emu: &mut QPUEmu, payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let mut reader = PayloadReader::new(payload);
+        let size = reader.u32()? as usize;
+        let align = reader.u32()? as usize;
+        if !align.is_power_of_two() {
+            return Err("The alignment is not a power of two.".to_string());
+        }
+
+        match emu.alloc(size, align) {
+            Some(buffer) => Ok(Some(PayloadWriter::new().u32(buffer.addr()).finish())),
+            None => Err("Out of memory.".to_string()),
+        }
+    }
+
+    fn free(emu: &mut QPUEmu, payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let addr = PayloadReader::new(payload).u32()?;
+        match emu.buffer_at(addr) {
+            Some(buffer) => {
+                emu.free(buffer);
+                Ok(Some(vec![]))
+            },
+            None => Err(format!("No buffer at 0x{:08x}.", addr)),
+        }
+    }
+
+    fn write(emu: &mut QPUEmu, payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let mut reader = PayloadReader::new(payload);
+        let addr = reader.u32()?;
+        emu.write_bytes(addr, reader.rest()).map_err(|fault| fault.to_string())?;
+        Ok(Some(vec![]))
+    }
+
+    fn read(emu: &QPUEmu, payload: &[u8]) -> Result<Option<Vec<u8>>, String> {
+        let mut reader = PayloadReader::new(payload);
+        let addr = reader.u32()?;
+        let len = reader.u32()? as usize;
+        if len > MAX_PAYLOAD {
+            return Err("The length is too large.".to_string());
+        }
+
+        let mut data = vec![0u8; len];
+        emu.read_bytes(addr, &mut data).map_err(|fault| fault.to_string())?;
+        Ok(Some(data))
+    }
+
+    // Streams the thread results while the job runs. Returns Ok(None) once the job is done.
+    fn run_job<W: Write>(emu: &mut QPUEmu, payload: &[u8], stream: &mut W) -> io::Result<Result<Option<Vec<u8>>, String>> {
+        let Job { insts, threads, uploads, downloads } = match Job::parse(payload) {
+            Ok(job) => job,
+            Err(message) => return Ok(Err(message)),
+        };
+        if insts.is_empty() {
+            return Ok(Err("The program is empty.".to_string()));
+        }
+        for (addr, data) in uploads {
+            if let Err(fault) = emu.write_bytes(addr, data) {
+                return Ok(Err(fault.to_string()));
+            }
+        }
+
+        let kernel = Kernel::new(insts);
+        let mut status = (JOB_COMPLETED, 0);
+        for (thread, uniforms) in threads.into_iter().enumerate() {
+            let launch = kernel.launch().thread(uniforms);
+            let result = match panic::catch_unwind(AssertUnwindSafe(|| launch.run(emu))) {
+                Ok(result) => result,
+                Err(_) => return Ok(Err(format!("The emulator panicked in thread {}.", thread))),
+            };
+            if result.status == LaunchStatus::OutOfMemory {
+                return Ok(Err("No memory for the program and the uniforms.".to_string()));
+            }
+            let stats = result.threads[0];
+
+            if stats.host_interrupts > 0 {
+                let payload = PayloadWriter::new().u32(thread as u32).u32(stats.host_interrupts as u32).finish();
+                write_message(stream, RESP_INTERRUPT, &payload)?;
+            }
+            let payload = PayloadWriter::new()
+                .u32(thread as u32)
+                .u64(stats.instructions)
+                .u64(stats.uniforms)
+                .u64(stats.tmu_loads)
+                .u64(stats.vpm_dma_loads)
+                .u64(stats.vpm_dma_stores)
+                .u64(stats.host_interrupts)
+                .finish();
+            write_message(stream, RESP_THREAD, &payload)?;
+
+            if let Some(fault) = emu.fault() {
+                status = (JOB_FAULT, fault.addr);
+                break;
+            }
+        }
+
+        let mut writer = PayloadWriter::new();
+        writer.u32(status.0).u32(status.1).u32(downloads.len() as u32);
+        for (addr, len) in downloads {
+            let mut data = vec![0u8; len.min(MAX_PAYLOAD)];
+            // An unmapped range is returned as zeros.
+            let _ = emu.read_bytes(addr, &mut data);
+            writer.u32(addr).u32(data.len() as u32).bytes(&data);
+        }
+        write_message(stream, RESP_JOB_DONE, &writer.finish())?;
+        Ok(Ok(None))
+    }
+}
+
+// Removes a socket file left at the path by a previous server. Any other file is kept and
+// returned as an error.
+pub fn remove_stale_socket(path: &Path) -> io::Result<()> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(path),
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AlreadyExists, "The path exists and is not a socket.")),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+// Listens on the socket path until the process is killed. A stale socket file is replaced.
+pub fn serve<P: AsRef<Path>>(path: P, mem_size: usize) -> io::Result<()> {
+    let path = path.as_ref();
+    remove_stale_socket(path)?;
+    let listener = UnixListener::bind(path)?;
+    let server = Arc::new(Server::new(mem_size));
+
+    for stream in listener.incoming() {
+        let mut stream = stream?;
+        let server = Arc::clone(&server);
+        thread::spawn(move || {
+            if let Err(err) = server.handle_connection(&mut stream) {
+                eprintln!("connection error: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+#[test]
+fn test_server_requests() {
+    use crate::constants::*;
+    use std::os::unix::net::UnixStream;
+
+    let (mut client, mut server_end) = UnixStream::pair().unwrap();
+    let server = Arc::new(Server::new(0x1000));
+    let handle = {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.handle_connection(&mut server_end).unwrap())
+    };
+
+    write_message(&mut client, REQ_ALLOC, &PayloadWriter::new().u32(16).u32(16).finish()).unwrap();
+    let (kind, payload) = read_message(&mut client).unwrap();
+    assert_eq!(kind, RESP_OK);
+    let addr = PayloadReader::new(&payload).u32().unwrap();
+
+    let payload = PayloadWriter::new().u32(addr | 0xc000_0000).bytes(&[1, 2, 3]).finish();
+    write_message(&mut client, REQ_WRITE, &payload).unwrap();
+    assert_eq!(read_message(&mut client).unwrap(), (RESP_OK, vec![]));
+
+    write_message(&mut client, REQ_READ, &PayloadWriter::new().u32(addr).u32(4).finish()).unwrap();
+    assert_eq!(read_message(&mut client).unwrap(), (RESP_OK, vec![1, 2, 3, 0]));
+
+    write_message(&mut client, REQ_READ, &PayloadWriter::new().u32(0xffe).u32(4).finish()).unwrap();
+    assert_eq!(read_message(&mut client).unwrap().0, RESP_ERROR);
+
+    write_message(&mut client, REQ_FREE, &PayloadWriter::new().u32(addr).finish()).unwrap();
+    assert_eq!(read_message(&mut client).unwrap(), (RESP_OK, vec![]));
+    write_message(&mut client, REQ_FREE, &PayloadWriter::new().u32(addr).finish()).unwrap();
+    assert_eq!(read_message(&mut client).unwrap().0, RESP_ERROR);
+
+    write_message(&mut client, 99, &[]).unwrap();
+    assert_eq!(read_message(&mut client).unwrap().0, RESP_ERROR);
+
+    // The counts of a job are checked before the threads are allocated.
+    for (num_insts, num_threads, num_uniforms) in [(0xffff_ffff, 0, 0), (0, 0xffff_ffff, 0), (0, 0x1000, 0x1000)].iter() {
+        let payload = PayloadWriter::new().u32(*num_insts).u32(*num_threads).u32(*num_uniforms).u32(0).u32(0).finish();
+        write_message(&mut client, REQ_JOB, &payload).unwrap();
+        assert_eq!(read_message(&mut client).unwrap().0, RESP_ERROR);
+    }
+
+    // A job which panics frees the program and the uniforms. Reading the VPM before setting up
+    // the read is not implemented.
+    let inst = (SIG_NOP as u64) << 60 | (COND_ALWAYS as u64) << 49 | (WA_ACC0 as u64) << 38 | (WB_NOP as u64) << 32
+        | (ADDOP_OR as u64) << 24 | (RA_VPM_READ as u64) << 18 | (RB_NOP as u64) << 12 | (ALU_SRC_RA as u64) << 9 | (ALU_SRC_RA as u64) << 6;
+    let payload = PayloadWriter::new().u32(1).u64(inst).u32(1).u32(0).u32(0).u32(0).finish();
+    write_message(&mut client, REQ_JOB, &payload).unwrap();
+    assert_eq!(read_message(&mut client).unwrap().0, RESP_ERROR);
+    write_message(&mut client, REQ_ALLOC, &PayloadWriter::new().u32(0x1000).u32(16).finish()).unwrap();
+    assert_eq!(read_message(&mut client).unwrap().0, RESP_OK);
+
+    drop(client);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_server_stale_socket() {
+    let dir = std::env::temp_dir().join(format!("videocoreiv-sim-server-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+
+    // A file which is not a socket is never removed.
+    let file = dir.join("data.bin");
+    std::fs::write(&file, [1, 2, 3]).unwrap();
+    assert!(serve(&file, 0x1000).is_err());
+    assert_eq!(std::fs::read(&file).unwrap(), vec![1, 2, 3]);
+
+    let socket = dir.join("server.sock");
+    drop(UnixListener::bind(&socket).unwrap());
+    remove_stale_socket(&socket).unwrap();
+    assert!(!socket.exists());
+    remove_stale_socket(&socket).unwrap();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
@@ -0,0 +1,404 @@
+// Test cases for programs, declared in a line based text format.
+//
+//   # comment
+//   program PATH                                  program binary or .asm source, relative to the spec file
+//   threads N                                     number of threads (default: as the runner)
+//   mem_size BYTES
+//   buffer NAME TYPE[COUNT]                       zeroed
+//   buffer NAME TYPE[COUNT] = VALUES...           inline values, the rest is zeroed
+//   buffer NAME TYPE[COUNT] random SEED [MIN MAX] uniformly distributed values
+//   buffer NAME TYPE[COUNT] file PATH
+//   uniforms VALUES...                            one line per thread, or one for every thread
+//   expect NAME TYPE = VALUES... [tolerance T]    the first elements of the buffer
+//   expect NAME TYPE file PATH [tolerance T]
+//   expect NAME TYPE initial OTHER [tolerance T]  the initial contents of another buffer
+//   expect fault                                  the run faults instead of completing
+//
+// TYPE is u32, i32, f32, u16 or u8. The count may be omitted for inline values and files.
+// Uniforms are written as for the runner, e.g. @NAME for the address of a buffer. The
+// tolerance is the largest absolute difference allowed between elements.
+
+use crate::kernel::LaunchStatus;
+use crate::runner::{self, MemoryInit, Options};
+
+use rand::distributions::{Distribution, Uniform};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ElemType {
+    U32,
+    I32,
+    F32,
+    U16,
+    U8,
+}
+
+impl ElemType {
+    fn parse(s: &str) -> Result<ElemType, String> {
+        match s {
+            "u32" => Ok(ElemType::U32),
+            "i32" => Ok(ElemType::I32),
+            "f32" => Ok(ElemType::F32),
+            "u16" => Ok(ElemType::U16),
+            "u8" => Ok(ElemType::U8),
+            _ => Err(format!("Unknown type '{}'.", s)),
+        }
+    }
+
+    pub fn size(self) -> usize {
+        match self {
+            ElemType::U32 | ElemType::I32 | ElemType::F32 => 4,
+            ElemType::U16 => 2,
+            ElemType::U8 => 1,
+        }
+    }
+
+    fn range(self) -> (f64, f64) {
+        match self {
+            ElemType::U32 => (0.0, u32::MAX as f64),
+            ElemType::I32 => (i32::MIN as f64, i32::MAX as f64),
+            ElemType::F32 => (0.0, 1.0),
+            ElemType::U16 => (0.0, u16::MAX as f64),
+            ElemType::U8 => (0.0, u8::MAX as f64),
+        }
+    }
+
+    // Converts the value to the little endian bytes of an element, checking its range.
+    fn encode(self, val: f64, bytes: &mut Vec<u8>) -> Result<(), String> {
+        let (min, max) = self.range();
+        if self != ElemType::F32 && (val.fract() != 0.0 || val < min || val > max) {
+            return Err(format!("The value {} is out of range.", val));
+        }
+        match self {
+            ElemType::U32 => bytes.extend_from_slice(&(val as u32).to_le_bytes()),
+            ElemType::I32 => bytes.extend_from_slice(&(val as i32).to_le_bytes()),
+            ElemType::F32 => bytes.extend_from_slice(&(val as f32).to_le_bytes()),
+            ElemType::U16 => bytes.extend_from_slice(&(val as u16).to_le_bytes()),
+            ElemType::U8 => bytes.push(val as u8),
+        }
+        Ok(())
+    }
+
+    fn decode(self, bytes: &[u8]) -> f64 {
+        match self {
+            ElemType::U32 => u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            ElemType::I32 => i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            ElemType::F32 => f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) as f64,
+            ElemType::U16 => u16::from_le_bytes([bytes[0], bytes[1]]) as f64,
+            ElemType::U8 => bytes[0] as f64,
+        }
+    }
+
+    fn decode_all(self, bytes: &[u8]) -> Vec<f64> {
+        bytes.chunks_exact(self.size()).map(|chunk| self.decode(chunk)).collect()
+    }
+}
+
+fn parse_value(s: &str) -> Result<f64, String> {
+    if s.contains('.') || s.contains("inf") || s.contains("nan") {
+        s.parse().map_err(|_| format!("Invalid value '{}'.", s))
+    } else if let Some(val) = s.strip_prefix('-') {
+        runner::parse_number(val).map(|val| -(val as f64))
+    } else {
+        runner::parse_number(s).map(|val| val as f64)
+    }
+}
+
+// Splits "TYPE[COUNT]" or "TYPE".
+fn parse_typed_count(s: &str) -> Result<(ElemType, Option<usize>), String> {
+    match s.find('[') {
+        Some(idx) if s.ends_with(']') => Ok((ElemType::parse(&s[..idx])?, Some(runner::parse_usize(&s[idx + 1..s.len() - 1])?))),
+        Some(_) => Err(format!("Invalid type '{}'.", s)),
+        None => Ok((ElemType::parse(s)?, None)),
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expected {
+    Values(Vec<f64>),
+    File(PathBuf),
+    Initial(String),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Expect {
+    pub name: String,
+    pub elem: ElemType,
+    pub expected: Expected,
+    pub tolerance: f64,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Spec {
+    pub options: Options,
+    pub expects: Vec<Expect>,
+    pub expect_fault: bool,
+}
+
+impl Spec {
+    pub fn load(path: &Path) -> Result<Spec, String> {
+        let text = fs::read_to_string(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        Spec::parse(&text, path.parent().unwrap_or_else(|| Path::new(".")))
+    }
+
+    // Parses the spec. Paths in it are relative to base_dir.
+    pub fn parse(text: &str, base_dir: &Path) -> Result<Spec, String> {
+        let mut spec = Spec {
+            options: Options::new(String::new()),
+            expects: vec![],
+            expect_fault: false,
+        };
+        let mut program = None;
+
+        for (idx, line) in text.lines().enumerate() {
+            let tokens: Vec<&str> = line.split('#').next().unwrap().split_whitespace().collect();
+            if tokens.is_empty() {
+                continue;
+            }
+            spec.parse_line(&tokens, base_dir, &mut program).map_err(|err| format!("line {}: {}", idx + 1, err))?;
+        }
+
+        match program {
+            Some(program) => spec.options.program = program,
+            None => return Err("No program is given.".to_string()),
+        }
+        Ok(spec)
+    }
+
+    fn parse_line(&mut self, tokens: &[&str], base_dir: &Path, program: &mut Option<String>) -> Result<(), String> {
+        let path = |s: &str| base_dir.join(s).to_str().unwrap().to_string();
+
+        match (tokens[0], tokens.len()) {
+            ("program", 2) => *program = Some(path(tokens[1])),
+            ("threads", 2) => self.options.threads = Some(runner::parse_usize(tokens[1])?),
+            ("mem_size", 2) => self.options.mem_size = runner::parse_usize(tokens[1])?,
+            ("uniforms", _) => self.options.uniforms.push(runner::parse_uniform_list(&tokens[1..].join(" "))?),
+            ("buffer", n) if n >= 3 => {
+                let name = tokens[1];
+                if !runner::is_name(name) {
+                    return Err(format!("Invalid buffer name '{}'.", name));
+                }
+                let (elem, count) = parse_typed_count(tokens[2])?;
+                let data = Spec::parse_contents(elem, count, &tokens[3..], &path)?;
+                self.options.memory.push(MemoryInit::Data { name: name.to_string(), data });
+            }
+            ("expect", 2) if tokens[1] == "fault" => self.expect_fault = true,
+            ("expect", n) if n >= 4 => {
+                let mut args = &tokens[3..];
+                let mut tolerance = 0.0;
+                if args.len() >= 2 && args[args.len() - 2] == "tolerance" {
+                    tolerance = parse_value(args[args.len() - 1])?;
+                    args = &args[..args.len() - 2];
+                }
+
+                let expected = match (tokens[3], args.len()) {
+                    ("=", _) => Expected::Values(args[1..].iter().map(|s| parse_value(s)).collect::<Result<_, _>>()?),
+                    ("file", 2) => Expected::File(PathBuf::from(path(args[1]))),
+                    ("initial", 2) => Expected::Initial(args[1].to_string()),
+                    _ => return Err("Invalid expectation.".to_string()),
+                };
+                self.expects.push(Expect {
+                    name: tokens[1].to_string(),
+                    elem: ElemType::parse(tokens[2])?,
+                    expected,
+                    tolerance,
+                });
+            }
+            _ => return Err(format!("Invalid statement '{}'.", tokens[0])),
+        }
+        Ok(())
+    }
+
+    fn parse_contents(elem: ElemType, count: Option<usize>, args: &[&str], path: &dyn Fn(&str) -> String) -> Result<Vec<u8>, String> {
+        let mut data = vec![];
+        match args.first().copied() {
+            None => {}
+            Some("=") => {
+                for arg in &args[1..] {
+                    elem.encode(parse_value(arg)?, &mut data)?;
+                }
+            }
+            Some("random") if args.len() == 2 || args.len() == 4 => {
+                let count = count.ok_or("The count of a random buffer is not given.")?;
+                let seed = runner::parse_number(args[1])?;
+                let (min, max) = if args.len() == 4 { (parse_value(args[2])?, parse_value(args[3])?) } else { elem.range() };
+                if min > max {
+                    return Err("The range of random values is empty.".to_string());
+                }
+
+                let mut rng = StdRng::seed_from_u64(seed);
+                for _ in 0..count {
+                    let val = if elem == ElemType::F32 {
+                        Uniform::new_inclusive(min, max).sample(&mut rng)
+                    } else {
+                        rng.gen_range(min as i64, max as i64 + 1) as f64
+                    };
+                    elem.encode(val, &mut data)?;
+                }
+            }
+            Some("file") if args.len() == 2 => {
+                let path = path(args[1]);
+                data = fs::read(&path).map_err(|err| format!("{}: {}", path, err))?;
+            }
+            Some(arg) => return Err(format!("Invalid contents '{}'.", arg)),
+        }
+
+        if let Some(count) = count {
+            if data.len() > count * elem.size() {
+                return Err("The contents are larger than the buffer.".to_string());
+            }
+            data.resize(count * elem.size(), 0);
+        }
+        if data.is_empty() {
+            return Err("The buffer is empty.".to_string());
+        }
+        Ok(data)
+    }
+
+    // Runs the program and checks the expectations.
+    pub fn run(&self) -> Result<(), String> {
+        let run = runner::launch(&self.options)?;
+
+        match (run.result.status, self.expect_fault) {
+            (LaunchStatus::Completed, true) => return Err("The run completed without a fault.".to_string()),
+            (LaunchStatus::Fault { thread, fault }, false) => return Err(format!("thread {}: {}", thread, fault)),
+            (LaunchStatus::OutOfMemory, _) => return Err("No memory for the program and the uniforms.".to_string()),
+            (LaunchStatus::Stopped { thread, pc }, _) => return Err(format!("thread {}: stopped at 0x{:08x}", thread, pc)),
+            _ => {}
+        }
+
+        let initial: HashMap<&str, &[u8]> = self.options.memory.iter().filter_map(|init| match init {
+            MemoryInit::Data { name, data } => Some((name.as_str(), data.as_slice())),
+            _ => None,
+        }).collect();
+
+        for expect in &self.expects {
+            let buffer = run.buffers.get(&expect.name).ok_or_else(|| format!("Unknown buffer '{}'.", expect.name))?;
+            let expected = match &expect.expected {
+                Expected::Values(values) => values.clone(),
+                Expected::File(path) => expect.elem.decode_all(&fs::read(path).map_err(|err| format!("{}: {}", path.display(), err))?),
+                Expected::Initial(name) => expect.elem.decode_all(initial.get(name.as_str()).ok_or_else(|| format!("Unknown buffer '{}'.", name))?),
+            };
+            if expected.len() * expect.elem.size() > buffer.size() {
+                return Err(format!("The buffer '{}' is smaller than the expected values.", expect.name));
+            }
+
+            let bytes = buffer.read_u8(&run.emu, 0, expected.len() * expect.elem.size()).map_err(|fault| fault.to_string())?;
+            let actual = expect.elem.decode_all(&bytes);
+            let mismatches: Vec<String> = actual.iter().zip(expected.iter()).enumerate()
+                .filter(|(_, (actual, expected))| !((*actual - *expected).abs() <= expect.tolerance || actual == expected))
+                .map(|(idx, (actual, expected))| format!("{}[{}] = {}, expected {}", expect.name, idx, actual, expected))
+                .collect();
+            if !mismatches.is_empty() {
+                let shown: Vec<String> = mismatches.iter().take(8).cloned().collect();
+                return Err(format!("{} mismatches: {}", mismatches.len(), shown.join(", ")));
+            }
+        }
+        Ok(())
+    }
+}
+
+// The result of each spec by its path.
+pub type SpecResults = Vec<(PathBuf, Result<(), String>)>;
+
+// Runs every *.spec file in the directory, in the order of the file names.
+pub fn run_dir(dir: &Path) -> Result<SpecResults, String> {
+    let mut paths: Vec<PathBuf> = fs::read_dir(dir).map_err(|err| format!("{}: {}", dir.display(), err))?
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "spec"))
+        .collect();
+    paths.sort();
+
+    Ok(paths.into_iter().map(|path| {
+        let result = Spec::load(&path).and_then(|spec| spec.run());
+        (path, result)
+    }).collect())
+}
+
+// Runs the specs and the directories of specs given as arguments. Returns the exit status:
+// 0 if all of them pass and 1 otherwise.
+pub fn main(args: &[String]) -> i32 {
+    let mut results = vec![];
+    for arg in args {
+        let path = Path::new(arg);
+        if path.is_dir() {
+            match run_dir(path) {
+                Ok(dir_results) => results.extend(dir_results),
+                Err(err) => results.push((path.to_path_buf(), Err(err))),
+            }
+        } else {
+            results.push((path.to_path_buf(), Spec::load(path).and_then(|spec| spec.run())));
+        }
+    }
+
+    let mut failed = 0;
+    for (path, result) in &results {
+        match result {
+            Ok(()) => println!("{} ... ok", path.display()),
+            Err(err) => {
+                println!("{} ... FAILED: {}", path.display(), err);
+                failed += 1;
+            }
+        }
+    }
+    println!("{} passed, {} failed", results.len() - failed, failed);
+    if failed == 0 { 0 } else { 1 }
+}
+
+#[test]
+fn test_spec_parse() {
+    let spec = Spec::parse("
+        # Comment
+        program prog.bin
+        threads 2
+        buffer a u32[4] = 1 0x2 3   # The last element is zeroed.
+        buffer b i16 = 1
+    ", Path::new("specs"));
+    assert_eq!(spec, Err("line 6: Unknown type 'i16'.".to_string()));
+
+    let spec = Spec::parse("
+        program prog.bin
+        buffer a i32 = -1 2
+        buffer b f32[2] random 7 -1.0 1.0
+        buffer c u8[3] random 7
+        uniforms @a 1.5 %thread
+        uniforms @b+4 0 %thread
+        expect a i32 = -1 2
+        expect b f32 initial a tolerance 0.5
+        expect fault
+    ", Path::new("specs")).unwrap();
+
+    assert_eq!(spec.options.program, Path::new("specs").join("prog.bin").to_str().unwrap());
+    assert_eq!(spec.options.num_threads(), 2);
+    assert_eq!(spec.options.memory[0], MemoryInit::Data { name: "a".to_string(), data: vec![0xff, 0xff, 0xff, 0xff, 2, 0, 0, 0] });
+    match &spec.options.memory[1] {
+        MemoryInit::Data { data, .. } => {
+            let values = ElemType::F32.decode_all(data);
+            assert!(values.len() == 2 && values.iter().all(|val| (-1.0..1.0).contains(val)));
+        }
+        init => panic!("{:?}", init),
+    }
+    assert_eq!(spec.expects[1].expected, Expected::Initial("a".to_string()));
+    assert_eq!(spec.expects[1].tolerance, 0.5);
+    assert!(spec.expect_fault);
+
+    // The random contents only depend on the seed.
+    let again = Spec::parse("program p\nbuffer b f32[2] random 7 -1.0 1.0\nbuffer c u8[3] random 7\n", Path::new("")).unwrap();
+    assert_eq!(again.options.memory[0], spec.options.memory[1]);
+    assert_eq!(again.options.memory[1], spec.options.memory[2]);
+
+    // A single value is an inclusive range.
+    let single = Spec::parse("program p\nbuffer b f32[1] random 7 2.5 2.5\nbuffer c u8[1] random 7 3 3\n", Path::new("")).unwrap();
+    assert_eq!(single.options.memory[0], MemoryInit::Data { name: "b".to_string(), data: 2.5f32.to_le_bytes().to_vec() });
+    assert_eq!(single.options.memory[1], MemoryInit::Data { name: "c".to_string(), data: vec![3] });
+    assert!(Spec::parse("program p\nbuffer b f32[1] random 7 1.0 0.5\n", Path::new("")).is_err());
+
+    assert!(Spec::parse("program p\nbuffer a u8[1] = 256\n", Path::new("")).is_err());
+    assert!(Spec::parse("program p\nbuffer a u32[1] = 1 2\n", Path::new("")).is_err());
+    assert!(Spec::parse("buffer a u32[1]\n", Path::new("")).is_err());
+}
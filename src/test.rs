@@ -0,0 +1,1154 @@
+use crate::constants::*;
+use crate::processor::{Event, QPUEmu, StopReason};
+use crate::tile_buffer::*;
+use crate::tiling::*;
+use crate::texture::*;
+use crate::trace::*;
+use crate::image::Image;
+use crate::utils::*;
+use crate::mailbox::*;
+use crate::v3d::*;
+use crate::memory_map::*;
+use crate::kernel::*;
+use crate::grid::Grid;
+use crate::server::*;
+use crate::runner;
+use crate::spec;
+use crate::builder::*;
+use crate::assembler;
+use crate::hooks::*;
+use crate::breakpoint::{Access, Condition, WatchHit, WatchTarget, Watchpoint};
+
+use std::sync::{Arc, Mutex};
+
+fn inst_nop() -> u64 {
+    inst_sig(SIG_NOP)
+}
+
+fn inst_sig(sig: u8) -> u64 {
+    (sig as u64) << 60 | (WA_NOP as u64) << 38 | (WB_NOP as u64) << 32
+        | (RA_NOP as u64) << 18 | (RB_NOP as u64) << 12
+}
+
+fn inst_ldi(waddr_add: u8, imm: u32) -> u64 {
+    inst_ldi_mul(waddr_add, WB_NOP, imm)
+}
+
+fn inst_ldi_mul(waddr_add: u8, waddr_mul: u8, imm: u32) -> u64 {
+    (SIG_LDI as u64) << 60 | (COND_ALWAYS as u64) << 49 | (COND_ALWAYS as u64) << 46
+        | (waddr_add as u64) << 38 | (waddr_mul as u64) << 32 | imm as u64
+}
+
+fn inst_alu(op_add: u8, waddr_add: u8, raddr_a: u8, add_a: u8, add_b: u8) -> u64 {
+    (SIG_NOP as u64) << 60 | (COND_ALWAYS as u64) << 49 | (waddr_add as u64) << 38
+        | (WB_NOP as u64) << 32 | (op_add as u64) << 24 | (raddr_a as u64) << 18
+        | (RB_NOP as u64) << 12 | (add_a as u64) << 9 | (add_b as u64) << 6
+}
+
+// Like inst_alu, with add_b reading the small immediate imm, 0 to 15, instead of regfile B.
+fn inst_alu_imm(op_add: u8, waddr_add: u8, raddr_a: u8, add_a: u8, imm: u8) -> u64 {
+    (SIG_NOPSI as u64) << 60 | (COND_ALWAYS as u64) << 49 | (waddr_add as u64) << 38
+        | (WB_NOP as u64) << 32 | (op_add as u64) << 24 | (raddr_a as u64) << 18
+        | (imm as u64) << 12 | (add_a as u64) << 9 | (ALU_SRC_RB as u64) << 6
+}
+
+// Stores the first uniform plus add to the address given by the second uniform, then
+// interrupts the host if asked. The program of the tests of the launch paths.
+pub(crate) fn store_uniform_program(add: u8, interrupt: bool) -> Vec<u64> {
+    let mut insts = vec![
+        inst_ldi_mul(WA_NOP, WB_VPMVCD_WR_SETUP, 0x1a00),
+        inst_alu_imm(ADDOP_ADD, WA_VPM_WRITE, RA_UNIFORM_READ, ALU_SRC_RA, add),
+        inst_ldi_mul(WA_NOP, WB_VPMVCD_WR_SETUP, 0x8000_0000 | 1 << 23 | 1 << 16 | 1 << 14),
+        inst_alu(ADDOP_OR, WB_VPM_ST_ADDR, RA_UNIFORM_READ, ALU_SRC_RA, ALU_SRC_RA) | 1 << 44,
+    ];
+    if interrupt {
+        insts.push(inst_ldi(WA_HOST_INT, 1));
+    }
+    insts.extend_from_slice(&[inst_sig(SIG_THREND), inst_nop(), inst_nop()]);
+    insts
+}
+
+#[test]
+fn test_tlb_colour_write_and_store() {
+    // Instructions are retired three slots after they are fetched.
+    let insts = vec![
+        inst_ldi(WA_TLB_COLOUR_ALL, 0xff20_4060),
+        inst_nop(),
+        inst_nop(),
+        inst_nop(),
+    ];
+
+    // The frame buffer is at the physical address 0x1000_0000, seen through the uncached alias.
+    let fb = FrameBuffer::new(0xd000_0000, 8, 4, TilingFormat::TFormat, ColourFormat::Rgba8888);
+    let mut emu = QPUEmu::new(fb.size());
+    let mut memory_map = MemoryMap::new();
+    memory_map.add_region(0x1000_0000, fb.size(), 0);
+    emu.set_memory_map(memory_map).unwrap();
+
+    let mut coords = [(0, 0); 16];
+    for (elem, coord) in coords.iter_mut().enumerate() {
+        *coord = ((elem % 8) as u32, (elem / 8) as u32 + 1);
+    }
+    emu.set_pixel_coords(&coords);
+    emu.tile_buffer.clear_colour(0xff00_0000);
+    emu.execute(&insts, &vec![0], 1);
+    emu.store_tile_buffer(&fb).unwrap();
+
+    let mut expected = Image::new(8, 4);
+    for y in 0..4 {
+        for x in 0..8 {
+            expected.set_pixel(x, y, if y == 1 || y == 2 { 0xff20_4060 } else { 0xff00_0000 });
+        }
+    }
+    assert_eq!(Image::from_frame_buffer(&emu, &fb), Ok(expected));
+
+    // A frame buffer after the mapped memory faults.
+    let unmapped = FrameBuffer::new(0xd000_0000 + fb.size() as u32, 8, 4, TilingFormat::TFormat, ColourFormat::Rgba8888);
+    assert!(emu.store_tile_buffer(&unmapped).is_err());
+    assert!(emu.load_tile_buffer(&unmapped).is_err());
+}
+
+#[test]
+fn test_tlb_colour_load_outside_tile() {
+    let insts = vec![
+        inst_sig(SIG_LOADC),
+        inst_alu(ADDOP_OR, WA_ACC0, RA_NOP, ALU_SRC_R4, ALU_SRC_R4),
+        inst_ldi(WA_TLB_COLOUR_ALL, 0xff20_4060),
+        inst_nop(),
+        inst_nop(),
+        inst_nop(),
+    ];
+
+    // The elements 0-7 are in the tile, of which 0-3 are covered, and 8-15 are outside it.
+    let mut emu = QPUEmu::new(4);
+    let mut coords = [(0, 0); 16];
+    for (elem, coord) in coords.iter_mut().enumerate() {
+        *coord = if elem < 8 { (elem as u32, 0) } else { (100 + elem as u32, 0) };
+    }
+    emu.set_pixel_coords(&coords);
+    emu.set_coverage(0x000f | 0xff00);
+    emu.tile_buffer.clear_colour(0x1234);
+    emu.execute(&insts, &vec![0], 1);
+
+    let r0 = emu.register(Register::Acc(0));
+    assert_eq!(r0[..4], [0x1234; 4]);
+    assert_eq!(r0[4..], [0; 12]);
+    for x in 0..8 {
+        assert_eq!(emu.tile_buffer.read_colour(x, 0, 0), if x < 4 { 0xff20_4060 } else { 0x1234 });
+    }
+}
+
+#[test]
+fn test_tlb_depth_test() {
+    let insts = vec![
+        inst_ldi(WA_TLB_Z, 0x40_0000),
+        inst_ldi(WA_TLB_COLOUR_ALL, 0xffff_ffff),
+        inst_nop(),
+        inst_nop(),
+        inst_nop(),
+    ];
+
+    let mut emu = QPUEmu::new(4);
+    let mut coords = [(0, 0); 16];
+    for (elem, coord) in coords.iter_mut().enumerate() {
+        *coord = (elem as u32, 0);
+    }
+    emu.set_pixel_coords(&coords);
+
+    emu.tile_buffer.clear_colour(0);
+    emu.tile_buffer.clear_z_stencil(0x80_0000, 0);
+    emu.tile_buffer.depth = DepthState { func: CompareFunc::Less, write_enable: true, early_z: true };
+    for x in 8..16 {
+        emu.tile_buffer.test_sample(x, 0, 0, 0x20_0000, false);
+    }
+
+    // Element 0 is rejected before the shader runs by its interpolated Z.
+    let mut fragment_z = [0x10_0000; 16];
+    fragment_z[0] = 0x90_0000;
+    emu.set_fragment_z(&fragment_z);
+    assert_eq!(emu.early_z(), 0xfffe);
+
+    emu.execute(&insts, &vec![0], 1);
+
+    for x in 0..16 {
+        let written = (1..8).contains(&x);
+        assert_eq!(emu.tile_buffer.read_colour(x, 0, 0), if written { 0xffff_ffff } else { 0 });
+        assert_eq!(emu.tile_buffer.read_z(x, 0, 0), if written { 0x40_0000 } else if x == 0 { 0x80_0000 } else { 0x20_0000 });
+    }
+}
+
+#[test]
+fn test_tlb_multisample() {
+    let ms_insts = vec![
+        inst_ldi(WA_TLB_COLOUR_MS, 0xff00_0010),
+        inst_ldi(WA_TLB_COLOUR_MS, 0xff00_0020),
+        inst_ldi(WA_TLB_COLOUR_MS, 0xff00_0030),
+        inst_ldi(WA_TLB_COLOUR_MS, 0xff00_0040),
+        inst_nop(),
+        inst_nop(),
+        inst_nop(),
+    ];
+    let ms_flags_insts = vec![
+        inst_alu(ADDOP_OR, WA_TLB_COLOUR_ALL, RA_MS_FLAGS, ALU_SRC_RA, ALU_SRC_RA),
+        inst_nop(),
+        inst_nop(),
+        inst_nop(),
+    ];
+
+    let mut emu = QPUEmu::new(4);
+    emu.tile_buffer.set_multisample(true);
+    emu.tile_buffer.clear_colour(0);
+
+    let mut coords = [(0, 0); 16];
+    for (elem, coord) in coords.iter_mut().enumerate() {
+        *coord = (elem as u32, 0);
+    }
+    emu.set_pixel_coords(&coords);
+
+    let mut ms_flags = [0b1111; 16];
+    ms_flags[1] = 0b0101;
+    ms_flags[2] = 0;
+    emu.set_ms_flags(&ms_flags);
+    assert_eq!(emu.coverage(), 0xfffb);
+
+    emu.execute(&ms_insts, &vec![0], 1);
+
+    let colours = [0xff00_0010, 0xff00_0020, 0xff00_0030, 0xff00_0040];
+    for sample in 0..4 {
+        assert_eq!(emu.tile_buffer.read_colour(0, 0, sample), colours[sample]);
+        let expected = if sample % 2 == 0 { colours[sample] } else { 0 };
+        assert_eq!(emu.tile_buffer.read_colour(1, 0, sample), expected);
+        assert_eq!(emu.tile_buffer.read_colour(2, 0, sample), 0);
+    }
+    assert_eq!(emu.tile_buffer.resolve_colour(0, 0), 0xff00_0028);
+
+    emu.execute(&ms_flags_insts, &vec![0], 1);
+
+    for sample in 0..4 {
+        assert_eq!(emu.tile_buffer.read_colour(0, 0, sample), 0b1111);
+        let expected = if sample % 2 == 0 { 0b0101 } else { 0 };
+        assert_eq!(emu.tile_buffer.read_colour(1, 0, sample), expected);
+    }
+}
+
+#[test]
+fn test_ms_flags_regfile_b() {
+    let insts = vec![
+        inst_ldi_mul(WA_NOP, WB_MS_FLAGS, 0b0110),
+        inst_alu(ADDOP_OR, WA_ACC0, RA_MS_FLAGS, ALU_SRC_RA, ALU_SRC_RA),
+        inst_nop(),
+        inst_nop(),
+        inst_nop(),
+    ];
+
+    let mut emu = QPUEmu::new(4);
+    emu.tile_buffer.set_multisample(true);
+    let mut ms_flags = [0b1111; 16];
+    ms_flags[1] = 0b0011;
+    emu.set_ms_flags(&ms_flags);
+    emu.execute(&insts, &vec![0], 1);
+
+    let r0 = emu.register(Register::Acc(0));
+    assert_eq!(r0[0], 0b0110);
+    assert_eq!(r0[1], 0b0010);
+}
+
+#[test]
+fn test_fmax_execute() {
+    // fmax is add op 4 in the hardware encoding.
+    assert_eq!(ADDOP_FMAX, 4);
+    let insts = assembler::assemble("
+        ldi(r1, -3.0)
+        ldi(r2, -1.0)
+        fmax(r0, r1, r2)
+        fmax(r3, r2, 2.0)
+        nop(sig='thread end')
+        nop()
+        nop()
+    ").unwrap();
+    assert_eq!(get_bits(insts[2], 28, 24) as u8, ADDOP_FMAX);
+
+    let mut emu = QPUEmu::new(4);
+    emu.execute(&insts, &vec![0], 1);
+
+    assert_eq!(u32_to_f32(emu.register(Register::Acc(0))[0]), -1.0);
+    assert_eq!(u32_to_f32(emu.register(Register::Acc(3))[5]), 2.0);
+}
+
+#[test]
+fn test_tmu_2d_texture_lookup() {
+    let insts = vec![
+        inst_ldi(WA_TMU0_T, f32_to_u32(0.3)),
+        inst_ldi(WA_TMU0_S, f32_to_u32(0.7)),
+        inst_sig(SIG_LDTMU0),
+        inst_alu(ADDOP_OR, WA_TLB_COLOUR_ALL, RA_NOP, ALU_SRC_R4, ALU_SRC_R4),
+        inst_nop(),
+        inst_nop(),
+        inst_nop(),
+    ];
+
+    // An 8x8 RGBA8888 texture at 0x1000 is stored in LT-format.
+    let mut emu = QPUEmu::new(0x1000 + 8 * 8 * 4);
+    emu.mem[0..4].copy_from_slice(&0x1000u32.to_le_bytes());
+    emu.mem[4..8].copy_from_slice(&(8u32 << 20 | 8 << 8).to_le_bytes());
+
+    // Texel (5, 2) is in the second utile.
+    let offset = 0x1000 + 64 + (2 * 4 + 1) * 4;
+    emu.mem[offset..offset + 4].copy_from_slice(&0xff11_2233u32.to_le_bytes());
+
+    emu.set_pixel_coords(&[(0, 0); 16]);
+    emu.set_coverage(1);
+    emu.execute(&insts, &vec![0], 1);
+
+    assert_eq!(emu.tile_buffer.read_colour(0, 0, 0), 0xff11_2233);
+}
+
+#[test]
+fn test_mailbox_execute_qpu() {
+    let insts = store_uniform_program(0, false);
+
+    let mut emu = QPUEmu::new(0x10000);
+    let mut mailbox = Mailbox::new();
+    let message = emu.alloc(0x100, 16).unwrap();
+
+    let mut request = |emu: &mut QPUEmu, tag: u32, values: &[u32]| -> u32 {
+        let mut buffer = vec![0, CODE_REQUEST, tag, values.len() as u32 * 4, 0];
+        buffer.extend_from_slice(values);
+        buffer.push(TAG_END);
+        buffer[0] = buffer.len() as u32 * 4;
+
+        message.write_u32(emu, 0, &buffer).unwrap();
+        assert!(mailbox.property_at(emu, message.addr()));
+        assert_eq!(message.read_u32(emu, 1, 1), Ok(vec![CODE_RESPONSE_SUCCESS]));
+        assert_eq!(message.read_u32(emu, 4, 1), Ok(vec![CODE_RESPONSE_SUCCESS | 4]));
+        message.read_u32(emu, 5, 1).unwrap()[0]
+    };
+
+    assert_eq!(request(&mut emu, TAG_QPU_ENABLE, &[1]), 0);
+    let handle = request(&mut emu, TAG_MEM_ALLOC, &[0x1000, 0x1000, MEM_FLAG_L1_NONALLOCATING, 0]);
+    let base = request(&mut emu, TAG_MEM_LOCK, &[handle]);
+    assert_eq!(base, 0x4000_1000);
+
+    // Code at base, uniforms at base + 0x100, control at base + 0x200, output at base + 0x300.
+    for (idx, inst) in insts.iter().enumerate() {
+        emu.write_bytes(base + idx as u32 * 8, &inst.to_le_bytes()).unwrap();
+    }
+    emu.write_u32(base + 0x100, 0x1234_5678).unwrap();
+    emu.write_u32(base + 0x104, base + 0x300).unwrap();
+    emu.write_u32(base + 0x200, base + 0x100).unwrap();
+    emu.write_u32(base + 0x204, base).unwrap();
+
+    assert_eq!(request(&mut emu, TAG_EXECUTE_QPU, &[1, base + 0x200, 1, 1000]), 0);
+    assert_eq!(emu.read_u32(base + 0x300), Ok(0x1234_5678));
+
+    // A timeout of 0 does not limit the program.
+    emu.write_u32(base + 0x300, 0).unwrap();
+    assert_eq!(request(&mut emu, TAG_EXECUTE_QPU, &[1, base + 0x200, 1, 0]), 0);
+    assert_eq!(emu.read_u32(base + 0x300), Ok(0x1234_5678));
+
+    // A program which never ends fails the request when it times out.
+    let branch_to_itself = 0xf0f8_09e7_ffff_ffe0u64;
+    for (idx, inst) in [inst_nop(), branch_to_itself, inst_nop(), inst_nop(), inst_nop()].iter().enumerate() {
+        emu.write_bytes(base + idx as u32 * 8, &inst.to_le_bytes()).unwrap();
+    }
+    assert_eq!(request(&mut emu, TAG_EXECUTE_QPU, &[1, base + 0x200, 1, 1]), 1);
+    assert_eq!(emu.retired(), 250_000);
+
+    assert_eq!(request(&mut emu, TAG_MEM_UNLOCK, &[handle]), 0);
+    assert_eq!(request(&mut emu, TAG_MEM_FREE, &[handle]), 0);
+
+    // A property buffer out of the memory is an error.
+    assert!(!mailbox.property_at(&mut emu, 0xffff_fffc));
+}
+
+#[test]
+fn test_v3d_user_programs() {
+    let insts = store_uniform_program(0, true);
+
+    let mut emu = QPUEmu::new(0x1000);
+    for (idx, inst) in insts.iter().enumerate() {
+        emu.mem[idx * 8..idx * 8 + 8].copy_from_slice(&inst.to_le_bytes());
+    }
+
+    let mut v3d = V3D::new();
+    v3d.write(V3D_DBQITE, 0b01);
+    for qpu in 0..2u32 {
+        let uniforms = 0x100 + qpu * 8;
+        emu.mem[uniforms as usize..uniforms as usize + 4].copy_from_slice(&(0x42 + qpu).to_le_bytes());
+        emu.mem[uniforms as usize + 4..uniforms as usize + 8].copy_from_slice(&(0x200 + qpu * 4).to_le_bytes());
+
+        v3d.write(V3D_SRQUA, uniforms);
+        v3d.write(V3D_SRQUL, 2);
+        v3d.write(V3D_SRQPC, 0);
+    }
+    assert_eq!(v3d.read(V3D_SRQCS), 2 << 8 | 2);
+
+    assert_eq!(v3d.dispatch(&mut emu), 2);
+    assert_eq!(v3d.read(V3D_SRQCS), 2 << 16 | 2 << 8);
+    assert_eq!(&emu.mem[0x200..0x208], &[0x42, 0, 0, 0, 0x43, 0, 0, 0]);
+    assert_eq!(emu.host_interrupts(), 2);
+
+    // Only the interrupt of QPU 0 is enabled.
+    assert_eq!(v3d.read(V3D_DBQITC), 0b01);
+    v3d.write(V3D_DBQITC, 0b01);
+    assert_eq!(v3d.read(V3D_DBQITC), 0);
+}
+
+#[test]
+fn test_memory_map_fault() {
+    // Reads a uniform through the uncached alias, then loads from an unmapped address via the TMU.
+    let insts = vec![
+        inst_alu(ADDOP_OR, WA_TMU0_S, RA_UNIFORM_READ, ALU_SRC_RA, ALU_SRC_RA),
+        inst_sig(SIG_LDTMU0),
+        inst_alu(ADDOP_OR, WA_RA0, RA_NOP, ALU_SRC_R4, ALU_SRC_R4),
+        inst_sig(SIG_THREND),
+        inst_nop(),
+        inst_nop(),
+    ];
+
+    // 4KB of RAM at the physical address 0x1000_0000.
+    let mut emu = QPUEmu::new(0x1000);
+    let mut memory_map = MemoryMap::new();
+    memory_map.add_region(0x1000_0000, 0x1000, 0);
+    emu.set_memory_map(memory_map).unwrap();
+
+    for (idx, inst) in insts.iter().enumerate() {
+        emu.write_bytes(0xd000_0000 + idx as u32 * 8, &inst.to_le_bytes()).unwrap();
+    }
+    emu.write_u32(0xd000_0100, 0x1000_0200).unwrap();
+    emu.execute_at(0xd000_0000, 0xd000_0100);
+    assert_eq!(emu.fault(), None);
+
+    emu.write_u32(0xd000_0100, 0x2000_0000).unwrap();
+    emu.execute_at(0xd000_0000, 0xd000_0100);
+    assert_eq!(emu.fault(), Some(MemoryFault { addr: 0x2000_0000, len: 4 }));
+    assert_eq!(emu.read_u32(0x1000_1000), Err(MemoryFault { addr: 0x1000_1000, len: 4 }));
+}
+
+#[test]
+fn test_kernel_launch() {
+    let kernel = Kernel::new(store_uniform_program(1, false));
+
+    let mut emu = QPUEmu::new(0x1000);
+    let out = emu.alloc(8, 4).unwrap();
+
+    let result = kernel.launch()
+        .thread(vec![Uniform::U32(41), Uniform::Buffer(out)])
+        .thread(vec![Uniform::F32(1.0), Uniform::U32(out.addr() + 4)])
+        .run(&mut emu);
+
+    assert!(result.is_completed());
+    assert_eq!(out.read_u32(&emu, 0, 2), Ok(vec![42, f32_to_u32(1.0) + 1]));
+    assert_eq!(result.threads.len(), 2);
+    assert_eq!(result.threads[0].instructions, 7);
+    assert_eq!(result.threads[0].uniforms, 2);
+    assert_eq!(result.threads[0].vpm_dma_stores, 1);
+
+    let result = kernel.launch()
+        .thread(vec![Uniform::U32(0), Uniform::U32(0x1000)])
+        .thread(vec![Uniform::U32(0), Uniform::Buffer(out)])
+        .run(&mut emu);
+
+    assert_eq!(result.status, LaunchStatus::Fault { thread: 0, fault: MemoryFault { addr: 0x1000, len: 4 } });
+    assert_eq!(result.threads.len(), 1);
+
+    let (code_buf, uniforms_buf) = kernel.launch().thread(vec![Uniform::U32(0), Uniform::Buffer(out)]).start(&mut emu).unwrap();
+    let reason = emu.run_until(3);
+    assert_eq!(LaunchResult::from_stop(&emu, reason).status, LaunchStatus::Stopped { thread: 0, pc: 3 });
+    let reason = emu.run();
+    assert_eq!(LaunchResult::from_stop(&emu, reason).status, LaunchStatus::Completed);
+    emu.free(uniforms_buf);
+    emu.free(code_buf);
+
+    let mut small = QPUEmu::new(0x40);
+    let result = kernel.launch().thread(vec![Uniform::U32(0), Uniform::U32(0)]).run(&mut small);
+    assert_eq!(result, LaunchResult { status: LaunchStatus::OutOfMemory, threads: vec![] });
+    assert_eq!(small.alloc(0x40, 4).map(|buffer| buffer.size()), Some(0x40));
+
+    assert!(Kernel::from_bytes(&[0; 12]).is_err());
+    assert_eq!(Kernel::from_bytes(&[0; 16]).map(|kernel| kernel.insts().len()), Ok(2));
+}
+
+#[test]
+fn test_kernel_launch_grid() {
+    let kernel = Kernel::new(store_uniform_program(1, false));
+
+    let mut emu = QPUEmu::new(0x1000);
+    let out = emu.alloc(12 * 4, 4).unwrap();
+
+    let grid = Grid::new_1d(1000, 64);
+    let result = kernel.launch()
+        .grid(&grid, 12, |item| vec![Uniform::U32((item.x + item.width) as u32), Uniform::U32(out.addr() + item.thread as u32 * 4)])
+        .run(&mut emu);
+
+    assert!(result.is_completed());
+    assert_eq!(result.threads.len(), 12);
+    let ends: Vec<u32> = grid.partition(12).iter().map(|item| (item.x + item.width) as u32 + 1).collect();
+    assert_eq!(ends[11], 1001);
+    assert_eq!(out.read_u32(&emu, 0, 12), Ok(ends));
+}
+
+#[test]
+fn test_server_job() {
+    use std::os::unix::net::UnixStream;
+
+    let insts = store_uniform_program(0, true);
+
+    let (mut client, mut server_end) = UnixStream::pair().unwrap();
+    let handle = std::thread::spawn(move || Server::new(0x1000).handle_connection(&mut server_end).unwrap());
+
+    let mut job = PayloadWriter::new();
+    job.u32(insts.len() as u32);
+    for inst in &insts {
+        job.u64(*inst);
+    }
+    job.u32(3).u32(2)
+        .u32(0x11).u32(0x800)
+        .u32(0x22).u32(0x804)
+        .u32(0x33).u32(0x2000);
+    job.u32(1).u32(0x808).u32(2).bytes(&[0xaa, 0xbb]);
+    job.u32(1).u32(0x800).u32(12);
+    write_message(&mut client, REQ_JOB, &job.finish()).unwrap();
+
+    let mut messages = vec![];
+    loop {
+        let (kind, payload) = read_message(&mut client).unwrap();
+        messages.push(kind);
+        if kind == RESP_THREAD {
+            let mut reader = PayloadReader::new(&payload);
+            let thread = reader.u32().unwrap();
+            assert_eq!(reader.u64().unwrap(), if thread < 2 { 8 } else { 4 });
+        }
+        if kind == RESP_JOB_DONE {
+            let mut reader = PayloadReader::new(&payload);
+            assert_eq!(reader.u32().unwrap(), JOB_FAULT);
+            assert_eq!(reader.u32().unwrap(), 0x2000);
+            assert_eq!(reader.u32().unwrap(), 1);
+            assert_eq!((reader.u32().unwrap(), reader.u32().unwrap()), (0x800, 12));
+            assert_eq!(reader.bytes(12).unwrap(), &[0x11, 0, 0, 0, 0x22, 0, 0, 0, 0xaa, 0xbb, 0, 0]);
+            break;
+        }
+    }
+    // The third thread faults on its store before the interrupt.
+    assert_eq!(messages, vec![RESP_INTERRUPT, RESP_THREAD, RESP_INTERRUPT, RESP_THREAD, RESP_THREAD, RESP_JOB_DONE]);
+
+    drop(client);
+    handle.join().unwrap();
+}
+
+#[test]
+fn test_runner_program_file() {
+    let insts = store_uniform_program(1, false);
+
+    let dir = std::env::temp_dir().join(format!("videocoreiv-sim-runner-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let path = |name: &str| dir.join(name).to_str().unwrap().to_string();
+
+    let program: Vec<u8> = insts.iter().flat_map(|inst| inst.to_le_bytes().to_vec()).collect();
+    std::fs::write(path("prog.bin"), &program).unwrap();
+    std::fs::write(path("init.bin"), [0xaa; 8]).unwrap();
+    std::fs::write(path("uniforms.txt"), "# value, address\n41 0x100\n%thread 0x104\n").unwrap();
+
+    let args: Vec<String> = vec![path("prog.bin"), "-U".to_string(), path("uniforms.txt"),
+                                 "-l".to_string(), format!("0x100={}", path("init.bin")),
+                                 "-d".to_string(), format!("0x100:8={}", path("out.bin")),
+                                 "-m".to_string(), "0x1000".to_string()];
+    let options = runner::Options::parse(&args).unwrap();
+    let result = runner::run(&options).unwrap();
+
+    assert!(result.is_completed());
+    assert_eq!(result.threads.len(), 2);
+    assert_eq!(std::fs::read(path("out.bin")).unwrap(), vec![42, 0, 0, 0, 2, 0, 0, 0]);
+
+    // Both threads store to the same buffer, so the value of the last one remains.
+    let args: Vec<String> = vec![path("prog.bin"), "-n".to_string(), "2".to_string(),
+                                 "-u".to_string(), "%thread,@out".to_string(), "-b".to_string(), "out=4".to_string(),
+                                 "-d".to_string(), format!("out={}", path("out.bin")), "-m".to_string(), "0x1000".to_string()];
+    let options = runner::Options::parse(&args).unwrap();
+    let result = runner::run(&options).unwrap();
+    assert!(result.is_completed());
+    assert_eq!(std::fs::read(path("out.bin")).unwrap(), vec![2, 0, 0, 0]);
+
+    let args: Vec<String> = vec![path("prog.bin"), "-u".to_string(), "0,0x1000".to_string(), "-m".to_string(), "0x1000".to_string()];
+    assert_eq!(runner::main(&args), 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn test_specs() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("data/specs");
+    let results = spec::run_dir(&dir).unwrap();
+    assert!(!results.is_empty());
+
+    let failures: Vec<String> = results.iter()
+        .filter_map(|(path, result)| result.as_ref().err().map(|err| format!("{}: {}", path.display(), err)))
+        .collect();
+    if !failures.is_empty() {
+        panic!("{}", failures.join("\n"));
+    }
+}
+
+#[test]
+fn test_builder_kernel() {
+    use crate::builder::regs::*;
+
+    // Adds step to zero as many times as the first uniform, then stores the maximum of the
+    // float of the sum and 8.0 to the address given by the second uniform.
+    let build = |step: i32| {
+        let mut prog = Program::new();
+        prog.mov(ra0, uniform);
+        prog.ldi(r1, 0);
+        let lp = prog.here();
+        prog.isub(ra0, ra0, 1).setf();
+        prog.bra(BranchCond::Zc, lp);
+        prog.iadd(r1, r1, step);
+        prog.nop();
+        prog.nop();
+        prog.ldi(vpmvcd_wr_setup, 0x1a00);
+        prog.itof(r1, r1);
+        prog.fmax(vpm, r1, 8.0f32);
+        prog.ldi(vpmvcd_wr_setup, 0x8000_0000u32 | 1 << 23 | 1 << 16 | 1 << 14);
+        prog.mov(vpm_st_addr, uniform);
+        prog.nop().sig(Signal::ThreadEnd);
+        prog.nop();
+        prog.nop();
+        Kernel::new(prog.build().unwrap())
+    };
+
+    let mut emu = QPUEmu::new(0x1000);
+    let out = emu.alloc(12, 4).unwrap();
+    let result = build(3).launch()
+        .thread(vec![Uniform::U32(4), Uniform::Buffer(out)])
+        .thread(vec![Uniform::U32(1), Uniform::U32(out.addr() + 4)])
+        .run(&mut emu);
+    assert!(result.is_completed());
+
+    let result = build(-5).launch()
+        .thread(vec![Uniform::U32(2), Uniform::U32(out.addr() + 8)])
+        .run(&mut emu);
+    assert!(result.is_completed());
+    assert_eq!(out.read_u32(&emu, 0, 3), Ok(vec![f32_to_u32(12.0), f32_to_u32(8.0), f32_to_u32(8.0)]));
+}
+
+#[test]
+fn test_breakpoints() {
+    // Steps from the breakpoint at 1 to 3, then stops at the breakpoint signal at 5.
+    #[derive(Default)]
+    struct Stepper {
+        stops: Vec<u32>,
+    }
+
+    impl Hooks for Stepper {
+        fn breakpoint(&mut self, emu: &mut QPUEmu, pc: u32) {
+            assert_eq!(emu.pc(), pc);
+            self.stops.push(pc);
+            emu.set_single_step(pc < 3);
+            if pc == 2 {
+                emu.set_accumulator(0, 3, 42);
+            }
+        }
+    }
+
+    let insts = vec![
+        inst_nop(),
+        inst_nop(),
+        inst_nop(),
+        inst_alu(ADDOP_OR, WA_RA1, RA_NOP, ALU_SRC_R0, ALU_SRC_R0),
+        inst_nop(),
+        inst_sig(SIG_BPKT),
+        inst_sig(SIG_THREND),
+        inst_nop(),
+        inst_nop(),
+    ];
+
+    let stepper = Arc::new(Mutex::new(Stepper::default()));
+    let mut emu = QPUEmu::new(0x1000);
+    emu.set_hooks(Box::new(stepper.clone()));
+    emu.set_breakpoint(1);
+    emu.set_breakpoint(7);
+    assert!(emu.clear_breakpoint(7));
+    assert!(!emu.clear_breakpoint(7));
+    assert_eq!(emu.breakpoints(), vec![(1, None)]);
+
+    emu.execute(&insts, &vec![0], 1);
+    assert_eq!(stepper.lock().unwrap().stops, vec![1, 2, 3, 5]);
+    assert_eq!(emu.reg_a(1)[3], 42);
+    assert_eq!(emu.program(), insts);
+}
+
+#[test]
+fn test_hooks() {
+    #[derive(Default)]
+    struct Recorder {
+        events: Vec<String>,
+        retired: Vec<u32>,
+    }
+
+    impl Hooks for Recorder {
+        fn retire(&mut self, _emu: &mut QPUEmu, pc: u32, _inst: u64) {
+            self.retired.push(pc);
+        }
+
+        fn register_write(&mut self, _emu: &QPUEmu, reg: Register, values: &[Option<u32>; 16]) {
+            self.events.push(format!("write {:?} {:?}", reg, values[1]));
+        }
+
+        fn memory_read(&mut self, _emu: &QPUEmu, addr: u32, len: usize) {
+            self.events.push(format!("read 0x{:x} {}", addr, len));
+        }
+
+        fn memory_write(&mut self, _emu: &QPUEmu, addr: u32, len: usize) {
+            self.events.push(format!("write 0x{:x} {}", addr, len));
+        }
+
+        fn vpm_dma(&mut self, _emu: &QPUEmu, direction: DmaDirection, addr: u32) {
+            self.events.push(format!("dma {:?} 0x{:x}", direction, addr));
+        }
+
+        fn tmu_request(&mut self, _emu: &QPUEmu, tmu: u8, s: &[u32; 16]) {
+            self.events.push(format!("tmu{} 0x{:x}", tmu, s[1]));
+        }
+
+        fn semaphore(&mut self, _emu: &QPUEmu, idx: u8, increment: bool) {
+            self.events.push(format!("semaphore {} {}", idx, increment));
+        }
+
+        fn mutex(&mut self, _emu: &QPUEmu, acquire: bool) {
+            self.events.push(format!("mutex {}", acquire));
+        }
+
+        fn host_interrupt(&mut self, _emu: &QPUEmu) {
+            self.events.push("host interrupt".to_string());
+        }
+    }
+
+    // Every element loads the same word with the TMU, then the row is stored with the VPM DMA to the address in the uniform.
+    let insts = assembler::assemble("
+        mov(r0, mutex_acquire)
+        sema_up(3)
+        mov(tmu0_s, uniform)
+        nop(sig='load tmu0')
+        ldi(vpmvcd_wr_setup, 0x00001a00)
+        mov(vpm, r4)
+        ldi(vpmvcd_wr_setup, 0x80814000)
+        mov(vpm_st_addr, uniform)
+        mov(mutex_release, 0)
+        mov(host_interrupt, 1)
+        nop(sig='thread end')
+        nop()
+        nop()
+        nop()
+        nop()
+        nop()
+    ").unwrap();
+
+    let recorder = Arc::new(Mutex::new(Recorder::default()));
+    let mut emu = QPUEmu::new(0x1000);
+    emu.write_u32(0x100, 0x200).unwrap();
+    emu.write_u32(0x104, 0x300).unwrap();
+    emu.write_u32(0x200, 7).unwrap();
+    emu.set_hooks(Box::new(recorder.clone()));
+    emu.execute(&insts, &vec![0x100], 1);
+
+    let recorder = recorder.lock().unwrap();
+    assert_eq!(recorder.retired, (0..13).collect::<Vec<u32>>());
+    assert_eq!(recorder.events[..5], [
+        "mutex true", "write Acc(0) Some(0)",
+        "semaphore 3 true",
+        "read 0x100 4", "tmu0 0x200",
+    ]);
+    assert_eq!(recorder.events[5..21], vec!["read 0x200 4"; 16][..]);
+    assert_eq!(recorder.events[21..], [
+        "write Acc(4) Some(7)",
+        "read 0x104 4", "dma Store 0x300", "write 0x300 4",
+        "mutex false",
+        "host interrupt",
+    ]);
+    assert_eq!(emu.read_u32(0x300), Ok(7));
+}
+
+#[test]
+fn test_run_control() {
+    // Counts r1 down from 2, then raises a host interrupt.
+    let insts = assembler::assemble("
+        mov(ra0, uniform)
+        mov(r1, 2)
+    L.loop
+        isub(r1, r1, 1, set_flags=True)
+        jzc(L.loop)
+        nop()
+        nop()
+        nop()
+        mov(host_interrupt, 1)
+        nop(sig='thread end')
+        nop()
+        nop()
+        nop()
+        nop()
+        nop()
+    ").unwrap();
+
+    let mut emu = QPUEmu::new(0x1000);
+    emu.write_u32(0x100, 10).unwrap();
+    emu.write_u32(0x104, 11).unwrap();
+    emu.start(&insts, &[0x100, 0x104]);
+    assert_eq!(emu.num_threads(), 2);
+
+    assert_eq!(emu.step(), StopReason::Stepped);
+    assert_eq!((emu.thread(), emu.pc(), emu.retired()), (Some(0), 1, 1));
+    assert_eq!(emu.run_until(3), StopReason::Reached { thread: 0, pc: 3 });
+    assert_eq!(emu.accumulator(1)[0], 1);
+    assert_eq!(emu.run_until(3), StopReason::Reached { thread: 0, pc: 3 });
+    assert_eq!(emu.accumulator(1)[0], 0);
+
+    // Resuming from the breakpoint executes the instruction.
+    emu.set_breakpoint(7);
+    assert_eq!(emu.run(), StopReason::Breakpoint { thread: 0, pc: 7 });
+    assert_eq!(emu.host_interrupts(), 0);
+    assert_eq!(emu.run_until_event(&[Event::HostInterrupt]), StopReason::Event { thread: 0, event: Event::HostInterrupt });
+    assert_eq!(emu.host_interrupts(), 1);
+    assert_eq!(emu.run_until_event(&[Event::ThreadEnd]), StopReason::Event { thread: 0, event: Event::ThreadEnd });
+    assert_eq!((emu.thread(), emu.pc(), emu.retired()), (Some(1), 0, 16));
+    assert_eq!(emu.step_qpu(0), StopReason::Event { thread: 0, event: Event::ThreadEnd });
+    assert_eq!(emu.step_qpu(2), StopReason::InvalidThread { thread: 2 });
+
+    emu.clear_breakpoint(7);
+    assert_eq!(emu.run_for(5), StopReason::Stepped);
+    assert_eq!(emu.step_qpu(1), StopReason::Stepped);
+    assert_eq!((emu.pc(), emu.retired()), (6, 22));
+    assert_eq!(emu.run(), StopReason::Finished);
+    assert_eq!(emu.thread(), None);
+    assert_eq!(emu.reg_a(0)[0], 11);
+    assert_eq!(emu.host_interrupts(), 2);
+
+    // A hook stops the run before the instruction at the breakpoint, which resuming executes.
+    struct Quit;
+
+    impl Hooks for Quit {
+        fn breakpoint(&mut self, emu: &mut QPUEmu, _pc: u32) {
+            emu.request_stop();
+        }
+    }
+
+    emu.set_hooks(Box::new(Quit));
+    emu.set_breakpoint(7);
+    emu.execute(&insts, &vec![0x100, 0x104], 2);
+    assert_eq!((emu.thread(), emu.pc(), emu.retired()), (Some(0), 7, 12));
+    assert_eq!(emu.step(), StopReason::Stepped);
+    assert_eq!(emu.pc(), 8);
+    assert_eq!(emu.run(), StopReason::Stopped { thread: 1, pc: 7 });
+    emu.take_hooks();
+    emu.clear_breakpoint(7);
+
+    // A fault stops every thread.
+    emu.start_at(0x2000, &[0x100, 0x104]);
+    let fault = StopReason::Fault { thread: 0, fault: MemoryFault { addr: 0x2000, len: 4 } };
+    assert_eq!(emu.step(), fault);
+    assert_eq!(emu.run(), fault);
+
+    // The fetches past the end of the address space fault as well.
+    let fault = StopReason::Fault { thread: 0, fault: MemoryFault { addr: 0xffff_fff8, len: 4 } };
+    assert_eq!(emu.execute_threads_at(0xffff_fff8, &[0x100]), fault);
+}
+
+#[test]
+fn test_watchpoints() {
+    // Copies the uniform to rb1 in the elements 0 and 1, then writes rb1 to the VPM row 2.
+    let insts = assembler::assemble("
+        mov(ra0, uniform)
+        mov(r1, element_number)
+        isub(r2, r1, 2, set_flags=True)
+        mov(rb1, ra0, cond='ns')
+        ldi(vpmvcd_wr_setup, 0x00001a02)
+        mov(vpm, rb1)
+        nop(sig='thread end')
+        nop()
+        nop()
+        nop()
+        nop()
+        nop()
+    ").unwrap();
+
+    let mut emu = QPUEmu::new(0x1000);
+    emu.write_u32(0x100, 9).unwrap();
+    emu.start(&insts, &[0x100]);
+    let watch = |target: &str, access: Access| Watchpoint { target: WatchTarget::parse(target).unwrap(), access };
+    assert_eq!(emu.add_watchpoint(watch("mem[0x100]", Access::Read)), 0);
+    assert_eq!(emu.add_watchpoint(watch("ra0", Access::ReadWrite)), 1);
+    assert_eq!(emu.add_watchpoint(watch("rb1", Access::Write)), 2);
+    assert_eq!(emu.add_watchpoint(watch("vpm[1:2]", Access::Write)), 3);
+    assert_eq!(emu.add_watchpoint(watch("mem[0x104:16]", Access::ReadWrite)), 4);
+    assert!(emu.remove_watchpoint(4));
+    assert!(!emu.remove_watchpoint(4));
+    assert_eq!(emu.watchpoints().len(), 4);
+    emu.set_conditional_breakpoint(3, Condition::parse("r2[0] == -2").unwrap());
+    emu.set_conditional_breakpoint(5, Condition::parse("all nf").unwrap());
+
+    let hit = |watchpoint: usize, access: Access, lanes: u16, pc: u32| WatchHit { watchpoint, access, lanes, thread: 0, pc };
+    assert_eq!(emu.run(), StopReason::Watchpoint(hit(0, Access::Read, 0xffff, 0)));
+    assert_eq!(emu.watch_hits(), [hit(0, Access::Read, 0xffff, 0), hit(1, Access::Write, 0xffff, 0)]);
+    assert_eq!(emu.run(), StopReason::Breakpoint { thread: 0, pc: 3 });
+    assert_eq!(emu.run(), StopReason::Watchpoint(hit(1, Access::Read, 0x0003, 3)));
+    assert_eq!(emu.watch_hits(), [hit(1, Access::Read, 0x0003, 3), hit(2, Access::Write, 0x0003, 3)]);
+    assert_eq!(emu.run(), StopReason::Watchpoint(hit(3, Access::Write, 0xffff, 5)));
+    assert_eq!(emu.vpm_row(2)[..3], [9, 9, 0]);
+    assert_eq!(emu.run(), StopReason::Finished);
+}
+
+#[test]
+fn test_tiling_offsets() {
+    // Second pixel row of the first utile.
+    assert_eq!(lt_offset(0, 1, 4, 16), 16);
+    // Second utile in the first utile row.
+    assert_eq!(lt_offset(4, 0, 4, 16), 64);
+    // First pixel of the second utile row.
+    assert_eq!(lt_offset(0, 4, 4, 16), 4 * 64);
+
+    // Sub-tiles of an even tile row: bottom-left, bottom-right, top-right, top-left.
+    assert_eq!(t_offset(0, 0, 4, 64), 0);
+    assert_eq!(t_offset(16, 0, 4, 64), SUBTILE_SIZE);
+    assert_eq!(t_offset(16, 16, 4, 64), 2 * SUBTILE_SIZE);
+    assert_eq!(t_offset(0, 16, 4, 64), 3 * SUBTILE_SIZE);
+
+    // Odd tile rows run right to left.
+    assert_eq!(t_offset(32, 32, 4, 64), 2 * TILE_SIZE + 2 * SUBTILE_SIZE);
+    assert_eq!(t_offset(0, 32, 4, 64), 3 * TILE_SIZE + 2 * SUBTILE_SIZE);
+}
+
+#[test]
+fn test_tiling_round_trip() {
+    for &format in [TilingFormat::Raster, TilingFormat::TFormat, TilingFormat::LTFormat].iter() {
+        for &cpp in [1, 2, 4, 8].iter() {
+            let (width, height) = (75, 37);
+            let src: Vec<u8> = (0..width * height * cpp).map(|i| (i * 7 + i / 251) as u8).collect();
+
+            let tiled = raster_to_tiled(format, &src, width, height, cpp);
+            assert_eq!(tiled.len(), image_size(format, width, height, cpp));
+            assert_eq!(tiled_to_raster(format, &tiled, width, height, cpp), src);
+
+            let mut emu = QPUEmu::new(0x10000);
+            assert_eq!(upload_raster(&mut emu, 16, format, &src, width, height, cpp), Ok(()));
+            assert_eq!(&emu.mem[16..16 + tiled.len()], &tiled[..]);
+            assert_eq!(download_raster(&emu, 16, format, width, height, cpp), Ok(src));
+            assert!(download_raster(&emu, 0x10000 - 16, format, width, height, cpp).is_err());
+        }
+    }
+}
+
+#[test]
+fn test_tile_buffer_store_load() {
+    for &tiling in [TilingFormat::Raster, TilingFormat::TFormat, TilingFormat::LTFormat].iter() {
+        for &format in [ColourFormat::Rgba8888, ColourFormat::Rgb565].iter() {
+            let fb = FrameBuffer::new(64, 100, 70, tiling, format);
+            let mut data = vec![0u8; fb.addr as usize + fb.size()];
+            let memory_map = MemoryMap::flat(data.len());
+            let mut mem = MappedMemory { mem: &mut data, memory_map: &memory_map };
+            let mut tile_buffer = TileBuffer::new();
+
+            for tile_y in 0..2 {
+                for tile_x in 0..2 {
+                    tile_buffer.set_tile(tile_x, tile_y);
+                    for y in 0..TILE_HEIGHT as u32 {
+                        for x in 0..TILE_WIDTH as u32 {
+                            let (sx, sy) = (tile_x as u32 * 64 + x, tile_y as u32 * 64 + y);
+                            tile_buffer.write_colour(sx, sy, 0, 0xff00_0000 | (sy << 8) | (sx & 0xf8));
+                        }
+                    }
+                    tile_buffer.store_colour(&mut mem, &fb).unwrap();
+                }
+            }
+
+            let expected = |colour: u32| match format {
+                ColourFormat::Rgba8888 => colour,
+                ColourFormat::Rgb565 => rgb565_to_rgba8888(rgba8888_to_rgb565(colour)),
+            };
+            assert_eq!(fb.read_pixel(&mem, 99, 69), Ok(expected(0xff00_0000 | (69 << 8) | 96)));
+
+            let mut reloaded = TileBuffer::new();
+            reloaded.set_tile(1, 1);
+            reloaded.load_colour(&mem, &fb).unwrap();
+            assert_eq!(reloaded.read_colour(72, 66, 0), expected(0xff00_0000 | (66 << 8) | 72));
+        }
+    }
+}
+
+#[test]
+fn test_tile_buffer_depth_stencil() {
+    let mut tile_buffer = TileBuffer::new();
+    tile_buffer.clear_z_stencil(0x80_0000, 0);
+    tile_buffer.depth = DepthState { func: CompareFunc::Less, write_enable: true, early_z: true };
+
+    // Front: always pass, replace with 5 on z pass, increment on z fail.
+    tile_buffer.setup_stencil(1 << 30 | 2 << 25 | 3 << 22 | 1 << 19 | 7 << 16 | 0xff << 8 | 5);
+
+    assert!(!tile_buffer.early_z_test(1, 1, 0, 0x90_0000));
+    assert!(tile_buffer.early_z_test(1, 1, 0, 0x70_0000));
+
+    assert!(tile_buffer.test_sample(1, 1, 0, 0x70_0000, false));
+    assert_eq!(tile_buffer.read_z(1, 1, 0), 0x70_0000);
+    assert_eq!(tile_buffer.read_stencil(1, 1, 0), 5);
+
+    assert!(!tile_buffer.test_sample(1, 1, 0, 0x75_0000, false));
+    assert_eq!(tile_buffer.read_z(1, 1, 0), 0x70_0000);
+    assert_eq!(tile_buffer.read_stencil(1, 1, 0), 6);
+
+    // Back faces have no stencil test configured.
+    assert!(tile_buffer.test_sample(2, 1, 0, 0x10_0000, true));
+    assert_eq!(tile_buffer.read_stencil(2, 1, 0), 0);
+
+    // Front: pass only where stencil == 6, keep everything, write mask 0x0f.
+    tile_buffer.setup_stencil(1 << 30 | 1 << 25 | 1 << 22 | 1 << 19 | 2 << 16 | 0xff << 8 | 6);
+    tile_buffer.setup_stencil(0x0f);
+    tile_buffer.depth.func = CompareFunc::Always;
+    assert!(tile_buffer.test_sample(1, 1, 0, 0, false));
+    assert!(!tile_buffer.test_sample(2, 1, 0, 0, false));
+}
+
+#[test]
+fn test_tile_buffer_multisample_resolve() {
+    let fb = FrameBuffer::new(0, 40, 40, TilingFormat::LTFormat, ColourFormat::Rgba8888);
+    let mut data = vec![0u8; fb.size()];
+    let memory_map = MemoryMap::flat(data.len());
+    let mut mem = MappedMemory { mem: &mut data, memory_map: &memory_map };
+    let mut tile_buffer = TileBuffer::new();
+    tile_buffer.set_multisample(true);
+    tile_buffer.set_tile(1, 1);
+    tile_buffer.clear_colour(0xff00_0000);
+
+    tile_buffer.write_colour(33, 34, 0, 0xff00_00ff);
+    tile_buffer.write_colour(33, 34, 3, 0xff00_00ff);
+    tile_buffer.write_colour(39, 39, 2, 0xffff_ffff);
+    tile_buffer.store_colour(&mut mem, &fb).unwrap();
+
+    assert_eq!(fb.read_pixel(&mem, 33, 34), Ok(0xff00_0080));
+    assert_eq!(fb.read_pixel(&mem, 39, 39), Ok(0xff40_4040));
+    assert_eq!(fb.read_pixel(&mem, 32, 32), Ok(0xff00_0000));
+    assert_eq!(fb.read_pixel(&mem, 31, 31), Ok(0));
+}
+
+#[test]
+fn test_etc1_decode() {
+    // Individual mode, no flip: red left half, blue right half, table 0.
+    // Pixel (0, 0) uses modifier index 0 (+2), pixel (3, 3) uses index 1 (+8).
+    let block = [0xf0, 0x00, 0x0f, 0x00, 0x00, 0x00, 0x80, 0x00];
+    assert_eq!(etc1_decode_texel(&block, 0, 0), 0xff02_02ff);
+    assert_eq!(etc1_decode_texel(&block, 3, 3), 0xffff_0808);
+
+    // Differential mode with flip: base red 16, delta +3 on the bottom half.
+    let block = [0x83, 0x00, 0x00, 0x03, 0x00, 0x00, 0x00, 0x00];
+    assert_eq!(etc1_decode_texel(&block, 1, 1) & 0xff, (16 << 3 | 16 >> 2) + 2);
+    assert_eq!(etc1_decode_texel(&block, 1, 2) & 0xff, (19 << 3 | 19 >> 2) + 2);
+}
+
+#[test]
+fn test_texture_sample_etc1() {
+    // A 16x16 ETC1 texture is 4x4 blocks, stored in LT-format as two utiles of 2x4 blocks.
+    let config = TextureConfig::decode(0x1000 | 8 << 4, 16 << 20 | 16 << 8);
+    assert_eq!(config.tiling(), TilingFormat::LTFormat);
+
+    let mut mem = vec![0u8; 0x1000 + 16 * 8];
+    // Block (2, 1): second utile, second block row.
+    let offset = 0x1000 + 64 + 2 * 8;
+    mem[offset..offset + 8].copy_from_slice(&[0x80, 0x80, 0x80, 0x02, 0, 0, 0, 0]);
+
+    let texel = config.sample(&mem, 9.5 / 16.0, 5.5 / 16.0);
+    let expected = (16 << 3 | 16 >> 2) + 2;
+    assert_eq!(texel, 0xff00_0000 | expected << 16 | expected << 8 | expected);
+    assert_eq!(config.sample(&mem, 0.0, 0.0), 0xff02_0202);
+}
+
+#[test]
+fn test_trace() {
+    // Writes the uniform to the VPM row 0 and stores the row to the address in the next uniform.
+    let insts = assembler::assemble("
+        mov(r1, uniform)
+        ldi(vpmvcd_wr_setup, 0x00001a00)
+        mov(vpm, r1)
+        ldi(vpmvcd_wr_setup, 0x80814000)
+        mov(vpm_st_addr, uniform)
+        nop(sig='thread end')
+        nop()
+        nop()
+        nop()
+        nop()
+        nop()
+    ").unwrap();
+
+    let run = |format: TraceFormat, filter: TraceFilter| {
+        let tracer = Arc::new(Mutex::new(Tracer::new(vec![], format, filter)));
+        let mut emu = QPUEmu::new(0x1000);
+        for (addr, val) in [(0x100, 5), (0x104, 0x200), (0x108, 6), (0x10c, 0x300)].iter() {
+            emu.write_u32(*addr, *val).unwrap();
+        }
+        emu.set_hooks(Box::new(tracer.clone()));
+        emu.execute(&insts, &vec![0x100, 0x108], 2);
+        emu.execute(&insts, &vec![0x108], 1);
+        emu.take_hooks();
+        let out = tracer.lock().unwrap().get_ref().clone();
+        out
+    };
+
+    let text = String::from_utf8(run(TraceFormat::Text, TraceFilter::default())).unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    assert_eq!(lines[0], "qpu=0 thread=0 pc=0x00000000 inst=0x1002086715827d80 lanes=0xffff zf=0xffff nf=0x0000 cf=0x0000 mov(r1, uniform)");
+    assert_eq!(lines[1], format!("  write r1{}", " 0x00000005".repeat(16)));
+    assert_eq!(lines[2..6], ["  read mem 0x00000100 4",
+                             "qpu=0 thread=0 pc=0x00000008 inst=0xe00249f100001a00 lanes=0x0000 zf=0xffff nf=0x0000 cf=0x0000 ldi(vpmvcd_wr_setup, 0x00001a00)",
+                             "qpu=0 thread=0 pc=0x00000010 inst=0x10020c27159e7240 lanes=0xffff zf=0xffff nf=0x0000 cf=0x0000 mov(vpm, r1)",
+                             "  write vpm 0 0xffff"]);
+    assert_eq!(lines[7..11], ["qpu=0 thread=0 pc=0x00000020 inst=0x10021ca715827d80 lanes=0x0000 zf=0xffff nf=0x0000 cf=0x0000 mov(vpm_st_addr, uniform)",
+                              "  read mem 0x00000104 4",
+                              "  dma store 0x00000200",
+                              "  write mem 0x00000200 4"]);
+    let starts: Vec<&str> = lines.iter().filter(|line| line.contains("pc=0x00000000")).map(|line| &line[..16]).collect();
+    assert_eq!(starts, ["qpu=0 thread=0 p", "qpu=1 thread=1 p", "qpu=0 thread=2 p"]);
+
+    // The binary trace holds the same entries.
+    let entries = read_binary(&run(TraceFormat::Binary, TraceFilter::default())).unwrap();
+    assert_eq!(entries.len(), 24);
+    let mut decoded = vec![];
+    for entry in &entries {
+        entry.write_text(&mut decoded).unwrap();
+    }
+    assert_eq!(String::from_utf8(decoded).unwrap(), text);
+
+    let filter = TraceFilter { pcs: Some(0x10..0x28), qpus: Some(vec![1]) };
+    let entries = read_binary(&run(TraceFormat::Binary, filter)).unwrap();
+    let pcs: Vec<(usize, u32)> = entries.iter().map(|entry| (entry.thread, entry.pc)).collect();
+    assert_eq!(pcs, [(1, 0x10), (1, 0x18), (1, 0x20)]);
+    assert_eq!(entries[0].effects, [Effect::VpmWrite { row: 0, lanes: 0xffff }]);
+
+    assert_eq!(read_binary(b"QTRC\x02\0\0\0"), Err("The trace version 2 is not supported.".to_string()));
+    assert_eq!(read_binary(b"QTRC\x01\0\0\0\0"), Err("The trace is truncated.".to_string()));
+    assert_eq!(read_binary(b"trace"), Err("The file is not a binary trace.".to_string()));
+}
+
+#[test]
+fn test_v3d_registers() {
+    let mut v3d = V3D::new();
+
+    assert_eq!(&u32_to_u8x4(v3d.read(V3D_IDENT0))[0..3], b"V3D");
+    assert_eq!(get_bits_u32(v3d.read(V3D_IDENT1), 11, 8), 4);
+
+    v3d.write(V3D_SCRATCH, 0xdead_beef);
+    assert_eq!(v3d.read(V3D_SCRATCH), 0xdead_beef);
+
+    for idx in 0..USER_PROGRAM_QUEUE_SIZE as u32 + 1 {
+        v3d.write(V3D_SRQUA, 0x100);
+        v3d.write(V3D_SRQUL, 4);
+        v3d.write(V3D_SRQPC, 0x1000 + idx * 8);
+    }
+    assert_eq!(v3d.pending(), USER_PROGRAM_QUEUE_SIZE);
+    assert_eq!(v3d.read(V3D_SRQCS), 16 << 8 | SRQCS_ERROR | 16);
+    assert_eq!(v3d.read(V3D_SRQCS) >> 16 & 0xff, 0);
+
+    v3d.write(V3D_SRQCS, SRQCS_ERROR | SRQCS_CLEAR_COMPLETED | SRQCS_CLEAR_REQUESTED);
+    assert_eq!(v3d.read(V3D_SRQCS), 16);
+
+    // The cache and debug setup of a submission is accepted.
+    for reg in [V3D_L2CACTL, V3D_SLCACTL, V3D_DBCFG, 0xf00].iter() {
+        v3d.write(*reg, 0xffff_ffff);
+        assert_eq!(v3d.read(*reg), 0);
+    }
+}
@@ -0,0 +1,269 @@
+use crate::tiling::*;
+use crate::utils::*;
+
+// Texel formats selected by the TYPE field of the texture config uniforms.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TextureType {
+    Rgba8888,
+    Rgbx8888,
+    Rgba4444,
+    Rgba5551,
+    Rgb565,
+    Luminance,
+    Alpha,
+    LumAlpha,
+    Etc1,
+}
+
+impl TextureType {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits {
+            0 => TextureType::Rgba8888,
+            1 => TextureType::Rgbx8888,
+            2 => TextureType::Rgba4444,
+            3 => TextureType::Rgba5551,
+            4 => TextureType::Rgb565,
+            5 => TextureType::Luminance,
+            6 => TextureType::Alpha,
+            7 => TextureType::LumAlpha,
+            8 => TextureType::Etc1,
+            _ => panic!("Not implemented for texture type {}.", bits),
+        }
+    }
+
+    // Bytes per texel. ETC1 is addressed in units of 4x4 blocks of 8 bytes.
+    pub fn bytes_per_texel(&self) -> usize {
+        match self {
+            TextureType::Rgba8888 | TextureType::Rgbx8888 => 4,
+            TextureType::Rgba4444 | TextureType::Rgba5551 | TextureType::Rgb565 | TextureType::LumAlpha => 2,
+            TextureType::Luminance | TextureType::Alpha => 1,
+            TextureType::Etc1 => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WrapMode {
+    Repeat,
+    Clamp,
+    Mirror,
+    Border,
+}
+
+impl WrapMode {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits & 0x3 {
+            0 => WrapMode::Repeat,
+            1 => WrapMode::Clamp,
+            2 => WrapMode::Mirror,
+            _ => WrapMode::Border,
+        }
+    }
+
+    // Maps an integer texel coordinate into [0, size). Returns None for the border colour.
+    fn apply(&self, coord: i64, size: usize) -> Option<usize> {
+        let size = size as i64;
+        match self {
+            WrapMode::Repeat => Some(coord.rem_euclid(size) as usize),
+            WrapMode::Clamp => Some(coord.clamp(0, size - 1) as usize),
+            WrapMode::Mirror => {
+                let period = coord.rem_euclid(size * 2);
+                Some(if period < size { period } else { size * 2 - 1 - period } as usize)
+            },
+            WrapMode::Border => {
+                if coord < 0 || coord >= size {
+                    None
+                } else {
+                    Some(coord as usize)
+                }
+            },
+        }
+    }
+}
+
+// Texture configuration read from the uniform stream for a 2D lookup.
+//
+// Parameter 0: 31:12 base address, 8 flip y, 7:4 type, 3:0 mip levels.
+// Parameter 1: 31 type bit 4, 30:20 height, 18:8 width, 3:2 wrap t, 1:0 wrap s.
+// A width or height of 0 means 2048. Only the base level is sampled, with nearest filtering.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TextureConfig {
+    pub addr: usize,
+    pub flip_y: bool,
+    pub texture_type: TextureType,
+    pub width: usize,
+    pub height: usize,
+    pub wrap_s: WrapMode,
+    pub wrap_t: WrapMode,
+}
+
+impl TextureConfig {
+    pub fn decode(param0: u32, param1: u32) -> Self {
+        let type_bits = get_bits_u32(param1, 31, 31) << 4 | get_bits_u32(param0, 7, 4);
+        let height = get_bits_u32(param1, 30, 20) as usize;
+        let width = get_bits_u32(param1, 18, 8) as usize;
+
+        TextureConfig {
+            addr: (get_bits_u32(param0, 31, 12) << 12) as usize,
+            flip_y: get_bits_u32(param0, 8, 8) != 0,
+            texture_type: TextureType::from_bits(type_bits),
+            width: if width == 0 { 2048 } else { width },
+            height: if height == 0 { 2048 } else { height },
+            wrap_s: WrapMode::from_bits(get_bits_u32(param1, 1, 0)),
+            wrap_t: WrapMode::from_bits(get_bits_u32(param1, 3, 2)),
+        }
+    }
+
+    // Textures are stored in LT-format when they are at most 4 utiles wide or high,
+    // and in T-format otherwise.
+    pub fn tiling(&self) -> TilingFormat {
+        let cpp = self.texture_type.bytes_per_texel();
+        let (width, height) = self.addressed_size();
+        if width <= 4 * utile_width(cpp) || height <= 4 * utile_height(cpp) {
+            TilingFormat::LTFormat
+        } else {
+            TilingFormat::TFormat
+        }
+    }
+
+    // Size of the base level in bytes.
+    pub fn size(&self) -> usize {
+        let (width, height) = self.addressed_size();
+        image_size(self.tiling(), width, height, self.texture_type.bytes_per_texel())
+    }
+
+    // Size in addressing units, which are blocks for ETC1 and texels otherwise.
+    fn addressed_size(&self) -> (usize, usize) {
+        if self.texture_type == TextureType::Etc1 {
+            (self.width.div_ceil(4), self.height.div_ceil(4))
+        } else {
+            (self.width, self.height)
+        }
+    }
+
+    fn unit_addr(&self, x: usize, y: usize) -> usize {
+        let cpp = self.texture_type.bytes_per_texel();
+        let tiling = self.tiling();
+        let (width, height) = self.addressed_size();
+        let (padded_w, _) = padded_size(tiling, width, height, cpp);
+        self.addr + pixel_offset(tiling, x, y, cpp, padded_w)
+    }
+
+    // Fetches the texel (x, y) and returns it with red in bits 7:0 and alpha in bits 31:24.
+    pub fn fetch(&self, mem: &[u8], x: usize, y: usize) -> u32 {
+        if self.texture_type == TextureType::Etc1 {
+            let addr = self.unit_addr(x / 4, y / 4);
+            let mut block = [0u8; 8];
+            block.copy_from_slice(&mem[addr..addr + 8]);
+            return etc1_decode_texel(&block, x % 4, y % 4);
+        }
+
+        let addr = self.unit_addr(x, y);
+        let expand4 = |v: u32| v << 4 | v;
+        let expand5 = |v: u32| v << 3 | v >> 2;
+        let expand6 = |v: u32| v << 2 | v >> 4;
+        let rgba = |r: u32, g: u32, b: u32, a: u32| a << 24 | b << 16 | g << 8 | r;
+
+        match self.texture_type {
+            TextureType::Rgba8888 => {
+                u32::from_le_bytes([mem[addr], mem[addr + 1], mem[addr + 2], mem[addr + 3]])
+            },
+            TextureType::Rgbx8888 => {
+                u32::from_le_bytes([mem[addr], mem[addr + 1], mem[addr + 2], 0xff])
+            },
+            TextureType::Rgba4444 => {
+                let v = u16::from_le_bytes([mem[addr], mem[addr + 1]]) as u32;
+                rgba(expand4(v >> 12), expand4((v >> 8) & 0xf), expand4((v >> 4) & 0xf), expand4(v & 0xf))
+            },
+            TextureType::Rgba5551 => {
+                let v = u16::from_le_bytes([mem[addr], mem[addr + 1]]) as u32;
+                rgba(expand5(v >> 11), expand5((v >> 6) & 0x1f), expand5((v >> 1) & 0x1f), (v & 1) * 0xff)
+            },
+            TextureType::Rgb565 => {
+                let v = u16::from_le_bytes([mem[addr], mem[addr + 1]]) as u32;
+                rgba(expand5(v >> 11), expand6((v >> 5) & 0x3f), expand5(v & 0x1f), 0xff)
+            },
+            TextureType::Luminance => {
+                let l = mem[addr] as u32;
+                rgba(l, l, l, 0xff)
+            },
+            TextureType::Alpha => rgba(0, 0, 0, mem[addr] as u32),
+            TextureType::LumAlpha => {
+                let l = mem[addr] as u32;
+                rgba(l, l, l, mem[addr + 1] as u32)
+            },
+            TextureType::Etc1 => unreachable!(),
+        }
+    }
+
+    // Samples the texture at normalized coordinates with nearest filtering.
+    pub fn sample(&self, mem: &[u8], s: f32, t: f32) -> u32 {
+        let t = if self.flip_y { 1.0 - t } else { t };
+        let x = (s * self.width as f32).floor() as i64;
+        let y = (t * self.height as f32).floor() as i64;
+
+        match (self.wrap_s.apply(x, self.width), self.wrap_t.apply(y, self.height)) {
+            (Some(x), Some(y)) => self.fetch(mem, x, y),
+            _ => 0,
+        }
+    }
+}
+
+const ETC1_MODIFIERS: [[i32; 4]; 8] = [
+    [2, 8, -2, -8],
+    [5, 17, -5, -17],
+    [9, 29, -9, -29],
+    [13, 42, -13, -42],
+    [18, 60, -18, -60],
+    [24, 80, -24, -80],
+    [33, 106, -33, -106],
+    [47, 183, -47, -183],
+];
+
+// Decodes the texel (x, y) of an ETC1 block stored in big endian byte order as in PKM files.
+// Returns the colour with red in bits 7:0 and an opaque alpha.
+pub fn etc1_decode_texel(block: &[u8; 8], x: usize, y: usize) -> u32 {
+    let high = u32::from_be_bytes([block[0], block[1], block[2], block[3]]);
+    let low = u32::from_be_bytes([block[4], block[5], block[6], block[7]]);
+
+    let diff = get_bits_u32(high, 1, 1) != 0;
+    let flip = get_bits_u32(high, 0, 0) != 0;
+
+    // The block is split in two 2x4 (or 4x2 if flipped) sub-blocks.
+    let second = if flip { y >= 2 } else { x >= 2 };
+
+    let channel = |shift: usize| -> u32 {
+        if diff {
+            let base = get_bits_u32(high, shift + 7, shift + 3);
+            let delta = sign_extend(get_bits_u32(high, shift + 2, shift), 3) as i32;
+            let value = if second { (base as i32 + delta) as u32 & 0x1f } else { base };
+            value << 3 | value >> 2
+        } else {
+            let value = if second {
+                get_bits_u32(high, shift + 3, shift)
+            } else {
+                get_bits_u32(high, shift + 7, shift + 4)
+            };
+            value << 4 | value
+        }
+    };
+
+    let table = if second {
+        get_bits_u32(high, 4, 2)
+    } else {
+        get_bits_u32(high, 7, 5)
+    } as usize;
+
+    // Pixel indices are in column-major order.
+    let bit = x * 4 + y;
+    let lsb = get_bits_u32(low, bit, bit);
+    let msb = get_bits_u32(low, bit + 16, bit + 16);
+    let modifier = ETC1_MODIFIERS[table][(msb << 1 | lsb) as usize];
+
+    let apply = |base: u32| (base as i32 + modifier).clamp(0, 255) as u32;
+
+    let r = apply(channel(24));
+    let g = apply(channel(16));
+    let b = apply(channel(8));
+    0xff00_0000 | b << 16 | g << 8 | r
+}
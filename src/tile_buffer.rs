@@ -0,0 +1,498 @@
+use crate::tiling::*;
+use crate::utils::*;
+use crate::memory_map::{Memory, MemoryFault};
+
+pub const TILE_WIDTH: usize = 64;
+pub const TILE_HEIGHT: usize = 64;
+
+// Colours are held in the tile buffer as 32bit words with red in bits 7:0, green in bits 15:8,
+// blue in bits 23:16 and alpha in bits 31:24.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ColourFormat {
+    Rgba8888,
+    Rgb565,
+}
+
+impl ColourFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            ColourFormat::Rgba8888 => 4,
+            ColourFormat::Rgb565 => 2,
+        }
+    }
+}
+
+pub fn rgba8888_to_rgb565(colour: u32) -> u16 {
+    let r = (colour & 0xff) >> 3;
+    let g = ((colour >> 8) & 0xff) >> 2;
+    let b = ((colour >> 16) & 0xff) >> 3;
+    (r << 11 | g << 5 | b) as u16
+}
+
+pub fn rgb565_to_rgba8888(colour: u16) -> u32 {
+    let r5 = ((colour >> 11) & 0x1f) as u32;
+    let g6 = ((colour >> 5) & 0x3f) as u32;
+    let b5 = (colour & 0x1f) as u32;
+
+    let r = (r5 << 3) | (r5 >> 2);
+    let g = (g6 << 2) | (g6 >> 4);
+    let b = (b5 << 3) | (b5 >> 2);
+    0xff00_0000 | b << 16 | g << 8 | r
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CompareFunc {
+    Never,
+    Less,
+    Equal,
+    LessEqual,
+    Greater,
+    NotEqual,
+    GreaterEqual,
+    Always,
+}
+
+impl CompareFunc {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits & 0x7 {
+            0 => CompareFunc::Never,
+            1 => CompareFunc::Less,
+            2 => CompareFunc::Equal,
+            3 => CompareFunc::LessEqual,
+            4 => CompareFunc::Greater,
+            5 => CompareFunc::NotEqual,
+            6 => CompareFunc::GreaterEqual,
+            _ => CompareFunc::Always,
+        }
+    }
+
+    // Compares the incoming value against the value held in the tile buffer.
+    pub fn test(&self, value: u32, stored: u32) -> bool {
+        match self {
+            CompareFunc::Never => false,
+            CompareFunc::Less => value < stored,
+            CompareFunc::Equal => value == stored,
+            CompareFunc::LessEqual => value <= stored,
+            CompareFunc::Greater => value > stored,
+            CompareFunc::NotEqual => value != stored,
+            CompareFunc::GreaterEqual => value >= stored,
+            CompareFunc::Always => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StencilOp {
+    Zero,
+    Keep,
+    Replace,
+    Incr,
+    Decr,
+    Invert,
+    IncrWrap,
+    DecrWrap,
+}
+
+impl StencilOp {
+    pub fn from_bits(bits: u32) -> Self {
+        match bits & 0x7 {
+            0 => StencilOp::Zero,
+            1 => StencilOp::Keep,
+            2 => StencilOp::Replace,
+            3 => StencilOp::Incr,
+            4 => StencilOp::Decr,
+            5 => StencilOp::Invert,
+            6 => StencilOp::IncrWrap,
+            _ => StencilOp::DecrWrap,
+        }
+    }
+
+    pub fn apply(&self, stencil: u8, reference: u8) -> u8 {
+        match self {
+            StencilOp::Zero => 0,
+            StencilOp::Keep => stencil,
+            StencilOp::Replace => reference,
+            StencilOp::Incr => stencil.saturating_add(1),
+            StencilOp::Decr => stencil.saturating_sub(1),
+            StencilOp::Invert => !stencil,
+            StencilOp::IncrWrap => stencil.wrapping_add(1),
+            StencilOp::DecrWrap => stencil.wrapping_sub(1),
+        }
+    }
+}
+
+// Stencil test of one face, as configured through TLB_STENCIL_SETUP.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct StencilState {
+    pub func: CompareFunc,
+    pub reference: u8,
+    pub value_mask: u8,
+    pub write_mask: u8,
+    pub fail_op: StencilOp,
+    pub zfail_op: StencilOp,
+    pub zpass_op: StencilOp,
+}
+
+impl StencilState {
+    pub fn new() -> Self {
+        StencilState {
+            func: CompareFunc::Always,
+            reference: 0,
+            value_mask: 0xff,
+            write_mask: 0xff,
+            fail_op: StencilOp::Keep,
+            zfail_op: StencilOp::Keep,
+            zpass_op: StencilOp::Keep,
+        }
+    }
+
+    fn update(&self, stencil: u8, op: StencilOp) -> u8 {
+        let updated = op.apply(stencil, self.reference);
+        (updated & self.write_mask) | (stencil & !self.write_mask)
+    }
+}
+
+impl Default for StencilState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Depth test configuration. Z values are 24bit unsigned integers.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct DepthState {
+    pub func: CompareFunc,
+    pub write_enable: bool,
+    pub early_z: bool,
+}
+
+impl DepthState {
+    pub fn new() -> Self {
+        DepthState {
+            func: CompareFunc::Always,
+            write_enable: false,
+            early_z: false,
+        }
+    }
+}
+
+impl Default for DepthState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub const Z_MASK: u32 = 0xff_ffff;
+
+// A colour buffer in memory which tiles are stored to and loaded from.
+#[derive(Debug, Clone)]
+pub struct FrameBuffer {
+    // Bus address of the buffer.
+    pub addr: u32,
+    pub width: usize,
+    pub height: usize,
+    pub tiling: TilingFormat,
+    pub format: ColourFormat,
+}
+
+impl FrameBuffer {
+    pub fn new(addr: u32, width: usize, height: usize, tiling: TilingFormat, format: ColourFormat) -> Self {
+        FrameBuffer {
+            addr,
+            width,
+            height,
+            tiling,
+            format,
+        }
+    }
+
+    // Size of the buffer in bytes including the padding required by its layout.
+    pub fn size(&self) -> usize {
+        image_size(self.tiling, self.width, self.height, self.format.bytes_per_pixel())
+    }
+
+    fn pixel_addr(&self, x: usize, y: usize) -> u32 {
+        let cpp = self.format.bytes_per_pixel();
+        let (padded_w, _) = padded_size(self.tiling, self.width, self.height, cpp);
+        self.addr.wrapping_add(pixel_offset(self.tiling, x, y, cpp, padded_w) as u32)
+    }
+
+    pub fn read_pixel<M: Memory + ?Sized>(&self, mem: &M, x: usize, y: usize) -> Result<u32, MemoryFault> {
+        let addr = self.pixel_addr(x, y);
+        match self.format {
+            ColourFormat::Rgba8888 => {
+                let mut bytes = [0u8; 4];
+                mem.read_bytes(addr, &mut bytes)?;
+                Ok(u32::from_le_bytes(bytes))
+            },
+            ColourFormat::Rgb565 => {
+                let mut bytes = [0u8; 2];
+                mem.read_bytes(addr, &mut bytes)?;
+                Ok(rgb565_to_rgba8888(u16::from_le_bytes(bytes)))
+            },
+        }
+    }
+
+    pub fn write_pixel<M: Memory + ?Sized>(&self, mem: &mut M, x: usize, y: usize, colour: u32) -> Result<(), MemoryFault> {
+        let addr = self.pixel_addr(x, y);
+        match self.format {
+            ColourFormat::Rgba8888 => mem.write_bytes(addr, &colour.to_le_bytes()),
+            ColourFormat::Rgb565 => mem.write_bytes(addr, &rgba8888_to_rgb565(colour).to_le_bytes()),
+        }
+    }
+}
+
+// In 4x multisample mode a tile covers 32x32 pixels with 4 samples each, held in the same storage.
+pub const MS_SAMPLES: usize = 4;
+
+pub struct TileBuffer {
+    tile_x: usize,
+    tile_y: usize,
+    multisample: bool,
+    colour: Vec<u32>,
+    z: Vec<u32>,
+    stencil: Vec<u8>,
+    pub depth: DepthState,
+    pub stencil_front: Option<StencilState>,
+    pub stencil_back: Option<StencilState>,
+}
+
+impl Default for TileBuffer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TileBuffer {
+    pub fn new() -> Self {
+        TileBuffer {
+            tile_x: 0,
+            tile_y: 0,
+            multisample: false,
+            colour: vec![0; TILE_WIDTH * TILE_HEIGHT],
+            z: vec![Z_MASK; TILE_WIDTH * TILE_HEIGHT],
+            stencil: vec![0; TILE_WIDTH * TILE_HEIGHT],
+            depth: DepthState::new(),
+            stencil_front: None,
+            stencil_back: None,
+        }
+    }
+
+    // Selects the tile which is rendered, loaded and stored.
+    pub fn set_tile(&mut self, tile_x: usize, tile_y: usize) {
+        self.tile_x = tile_x;
+        self.tile_y = tile_y;
+    }
+
+    pub fn tile(&self) -> (usize, usize) {
+        (self.tile_x, self.tile_y)
+    }
+
+    // Switches between 64x64 single sample and 32x32 4x multisample tiles.
+    // The contents are not converted, so the buffer should be cleared or loaded afterwards.
+    pub fn set_multisample(&mut self, multisample: bool) {
+        self.multisample = multisample;
+    }
+
+    pub fn multisample(&self) -> bool {
+        self.multisample
+    }
+
+    pub fn samples(&self) -> usize {
+        if self.multisample { MS_SAMPLES } else { 1 }
+    }
+
+    pub fn width(&self) -> usize {
+        if self.multisample { TILE_WIDTH / 2 } else { TILE_WIDTH }
+    }
+
+    pub fn height(&self) -> usize {
+        if self.multisample { TILE_HEIGHT / 2 } else { TILE_HEIGHT }
+    }
+
+    pub fn clear_colour(&mut self, colour: u32) {
+        for value in self.colour.iter_mut() {
+            *value = colour;
+        }
+    }
+
+    pub fn clear_z_stencil(&mut self, z: u32, stencil: u8) {
+        for value in self.z.iter_mut() {
+            *value = z & Z_MASK;
+        }
+        for value in self.stencil.iter_mut() {
+            *value = stencil;
+        }
+    }
+
+    // Applies a TLB_STENCIL_SETUP word.
+    //
+    // If either of bits 31:30 is set, the word configures the back (bit 31) and/or front (bit 30)
+    // face and enables its stencil test:
+    //   27:25 z pass op, 24:22 z fail op, 21:19 stencil fail op, 18:16 function,
+    //   15:8 value mask, 7:0 reference value.
+    // Otherwise bits 15:8 and 7:0 are the write masks of the back and front faces.
+    pub fn setup_stencil(&mut self, config: u32) {
+        let front = get_bits_u32(config, 30, 30) != 0;
+        let back = get_bits_u32(config, 31, 31) != 0;
+
+        if !front && !back {
+            let front_mask = (config & 0xff) as u8;
+            let back_mask = ((config >> 8) & 0xff) as u8;
+            self.stencil_front.get_or_insert_with(StencilState::new).write_mask = front_mask;
+            self.stencil_back.get_or_insert_with(StencilState::new).write_mask = back_mask;
+            return;
+        }
+
+        let faces = [(front, &mut self.stencil_front), (back, &mut self.stencil_back)];
+        for (selected, state) in faces {
+            if !selected {
+                continue;
+            }
+            let state = state.get_or_insert_with(StencilState::new);
+            state.reference = (config & 0xff) as u8;
+            state.value_mask = ((config >> 8) & 0xff) as u8;
+            state.func = CompareFunc::from_bits(config >> 16);
+            state.fail_op = StencilOp::from_bits(config >> 19);
+            state.zfail_op = StencilOp::from_bits(config >> 22);
+            state.zpass_op = StencilOp::from_bits(config >> 25);
+        }
+    }
+
+    pub fn read_z(&self, x: u32, y: u32, sample: usize) -> u32 {
+        self.z[self.index_checked(x, y, sample)]
+    }
+
+    pub fn read_stencil(&self, x: u32, y: u32, sample: usize) -> u8 {
+        self.stencil[self.index_checked(x, y, sample)]
+    }
+
+    // Depth test without updating the tile buffer, performed before the fragment shader runs.
+    // Fragments are only rejected here if early Z is enabled.
+    pub fn early_z_test(&self, x: u32, y: u32, sample: usize, z: u32) -> bool {
+        if !self.depth.early_z {
+            return true;
+        }
+        self.depth.func.test(z & Z_MASK, self.read_z(x, y, sample))
+    }
+
+    // Runs the stencil and depth tests for a sample and updates stencil and Z.
+    // Returns whether the sample passed both tests.
+    pub fn test_sample(&mut self, x: u32, y: u32, sample: usize, z: u32, back_facing: bool) -> bool {
+        let idx = self.index_checked(x, y, sample);
+        let z = z & Z_MASK;
+        let stencil_state = if back_facing { self.stencil_back } else { self.stencil_front };
+
+        if let Some(state) = stencil_state {
+            let reference = (state.reference & state.value_mask) as u32;
+            let stencil = (self.stencil[idx] & state.value_mask) as u32;
+            if !state.func.test(reference, stencil) {
+                self.stencil[idx] = state.update(self.stencil[idx], state.fail_op);
+                return false;
+            }
+        }
+
+        if !self.depth.func.test(z, self.z[idx]) {
+            if let Some(state) = stencil_state {
+                self.stencil[idx] = state.update(self.stencil[idx], state.zfail_op);
+            }
+            return false;
+        }
+
+        if let Some(state) = stencil_state {
+            self.stencil[idx] = state.update(self.stencil[idx], state.zpass_op);
+        }
+        if self.depth.write_enable {
+            self.z[idx] = z;
+        }
+        true
+    }
+
+    // Converts screen coordinates and a sample number into an index of the current tile.
+    fn index(&self, x: u32, y: u32, sample: usize) -> Option<usize> {
+        let x = (x as usize).checked_sub(self.tile_x * self.width())?;
+        let y = (y as usize).checked_sub(self.tile_y * self.height())?;
+
+        if x < self.width() && y < self.height() && sample < self.samples() {
+            Some((y * self.width() + x) * self.samples() + sample)
+        } else {
+            None
+        }
+    }
+
+    // Whether the pixel is in the current tile.
+    pub fn contains(&self, x: u32, y: u32) -> bool {
+        self.index(x, y, 0).is_some()
+    }
+
+    fn index_checked(&self, x: u32, y: u32, sample: usize) -> usize {
+        match self.index(x, y, sample) {
+            Some(idx) => idx,
+            None => panic!("The sample {} of pixel ({}, {}) is out of the current tile.", sample, x, y),
+        }
+    }
+
+    pub fn read_colour(&self, x: u32, y: u32, sample: usize) -> u32 {
+        self.colour[self.index_checked(x, y, sample)]
+    }
+
+    pub fn write_colour(&mut self, x: u32, y: u32, sample: usize, colour: u32) {
+        let idx = self.index_checked(x, y, sample);
+        self.colour[idx] = colour;
+    }
+
+    // Colour of a pixel with its samples averaged.
+    pub fn resolve_colour(&self, x: u32, y: u32) -> u32 {
+        let samples = self.samples() as u32;
+        let mut result = 0;
+
+        for shift in [0, 8, 16, 24] {
+            let mut sum = 0;
+            for sample in 0..self.samples() {
+                sum += (self.read_colour(x, y, sample) >> shift) & 0xff;
+            }
+            result |= ((sum + samples / 2) / samples) << shift;
+        }
+        result
+    }
+
+    // Writes the current tile back to the frame buffer, resolving multisampled pixels.
+    // Pixels outside the frame buffer are dropped.
+    pub fn store_colour<M: Memory + ?Sized>(&self, mem: &mut M, fb: &FrameBuffer) -> Result<(), MemoryFault> {
+        for ty in 0..self.height() {
+            let y = self.tile_y * self.height() + ty;
+            if y >= fb.height {
+                break;
+            }
+            for tx in 0..self.width() {
+                let x = self.tile_x * self.width() + tx;
+                if x >= fb.width {
+                    break;
+                }
+                fb.write_pixel(mem, x, y, self.resolve_colour(x as u32, y as u32))?;
+            }
+        }
+        Ok(())
+    }
+
+    // Fills the current tile from the frame buffer. Every sample of a pixel gets the same colour.
+    pub fn load_colour<M: Memory + ?Sized>(&mut self, mem: &M, fb: &FrameBuffer) -> Result<(), MemoryFault> {
+        for ty in 0..self.height() {
+            let y = self.tile_y * self.height() + ty;
+            if y >= fb.height {
+                break;
+            }
+            for tx in 0..self.width() {
+                let x = self.tile_x * self.width() + tx;
+                if x >= fb.width {
+                    break;
+                }
+                let colour = fb.read_pixel(mem, x, y)?;
+                for sample in 0..self.samples() {
+                    self.write_colour(x as u32, y as u32, sample, colour);
+                }
+            }
+        }
+        Ok(())
+    }
+}
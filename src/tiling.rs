@@ -0,0 +1,172 @@
+// Address calculation and conversion for the memory layouts used by the texture unit and the tile buffer.
+//
+// A micro-tile (utile) is a 64 byte block of pixels in raster order. Its shape depends on
+// the pixel size: 2x4 pixels for 64bpp, 4x4 for 32bpp, 8x4 for 16bpp and 8x8 for 8bpp.
+//
+// LT-format stores utiles in raster order.
+//
+// T-format groups 4x4 utiles into 1KB sub-tiles and 2x2 sub-tiles into 4KB tiles. Tiles are
+// stored in raster order, but every odd tile row runs right to left and uses a different
+// sub-tile order.
+
+use crate::memory_map::MemoryFault;
+use crate::processor::QPUEmu;
+
+pub const UTILE_SIZE: usize = 64;
+pub const SUBTILE_SIZE: usize = 1024;
+pub const TILE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TilingFormat {
+    Raster,
+    TFormat,
+    LTFormat,
+}
+
+pub fn utile_width(cpp: usize) -> usize {
+    match cpp {
+        1 => 8,
+        2 => 8,
+        4 => 4,
+        8 => 2,
+        _ => panic!("Unsupported pixel size."),
+    }
+}
+
+pub fn utile_height(cpp: usize) -> usize {
+    match cpp {
+        1 => 8,
+        2 => 4,
+        4 => 4,
+        8 => 4,
+        _ => panic!("Unsupported pixel size."),
+    }
+}
+
+// Width and height in pixels after padding to the allocation unit of the layout.
+pub fn padded_size(format: TilingFormat, width: usize, height: usize, cpp: usize) -> (usize, usize) {
+    let (align_w, align_h) = match format {
+        TilingFormat::Raster => (1, 1),
+        TilingFormat::LTFormat => (utile_width(cpp), utile_height(cpp)),
+        TilingFormat::TFormat => (utile_width(cpp) * 8, utile_height(cpp) * 8),
+    };
+
+    (width.div_ceil(align_w) * align_w, height.div_ceil(align_h) * align_h)
+}
+
+// Size of the image in bytes, including padding.
+pub fn image_size(format: TilingFormat, width: usize, height: usize, cpp: usize) -> usize {
+    let (padded_w, padded_h) = padded_size(format, width, height, cpp);
+    padded_w * padded_h * cpp
+}
+
+pub fn raster_offset(x: usize, y: usize, cpp: usize, width: usize) -> usize {
+    (y * width + x) * cpp
+}
+
+fn utile_offset(x: usize, y: usize, cpp: usize) -> usize {
+    let utile_w = utile_width(cpp);
+    let utile_h = utile_height(cpp);
+
+    ((y % utile_h) * utile_w + (x % utile_w)) * cpp
+}
+
+pub fn lt_offset(x: usize, y: usize, cpp: usize, width: usize) -> usize {
+    let utile_w = utile_width(cpp);
+    let utile_h = utile_height(cpp);
+    let utile_stride = width.div_ceil(utile_w);
+
+    let utile_x = x / utile_w;
+    let utile_y = y / utile_h;
+
+    (utile_y * utile_stride + utile_x) * UTILE_SIZE + utile_offset(x, y, cpp)
+}
+
+pub fn t_offset(x: usize, y: usize, cpp: usize, width: usize) -> usize {
+    // Sub-tile index in a 4KB tile, indexed by [subtile_x][subtile_y].
+    const EVEN_ROW_ORDER: [[usize; 2]; 2] = [[0, 3], [1, 2]];
+    const ODD_ROW_ORDER: [[usize; 2]; 2] = [[2, 1], [3, 0]];
+
+    let utile_w = utile_width(cpp);
+    let utile_h = utile_height(cpp);
+    let tile_stride = width.div_ceil(utile_w * 8);
+
+    let utile_x = x / utile_w;
+    let utile_y = y / utile_h;
+
+    let tile_y = utile_y / 8;
+    let odd_row = tile_y % 2 == 1;
+    let tile_x = if odd_row {
+        tile_stride - utile_x / 8 - 1
+    } else {
+        utile_x / 8
+    };
+
+    let subtile_x = (utile_x / 4) % 2;
+    let subtile_y = (utile_y / 4) % 2;
+    let subtile = if odd_row {
+        ODD_ROW_ORDER[subtile_x][subtile_y]
+    } else {
+        EVEN_ROW_ORDER[subtile_x][subtile_y]
+    };
+
+    let utile = (utile_y % 4) * 4 + (utile_x % 4);
+
+    (tile_y * tile_stride + tile_x) * TILE_SIZE
+        + subtile * SUBTILE_SIZE
+        + utile * UTILE_SIZE
+        + utile_offset(x, y, cpp)
+}
+
+// Byte offset of the pixel (x, y) from the start of an image with the given layout.
+pub fn pixel_offset(format: TilingFormat, x: usize, y: usize, cpp: usize, width: usize) -> usize {
+    match format {
+        TilingFormat::Raster => raster_offset(x, y, cpp, width),
+        TilingFormat::TFormat => t_offset(x, y, cpp, width),
+        TilingFormat::LTFormat => lt_offset(x, y, cpp, width),
+    }
+}
+
+// Converts a raster image into the given layout. The source rows are `width * cpp` bytes
+// long and the destination is padded as returned by `image_size`.
+pub fn raster_to_tiled(format: TilingFormat, src: &[u8], width: usize, height: usize, cpp: usize) -> Vec<u8> {
+    let (padded_w, _) = padded_size(format, width, height, cpp);
+    let mut dst = vec![0u8; image_size(format, width, height, cpp)];
+
+    for y in 0..height {
+        for x in 0..width {
+            let src_offset = raster_offset(x, y, cpp, width);
+            let dst_offset = pixel_offset(format, x, y, cpp, padded_w);
+            dst[dst_offset..dst_offset + cpp].copy_from_slice(&src[src_offset..src_offset + cpp]);
+        }
+    }
+    dst
+}
+
+// Converts an image in the given layout back into a raster image without padding.
+pub fn tiled_to_raster(format: TilingFormat, src: &[u8], width: usize, height: usize, cpp: usize) -> Vec<u8> {
+    let (padded_w, _) = padded_size(format, width, height, cpp);
+    let mut dst = vec![0u8; width * height * cpp];
+
+    for y in 0..height {
+        for x in 0..width {
+            let src_offset = pixel_offset(format, x, y, cpp, padded_w);
+            let dst_offset = raster_offset(x, y, cpp, width);
+            dst[dst_offset..dst_offset + cpp].copy_from_slice(&src[src_offset..src_offset + cpp]);
+        }
+    }
+    dst
+}
+
+// Writes a raster image into emulator memory at the bus address in the given layout.
+pub fn upload_raster(emu: &mut QPUEmu, addr: u32, format: TilingFormat, src: &[u8], width: usize, height: usize, cpp: usize) -> Result<(), MemoryFault> {
+    let tiled = raster_to_tiled(format, src, width, height, cpp);
+    emu.write_bytes(addr, &tiled)
+}
+
+// Reads an image in the given layout from emulator memory at the bus address as a raster image.
+pub fn download_raster(emu: &QPUEmu, addr: u32, format: TilingFormat, width: usize, height: usize, cpp: usize) -> Result<Vec<u8>, MemoryFault> {
+    let mut tiled = vec![0u8; image_size(format, width, height, cpp)];
+    emu.read_bytes(addr, &mut tiled)?;
+    Ok(tiled_to_raster(format, &tiled, width, height, cpp))
+}
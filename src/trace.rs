@@ -0,0 +1,344 @@
+// Trace of the executed instructions with their effects, for comparing runs with the hardware.
+//
+// The QPU of an entry is the thread index of QPUEmu::start, and the thread counts the threads
+// run since the tracer was set, so that the threads of separate launches are told apart. The
+// PC is the bus address of the instruction. The lanes are the elements which wrote a register
+// or the VPM, and the flags are the ones after the instruction, one bit per element.
+//
+// Text format, a line per instruction followed by a line per effect:
+//
+//   qpu=0 thread=0 pc=0x00000000 inst=0x1002086715827d80 lanes=0xffff zf=0x0000 nf=0x0000 cf=0x0000 mov(r1, uniform)
+//     write r1 0x00000005 ... 0x00000005    16 elements, '-' for the ones not written
+//     read mem 0x00000100 4                 memory address and length in bytes
+//     write mem 0x00000200 64
+//     read vpm 2 0xffff                     VPM row and elements
+//     write vpm 2 0x0001
+//     dma load 0x00000200                   VPM DMA with its memory address
+//     dma store 0x00000200
+//
+// Binary format, little endian, starting with "QTRC" and the version 1 as u32:
+//
+//   entry   qpu u32, thread u32, pc u32, inst u64, lanes u16, zf u16, nf u16, cf u16,
+//           number of writes u8, number of effects u8, writes..., effects...
+//   write   register u8 (r0-r5: 0-5, ra0-ra31: 32-63, rb0-rb31: 64-95), elements u16,
+//           a u32 per element written
+//   effect  kind u8 (read mem 0, write mem 1, read vpm 2, write vpm 3, dma load 4,
+//           dma store 5), address or row u32, length or elements u32 (0 for DMA)
+
+use crate::disassembler;
+use crate::hooks::{DmaDirection, Hooks, Register};
+use crate::processor::QPUEmu;
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::ops::Range;
+
+const MAGIC: &[u8; 4] = b"QTRC";
+const VERSION: u32 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Effect {
+    MemoryRead { addr: u32, len: u32 },
+    MemoryWrite { addr: u32, len: u32 },
+    VpmRead { row: u32, lanes: u16 },
+    VpmWrite { row: u32, lanes: u16 },
+    DmaLoad { addr: u32 },
+    DmaStore { addr: u32 },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub qpu: usize,
+    pub thread: usize,
+    pub pc: u32,
+    pub inst: u64,
+    pub lanes: u16,
+    // zf, nf and cf.
+    pub flags: [u16; 3],
+    pub writes: Vec<(Register, [Option<u32>; 16])>,
+    pub effects: Vec<Effect>,
+}
+
+impl TraceEntry {
+    fn new() -> TraceEntry {
+        TraceEntry { qpu: 0, thread: 0, pc: 0, inst: 0, lanes: 0, flags: [0; 3], writes: vec![], effects: vec![] }
+    }
+
+    // Adds an effect, merging it into the previous one if it continues it.
+    fn add_effect(&mut self, effect: Effect) {
+        match (self.effects.last_mut(), effect) {
+            (Some(Effect::MemoryRead { addr, len }), Effect::MemoryRead { addr: next, len: next_len })
+            | (Some(Effect::MemoryWrite { addr, len }), Effect::MemoryWrite { addr: next, len: next_len })
+                if addr.wrapping_add(*len) == next => *len += next_len,
+            (Some(Effect::VpmRead { row, lanes }), Effect::VpmRead { row: next, lanes: next_lanes })
+            | (Some(Effect::VpmWrite { row, lanes }), Effect::VpmWrite { row: next, lanes: next_lanes })
+                if *row == next => *lanes |= next_lanes,
+            _ => self.effects.push(effect),
+        }
+    }
+
+    pub fn write_text<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        writeln!(out, "qpu={} thread={} pc=0x{:08x} inst=0x{:016x} lanes=0x{:04x} zf=0x{:04x} nf=0x{:04x} cf=0x{:04x} {}",
+                 self.qpu, self.thread, self.pc, self.inst, self.lanes, self.flags[0], self.flags[1], self.flags[2],
+                 disassembler::disassemble_at(self.inst, self.pc, &BTreeMap::new()))?;
+        for (reg, values) in &self.writes {
+            let values: Vec<String> = values.iter().map(|value| match value {
+                Some(value) => format!("0x{:08x}", value),
+                None => "-".to_string(),
+            }).collect();
+            writeln!(out, "  write {} {}", reg, values.join(" "))?;
+        }
+        for effect in &self.effects {
+            match effect {
+                Effect::MemoryRead { addr, len } => writeln!(out, "  read mem 0x{:08x} {}", addr, len)?,
+                Effect::MemoryWrite { addr, len } => writeln!(out, "  write mem 0x{:08x} {}", addr, len)?,
+                Effect::VpmRead { row, lanes } => writeln!(out, "  read vpm {} 0x{:04x}", row, lanes)?,
+                Effect::VpmWrite { row, lanes } => writeln!(out, "  write vpm {} 0x{:04x}", row, lanes)?,
+                Effect::DmaLoad { addr } => writeln!(out, "  dma load 0x{:08x}", addr)?,
+                Effect::DmaStore { addr } => writeln!(out, "  dma store 0x{:08x}", addr)?,
+            }
+        }
+        Ok(())
+    }
+
+    pub fn write_binary<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let mut data = vec![];
+        data.extend_from_slice(&(self.qpu as u32).to_le_bytes());
+        data.extend_from_slice(&(self.thread as u32).to_le_bytes());
+        data.extend_from_slice(&self.pc.to_le_bytes());
+        data.extend_from_slice(&self.inst.to_le_bytes());
+        for bits in [self.lanes, self.flags[0], self.flags[1], self.flags[2]].iter() {
+            data.extend_from_slice(&bits.to_le_bytes());
+        }
+        data.push(self.writes.len() as u8);
+        data.push(self.effects.len() as u8);
+        for (reg, values) in &self.writes {
+            data.push(match reg {
+                Register::Acc(idx) => *idx,
+                Register::A(idx) => 32 + idx,
+                Register::B(idx) => 64 + idx,
+            });
+            let mask = values.iter().enumerate().fold(0u16, |mask, (elem, value)| mask | (value.is_some() as u16) << elem);
+            data.extend_from_slice(&mask.to_le_bytes());
+            for value in values.iter().flatten() {
+                data.extend_from_slice(&value.to_le_bytes());
+            }
+        }
+        for effect in &self.effects {
+            let (kind, addr, len) = match *effect {
+                Effect::MemoryRead { addr, len } => (0, addr, len),
+                Effect::MemoryWrite { addr, len } => (1, addr, len),
+                Effect::VpmRead { row, lanes } => (2, row, lanes as u32),
+                Effect::VpmWrite { row, lanes } => (3, row, lanes as u32),
+                Effect::DmaLoad { addr } => (4, addr, 0),
+                Effect::DmaStore { addr } => (5, addr, 0),
+            };
+            data.push(kind);
+            data.extend_from_slice(&addr.to_le_bytes());
+            data.extend_from_slice(&len.to_le_bytes());
+        }
+        out.write_all(&data)
+    }
+}
+
+// Reads the fields of a binary trace.
+struct Reader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], String> {
+        if self.data.len() - self.pos < len {
+            return Err("The trace is truncated.".to_string());
+        }
+        self.pos += len;
+        Ok(&self.data[self.pos - len..self.pos])
+    }
+
+    fn u8(&mut self) -> Result<u8, String> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, String> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, String> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    fn u64(&mut self) -> Result<u64, String> {
+        Ok(self.u32()? as u64 | (self.u32()? as u64) << 32)
+    }
+}
+
+pub fn read_binary(data: &[u8]) -> Result<Vec<TraceEntry>, String> {
+    let mut reader = Reader { data, pos: 0 };
+    if reader.bytes(4).ok() != Some(&MAGIC[..]) {
+        return Err("The file is not a binary trace.".to_string());
+    }
+    let version = reader.u32()?;
+    if version != VERSION {
+        return Err(format!("The trace version {} is not supported.", version));
+    }
+
+    let mut entries = vec![];
+    while reader.pos < data.len() {
+        let mut entry = TraceEntry::new();
+        entry.qpu = reader.u32()? as usize;
+        entry.thread = reader.u32()? as usize;
+        entry.pc = reader.u32()?;
+        entry.inst = reader.u64()?;
+        entry.lanes = reader.u16()?;
+        for flags in entry.flags.iter_mut() {
+            *flags = reader.u16()?;
+        }
+        let (num_writes, num_effects) = (reader.u8()?, reader.u8()?);
+        for _ in 0..num_writes {
+            let reg = match reader.u8()? {
+                idx @ 0..=5 => Register::Acc(idx),
+                idx @ 32..=63 => Register::A(idx - 32),
+                idx @ 64..=95 => Register::B(idx - 64),
+                idx => return Err(format!("Invalid register {}.", idx)),
+            };
+            let mask = reader.u16()?;
+            let mut values = [None; 16];
+            for (elem, value) in values.iter_mut().enumerate() {
+                if mask & 1 << elem != 0 {
+                    *value = Some(reader.u32()?);
+                }
+            }
+            entry.writes.push((reg, values));
+        }
+        for _ in 0..num_effects {
+            let (kind, addr, len) = (reader.u8()?, reader.u32()?, reader.u32()?);
+            entry.effects.push(match kind {
+                0 => Effect::MemoryRead { addr, len },
+                1 => Effect::MemoryWrite { addr, len },
+                2 => Effect::VpmRead { row: addr, lanes: len as u16 },
+                3 => Effect::VpmWrite { row: addr, lanes: len as u16 },
+                4 => Effect::DmaLoad { addr },
+                5 => Effect::DmaStore { addr },
+                _ => return Err(format!("Invalid effect {}.", kind)),
+            });
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TraceFormat {
+    Text,
+    Binary,
+}
+
+// The instructions traced, every one by default.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TraceFilter {
+    // Bus addresses of the instructions, the end excluded.
+    pub pcs: Option<Range<u32>>,
+    pub qpus: Option<Vec<usize>>,
+}
+
+impl TraceFilter {
+    // Option::is_none_or needs Rust 1.82.
+    #[allow(clippy::unnecessary_map_or)]
+    pub fn matches(&self, qpu: usize, pc: u32) -> bool {
+        self.pcs.as_ref().map_or(true, |pcs| pcs.contains(&pc)) && self.qpus.as_ref().map_or(true, |qpus| qpus.contains(&qpu))
+    }
+}
+
+// Hooks which write an entry for each instruction executed. The first write error stops the
+// trace and is returned by flush.
+pub struct Tracer<W: Write> {
+    out: W,
+    format: TraceFormat,
+    filter: TraceFilter,
+    // The effects of the instruction being executed.
+    entry: TraceEntry,
+    // The thread and the QPU of the last instruction, with the instructions retired since the
+    // start of the emulator.
+    last: Option<(usize, usize, u64)>,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> Tracer<W> {
+    pub fn new(mut out: W, format: TraceFormat, filter: TraceFilter) -> Tracer<W> {
+        let mut error = None;
+        if format == TraceFormat::Binary {
+            error = out.write_all(MAGIC).and_then(|_| out.write_all(&VERSION.to_le_bytes())).err();
+        }
+        Tracer { out, format, filter, entry: TraceEntry::new(), last: None, error }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.out
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.error.take() {
+            Some(err) => Err(err),
+            None => self.out.flush(),
+        }
+    }
+}
+
+impl<W: Write> Hooks for Tracer<W> {
+    fn retire(&mut self, emu: &mut QPUEmu, pc: u32, inst: u64) {
+        let mut entry = std::mem::replace(&mut self.entry, TraceEntry::new());
+
+        // A thread starts when the running QPU changes or the emulator is started again.
+        entry.qpu = emu.thread().unwrap_or(0);
+        entry.thread = match self.last {
+            Some((thread, qpu, retired)) if qpu == entry.qpu && retired < emu.retired() => thread,
+            Some((thread, ..)) => thread + 1,
+            None => 0,
+        };
+        self.last = Some((entry.thread, entry.qpu, emu.retired()));
+        entry.pc = emu.code_addr().wrapping_add(pc * 8);
+        if self.error.is_some() || !self.filter.matches(entry.qpu, entry.pc) {
+            return;
+        }
+        entry.inst = inst;
+        let bits = |flags: [bool; 16]| flags.iter().enumerate().fold(0u16, |bits, (elem, flag)| bits | (*flag as u16) << elem);
+        entry.flags = [bits(emu.zero_flags()), bits(emu.negative_flags()), bits(emu.carry_flags())];
+
+        let result = match self.format {
+            TraceFormat::Text => entry.write_text(&mut self.out),
+            TraceFormat::Binary => entry.write_binary(&mut self.out),
+        };
+        self.error = result.err();
+    }
+
+    fn register_write(&mut self, _emu: &QPUEmu, reg: Register, values: &[Option<u32>; 16]) {
+        for (elem, value) in values.iter().enumerate() {
+            self.entry.lanes |= (value.is_some() as u16) << elem;
+        }
+        self.entry.writes.push((reg, *values));
+    }
+
+    fn memory_read(&mut self, _emu: &QPUEmu, addr: u32, len: usize) {
+        self.entry.add_effect(Effect::MemoryRead { addr, len: len as u32 });
+    }
+
+    fn memory_write(&mut self, _emu: &QPUEmu, addr: u32, len: usize) {
+        self.entry.add_effect(Effect::MemoryWrite { addr, len: len as u32 });
+    }
+
+    fn vpm_dma(&mut self, _emu: &QPUEmu, direction: DmaDirection, addr: u32) {
+        self.entry.add_effect(match direction {
+            DmaDirection::Load => Effect::DmaLoad { addr },
+            DmaDirection::Store => Effect::DmaStore { addr },
+        });
+    }
+
+    fn vpm_access(&mut self, _emu: &QPUEmu, row: usize, write: bool, lanes: u16) {
+        self.entry.lanes |= lanes;
+        let row = row as u32;
+        self.entry.add_effect(if write { Effect::VpmWrite { row, lanes } } else { Effect::VpmRead { row, lanes } });
+    }
+}
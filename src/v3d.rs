@@ -0,0 +1,164 @@
+// Emulation of the V3D register block used to submit user programs to the QPUs directly.
+//
+// The registers are addressed by their offsets from the V3D base address. Queued programs
+// are run by dispatch(), which assigns them to the QPUs in order. The other registers, e.g. the
+// cache controls written before a submission, read as 0 and ignore writes.
+
+use crate::processor::QPUEmu;
+use crate::utils::*;
+
+use std::collections::VecDeque;
+
+pub const V3D_IDENT0: u32 = 0x000;
+pub const V3D_IDENT1: u32 = 0x004;
+pub const V3D_IDENT2: u32 = 0x008;
+pub const V3D_SCRATCH: u32 = 0x010;
+pub const V3D_L2CACTL: u32 = 0x020;
+pub const V3D_SLCACTL: u32 = 0x024;
+pub const V3D_SRQPC: u32 = 0x430;
+pub const V3D_SRQUA: u32 = 0x434;
+pub const V3D_SRQUL: u32 = 0x438;
+pub const V3D_SRQCS: u32 = 0x43c;
+pub const V3D_DBCFG: u32 = 0xe00;
+pub const V3D_DBQITE: u32 = 0xe2c;
+pub const V3D_DBQITC: u32 = 0xe30;
+
+pub const NUM_QPUS: usize = 12;
+pub const USER_PROGRAM_QUEUE_SIZE: usize = 16;
+
+// 'V3D' and technology version 2.
+const IDENT0: u32 = 0x0244_3356;
+// VPM size 12KB, 16 semaphores, 2 TMUs and 4 QPUs per slice, 3 slices, revision 1.
+const IDENT1: u32 = 12 << 28 | 16 << 16 | 2 << 12 | 4 << 8 | 3 << 4 | 1;
+// Tile buffer double-buffer mode, 16KB tile buffer and 1KB VRI memory.
+const IDENT2: u32 = 1 << 8 | 4 << 4 | 1;
+
+pub const SRQCS_ERROR: u32 = 1 << 7;
+pub const SRQCS_CLEAR_REQUESTED: u32 = 1 << 8;
+pub const SRQCS_CLEAR_COMPLETED: u32 = 1 << 16;
+
+pub struct UserProgram {
+    pub code_addr: u32,
+    pub uniforms_addr: u32,
+    pub uniforms_len: u32,
+}
+
+pub struct V3D {
+    scratch: u32,
+    uniforms_addr: u32,
+    uniforms_len: u32,
+    queue: VecDeque<UserProgram>,
+    queue_error: bool,
+    requested: u8,
+    completed: u8,
+    next_qpu: usize,
+    interrupt_enable: u16,
+    interrupt_status: u16,
+}
+
+impl Default for V3D {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl V3D {
+    pub fn new() -> Self {
+        V3D {
+            scratch: 0,
+            uniforms_addr: 0,
+            uniforms_len: 0,
+            queue: VecDeque::new(),
+            queue_error: false,
+            requested: 0,
+            completed: 0,
+            next_qpu: 0,
+            interrupt_enable: 0,
+            interrupt_status: 0,
+        }
+    }
+
+    pub fn read(&self, offset: u32) -> u32 {
+        match offset {
+            V3D_IDENT0 => IDENT0,
+            V3D_IDENT1 => IDENT1,
+            V3D_IDENT2 => IDENT2,
+            V3D_SCRATCH => self.scratch,
+            V3D_SRQPC => 0,
+            V3D_SRQUA => self.uniforms_addr,
+            V3D_SRQUL => self.uniforms_len,
+            V3D_SRQCS => {
+                (self.completed as u32) << 16 | (self.requested as u32) << 8
+                    | if self.queue_error { SRQCS_ERROR } else { 0 }
+                    | self.queue.len() as u32
+            },
+            V3D_DBQITE => self.interrupt_enable as u32,
+            V3D_DBQITC => self.interrupt_status as u32,
+            _ => 0,
+        }
+    }
+
+    pub fn write(&mut self, offset: u32, value: u32) {
+        match offset {
+            V3D_IDENT0 | V3D_IDENT1 | V3D_IDENT2 => {},
+            V3D_SCRATCH => self.scratch = value,
+            V3D_SRQPC => self.request_user_program(value),
+            V3D_SRQUA => self.uniforms_addr = value,
+            V3D_SRQUL => self.uniforms_len = get_bits_u32(value, 11, 0),
+            V3D_SRQCS => {
+                if value & SRQCS_ERROR != 0 {
+                    self.queue_error = false;
+                }
+                if value & SRQCS_CLEAR_COMPLETED != 0 {
+                    self.completed = 0;
+                }
+                if value & SRQCS_CLEAR_REQUESTED != 0 {
+                    self.requested = 0;
+                }
+            },
+            V3D_DBQITE => self.interrupt_enable = value as u16,
+            V3D_DBQITC => self.interrupt_status &= !(value as u16),
+            _ => {},
+        }
+    }
+
+    // Writing the program address pushes a request with the uniforms set up beforehand.
+    fn request_user_program(&mut self, code_addr: u32) {
+        if self.queue.len() >= USER_PROGRAM_QUEUE_SIZE {
+            self.queue_error = true;
+            return;
+        }
+
+        self.queue.push_back(UserProgram {
+            code_addr,
+            uniforms_addr: self.uniforms_addr,
+            uniforms_len: self.uniforms_len,
+        });
+        self.requested = self.requested.wrapping_add(1);
+    }
+
+    pub fn pending(&self) -> usize {
+        self.queue.len()
+    }
+
+    // Runs the queued programs to completion. A program which raises a host interrupt sets
+    // the status bit of its QPU if that interrupt is enabled. Returns the number of programs.
+    pub fn dispatch(&mut self, emu: &mut QPUEmu) -> usize {
+        let mut count = 0;
+
+        while let Some(program) = self.queue.pop_front() {
+            let qpu = self.next_qpu;
+            self.next_qpu = (self.next_qpu + 1) % NUM_QPUS;
+
+            let interrupts = emu.host_interrupts();
+            emu.execute_at(program.code_addr, program.uniforms_addr);
+
+            if emu.host_interrupts() != interrupts {
+                self.interrupt_status |= self.interrupt_enable & (1 << qpu);
+            }
+            self.completed = self.completed.wrapping_add(1);
+            count += 1;
+        }
+        count
+    }
+}